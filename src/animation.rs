@@ -0,0 +1,514 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use image::codecs::webp::WebPEncoder;
+use image::error::{DecodingError, LimitError, LimitErrorKind};
+use image::imageops::FilterType;
+use image::{
+    ColorType, DynamicImage, GrayImage, ImageError, ImageFormat, ImageResult, RgbImage, RgbaImage,
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    Gif,
+    Apng,
+    WebP,
+}
+
+/// What happens to the frame's area before the next frame is drawn.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Disposal {
+    None,
+    Background,
+    Previous,
+}
+
+/// How the frame is drawn over the canvas.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Blend {
+    Source,
+    Over,
+}
+
+pub struct Frame {
+    pub image: RgbaImage,
+    pub left: u32,
+    pub top: u32,
+    /// Frame duration in seconds, as a (numerator, denominator) fraction
+    pub delay: (u32, u32),
+    pub disposal: Disposal,
+    pub blend: Blend,
+}
+
+pub struct Animation {
+    pub format: Format,
+    pub width: u32,
+    pub height: u32,
+    /// Number of times the animation is played, 0 for forever
+    pub plays: u32,
+    /// Background colour as RGBA, only used by WebP
+    pub background: [u8; 4],
+    pub frames: Vec<Frame>,
+}
+
+/// Returns the format of `path` if it should go through the frame-by-frame path.
+/// Every GIF does, PNG and WebP files only when they contain an animation.
+pub fn format(path: &Path) -> Option<Format> {
+    let mut header = [0u8; 64];
+    let len = File::open(path).ok()?.read(&mut header).ok()?;
+    let header = &header[..len];
+    if header.starts_with(b"GIF8") {
+        Some(Format::Gif)
+    } else if header.starts_with(b"\x89PNG") {
+        let decoder = png::Decoder::new(BufReader::new(File::open(path).ok()?));
+        let reader = decoder.read_info().ok()?;
+        reader.info().animation_control.map(|_| Format::Apng)
+    } else if header.len() >= 21 && &header[..4] == b"RIFF" && &header[8..16] == b"WEBPVP8X" {
+        // Animation flag of the extended header
+        if header[20] & 0x02 != 0 {
+            Some(Format::WebP)
+        } else {
+            None
+        }
+    } else {
+        None
+    }
+}
+
+pub fn read(path: &Path, format: Format) -> ImageResult<Animation> {
+    match format {
+        Format::Gif => read_gif(path),
+        Format::Apng => read_apng(path),
+        Format::WebP => read_webp(path),
+    }
+}
+
+pub fn write(animation: &Animation, path: &Path) -> ImageResult<()> {
+    match animation.format {
+        Format::Gif => write_gif(animation, path),
+        Format::Apng => write_apng(animation, path),
+        Format::WebP => write_webp(animation, path),
+    }
+}
+
+impl Animation {
+    /// Resize the animation to `width` x `height`. Frame offsets and sizes are
+    /// scaled by the same factors so disposal and blending keep their meaning.
+    ///
+    /// `upscale` is given the RGB content of each frame and the size it must
+    /// come back at; the alpha channel is resized on its own.
+    pub fn scaled(
+        self,
+        width: u32,
+        height: u32,
+        mut upscale: impl FnMut(DynamicImage, u32, u32) -> RgbImage,
+    ) -> Animation {
+        let sx = width as f64 / self.width as f64;
+        let sy = height as f64 / self.height as f64;
+        let frames = self
+            .frames
+            .into_iter()
+            .map(|frame| {
+                let left = (frame.left as f64 * sx).round() as u32;
+                let top = (frame.top as f64 * sy).round() as u32;
+                // Frames lying outside of the canvas keep a pixel
+                let w = u32::min(
+                    (frame.image.width() as f64 * sx).round() as u32,
+                    width.saturating_sub(left),
+                )
+                .max(1);
+                let h = u32::min(
+                    (frame.image.height() as f64 * sy).round() as u32,
+                    height.saturating_sub(top),
+                )
+                .max(1);
+                let alpha =
+                    GrayImage::from_fn(frame.image.width(), frame.image.height(), |x, y| {
+                        image::Luma([frame.image.get_pixel(x, y)[3]])
+                    });
+                let alpha = image::imageops::resize(&alpha, w, h, FilterType::Triangle);
+                let rgb = upscale(DynamicImage::ImageRgba8(frame.image), w, h);
+                let image = RgbaImage::from_fn(w, h, |x, y| {
+                    let [r, g, b] = rgb.get_pixel(x, y).0;
+                    image::Rgba([r, g, b, alpha.get_pixel(x, y)[0]])
+                });
+                Frame {
+                    image,
+                    left,
+                    top,
+                    ..frame
+                }
+            })
+            .collect();
+        Animation {
+            width,
+            height,
+            frames,
+            ..self
+        }
+    }
+}
+
+fn read_gif(path: &Path) -> ImageResult<Animation> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options
+        .read_info(BufReader::new(File::open(path)?))
+        .map_err(codec_error)?;
+    let mut frames = vec![];
+    while let Some(frame) = decoder.read_next_frame().map_err(codec_error)? {
+        frames.push(Frame {
+            image: RgbaImage::from_raw(
+                frame.width as u32,
+                frame.height as u32,
+                frame.buffer.to_vec(),
+            )
+            .unwrap(),
+            left: frame.left as u32,
+            top: frame.top as u32,
+            delay: (frame.delay as u32, 100),
+            disposal: match frame.dispose {
+                gif::DisposalMethod::Background => Disposal::Background,
+                gif::DisposalMethod::Previous => Disposal::Previous,
+                _ => Disposal::None,
+            },
+            blend: Blend::Over,
+        });
+    }
+    // The loop extension is only known once the frames have been read.
+    // GIF counts repetitions after the first play, and no extension means play once.
+    let plays = match decoder.repeat() {
+        gif::Repeat::Infinite => 0,
+        gif::Repeat::Finite(n) => n as u32 + 1,
+    };
+    Ok(Animation {
+        format: Format::Gif,
+        width: decoder.width() as u32,
+        height: decoder.height() as u32,
+        plays,
+        background: [0; 4],
+        frames,
+    })
+}
+
+fn write_gif(animation: &Animation, path: &Path) -> ImageResult<()> {
+    // Sizes and offsets are u16 in GIF
+    let too_big = |v: u32| v > u16::MAX as u32;
+    if too_big(animation.width)
+        || too_big(animation.height)
+        || animation.frames.iter().any(|frame| {
+            too_big(frame.left + frame.image.width()) || too_big(frame.top + frame.image.height())
+        })
+    {
+        return Err(ImageError::Limits(LimitError::from_kind(
+            LimitErrorKind::DimensionError,
+        )));
+    }
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = gif::Encoder::new(file, animation.width as u16, animation.height as u16, &[])
+        .map_err(codec_error)?;
+    match animation.plays {
+        0 => encoder.set_repeat(gif::Repeat::Infinite),
+        1 => Ok(()),
+        n => encoder.set_repeat(gif::Repeat::Finite((n - 1) as u16)),
+    }
+    .map_err(codec_error)?;
+    for frame in &animation.frames {
+        // GIF only has on/off transparency
+        let mut pixels = frame.image.clone().into_raw();
+        pixels.chunks_exact_mut(4).for_each(|p| {
+            p[3] = if p[3] < 128 { 0 } else { 255 };
+        });
+        let mut out = gif::Frame::from_rgba_speed(
+            frame.image.width() as u16,
+            frame.image.height() as u16,
+            &mut pixels,
+            10,
+        );
+        out.left = frame.left as u16;
+        out.top = frame.top as u16;
+        out.delay = (frame.delay.0 * 100 / frame.delay.1.max(1)) as u16;
+        out.dispose = match frame.disposal {
+            Disposal::None => gif::DisposalMethod::Keep,
+            Disposal::Background => gif::DisposalMethod::Background,
+            Disposal::Previous => gif::DisposalMethod::Previous,
+        };
+        encoder.write_frame(&out).map_err(codec_error)?;
+    }
+    Ok(())
+}
+
+fn read_apng(path: &Path) -> ImageResult<Animation> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(codec_error)?;
+    let info = reader.info();
+    let (width, height) = (info.width, info.height);
+    let control = info.animation_control.unwrap();
+    let mut buf = vec![0; reader.output_buffer_size()];
+    if reader.info().frame_control.is_none() {
+        // The default image is not part of the animation
+        reader.next_frame(&mut buf).map_err(codec_error)?;
+    }
+    let mut frames = vec![];
+    for _ in 0..control.num_frames {
+        let output = reader.next_frame(&mut buf).map_err(codec_error)?;
+        let fc = reader.info().frame_control.unwrap();
+        let data = &buf[..output.buffer_size()];
+        let rgba: Vec<u8> = match output.color_type {
+            png::ColorType::Rgba => data.to_vec(),
+            png::ColorType::Rgb => data
+                .chunks_exact(3)
+                .flat_map(|p| [p[0], p[1], p[2], 255])
+                .collect(),
+            png::ColorType::GrayscaleAlpha => data
+                .chunks_exact(2)
+                .flat_map(|p| [p[0], p[0], p[0], p[1]])
+                .collect(),
+            _ => data.iter().flat_map(|&p| [p, p, p, 255]).collect(),
+        };
+        frames.push(Frame {
+            image: RgbaImage::from_raw(output.width, output.height, rgba).unwrap(),
+            left: fc.x_offset,
+            top: fc.y_offset,
+            // A zero denominator means hundredths of a second
+            delay: (
+                fc.delay_num as u32,
+                if fc.delay_den == 0 {
+                    100
+                } else {
+                    fc.delay_den as u32
+                },
+            ),
+            disposal: match fc.dispose_op {
+                png::DisposeOp::None => Disposal::None,
+                png::DisposeOp::Background => Disposal::Background,
+                png::DisposeOp::Previous => Disposal::Previous,
+            },
+            blend: match fc.blend_op {
+                png::BlendOp::Source => Blend::Source,
+                png::BlendOp::Over => Blend::Over,
+            },
+        });
+    }
+    Ok(Animation {
+        format: Format::Apng,
+        width,
+        height,
+        plays: control.num_plays,
+        background: [0; 4],
+        frames,
+    })
+}
+
+fn write_apng(animation: &Animation, path: &Path) -> ImageResult<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, animation.width, animation.height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .set_animated(animation.frames.len() as u32, animation.plays)
+        .map_err(codec_error)?;
+    let mut writer = encoder.write_header().map_err(codec_error)?;
+    for frame in &animation.frames {
+        // The delay fraction is stored as two u16
+        let (mut num, mut den) = frame.delay;
+        while num > u16::MAX as u32 || den > u16::MAX as u32 {
+            num /= 2;
+            den /= 2;
+        }
+        writer
+            .set_frame_dimension(frame.image.width(), frame.image.height())
+            .and_then(|_| writer.set_frame_position(frame.left, frame.top))
+            .and_then(|_| writer.set_frame_delay(num as u16, den.max(1) as u16))
+            .and_then(|_| {
+                writer.set_dispose_op(match frame.disposal {
+                    Disposal::None => png::DisposeOp::None,
+                    Disposal::Background => png::DisposeOp::Background,
+                    Disposal::Previous => png::DisposeOp::Previous,
+                })
+            })
+            .and_then(|_| {
+                writer.set_blend_op(match frame.blend {
+                    Blend::Source => png::BlendOp::Source,
+                    Blend::Over => png::BlendOp::Over,
+                })
+            })
+            .and_then(|_| writer.write_image_data(frame.image.as_raw()))
+            .map_err(codec_error)?;
+    }
+    writer.finish().map_err(codec_error)
+}
+
+// WebP is a RIFF container. The animated flavour is a VP8X header followed by
+// an ANIM chunk and one ANMF chunk per frame, each wrapping a still bitstream.
+fn riff_chunks(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut chunks = vec![];
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let fourcc: [u8; 4] = data[pos..pos + 4].try_into().unwrap();
+        let size = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let end = usize::min(pos + 8 + size, data.len());
+        chunks.push((fourcc, &data[pos + 8..end]));
+        // Chunks are padded to an even size
+        pos = end + (size & 1);
+    }
+    chunks
+}
+
+fn write_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() & 1 == 1 {
+        out.push(0);
+    }
+}
+
+fn riff(chunks: &[u8]) -> Vec<u8> {
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+    out.extend_from_slice(b"WEBP");
+    out.extend_from_slice(chunks);
+    out
+}
+
+fn u24(data: &[u8]) -> u32 {
+    data[0] as u32 | (data[1] as u32) << 8 | (data[2] as u32) << 16
+}
+
+fn put_u24(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes()[..3]);
+}
+
+fn vp8x(out: &mut Vec<u8>, flags: u8, width: u32, height: u32) {
+    let mut header = vec![flags, 0, 0, 0];
+    put_u24(&mut header, width - 1);
+    put_u24(&mut header, height - 1);
+    write_chunk(out, b"VP8X", &header);
+}
+
+fn read_webp(path: &Path) -> ImageResult<Animation> {
+    let data = std::fs::read(path)?;
+    let mut animation = Animation {
+        format: Format::WebP,
+        width: 0,
+        height: 0,
+        plays: 0,
+        background: [0; 4],
+        frames: vec![],
+    };
+    if data.len() < 12 {
+        return Err(webp_error("Truncated RIFF header".to_string()));
+    }
+    for (fourcc, chunk) in riff_chunks(&data[12..]) {
+        // Chunks get clamped to the end of the file, so check their fields fit
+        let min_len = match &fourcc {
+            b"VP8X" => 10,
+            b"ANIM" => 6,
+            b"ANMF" => 16,
+            _ => 0,
+        };
+        if chunk.len() < min_len {
+            return Err(webp_error(format!(
+                "Truncated {} chunk",
+                String::from_utf8_lossy(&fourcc)
+            )));
+        }
+        match &fourcc {
+            b"VP8X" => {
+                animation.width = u24(&chunk[4..]) + 1;
+                animation.height = u24(&chunk[7..]) + 1;
+            }
+            b"ANIM" => {
+                let [b, g, r, a] = chunk[..4].try_into().unwrap();
+                animation.background = [r, g, b, a];
+                animation.plays = u16::from_le_bytes([chunk[4], chunk[5]]) as u32;
+            }
+            b"ANMF" => {
+                let (w, h) = (u24(&chunk[6..]) + 1, u24(&chunk[9..]) + 1);
+                // Rebuild a still WebP from the frame's bitstream chunks
+                let mut still = vec![];
+                let subchunks = riff_chunks(&chunk[16..]);
+                if subchunks.iter().any(|(fourcc, _)| fourcc == b"ALPH") {
+                    vp8x(&mut still, 0x10, w, h);
+                }
+                for (fourcc, data) in subchunks {
+                    write_chunk(&mut still, &fourcc, data);
+                }
+                let image = image::load_from_memory_with_format(&riff(&still), ImageFormat::WebP)?;
+                animation.frames.push(Frame {
+                    image: image.to_rgba8(),
+                    left: u24(chunk) * 2,
+                    top: u24(&chunk[3..]) * 2,
+                    delay: (u24(&chunk[12..]), 1000),
+                    disposal: if chunk[15] & 0x01 != 0 {
+                        Disposal::Background
+                    } else {
+                        Disposal::None
+                    },
+                    blend: if chunk[15] & 0x02 != 0 {
+                        Blend::Source
+                    } else {
+                        Blend::Over
+                    },
+                });
+            }
+            _ => {}
+        }
+    }
+    if animation.width == 0 {
+        return Err(webp_error("No VP8X chunk".to_string()));
+    }
+    Ok(animation)
+}
+
+fn write_webp(animation: &Animation, path: &Path) -> ImageResult<()> {
+    let mut chunks = vec![];
+    // Animation and alpha flags
+    vp8x(&mut chunks, 0x12, animation.width, animation.height);
+    let [r, g, b, a] = animation.background;
+    let mut anim = vec![b, g, r, a];
+    anim.extend_from_slice(&(animation.plays.min(u16::MAX as u32) as u16).to_le_bytes());
+    write_chunk(&mut chunks, b"ANIM", &anim);
+    for frame in &animation.frames {
+        let mut still = vec![];
+        WebPEncoder::new_lossless(&mut still).encode(
+            frame.image.as_raw(),
+            frame.image.width(),
+            frame.image.height(),
+            ColorType::Rgba8,
+        )?;
+        let mut anmf = vec![];
+        // Offsets are stored halved, odd offsets get rounded down
+        put_u24(&mut anmf, frame.left / 2);
+        put_u24(&mut anmf, frame.top / 2);
+        put_u24(&mut anmf, frame.image.width() - 1);
+        put_u24(&mut anmf, frame.image.height() - 1);
+        put_u24(&mut anmf, frame.delay.0 * 1000 / frame.delay.1.max(1));
+        let mut flags = 0;
+        if frame.disposal == Disposal::Background {
+            flags |= 0x01;
+        }
+        if frame.blend == Blend::Source {
+            flags |= 0x02;
+        }
+        anmf.push(flags);
+        for (fourcc, data) in riff_chunks(&still[12..]) {
+            write_chunk(&mut anmf, &fourcc, data);
+        }
+        write_chunk(&mut chunks, b"ANMF", &anmf);
+    }
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&riff(&chunks))?;
+    Ok(())
+}
+
+fn webp_error(message: String) -> ImageError {
+    ImageError::Decoding(DecodingError::new(ImageFormat::WebP.into(), message))
+}
+
+fn codec_error<E: std::error::Error + Send + Sync + 'static>(err: E) -> image::ImageError {
+    image::ImageError::IoError(std::io::Error::new(std::io::ErrorKind::Other, err))
+}
@@ -0,0 +1,169 @@
+use std::time::{Duration, Instant};
+
+use candle_core::{DType, Device, Tensor};
+use clap::builder::RangedU64ValueParser;
+use esrgan_candle_rs::profile::{synchronize, Profiler};
+use esrgan_candle_rs::synthetic::SyntheticModel;
+
+use crate::{
+    arch_names, exit_with_error, forward, load_model, model_from_state_dict, LoadedModel,
+    ModelArgs, Precision,
+};
+
+/// Measure the speed of a model
+#[derive(clap::Args, Debug)]
+pub struct BenchArgs {
+    /// Path to the model file in safetensors format
+    #[arg(short, long, required_unless_present = "synthetic")]
+    model: Option<String>,
+
+    /// Benchmark a randomly initialised model of this architecture, with the
+    /// hyperparameters of the official releases, instead of a model file
+    #[arg(long, value_parser = arch_names(), conflicts_with = "model")]
+    synthetic: Option<String>,
+
+    /// Device to run the model on
+    /// -1 for CPU, 0 for GPU 0, 1 for GPU 1, etc.
+    #[arg(short, long, default_value = "-1")]
+    device: i32,
+
+    #[command(flatten)]
+    model_args: ModelArgs,
+
+    /// Input sizes as WIDTHxHEIGHT, can be given several times
+    #[arg(long, value_parser = parse_size, default_value = "128x128")]
+    size: Vec<(usize, usize)>,
+
+    /// Precisions to run the model in, can be given several times
+    #[arg(long, value_enum, default_value = "f32")]
+    dtype: Vec<Precision>,
+
+    /// Precision the weights are kept in, each --dtype by default
+    #[arg(long, value_enum)]
+    weight_dtype: Option<Precision>,
+
+    /// Batch sizes, can be given several times
+    #[arg(short, long, default_value = "1")]
+    batch: Vec<usize>,
+
+    /// Untimed iterations run first, to warm up caches and kernels
+    #[arg(long, default_value = "2")]
+    warmup: usize,
+
+    /// Timed iterations
+    #[arg(long, default_value = "10", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    iters: usize,
+
+    /// Also time every module of the model (conv_first, each RRDB, upsampling, ...)
+    #[arg(long)]
+    per_layer: bool,
+}
+
+fn parse_size(s: &str) -> Result<(usize, usize), String> {
+    let (w, h) = s
+        .split_once('x')
+        .ok_or(format!("expected WIDTHxHEIGHT, got {s}"))?;
+    let parse = |v: &str| v.parse::<usize>().map_err(|err| format!("{v}: {err}"));
+    Ok((parse(w)?, parse(h)?))
+}
+
+fn load(args: &BenchArgs, device: &Device, dtype: DType) -> LoadedModel {
+    match (&args.model, &args.synthetic) {
+        (Some(path), _) => load_model(path, &args.model_args, device, dtype),
+        (None, Some(arch)) => {
+            let state_dict = SyntheticModel::new(arch).state_dict(device).unwrap();
+            model_from_state_dict(state_dict, &args.model_args, device, dtype)
+                .unwrap_or_else(|err| exit_with_error(err))
+        }
+        (None, None) => unreachable!("clap requires --model or --synthetic"),
+    }
+}
+
+fn forward_profiled(model: &LoadedModel, xs: &Tensor, profiler: &mut Profiler) -> Tensor {
+    model.upscaler.forward_profiled(xs, profiler).unwrap()
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.
+}
+
+/// Value below which `p` percent of the sorted `times` fall
+fn percentile(times: &[Duration], p: f64) -> Duration {
+    let i = ((times.len() as f64 * p / 100.).ceil() as usize).clamp(1, times.len());
+    times[i - 1]
+}
+
+pub fn bench(args: &BenchArgs) {
+    let device = match args.device {
+        -1 => Device::Cpu,
+        _ => Device::new_cuda(args.device as usize).unwrap(),
+    };
+
+    println!(
+        "{:>11} {:>5} {:>5} {:>10} {:>10} {:>10} {:>10}",
+        "size", "dtype", "batch", "mean", "median", "p95", "MP/s out"
+    );
+    for &precision in &args.dtype {
+        let dtype = precision.compute_dtype(&device);
+        let model = load(
+            args,
+            &device,
+            args.weight_dtype.unwrap_or(precision).dtype(),
+        );
+        let in_nc = model.descriptor.in_nc;
+        for &(width, height) in &args.size {
+            for &batch in &args.batch {
+                let input = Tensor::rand(0f32, 1., (batch, in_nc, height, width), &device)
+                    .unwrap()
+                    .to_dtype(dtype)
+                    .unwrap();
+                for _ in 0..args.warmup {
+                    synchronize(&forward(&model, &input, None)).unwrap();
+                }
+
+                let mut times = vec![];
+                let mut out_pixels = 0;
+                let mut profiler = Profiler::new();
+                for _ in 0..args.iters {
+                    let now = Instant::now();
+                    let output = if args.per_layer {
+                        profiler.start();
+                        forward_profiled(&model, &input, &mut profiler)
+                    } else {
+                        forward(&model, &input, None)
+                    };
+                    synchronize(&output).unwrap();
+                    times.push(now.elapsed());
+                    let (b_size, _channels, h, w) = output.dims4().unwrap();
+                    out_pixels = b_size * h * w;
+                }
+                times.sort();
+
+                let mean = times.iter().sum::<Duration>() / times.len() as u32;
+                println!(
+                    "{:>11} {:>5} {:>5} {:>8.2}ms {:>8.2}ms {:>8.2}ms {:>10.3}",
+                    format!("{width}x{height}"),
+                    format!("{precision:?}").to_lowercase(),
+                    batch,
+                    millis(mean),
+                    millis(percentile(&times, 50.)),
+                    millis(percentile(&times, 95.)),
+                    out_pixels as f64 / 1e6 / mean.as_secs_f64(),
+                );
+
+                if args.per_layer {
+                    let timings = profiler.timings();
+                    let total: Duration = timings.iter().map(|(_, t)| *t).sum();
+                    for (name, time) in timings {
+                        println!(
+                            "{:>17} {:>25.2}ms {:>9.1}%",
+                            name,
+                            millis(time) / args.iters as f64,
+                            100. * time.as_secs_f64() / total.as_secs_f64()
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
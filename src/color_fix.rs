@@ -0,0 +1,113 @@
+use candle_core::{DType, Result, Tensor, D};
+use clap::ValueEnum;
+
+use crate::resize::{resize, ResizeFilter};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum ColorFix {
+    /// Match the per-channel mean and standard deviation of the input
+    MeanStd,
+    /// Take the low frequencies of the input from a wavelet decomposition
+    Wavelet,
+    /// Take the low frequencies of the input from a Gaussian blur
+    Gaussian,
+}
+
+/// Correct the colours of `output` using the model's `input`.
+/// Both are (batch, channels, height, width) tensors, `output` may be bigger.
+pub fn color_fix(output: &Tensor, input: &Tensor, method: ColorFix) -> Result<Tensor> {
+    match method {
+        ColorFix::MeanStd => mean_std(output, input),
+        ColorFix::Wavelet => {
+            let input = upscale_like(input, output)?;
+            let (output_high, _) = wavelet_decomposition(output, 5)?;
+            let (_, input_low) = wavelet_decomposition(&input, 5)?;
+            output_high + input_low
+        }
+        ColorFix::Gaussian => {
+            // Everything finer than one input pixel comes from the model
+            let sigma = (output.dim(2)? as f64 / input.dim(2)? as f64).max(1.);
+            let input = upscale_like(input, output)?;
+            let output_high = (output - gaussian_blur(output, sigma)?)?;
+            output_high + gaussian_blur(&input, sigma)?
+        }
+    }
+}
+
+fn upscale_like(input: &Tensor, output: &Tensor) -> Result<Tensor> {
+    let (_b_size, _channels, h, w) = output.dims4()?;
+    resize(input, h, w, ResizeFilter::Bicubic)
+}
+
+fn channel_stats(xs: &Tensor) -> Result<(Tensor, Tensor)> {
+    let xs = xs.to_dtype(DType::F32)?.flatten_from(2)?;
+    let mean = xs.mean_keepdim(D::Minus1)?;
+    let var = xs.broadcast_sub(&mean)?.sqr()?.mean_keepdim(D::Minus1)?;
+    Ok((mean.unsqueeze(3)?, (var + 1e-5)?.sqrt()?.unsqueeze(3)?))
+}
+
+fn mean_std(output: &Tensor, input: &Tensor) -> Result<Tensor> {
+    let (output_mean, output_std) = channel_stats(output)?;
+    let (input_mean, input_std) = channel_stats(input)?;
+    let dtype = output.dtype();
+    output
+        .to_dtype(DType::F32)?
+        .broadcast_sub(&output_mean)?
+        .broadcast_div(&output_std)?
+        .broadcast_mul(&input_std)?
+        .broadcast_add(&input_mean)?
+        .to_dtype(dtype)
+}
+
+// Depthwise convolution with replicate padding, one kernel for every channel.
+fn depthwise(xs: &Tensor, kernel: &Tensor, pad: (usize, usize), dilation: usize) -> Result<Tensor> {
+    let channels = xs.dim(1)?;
+    let (kh, kw) = kernel.dims2()?;
+    let kernel = kernel
+        .to_dtype(xs.dtype())?
+        .reshape((1, 1, kh, kw))?
+        .repeat((channels, 1, 1, 1))?;
+    let xs = xs
+        .pad_with_same(2, pad.0, pad.0)?
+        .pad_with_same(3, pad.1, pad.1)?;
+    xs.conv2d(&kernel, 0, 1, dilation, channels)
+}
+
+fn wavelet_blur(xs: &Tensor, radius: usize) -> Result<Tensor> {
+    let kernel = Tensor::new(
+        &[
+            [0.0625f32, 0.125, 0.0625],
+            [0.125, 0.25, 0.125],
+            [0.0625, 0.125, 0.0625],
+        ],
+        xs.device(),
+    )?;
+    depthwise(xs, &kernel, (radius, radius), radius)
+}
+
+// Returns the sum of all high-frequency bands and the final low-frequency band.
+fn wavelet_decomposition(xs: &Tensor, levels: usize) -> Result<(Tensor, Tensor)> {
+    let mut high = xs.zeros_like()?;
+    let mut low = xs.clone();
+    for i in 0..levels {
+        let blurred = wavelet_blur(&low, 1 << i)?;
+        high = (high + (&low - &blurred)?)?;
+        low = blurred;
+    }
+    Ok((high, low))
+}
+
+fn gaussian_blur(xs: &Tensor, sigma: f64) -> Result<Tensor> {
+    let radius = (3. * sigma).ceil() as usize;
+    let weights: Vec<f32> = (0..2 * radius + 1)
+        .map(|i| {
+            let x = i as f64 - radius as f64;
+            (-x * x / (2. * sigma * sigma)).exp() as f32
+        })
+        .collect();
+    let total: f32 = weights.iter().sum();
+    let weights: Vec<f32> = weights.iter().map(|w| w / total).collect();
+    let row = Tensor::from_vec(weights, (1, 2 * radius + 1), xs.device())?;
+    let xs = depthwise(xs, &row, (0, radius), 1)?;
+    depthwise(&xs, &row.t()?.contiguous()?, (radius, 0), 1)
+}
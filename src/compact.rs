@@ -1,6 +1,9 @@
-use candle_core::{Result, Tensor};
+use candle_core::{Module, Result, Tensor};
 use candle_nn as nn;
 
+use crate::conv::{conv2d, Conv2d};
+use crate::profile::Profiler;
+
 // pub enum ActType {
 //     PReLU,
 //     LeakyReLU,
@@ -8,7 +11,9 @@ use candle_nn as nn;
 // }
 
 pub struct SRVGGNetCompact {
-    body: nn::Sequential,
+    // The body alternates convs and PReLUs, with one more conv at the end
+    convs: Vec<Conv2d>,
+    prelus: Vec<nn::PReLU>,
     upscale: usize,
 }
 
@@ -28,19 +33,17 @@ impl SRVGGNetCompact {
             dilation: 1,
             groups: 1,
         };
-        let mut body = nn::seq();
-        // let mut body = Sequential {
-        //     modules: Vec::with_capacity(2 * num_conv + 3),
-        // };
-        body = body.add(nn::conv2d(num_in_ch, num_feat, 3, config, vb.pp("body.0"))?);
-        body = body.add(nn::prelu(Option::from(num_feat), vb.pp("body.1"))?);
+        let mut convs = vec![];
+        let mut prelus = vec![];
+        convs.push(conv2d(num_in_ch, num_feat, 3, config, vb.pp("body.0"))?);
+        prelus.push(nn::prelu(Option::from(num_feat), vb.pp("body.1"))?);
         // match act_type {
         //     ActType::PReLU => body.add(nn::prelu(Option::from(num_feat), vb.pp("body.1"))?),
         //     ActType::LeakyReLU => body.add(nn::Activation::LeakyRelu(0.1)),
         //     ActType::ReLU => body.add(nn::Activation::Relu),
         // };
         for i in 0..num_conv {
-            body = body.add(nn::conv2d(
+            convs.push(conv2d(
                 num_feat,
                 num_feat,
                 3,
@@ -49,7 +52,7 @@ impl SRVGGNetCompact {
             )?);
             // match act_type {
             // ActType::PReLU =>
-            body = body.add(nn::prelu(
+            prelus.push(nn::prelu(
                 Option::from(num_feat),
                 vb.pp(&format!("body.{}", 2 * i + 3)),
             )?);
@@ -58,24 +61,54 @@ impl SRVGGNetCompact {
             // ActType::ReLU => body.add(nn::Activation::Relu),
             // };
         }
-        let body = body.add(nn::conv2d(
+        convs.push(conv2d(
             num_feat,
             num_out_ch * upscale * upscale,
             3,
             config,
             vb.pp(&format!("body.{}", 2 * num_conv + 2)),
         )?);
-        Ok(Self { body, upscale })
+        Ok(Self {
+            convs,
+            prelus,
+            upscale,
+        })
+    }
+
+    /// Every conv of the model, for `conv::quantize`
+    pub fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        self.convs.iter_mut().collect()
     }
 }
 
-impl nn::Module for SRVGGNetCompact {
-    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+impl SRVGGNetCompact {
+    /// `forward`, recording the time of the body and the upsampling in `profiler`
+    pub fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
         let (_b_size, _channels, h, w) = xs.dims4()?;
+        let mut out = xs.clone();
+        for (i, conv) in self.convs.iter().enumerate() {
+            out = conv.forward(&out)?;
+            if let Some(prelu) = self.prelus.get(i) {
+                out = if prelu.weight().dtype() == out.dtype() {
+                    prelu.forward(&out)?
+                } else {
+                    // Weights kept in another dtype than the activations
+                    let weight = prelu.weight().to_dtype(out.dtype())?;
+                    nn::PReLU::new(weight, prelu.is_scalar()).forward(&out)?
+                };
+            }
+        }
+        profiler.record("body", &out)?;
         let base = xs.upsample_nearest2d(self.upscale * h, self.upscale * w)?;
-        let out = self.body.forward(xs)?;
         let out = nn::ops::pixel_shuffle(&out, self.upscale)?;
         let out = (base + out)?;
+        profiler.record("upsampling", &out)?;
         Ok(out)
     }
 }
+
+impl nn::Module for SRVGGNetCompact {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.forward_profiled(xs, &mut Profiler::disabled())
+    }
+}
@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+
+use candle_core::Tensor;
+
+// Compact models are a flat `body` sequence: conv, prelu, (conv, prelu) * num_conv, conv
+
+fn highest_body_layer(state_dict: &HashMap<String, Tensor>) -> usize {
+    return state_dict
+        .keys()
+        .filter(|x| x.starts_with("body."))
+        .map(|x| x.split(".").collect::<Vec<&str>>()[1])
+        .map(|y| y.parse::<usize>().unwrap())
+        .max()
+        .unwrap_or(0);
+}
+
+pub fn get_in_nc(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("body.0.weight") {
+        Some(x) => x.shape().dims()[1],
+        None => 3,
+    };
+}
+
+pub fn get_nf(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("body.0.weight") {
+        Some(x) => x.shape().dims()[0],
+        None => 64,
+    };
+}
+
+pub fn get_num_conv(state_dict: &HashMap<String, Tensor>) -> usize {
+    return (highest_body_layer(state_dict).max(2) - 2) / 2;
+}
+
+pub fn get_scale(state_dict: &HashMap<String, Tensor>) -> usize {
+    // The last conv outputs out_nc * scale * scale channels for the pixel shuffle,
+    // and the output channel count matches the input one.
+    let last = highest_body_layer(state_dict);
+    return match state_dict.get(&format!("body.{last}.weight")) {
+        Some(x) => {
+            let channels = x.shape().dims()[0] / get_in_nc(state_dict);
+            (channels as f64).sqrt().round() as usize
+        }
+        None => 4,
+    };
+}
@@ -0,0 +1,245 @@
+use candle_core::quantized::{GgmlDType, QMatMul, QTensor};
+use candle_core::{DType, Module, Result, Tensor};
+use candle_nn as nn;
+use clap::ValueEnum;
+
+/// Block-quantised weight formats of candle (the ggml ones) usable for convs.
+/// The k-quants are left out, their 256-value blocks don't divide the
+/// `in_channels * 9` values of most convs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Quantization {
+    /// 8-bit integers with an f16 scale per block of 32
+    #[value(name = "q8_0")]
+    Q8_0,
+    #[value(name = "q5_1")]
+    Q5_1,
+    #[value(name = "q5_0")]
+    Q5_0,
+    #[value(name = "q4_1")]
+    Q4_1,
+    #[value(name = "q4_0")]
+    Q4_0,
+}
+
+impl Quantization {
+    pub fn ggml_dtype(&self) -> GgmlDType {
+        match self {
+            Quantization::Q8_0 => GgmlDType::Q8_0,
+            Quantization::Q5_1 => GgmlDType::Q5_1,
+            Quantization::Q5_0 => GgmlDType::Q5_0,
+            Quantization::Q4_1 => GgmlDType::Q4_1,
+            Quantization::Q4_0 => GgmlDType::Q4_0,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Quantization::Q8_0 => "q8_0",
+            Quantization::Q5_1 => "q5_1",
+            Quantization::Q5_0 => "q5_0",
+            Quantization::Q4_1 => "q4_1",
+            Quantization::Q4_0 => "q4_0",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::from_str(name, false).ok()
+    }
+}
+
+/// Conv with its weight quantised, run as an im2col followed by a quantised
+/// matrix multiplication. Only for stride 1 and no groups, see
+/// `Conv2d::quantizable`.
+#[derive(Debug, Clone)]
+pub struct QConv2d {
+    weight: QMatMul,
+    bias: Option<Tensor>,
+    kernel_size: usize,
+    padding: usize,
+    dilation: usize,
+}
+
+impl QConv2d {
+    /// (batch, channels, height, width) to (batch, height * width, channels * k * k),
+    /// in the same order as the flattened weight
+    fn im2col(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b_size, channels, h, w) = xs.dims4()?;
+        let k = self.kernel_size;
+        let (p, d) = (self.padding, self.dilation);
+        let xs = xs.pad_with_zeros(2, p, p)?.pad_with_zeros(3, p, p)?;
+        let out_h = h + 2 * p - d * (k - 1);
+        let out_w = w + 2 * p - d * (k - 1);
+        let mut shifted = Vec::with_capacity(k * k);
+        for ky in 0..k {
+            for kx in 0..k {
+                shifted.push(xs.narrow(2, ky * d, out_h)?.narrow(3, kx * d, out_w)?);
+            }
+        }
+        Tensor::stack(&shifted, 2)?
+            .reshape((b_size, channels * k * k, out_h * out_w))?
+            .transpose(1, 2)?
+            .contiguous()
+    }
+}
+
+impl Module for QConv2d {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b_size, _channels, h, w) = xs.dims4()?;
+        let k = self.kernel_size;
+        let out_h = h + 2 * self.padding - self.dilation * (k - 1);
+        let out_w = w + 2 * self.padding - self.dilation * (k - 1);
+        // Quantised matmuls take f32 activations
+        let dtype = xs.dtype();
+        let cols = self.im2col(&xs.to_dtype(DType::F32)?)?;
+        let out = self.weight.forward(&cols)?.to_dtype(dtype)?;
+        let c_out = out.dim(2)?;
+        let out = out
+            .transpose(1, 2)?
+            .reshape((b_size, c_out, out_h, out_w))?;
+        match &self.bias {
+            Some(bias) => out.broadcast_add(&bias.to_dtype(dtype)?.reshape((1, c_out, 1, 1))?),
+            None => Ok(out),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Kind {
+    Float(nn::Conv2d),
+    Quantized(QConv2d),
+}
+
+/// Conv layer of the architectures, in full precision or quantised
+#[derive(Debug, Clone)]
+pub struct Conv2d {
+    // Path of the conv in the checkpoint, e.g. "conv_first", `None` for convs
+    // computed at load time
+    name: Option<String>,
+    kind: Kind,
+}
+
+impl Conv2d {
+    /// Full precision conv that isn't stored in the checkpoint as is
+    pub fn new(conv: nn::Conv2d) -> Self {
+        Self {
+            name: None,
+            kind: Kind::Float(conv),
+        }
+    }
+
+    /// Key of the weight in the checkpoint, e.g. `conv_first.weight`
+    pub fn weight_name(&self) -> Option<String> {
+        self.name.as_ref().map(|name| format!("{name}.weight"))
+    }
+
+    pub fn is_quantized(&self) -> bool {
+        matches!(self.kind, Kind::Quantized(_))
+    }
+
+    /// Whether `quantize` quantises the conv: full precision, stride and
+    /// groups of 1, and `in_channels * k * k` a multiple of the block size
+    pub fn quantizable(&self, quantization: Quantization) -> bool {
+        let conv = match &self.kind {
+            Kind::Float(conv) => conv,
+            Kind::Quantized(_) => return false,
+        };
+        let config = conv.config();
+        let (_c_out, c_in, kh, kw) = conv.weight().dims4().unwrap();
+        config.stride == 1
+            && config.groups == 1
+            && (c_in * kh * kw) % quantization.ggml_dtype().block_size() == 0
+    }
+
+    /// Quantised copy of the conv, or the conv itself if it isn't
+    /// `quantizable`
+    pub fn quantize(&self, quantization: Quantization) -> Result<Self> {
+        let conv = match &self.kind {
+            Kind::Float(conv) if self.quantizable(quantization) => conv,
+            _ => return Ok(self.clone()),
+        };
+        let config = conv.config();
+        let (c_out, c_in, k, _) = conv.weight().dims4()?;
+        let weight = conv
+            .weight()
+            .to_dtype(DType::F32)?
+            .reshape((c_out, c_in * k * k))?;
+        let weight = QMatMul::from_qtensor(QTensor::quantize(&weight, quantization.ggml_dtype())?)?;
+        Ok(Self {
+            name: self.name.clone(),
+            kind: Kind::Quantized(QConv2d {
+                weight,
+                bias: conv.bias().cloned(),
+                kernel_size: k,
+                padding: config.padding,
+                dilation: config.dilation,
+            }),
+        })
+    }
+}
+
+impl Module for Conv2d {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match &self.kind {
+            // Mixed precision, the weights stay in their own dtype and are cast
+            // to the one of the activations for every call
+            Kind::Float(conv) if conv.weight().dtype() != xs.dtype() => {
+                let bias = match conv.bias() {
+                    Some(bias) => Some(bias.to_dtype(xs.dtype())?),
+                    None => None,
+                };
+                nn::Conv2d::new(conv.weight().to_dtype(xs.dtype())?, bias, *conv.config())
+                    .forward(xs)
+            }
+            Kind::Float(conv) => conv.forward(xs),
+            Kind::Quantized(conv) => conv.forward(xs),
+        }
+    }
+}
+
+/// Quantise every conv of a model that allows it, see `Conv2d::quantizable`
+pub fn quantize(convs: Vec<&mut Conv2d>, quantization: Quantization) -> Result<()> {
+    for conv in convs {
+        *conv = conv.quantize(quantization)?;
+    }
+    Ok(())
+}
+
+/// Same as `candle_nn::conv2d`, wrapped in a `Conv2d`
+pub fn conv2d(
+    in_channels: usize,
+    out_channels: usize,
+    kernel_size: usize,
+    config: nn::Conv2dConfig,
+    vb: nn::VarBuilder,
+) -> Result<Conv2d> {
+    Ok(Conv2d {
+        name: Some(vb.prefix()),
+        kind: Kind::Float(nn::conv2d(
+            in_channels,
+            out_channels,
+            kernel_size,
+            config,
+            vb,
+        )?),
+    })
+}
+
+/// Same as `candle_nn::conv2d_no_bias`, wrapped in a `Conv2d`
+pub fn conv2d_no_bias(
+    in_channels: usize,
+    out_channels: usize,
+    kernel_size: usize,
+    config: nn::Conv2dConfig,
+    vb: nn::VarBuilder,
+) -> Result<Conv2d> {
+    Ok(Conv2d {
+        name: Some(vb.prefix()),
+        kind: Kind::Float(nn::conv2d_no_bias(
+            in_channels,
+            out_channels,
+            kernel_size,
+            config,
+            vb,
+        )?),
+    })
+}
@@ -0,0 +1,195 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+use candle_core::{DType, Device, Tensor};
+use esrgan_candle_rs::conv::Quantization;
+use esrgan_candle_rs::metrics::psnr;
+use image::DynamicImage;
+
+use crate::{
+    exit_with_error, img2tensor, load_model, model_from_state_dict, read_state_dict, tensor2img,
+    ModelArgs,
+};
+
+// GGUF metadata keys
+const QUANTIZATION_KEY: &str = "esrgan.quantization";
+const SHAPE_PREFIX: &str = "esrgan.shape.";
+
+/// Convert a model to safetensors, or to GGUF with quantised conv weights
+#[derive(clap::Args, Debug)]
+pub struct ConvertArgs {
+    /// Model to convert, in safetensors, pth or gguf format
+    #[arg(short, long)]
+    model: String,
+
+    /// Output file, .safetensors or, with --quantize, .gguf
+    #[arg(short, long)]
+    output: String,
+
+    #[command(flatten)]
+    model_args: ModelArgs,
+
+    /// Image used to measure the accuracy of the quantised model against the
+    /// f32 one, random noise otherwise
+    #[arg(long)]
+    image: Option<String>,
+}
+
+/// Read a GGUF file written by `convert`, dequantising every tensor
+pub fn read_gguf(path: &str, device: &Device) -> HashMap<String, Tensor> {
+    let mut file = std::fs::File::open(path).unwrap();
+    let content = gguf_file::Content::read(&mut file).unwrap();
+    let mut state_dict = HashMap::new();
+    for name in content.tensor_infos.keys() {
+        let tensor = content
+            .tensor(&mut file, name, device)
+            .unwrap()
+            .dequantize(device)
+            .unwrap();
+        // Quantised weights are stored flattened to (out_channels, in_channels * k * k)
+        let tensor = match content.metadata.get(&format!("{SHAPE_PREFIX}{name}")) {
+            Some(shape) => {
+                let shape: Vec<usize> = shape
+                    .to_vec()
+                    .unwrap()
+                    .iter()
+                    .map(|dim| dim.to_u32().unwrap() as usize)
+                    .collect();
+                tensor.reshape(shape).unwrap()
+            }
+            None => tensor,
+        };
+        state_dict.insert(name.clone(), tensor);
+    }
+    return state_dict;
+}
+
+/// Quantisation a GGUF file was written with
+pub fn stored_quantization(path: &str) -> Option<Quantization> {
+    let mut file = std::fs::File::open(path).unwrap();
+    let content = gguf_file::Content::read(&mut file).unwrap();
+    return match content.metadata.get(QUANTIZATION_KEY) {
+        Some(value) => Quantization::from_name(value.to_string().unwrap()),
+        None => None,
+    };
+}
+
+/// Write the state dict with the `quantized` conv weights in the quantised
+/// format and the rest in f32. Returns the number of quantised weights.
+fn write_gguf(
+    path: &str,
+    state_dict: &HashMap<String, Tensor>,
+    quantized: &HashSet<String>,
+    quantization: Quantization,
+) -> usize {
+    let dtype = quantization.ggml_dtype();
+    let mut metadata = vec![(
+        QUANTIZATION_KEY.to_string(),
+        gguf_file::Value::String(quantization.name().to_string()),
+    )];
+    let mut tensors = vec![];
+    let mut names: Vec<&String> = state_dict.keys().collect();
+    names.sort();
+    for name in names {
+        let tensor = state_dict[name].to_dtype(DType::F32).unwrap();
+        let qtensor = match tensor.dims() {
+            &[c_out, c_in, kh, kw] if quantized.contains(name) => {
+                let shape = tensor
+                    .dims()
+                    .iter()
+                    .map(|&dim| gguf_file::Value::U32(dim as u32))
+                    .collect();
+                metadata.push((
+                    format!("{SHAPE_PREFIX}{name}"),
+                    gguf_file::Value::Array(shape),
+                ));
+                let flat = tensor.reshape((c_out, c_in * kh * kw)).unwrap();
+                QTensor::quantize(&flat, dtype).unwrap()
+            }
+            _ => QTensor::quantize(&tensor, GgmlDType::F32).unwrap(),
+        };
+        tensors.push((name.clone(), qtensor));
+    }
+    let quantized = metadata.len() - 1;
+
+    let metadata: Vec<(&str, &gguf_file::Value)> =
+        metadata.iter().map(|(k, v)| (k.as_str(), v)).collect();
+    let tensors: Vec<(&str, &QTensor)> = tensors.iter().map(|(k, v)| (k.as_str(), v)).collect();
+    let mut file = std::fs::File::create(path).unwrap();
+    gguf_file::write(&mut file, &metadata, &tensors).unwrap();
+    return quantized;
+}
+
+fn megabytes(path: &str) -> f64 {
+    std::fs::metadata(path).unwrap().len() as f64 / 1e6
+}
+
+pub fn convert(args: &ConvertArgs) {
+    let device = Device::Cpu;
+    let state_dict = read_state_dict(&args.model, &device);
+
+    let quantization = match args.model_args.quantize {
+        Some(quantization) => quantization,
+        None => {
+            candle_core::safetensors::save(&state_dict, &args.output).unwrap();
+            println!("Saved {}", args.output);
+            return;
+        }
+    };
+    if !args.output.ends_with(".gguf") {
+        exit_with_error("Quantised models are saved in GGUF format, use a .gguf output");
+    }
+
+    // Only the weights the model quantises when loading, the others would lose
+    // precision for nothing
+    let reference_args = ModelArgs {
+        quantize: None,
+        ..args.model_args.clone()
+    };
+    let mut reference =
+        model_from_state_dict(state_dict.clone(), &reference_args, &device, DType::F32)
+            .unwrap_or_else(|err| exit_with_error(err));
+    let quantizable = reference
+        .upscaler
+        .convs_mut()
+        .into_iter()
+        .filter(|conv| conv.quantizable(quantization))
+        .filter_map(|conv| conv.weight_name())
+        .collect();
+    let quantized = write_gguf(&args.output, &state_dict, &quantizable, quantization);
+    println!(
+        "Saved {} with {quantized} of the weights in {}, {:.1} MB -> {:.1} MB",
+        args.output,
+        quantization.name(),
+        megabytes(&args.model),
+        megabytes(&args.output)
+    );
+
+    // Compare the quantised model, as it is loaded back, to the f32 one
+    let model = load_model(&args.output, &reference_args, &device, DType::F32);
+    let input = match &args.image {
+        Some(path) => img2tensor(image::open(path).unwrap(), &device, DType::F32),
+        None => img2tensor(DynamicImage::new_rgb8(64, 64), &device, DType::F32)
+            .rand_like(0., 1.)
+            .unwrap(),
+    };
+    let run = |model| {
+        let now = Instant::now();
+        let output = crate::forward(model, &input, None);
+        let elapsed = now.elapsed();
+        let output = (output.squeeze(0).unwrap().clamp(0., 1.).unwrap() * 255.)
+            .unwrap()
+            .round()
+            .unwrap();
+        (tensor2img(output), elapsed)
+    };
+    let (expected, reference_time) = run(&reference);
+    let (actual, time) = run(&model);
+    println!(
+        "PSNR against f32: {:.2} dB, {:.0} ms instead of {:.0} ms",
+        psnr(&actual, &expected, 0, false).unwrap(),
+        time.as_secs_f64() * 1000.,
+        reference_time.as_secs_f64() * 1000.
+    );
+}
@@ -0,0 +1,377 @@
+use candle_core::{DType, Module, Result, Tensor};
+use candle_nn as nn;
+
+use crate::conv::{conv2d, Conv2d};
+use crate::profile::Profiler;
+
+// Real-CUGAN: two U-Nets in cascade, the first one upscaling and the second one
+// refining its output. None of the convs are padded, so the input is
+// reflect-padded up front and every skip connection is cropped to the size of
+// the branch it joins. Every width is a multiple of the channels of the first
+// conv, 32 in all the official models.
+
+fn conv(c_in: usize, c_out: usize, k: usize, stride: usize, vb: nn::VarBuilder) -> Result<Conv2d> {
+    let config = nn::Conv2dConfig {
+        padding: 0,
+        stride,
+        dilation: 1,
+        groups: 1,
+    };
+    conv2d(c_in, c_out, k, config, vb)
+}
+
+/// Transposed conv, run in the dtype of its input like `Conv2d`
+#[derive(Debug)]
+struct ConvTranspose2d {
+    weight: Tensor,
+    bias: Tensor,
+    stride: usize,
+    padding: usize,
+}
+
+impl ConvTranspose2d {
+    fn load(
+        vb: nn::VarBuilder,
+        c_in: usize,
+        c_out: usize,
+        k: usize,
+        stride: usize,
+        padding: usize,
+    ) -> Result<Self> {
+        Ok(Self {
+            weight: vb.get((c_in, c_out, k, k), "weight")?,
+            bias: vb.get(c_out, "bias")?,
+            stride,
+            padding,
+        })
+    }
+}
+
+impl Module for ConvTranspose2d {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let weight = self.weight.to_dtype(xs.dtype())?;
+        let bias = self.bias.to_dtype(xs.dtype())?.reshape((1, (), 1, 1))?;
+        xs.conv_transpose2d(&weight, self.padding, 0, self.stride, 1)?
+            .broadcast_add(&bias)
+    }
+}
+
+/// Remove `n` pixels from every side, like `F.pad` with negative padding
+fn crop(xs: &Tensor, n: usize) -> Result<Tensor> {
+    let (_, _, h, w) = xs.dims4()?;
+    xs.narrow(2, n, h - 2 * n)?.narrow(3, n, w - 2 * n)
+}
+
+/// Reflect padding of `dim`, folding back and forth for pads larger than the
+/// image where PyTorch would fail
+fn pad_reflect(xs: &Tensor, dim: usize, before: usize, after: usize) -> Result<Tensor> {
+    let size = xs.dim(dim)? as i64;
+    let period = (2 * (size - 1)).max(1);
+    let index: Vec<u32> = (-(before as i64)..size + after as i64)
+        .map(|i| {
+            let i = i.rem_euclid(period);
+            (if i < size { i } else { period - i }) as u32
+        })
+        .collect();
+    let index = Tensor::new(index.as_slice(), xs.device())?;
+    xs.index_select(&index, dim)
+}
+
+/// Squeeze-excitation: every channel weighted by a function of the means of
+/// all of them
+#[derive(Debug)]
+struct SEBlock {
+    conv1: Conv2d,
+    conv2: Conv2d,
+}
+
+impl SEBlock {
+    fn load(vb: nn::VarBuilder, channels: usize) -> Result<Self> {
+        Ok(Self {
+            conv1: conv(channels, channels / 8, 1, 1, vb.pp("conv1"))?,
+            conv2: conv(channels / 8, channels, 1, 1, vb.pp("conv2"))?,
+        })
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        vec![&mut self.conv1, &mut self.conv2]
+    }
+}
+
+impl Module for SEBlock {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        // Like the original, the means of half precision inputs are taken in f32
+        let mean = xs
+            .to_dtype(DType::F32)?
+            .mean_keepdim(2)?
+            .mean_keepdim(3)?
+            .to_dtype(xs.dtype())?;
+        let out = self.conv1.forward(&mean)?.relu()?;
+        let out = nn::ops::sigmoid(&self.conv2.forward(&out)?)?;
+        xs.broadcast_mul(&out)
+    }
+}
+
+/// Two 3x3 convs, each trimming a pixel from every side, and optionally a
+/// squeeze-excitation
+#[derive(Debug)]
+struct UNetConv {
+    conv1: Conv2d,
+    conv2: Conv2d,
+    seblock: Option<SEBlock>,
+}
+
+impl UNetConv {
+    fn load(vb: nn::VarBuilder, c_in: usize, c_mid: usize, c_out: usize, se: bool) -> Result<Self> {
+        let seblock = match se {
+            true => Some(SEBlock::load(vb.pp("seblock"), c_out)?),
+            false => None,
+        };
+        Ok(Self {
+            conv1: conv(c_in, c_mid, 3, 1, vb.pp("conv.0"))?,
+            conv2: conv(c_mid, c_out, 3, 1, vb.pp("conv.2"))?,
+            seblock,
+        })
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = vec![&mut self.conv1, &mut self.conv2];
+        if let Some(seblock) = &mut self.seblock {
+            convs.extend(seblock.convs_mut());
+        }
+        convs
+    }
+}
+
+impl Module for UNetConv {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let out = nn::ops::leaky_relu(&self.conv1.forward(xs)?, 0.1)?;
+        let out = nn::ops::leaky_relu(&self.conv2.forward(&out)?, 0.1)?;
+        match &self.seblock {
+            Some(seblock) => seblock.forward(&out),
+            None => Ok(out),
+        }
+    }
+}
+
+/// The first U-Net, with one downscaling and a transposed conv upscaling by
+/// `factor` at the end
+#[derive(Debug)]
+struct UNet1 {
+    conv1: UNetConv,
+    conv1_down: Conv2d,
+    conv2: UNetConv,
+    conv2_up: ConvTranspose2d,
+    conv3: Conv2d,
+    conv_bottom: ConvTranspose2d,
+}
+
+impl UNet1 {
+    fn load(
+        vb: nn::VarBuilder,
+        c_in: usize,
+        c_out: usize,
+        nf: usize,
+        factor: usize,
+    ) -> Result<Self> {
+        let conv_bottom = match factor {
+            3 => ConvTranspose2d::load(vb.pp("conv_bottom"), nf * 2, c_out, 5, 3, 2)?,
+            _ => ConvTranspose2d::load(vb.pp("conv_bottom"), nf * 2, c_out, 4, 2, 3)?,
+        };
+        Ok(Self {
+            conv1: UNetConv::load(vb.pp("conv1"), c_in, nf, nf * 2, false)?,
+            conv1_down: conv(nf * 2, nf * 2, 2, 2, vb.pp("conv1_down"))?,
+            conv2: UNetConv::load(vb.pp("conv2"), nf * 2, nf * 4, nf * 2, true)?,
+            conv2_up: ConvTranspose2d::load(vb.pp("conv2_up"), nf * 2, nf * 2, 2, 2, 0)?,
+            conv3: conv(nf * 2, nf * 2, 3, 1, vb.pp("conv3"))?,
+            conv_bottom,
+        })
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = self.conv1.convs_mut();
+        convs.push(&mut self.conv1_down);
+        convs.extend(self.conv2.convs_mut());
+        convs.push(&mut self.conv3);
+        convs
+    }
+}
+
+impl Module for UNet1 {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let x1 = self.conv1.forward(xs)?;
+        let x2 = nn::ops::leaky_relu(&self.conv1_down.forward(&x1)?, 0.1)?;
+        let x2 = self.conv2.forward(&x2)?;
+        let x2 = nn::ops::leaky_relu(&self.conv2_up.forward(&x2)?, 0.1)?;
+        let x3 = self.conv3.forward(&(crop(&x1, 4)? + x2)?)?;
+        let x3 = nn::ops::leaky_relu(&x3, 0.1)?;
+        self.conv_bottom.forward(&x3)
+    }
+}
+
+/// The second U-Net, with two downscalings, keeping the size of its output
+/// but for the border its convs trim
+#[derive(Debug)]
+struct UNet2 {
+    conv1: UNetConv,
+    conv1_down: Conv2d,
+    conv2: UNetConv,
+    conv2_down: Conv2d,
+    conv3: UNetConv,
+    conv3_up: ConvTranspose2d,
+    conv4: UNetConv,
+    conv4_up: ConvTranspose2d,
+    conv5: Conv2d,
+    conv_bottom: Conv2d,
+}
+
+impl UNet2 {
+    fn load(vb: nn::VarBuilder, c_in: usize, c_out: usize, nf: usize) -> Result<Self> {
+        Ok(Self {
+            conv1: UNetConv::load(vb.pp("conv1"), c_in, nf, nf * 2, false)?,
+            conv1_down: conv(nf * 2, nf * 2, 2, 2, vb.pp("conv1_down"))?,
+            conv2: UNetConv::load(vb.pp("conv2"), nf * 2, nf * 2, nf * 4, true)?,
+            conv2_down: conv(nf * 4, nf * 4, 2, 2, vb.pp("conv2_down"))?,
+            conv3: UNetConv::load(vb.pp("conv3"), nf * 4, nf * 8, nf * 4, true)?,
+            conv3_up: ConvTranspose2d::load(vb.pp("conv3_up"), nf * 4, nf * 4, 2, 2, 0)?,
+            conv4: UNetConv::load(vb.pp("conv4"), nf * 4, nf * 2, nf * 2, true)?,
+            conv4_up: ConvTranspose2d::load(vb.pp("conv4_up"), nf * 2, nf * 2, 2, 2, 0)?,
+            conv5: conv(nf * 2, nf * 2, 3, 1, vb.pp("conv5"))?,
+            conv_bottom: conv(nf * 2, c_out, 3, 1, vb.pp("conv_bottom"))?,
+        })
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = self.conv1.convs_mut();
+        convs.push(&mut self.conv1_down);
+        convs.extend(self.conv2.convs_mut());
+        convs.push(&mut self.conv2_down);
+        convs.extend(self.conv3.convs_mut());
+        convs.extend(self.conv4.convs_mut());
+        convs.push(&mut self.conv5);
+        convs.push(&mut self.conv_bottom);
+        convs
+    }
+}
+
+impl Module for UNet2 {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let x1 = self.conv1.forward(xs)?;
+        let x2 = nn::ops::leaky_relu(&self.conv1_down.forward(&x1)?, 0.1)?;
+        let x2 = self.conv2.forward(&x2)?;
+        let x3 = nn::ops::leaky_relu(&self.conv2_down.forward(&x2)?, 0.1)?;
+        let x3 = self.conv3.forward(&x3)?;
+        let x3 = nn::ops::leaky_relu(&self.conv3_up.forward(&x3)?, 0.1)?;
+        let x4 = self.conv4.forward(&(crop(&x2, 4)? + x3)?)?;
+        let x4 = nn::ops::leaky_relu(&self.conv4_up.forward(&x4)?, 0.1)?;
+        let x5 = self.conv5.forward(&(crop(&x1, 16)? + x4)?)?;
+        let x5 = nn::ops::leaky_relu(&x5, 0.1)?;
+        self.conv_bottom.forward(&x5)
+    }
+}
+
+/// UpCunet2x, UpCunet3x or UpCunet4x depending on the scale
+#[derive(Debug)]
+pub struct UpCunet {
+    unet1: UNet1,
+    unet2: UNet2,
+    /// Only for x4, where the U-Nets upscale by 2 and the rest is done by a
+    /// pixel shuffle
+    conv_final: Option<Conv2d>,
+    scale: usize,
+    pro: bool,
+}
+
+impl UpCunet {
+    /// `pro` models take their input in [0.15, 0.85] instead of [0, 1]
+    pub fn load(
+        vb: nn::VarBuilder,
+        in_nc: usize,
+        out_nc: usize,
+        scale: usize,
+        nf: usize,
+        pro: bool,
+    ) -> Result<Self> {
+        let (unet1, unet2, conv_final) = match scale {
+            4 => (
+                UNet1::load(vb.pp("unet1"), in_nc, nf * 2, nf, 2)?,
+                UNet2::load(vb.pp("unet2"), nf * 2, nf * 2, nf)?,
+                Some(conv(nf * 2, out_nc * 4, 3, 1, vb.pp("conv_final"))?),
+            ),
+            _ => (
+                UNet1::load(vb.pp("unet1"), in_nc, out_nc, nf, scale)?,
+                UNet2::load(vb.pp("unet2"), out_nc, out_nc, nf)?,
+                None,
+            ),
+        };
+        Ok(Self {
+            unet1,
+            unet2,
+            conv_final,
+            scale,
+            pro,
+        })
+    }
+
+    /// Every conv of the model, for `conv::quantize`. The transposed convs
+    /// aren't among them and stay in full precision.
+    pub fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = self.unet1.convs_mut();
+        convs.extend(self.unet2.convs_mut());
+        if let Some(conv_final) = &mut self.conv_final {
+            convs.push(conv_final);
+        }
+        convs
+    }
+
+    /// `forward`, recording the time of every module in `profiler`
+    pub fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        let (_, _, h, w) = xs.dims4()?;
+        let xs = if self.pro {
+            ((xs * 0.7)? + 0.15)?
+        } else {
+            xs.clone()
+        };
+        // The padding makes up for the border the convs trim, and rounds the
+        // size up to a multiple of what the U-Nets downscale by
+        let (pad, multiple) = match self.scale {
+            2 => (18, 2),
+            3 => (14, 4),
+            _ => (19, 2),
+        };
+        let pad_h = h.div_ceil(multiple) * multiple - h;
+        let pad_w = w.div_ceil(multiple) * multiple - w;
+        let padded = pad_reflect(&xs, 2, pad, pad + pad_h)?;
+        let padded = pad_reflect(&padded, 3, pad, pad + pad_w)?;
+
+        let out = self.unet1.forward(&padded)?;
+        profiler.record("unet1", &out)?;
+        let out = (self.unet2.forward(&out)? + crop(&out, 20)?)?;
+        profiler.record("unet2", &out)?;
+        let out = match &self.conv_final {
+            Some(conv_final) => {
+                let out = crop(&conv_final.forward(&out)?, 1)?;
+                let out = nn::ops::pixel_shuffle(&out, 2)?
+                    .narrow(2, 0, h * 4)?
+                    .narrow(3, 0, w * 4)?;
+                let out = (out + xs.upsample_nearest2d(h * 4, w * 4)?)?;
+                profiler.record("conv_final", &out)?;
+                out
+            }
+            None => out
+                .narrow(2, 0, h * self.scale)?
+                .narrow(3, 0, w * self.scale)?,
+        };
+        if self.pro {
+            (out - 0.15)? / 0.7
+        } else {
+            Ok(out)
+        }
+    }
+}
+
+impl nn::Module for UpCunet {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.forward_profiled(xs, &mut Profiler::disabled())
+    }
+}
@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+
+use candle_core::Tensor;
+
+// Real-CUGAN models have a fixed layout for every scale, only the channel
+// counts vary and the scale shows in which layers there are
+
+pub fn get_in_nc(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("unet1.conv1.conv.0.weight") {
+        Some(x) => x.shape().dims()[1],
+        None => 3,
+    };
+}
+
+pub fn get_nf(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("unet1.conv1.conv.0.weight") {
+        Some(x) => x.shape().dims()[0],
+        None => 32,
+    };
+}
+
+pub fn get_scale(state_dict: &HashMap<String, Tensor>) -> usize {
+    // x4 models end with a conv and a pixel shuffle, and the transposed conv
+    // at the end of the first U-Net has a 5x5 kernel for x3 and 4x4 for x2
+    if state_dict.contains_key("conv_final.weight") {
+        return 4;
+    }
+    return match state_dict.get("unet1.conv_bottom.weight") {
+        Some(x) if x.shape().dims()[2] == 5 => 3,
+        _ => 2,
+    };
+}
+
+pub fn get_out_nc(state_dict: &HashMap<String, Tensor>) -> usize {
+    // The last conv of x4 models is before a x2 pixel shuffle
+    if let Some(x) = state_dict.get("conv_final.weight") {
+        return x.shape().dims()[0] / 4;
+    }
+    // Transposed conv weights are (in, out, k, k)
+    return match state_dict.get("unet1.conv_bottom.weight") {
+        Some(x) => x.shape().dims()[1],
+        None => 3,
+    };
+}
+
+/// Whether the model is one of the pro releases, which are marked with a `pro`
+/// entry
+pub fn get_pro(state_dict: &HashMap<String, Tensor>) -> bool {
+    return state_dict.contains_key("pro");
+}
@@ -0,0 +1,617 @@
+use candle_core::{Device, Module, Result, Tensor, D};
+use candle_nn as nn;
+
+use crate::conv::{conv2d, Conv2d};
+use crate::layers::{batch_norm, layer_norm, linear, linear_b, BatchNorm, LayerNorm, Linear};
+use crate::profile::Profiler;
+use crate::swinir::{
+    embed, in_shifted_windows, input_mean, relative_position_index, shifted_window_mask, unembed,
+    Reconstruction, ResiConnection, ResiConv, Upsampler,
+};
+
+// Dual Aggregation Transformer: blocks alternate between attention across the
+// pixels of rectangular windows and attention across the channels, and both
+// are mixed with a depthwise conv branch.
+
+#[derive(Debug, Clone)]
+pub struct DATConfig {
+    pub in_nc: usize,
+    pub out_nc: usize,
+    pub scale: usize,
+    pub embed_dim: usize,
+    /// Number of blocks of every residual group
+    pub depths: Vec<usize>,
+    /// Number of attention heads of every residual group
+    pub num_heads: Vec<usize>,
+    /// Height and width of the windows of the spatial attention, half of its
+    /// heads use them transposed
+    pub split_size: (usize, usize),
+    /// Hidden channels of the feed-forward networks are embed_dim * expansion_factor
+    pub expansion_factor: f64,
+    pub qkv_bias: bool,
+    pub upsampler: Upsampler,
+    pub num_feat: usize,
+    pub resi_connection: ResiConnection,
+}
+
+impl Default for DATConfig {
+    /// DAT x4 of the paper
+    fn default() -> Self {
+        Self {
+            in_nc: 3,
+            out_nc: 3,
+            scale: 4,
+            embed_dim: 180,
+            depths: vec![6; 6],
+            num_heads: vec![6; 6],
+            split_size: (8, 32),
+            expansion_factor: 4.,
+            qkv_bias: true,
+            upsampler: Upsampler::PixelShuffle,
+            num_feat: 64,
+            resi_connection: ResiConnection::OneConv,
+        }
+    }
+}
+
+impl DATConfig {
+    /// Name of the variant of the paper the config matches, S, the base model,
+    /// DAT-2 or light
+    pub fn variant(&self) -> &'static str {
+        if self.embed_dim == 60 && self.upsampler == Upsampler::PixelShuffleDirect {
+            return "dat-light";
+        }
+        if self.embed_dim != 180 || self.depths.len() != 6 {
+            return "dat";
+        }
+        match (self.split_size, self.expansion_factor as usize) {
+            ((8, 16), 2) => "dat-s",
+            ((8, 32), 2) => "dat-2",
+            _ => "dat",
+        }
+    }
+}
+
+fn conv(c_in: usize, c_out: usize, k: usize, vb: nn::VarBuilder) -> Result<Conv2d> {
+    let config = nn::Conv2dConfig {
+        padding: k / 2,
+        stride: 1,
+        dilation: 1,
+        groups: 1,
+    };
+    conv2d(c_in, c_out, k, config, vb)
+}
+
+/// 3x3 conv of every channel on its own
+fn depthwise_conv(dim: usize, vb: nn::VarBuilder) -> Result<Conv2d> {
+    let config = nn::Conv2dConfig {
+        padding: 1,
+        stride: 1,
+        dilation: 1,
+        groups: dim,
+    };
+    conv2d(dim, dim, 3, config, vb)
+}
+
+/// The bias of the spatial attention as a function of the relative position,
+/// instead of a table, so that the windows can have any size
+#[derive(Debug)]
+struct DynamicPosBias {
+    pos_proj: Linear,
+    /// Layer norm, ReLU and linear layer
+    pos: Vec<(LayerNorm, Linear)>,
+}
+
+impl DynamicPosBias {
+    fn load(vb: nn::VarBuilder, dim: usize, num_heads: usize) -> Result<Self> {
+        let pos_dim = dim / 4;
+        let mut pos = vec![];
+        for (i, out_dim) in [pos_dim, pos_dim, num_heads].into_iter().enumerate() {
+            let vb = vb.pp(format!("pos{}", i + 1));
+            pos.push((
+                layer_norm(pos_dim, vb.pp("0"))?,
+                linear(pos_dim, out_dim, vb.pp("2"))?,
+            ));
+        }
+        Ok(Self {
+            pos_proj: linear(2, pos_dim, vb.pp("pos_proj"))?,
+            pos,
+        })
+    }
+}
+
+impl Module for DynamicPosBias {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut out = self.pos_proj.forward(xs)?;
+        for (norm, linear) in self.pos.iter() {
+            out = linear.forward(&norm.forward(&out)?.relu()?)?;
+        }
+        Ok(out)
+    }
+}
+
+/// Every relative position (dy, dx) of a window, (positions, 2)
+pub(crate) fn relative_positions((wh, ww): (usize, usize), device: &Device) -> Result<Tensor> {
+    let (wh, ww) = (wh as i64, ww as i64);
+    let positions: Vec<f32> = (1 - wh..wh)
+        .flat_map(|dy| (1 - ww..ww).flat_map(move |dx| [dy as f32, dx as f32]))
+        .collect();
+    let n = positions.len() / 2;
+    Tensor::from_vec(positions, (n, 2), device)
+}
+
+/// Attention inside the windows of one half of the channels
+#[derive(Debug)]
+struct SpatialAttention {
+    pos: DynamicPosBias,
+    relative_positions: Tensor,
+    relative_position_index: Tensor,
+    num_heads: usize,
+    window: (usize, usize),
+}
+
+impl SpatialAttention {
+    fn load(
+        vb: nn::VarBuilder,
+        dim: usize,
+        window: (usize, usize),
+        num_heads: usize,
+    ) -> Result<Self> {
+        Ok(Self {
+            pos: DynamicPosBias::load(vb.pp("pos"), dim / 4, num_heads)?,
+            relative_positions: relative_positions(window, vb.device())?,
+            relative_position_index: relative_position_index(window, vb.device())?,
+            num_heads,
+            window,
+        })
+    }
+
+    /// Attention over the windows of (B * windows, N, 3 * C) holding the
+    /// queries, keys and values, `mask` is (windows, N, N)
+    fn forward(&self, qkv: &Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
+        let (b, n, c) = qkv.dims3()?;
+        let c = c / 3;
+        let head_dim = c / self.num_heads;
+        let qkv = qkv
+            .reshape((b, n, 3, self.num_heads, head_dim))?
+            .permute((2, 0, 3, 1, 4))?;
+        let q = (qkv.get(0)? * (head_dim as f64).powf(-0.5))?.contiguous()?;
+        let k = qkv.get(1)?.contiguous()?;
+        let v = qkv.get(2)?.contiguous()?;
+        let attn = q.matmul(&k.t()?)?;
+
+        let bias = self
+            .pos
+            .forward(&self.relative_positions)?
+            .index_select(&self.relative_position_index.flatten_all()?, 0)?
+            .reshape((n, n, self.num_heads))?
+            .permute((2, 0, 1))?
+            .to_dtype(attn.dtype())?;
+        let attn = attn.broadcast_add(&bias.unsqueeze(0)?)?;
+        let attn = match mask {
+            Some(mask) => {
+                let windows = mask.dim(0)?;
+                attn.reshape((b / windows, windows, self.num_heads, n, n))?
+                    .broadcast_add(&mask.unsqueeze(1)?.unsqueeze(0)?.to_dtype(attn.dtype())?)?
+                    .reshape((b, self.num_heads, n, n))?
+            }
+            None => attn,
+        };
+        let attn = nn::ops::softmax_last_dim(&attn)?;
+        attn.matmul(&v)?.transpose(1, 2)?.reshape((b, n, c))
+    }
+}
+
+/// The depthwise conv branch of both attentions, and the maps through which
+/// it and the attention weigh each other
+#[derive(Debug)]
+struct Interaction {
+    dwconv: Conv2d,
+    dwconv_norm: BatchNorm,
+    channel_conv1: Conv2d,
+    channel_norm: BatchNorm,
+    channel_conv2: Conv2d,
+    spatial_conv1: Conv2d,
+    spatial_norm: BatchNorm,
+    spatial_conv2: Conv2d,
+}
+
+impl Interaction {
+    fn load(vb: nn::VarBuilder, dim: usize) -> Result<Self> {
+        Ok(Self {
+            dwconv: depthwise_conv(dim, vb.pp("dwconv.0"))?,
+            dwconv_norm: batch_norm(dim, vb.pp("dwconv.1"))?,
+            channel_conv1: conv(dim, dim / 8, 1, vb.pp("channel_interaction.1"))?,
+            channel_norm: batch_norm(dim / 8, vb.pp("channel_interaction.2"))?,
+            channel_conv2: conv(dim / 8, dim, 1, vb.pp("channel_interaction.4"))?,
+            spatial_conv1: conv(dim, dim / 16, 1, vb.pp("spatial_interaction.0"))?,
+            spatial_norm: batch_norm(dim / 16, vb.pp("spatial_interaction.1"))?,
+            spatial_conv2: conv(dim / 16, 1, 1, vb.pp("spatial_interaction.3"))?,
+        })
+    }
+
+    /// The conv branch of the values, (B, C, H, W)
+    fn conv(&self, v: &Tensor) -> Result<Tensor> {
+        self.dwconv_norm
+            .forward(&self.dwconv.forward(v)?)?
+            .gelu_erf()
+    }
+
+    /// A weight for every channel of (B, C, H, W), before the sigmoid
+    fn channel_map(&self, xs: &Tensor) -> Result<Tensor> {
+        let out = self
+            .channel_conv1
+            .forward(&xs.mean_keepdim(2)?.mean_keepdim(3)?)?;
+        self.channel_conv2
+            .forward(&self.channel_norm.forward(&out)?.gelu_erf()?)
+    }
+
+    /// A weight for every pixel of (B, C, H, W), before the sigmoid
+    fn spatial_map(&self, xs: &Tensor) -> Result<Tensor> {
+        let out = self.spatial_conv1.forward(xs)?;
+        self.spatial_conv2
+            .forward(&self.spatial_norm.forward(&out)?.gelu_erf()?)
+    }
+}
+
+/// Spatial attention in windows of split_size for one half of the channels and
+/// of the transposed split_size for the other, next to the conv branch
+#[derive(Debug)]
+struct AdaptiveSpatialAttention {
+    qkv: Linear,
+    proj: Linear,
+    attns: Vec<SpatialAttention>,
+    interaction: Interaction,
+    split_size: (usize, usize),
+    shifted: bool,
+}
+
+impl AdaptiveSpatialAttention {
+    fn load(
+        vb: nn::VarBuilder,
+        dim: usize,
+        num_heads: usize,
+        shifted: bool,
+        config: &DATConfig,
+    ) -> Result<Self> {
+        let (sh, sw) = config.split_size;
+        let attns = vec![
+            SpatialAttention::load(vb.pp("attns.0"), dim / 2, (sh, sw), num_heads / 2)?,
+            SpatialAttention::load(vb.pp("attns.1"), dim / 2, (sw, sh), num_heads / 2)?,
+        ];
+        Ok(Self {
+            qkv: linear_b(dim, dim * 3, config.qkv_bias, vb.pp("qkv"))?,
+            proj: linear(dim, dim, vb.pp("proj"))?,
+            attns,
+            interaction: Interaction::load(vb, dim)?,
+            split_size: config.split_size,
+            shifted,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, (h, w): (usize, usize)) -> Result<Tensor> {
+        let (b, l, c) = xs.dims3()?;
+        let qkv = self.qkv.forward(xs)?;
+        let v = unembed(&qkv.narrow(2, 2 * c, c)?, (h, w))?;
+
+        // Both kinds of windows have to tile the image
+        let multiple = self.split_size.0.max(self.split_size.1);
+        let pad_h = (multiple - h % multiple) % multiple;
+        let pad_w = (multiple - w % multiple) % multiple;
+        let qkv = qkv
+            .reshape((b, h, w, 3, c))?
+            .pad_with_zeros(1, 0, pad_h)?
+            .pad_with_zeros(2, 0, pad_w)?;
+        let (ph, pw) = (h + pad_h, w + pad_w);
+
+        let mut halves = vec![];
+        for (i, attn) in self.attns.iter().enumerate() {
+            let qkv = qkv
+                .narrow(4, i * c / 2, c / 2)?
+                .reshape((b, ph, pw, 3 * c / 2))?;
+            let (wh, ww) = attn.window;
+            let shift = if self.shifted {
+                (wh / 2, ww / 2)
+            } else {
+                (0, 0)
+            };
+            let mask = match self.shifted {
+                true => Some(shifted_window_mask(
+                    ph,
+                    pw,
+                    attn.window,
+                    shift,
+                    xs.device(),
+                )?),
+                false => None,
+            };
+            let out = in_shifted_windows(&qkv, attn.window, shift, |windows| {
+                attn.forward(windows, mask.as_ref())
+            })?;
+            halves.push(
+                out.narrow(1, 0, h)?
+                    .narrow(2, 0, w)?
+                    .reshape((b, l, c / 2))?,
+            );
+        }
+        let attened_x = Tensor::cat(&halves, 2)?;
+
+        let conv_x = self.interaction.conv(&v)?;
+        let channel_map = self.interaction.channel_map(&conv_x)?;
+        let spatial_map = self
+            .interaction
+            .spatial_map(&unembed(&attened_x, (h, w))?)?;
+        let attened_x =
+            attened_x.broadcast_mul(&nn::ops::sigmoid(&channel_map)?.reshape((b, 1, c))?)?;
+        let conv_x = embed(&conv_x.broadcast_mul(&nn::ops::sigmoid(&spatial_map)?)?)?;
+        self.proj.forward(&(attened_x + conv_x)?)
+    }
+}
+
+/// Divide by the L2 norm of the last dimension, like `F.normalize`
+pub(crate) fn normalize(xs: &Tensor) -> Result<Tensor> {
+    let norm = xs.sqr()?.sum_keepdim(D::Minus1)?.sqrt()?.maximum(1e-12)?;
+    xs.broadcast_div(&norm)
+}
+
+/// Attention between the channels of every head, next to the conv branch
+#[derive(Debug)]
+struct AdaptiveChannelAttention {
+    temperature: Tensor,
+    qkv: Linear,
+    proj: Linear,
+    interaction: Interaction,
+    num_heads: usize,
+}
+
+impl AdaptiveChannelAttention {
+    fn load(vb: nn::VarBuilder, dim: usize, num_heads: usize, qkv_bias: bool) -> Result<Self> {
+        Ok(Self {
+            temperature: vb.get((num_heads, 1, 1), "temperature")?,
+            qkv: linear_b(dim, dim * 3, qkv_bias, vb.pp("qkv"))?,
+            proj: linear(dim, dim, vb.pp("proj"))?,
+            interaction: Interaction::load(vb, dim)?,
+            num_heads,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, (h, w): (usize, usize)) -> Result<Tensor> {
+        let (b, l, c) = xs.dims3()?;
+        let head_dim = c / self.num_heads;
+        // (3, B, heads, head_dim, L)
+        let qkv = self
+            .qkv
+            .forward(xs)?
+            .reshape((b, l, 3, self.num_heads, head_dim))?
+            .permute((2, 0, 3, 4, 1))?;
+        let q = normalize(&qkv.get(0)?.contiguous()?)?;
+        let k = normalize(&qkv.get(1)?.contiguous()?)?;
+        let v = qkv.get(2)?.contiguous()?;
+        let attn = q.matmul(&k.t()?)?;
+        let attn = attn.broadcast_mul(&self.temperature.to_dtype(attn.dtype())?.unsqueeze(0)?)?;
+        let attn = nn::ops::softmax_last_dim(&attn)?;
+        let attened_x = attn.matmul(&v)?.reshape((b, c, l))?.transpose(1, 2)?;
+
+        let conv_x = self.interaction.conv(&v.reshape((b, c, h, w))?)?;
+        let channel_map = self
+            .interaction
+            .channel_map(&unembed(&attened_x, (h, w))?)?;
+        let spatial_map = self.interaction.spatial_map(&conv_x)?;
+        let attened_x = attened_x.broadcast_mul(&embed(&nn::ops::sigmoid(&spatial_map)?)?)?;
+        let conv_x = embed(&conv_x.broadcast_mul(&nn::ops::sigmoid(&channel_map)?)?)?;
+        self.proj.forward(&(attened_x + conv_x)?)
+    }
+}
+
+/// Spatial-gate feed-forward network: the second half of the hidden channels,
+/// through a depthwise conv, gates the first half
+#[derive(Debug)]
+struct SGFN {
+    fc1: Linear,
+    norm: LayerNorm,
+    conv: Conv2d,
+    fc2: Linear,
+}
+
+impl SGFN {
+    fn load(vb: nn::VarBuilder, dim: usize, hidden_dim: usize) -> Result<Self> {
+        Ok(Self {
+            fc1: linear(dim, hidden_dim, vb.pp("fc1"))?,
+            norm: layer_norm(hidden_dim / 2, vb.pp("sg.norm"))?,
+            conv: depthwise_conv(hidden_dim / 2, vb.pp("sg.conv"))?,
+            fc2: linear(hidden_dim / 2, dim, vb.pp("fc2"))?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, size: (usize, usize)) -> Result<Tensor> {
+        let out = self.fc1.forward(xs)?.gelu_erf()?;
+        let half = out.dim(2)? / 2;
+        let gate = self.norm.forward(&out.narrow(2, half, half)?)?;
+        let gate = embed(&self.conv.forward(&unembed(&gate, size)?)?)?;
+        self.fc2.forward(&(out.narrow(2, 0, half)? * gate)?)
+    }
+}
+
+#[derive(Debug)]
+enum Attention {
+    Spatial(AdaptiveSpatialAttention),
+    Channel(AdaptiveChannelAttention),
+}
+
+#[derive(Debug)]
+struct DATB {
+    norm1: LayerNorm,
+    attn: Attention,
+    norm2: LayerNorm,
+    ffn: SGFN,
+}
+
+impl DATB {
+    fn load(vb: nn::VarBuilder, attn: Attention, dim: usize, config: &DATConfig) -> Result<Self> {
+        let hidden_dim = (dim as f64 * config.expansion_factor) as usize;
+        Ok(Self {
+            norm1: layer_norm(dim, vb.pp("norm1"))?,
+            attn,
+            norm2: layer_norm(dim, vb.pp("norm2"))?,
+            ffn: SGFN::load(vb.pp("ffn"), dim, hidden_dim)?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, size: (usize, usize)) -> Result<Tensor> {
+        let x = self.norm1.forward(xs)?;
+        let x = match &self.attn {
+            Attention::Spatial(attn) => attn.forward(&x, size)?,
+            Attention::Channel(attn) => attn.forward(&x, size)?,
+        };
+        let x = (xs + x)?;
+        &x + self.ffn.forward(&self.norm2.forward(&x)?, size)?
+    }
+}
+
+/// Blocks followed by a conv, with a residual connection around them
+#[derive(Debug)]
+struct ResidualGroup {
+    blocks: Vec<DATB>,
+    conv: ResiConv,
+}
+
+impl ResidualGroup {
+    fn load(
+        vb: nn::VarBuilder,
+        index: usize,
+        dim: usize,
+        depth: usize,
+        num_heads: usize,
+        config: &DATConfig,
+    ) -> Result<Self> {
+        let mut blocks = vec![];
+        for i in 0..depth {
+            let vb = vb.pp(format!("blocks.{i}"));
+            let attn = if i % 2 == 0 {
+                // The windows of every other spatial block are shifted, starting
+                // with the second one of even groups and the first of odd ones
+                let shifted = match index % 2 {
+                    0 => i % 4 == 2,
+                    _ => i % 4 == 0,
+                };
+                Attention::Spatial(AdaptiveSpatialAttention::load(
+                    vb.pp("attn"),
+                    dim,
+                    num_heads,
+                    shifted,
+                    config,
+                )?)
+            } else {
+                Attention::Channel(AdaptiveChannelAttention::load(
+                    vb.pp("attn"),
+                    dim,
+                    num_heads,
+                    config.qkv_bias,
+                )?)
+            };
+            blocks.push(DATB::load(vb, attn, dim, config)?);
+        }
+        let conv = ResiConv::load(vb.pp("conv"), dim, config.resi_connection)?;
+        Ok(Self { blocks, conv })
+    }
+
+    fn forward(&self, xs: &Tensor, size: (usize, usize)) -> Result<Tensor> {
+        let mut out = xs.clone();
+        for block in self.blocks.iter() {
+            out = block.forward(&out, size)?;
+        }
+        embed(&self.conv.forward(&unembed(&out, size)?)?)? + xs
+    }
+}
+
+#[derive(Debug)]
+pub struct DAT {
+    conv_first: Conv2d,
+    before_rg: LayerNorm,
+    layers: Vec<ResidualGroup>,
+    norm: LayerNorm,
+    conv_after_body: ResiConv,
+    reconstruction: Reconstruction,
+    in_nc: usize,
+    variant: &'static str,
+}
+
+impl DAT {
+    pub fn load(vb: nn::VarBuilder, config: &DATConfig) -> Result<Self> {
+        let dim = config.embed_dim;
+        let mut layers = vec![];
+        for (i, (depth, num_heads)) in config.depths.iter().zip(&config.num_heads).enumerate() {
+            layers.push(ResidualGroup::load(
+                vb.pp(format!("layers.{i}")),
+                i,
+                dim,
+                *depth,
+                *num_heads,
+                config,
+            )?);
+        }
+        Ok(Self {
+            conv_first: conv(config.in_nc, dim, 3, vb.pp("conv_first"))?,
+            before_rg: layer_norm(dim, vb.pp("before_RG.1"))?,
+            layers,
+            norm: layer_norm(dim, vb.pp("norm"))?,
+            conv_after_body: ResiConv::load(vb.pp("conv_after_body"), dim, config.resi_connection)?,
+            reconstruction: Reconstruction::load(
+                vb,
+                config.upsampler,
+                dim,
+                config.num_feat,
+                config.out_nc,
+                config.scale,
+            )?,
+            in_nc: config.in_nc,
+            variant: config.variant(),
+        })
+    }
+
+    /// See `DATConfig::variant`
+    pub fn variant(&self) -> &'static str {
+        self.variant
+    }
+
+    /// The convs outside of the attention blocks, for `conv::quantize`, see
+    /// `SwinIR::convs_mut`
+    pub fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = vec![&mut self.conv_first];
+        for layer in self.layers.iter_mut() {
+            convs.extend(layer.conv.convs_mut());
+        }
+        convs.extend(self.conv_after_body.convs_mut());
+        convs.extend(self.reconstruction.convs_mut());
+        convs
+    }
+
+    /// `forward`, recording the time of every module in `profiler`
+    pub fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        // The attention pads its windows itself, the image needs no padding
+        let mean = input_mean(self.in_nc, xs)?;
+        let xs = xs.broadcast_sub(&mean)?;
+        let size = (xs.dim(2)?, xs.dim(3)?);
+
+        let feat = self.conv_first.forward(&xs)?;
+        profiler.record("conv_first", &feat)?;
+        let mut out = self.before_rg.forward(&embed(&feat)?)?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            out = layer.forward(&out, size)?;
+            profiler.record(&format!("layers.{i}"), &out)?;
+        }
+        let out = unembed(&self.norm.forward(&out)?, size)?;
+        let out = (self.conv_after_body.forward(&out)? + feat)?;
+        profiler.record("conv_after_body", &out)?;
+
+        let out = self.reconstruction.forward(&out, &xs)?;
+        profiler.record("reconstruction", &out)?;
+        out.broadcast_add(&mean)
+    }
+}
+
+impl nn::Module for DAT {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.forward_profiled(xs, &mut Profiler::disabled())
+    }
+}
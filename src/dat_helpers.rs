@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Tensor};
+
+use crate::dat::DATConfig;
+use crate::swinir_helpers;
+
+// DAT shares the key names of SwinIR outside of its residual groups, see
+// `swinir_helpers` for those
+
+/// Number of blocks of every residual group
+pub fn get_depths(state_dict: &HashMap<String, Tensor>) -> Vec<usize> {
+    let mut depths: Vec<usize> = vec![];
+    for key in state_dict.keys() {
+        // layers.{i}.blocks.{j}.norm1.weight
+        let parts: Vec<&str> = key.split('.').collect();
+        if parts.len() < 4 || parts[0] != "layers" || parts[2] != "blocks" {
+            continue;
+        }
+        let (Ok(layer), Ok(block)) = (parts[1].parse::<usize>(), parts[3].parse::<usize>()) else {
+            continue;
+        };
+        if depths.len() <= layer {
+            depths.resize(layer + 1, 0);
+        }
+        depths[layer] = depths[layer].max(block + 1);
+    }
+    return depths;
+}
+
+/// Number of attention heads of every residual group, twice those of each
+/// half of the spatial attention of its first block
+pub fn get_num_heads(state_dict: &HashMap<String, Tensor>) -> Vec<usize> {
+    return (0..get_depths(state_dict).len())
+        .map(|i| {
+            let key = format!("layers.{i}.blocks.0.attn.attns.0.pos.pos3.2.weight");
+            2 * state_dict[&key].shape().dims()[0]
+        })
+        .collect();
+}
+
+pub fn get_split_size(state_dict: &HashMap<String, Tensor>) -> (usize, usize) {
+    // The relative positions saved with the spatial attention end with
+    // (height - 1, width - 1)
+    return match state_dict.get("layers.0.blocks.0.attn.attns.0.rpe_biases") {
+        Some(x) => {
+            let positions = x.to_dtype(DType::F32).unwrap().to_vec2::<f32>().unwrap();
+            let last = positions.last().unwrap();
+            (last[0] as usize + 1, last[1] as usize + 1)
+        }
+        None => (8, 32),
+    };
+}
+
+pub fn get_expansion_factor(state_dict: &HashMap<String, Tensor>) -> f64 {
+    return match state_dict.get("layers.0.blocks.0.ffn.fc1.weight") {
+        Some(x) => x.shape().dims()[0] as f64 / swinir_helpers::get_embed_dim(state_dict) as f64,
+        None => 4.,
+    };
+}
+
+/// Every hyperparameter of the model, `DATConfig::variant` tells which of
+/// the paper it is
+pub fn get_config(state_dict: &HashMap<String, Tensor>) -> DATConfig {
+    return DATConfig {
+        in_nc: swinir_helpers::get_in_nc(state_dict),
+        out_nc: swinir_helpers::get_out_nc(state_dict),
+        scale: swinir_helpers::get_scale(state_dict),
+        embed_dim: swinir_helpers::get_embed_dim(state_dict),
+        depths: get_depths(state_dict),
+        num_heads: get_num_heads(state_dict),
+        split_size: get_split_size(state_dict),
+        expansion_factor: get_expansion_factor(state_dict),
+        qkv_bias: state_dict.contains_key("layers.0.blocks.0.attn.qkv.bias"),
+        upsampler: swinir_helpers::get_upsampler(state_dict),
+        num_feat: swinir_helpers::get_num_feat(state_dict),
+        resi_connection: swinir_helpers::get_resi_connection(state_dict),
+    };
+}
@@ -0,0 +1,441 @@
+// Second-order degradation model of Real-ESRGAN (Wang et al., 2021), following
+// the basicsr implementation: blur, random resize, noise and JPEG compression,
+// applied twice, then a resize to the LR size combined with a sinc filter.
+// Every random choice is drawn from the caller's RNG, so a seed reproduces the
+// exact same pairs.
+
+use std::f64::consts::{FRAC_2_PI, PI};
+
+use candle_core::{DType, Error, Result, Tensor};
+use image::codecs::jpeg::JpegEncoder;
+use image::ColorType;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand_distr::{Distribution, Normal, Poisson};
+use serde::Deserialize;
+
+use crate::resize::{resize, ResizeFilter};
+
+/// Settings of one degradation round. The defaults are the first round of
+/// RealESRGAN_x4plus.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Stage {
+    /// Probability of blurring at all
+    pub blur_prob: f64,
+    /// Side lengths the blur kernel is picked from, all odd
+    pub kernel_sizes: Vec<usize>,
+    /// Probabilities of the isotropic, anisotropic, generalized isotropic,
+    /// generalized anisotropic, plateau isotropic and plateau anisotropic kernels
+    pub kernel_probs: [f64; 6],
+    /// Probability of using a sinc kernel instead of the ones above
+    pub sinc_prob: f64,
+    pub blur_sigma: (f64, f64),
+    pub betag_range: (f64, f64),
+    pub betap_range: (f64, f64),
+    /// Probabilities of scaling up, down or keeping the size
+    pub resize_probs: [f64; 3],
+    pub resize_range: (f64, f64),
+    /// Probability of Gaussian noise, Poisson noise is used otherwise
+    pub gaussian_noise_prob: f64,
+    /// Standard deviation of the Gaussian noise, on the 0-255 range
+    pub noise_range: (f64, f64),
+    pub poisson_scale_range: (f64, f64),
+    /// Probability of using the same noise for every channel
+    pub gray_noise_prob: f64,
+    pub jpeg_range: (u8, u8),
+}
+
+impl Default for Stage {
+    fn default() -> Self {
+        Self {
+            blur_prob: 1.,
+            kernel_sizes: (7..=21).step_by(2).collect(),
+            kernel_probs: [0.45, 0.25, 0.12, 0.03, 0.12, 0.03],
+            sinc_prob: 0.1,
+            blur_sigma: (0.2, 3.),
+            betag_range: (0.5, 4.),
+            betap_range: (1., 2.),
+            resize_probs: [0.2, 0.7, 0.1],
+            resize_range: (0.15, 1.5),
+            gaussian_noise_prob: 0.5,
+            noise_range: (1., 30.),
+            poisson_scale_range: (0.05, 3.),
+            gray_noise_prob: 0.4,
+            jpeg_range: (30, 95),
+        }
+    }
+}
+
+/// Both degradation rounds and the final sinc filter, RealESRGAN_x4plus
+/// settings by default. Can be read from JSON, missing fields keep their
+/// default.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DegradationConfig {
+    pub first: Stage,
+    pub second: Stage,
+    /// Probability of the sinc filter applied with the final resize
+    pub final_sinc_prob: f64,
+}
+
+impl Default for DegradationConfig {
+    fn default() -> Self {
+        Self {
+            first: Stage::default(),
+            second: Stage {
+                blur_prob: 0.8,
+                blur_sigma: (0.2, 1.5),
+                resize_probs: [0.3, 0.4, 0.3],
+                resize_range: (0.3, 1.2),
+                noise_range: (1., 25.),
+                poisson_scale_range: (0.05, 2.5),
+                ..Stage::default()
+            },
+            final_sinc_prob: 0.8,
+        }
+    }
+}
+
+impl DegradationConfig {
+    pub fn load(path: &str) -> Self {
+        return serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+    }
+}
+
+/// Turns HR images into LR images `scale` times smaller
+#[derive(Debug, Clone)]
+pub struct Degradation {
+    pub config: DegradationConfig,
+    pub scale: usize,
+}
+
+impl Degradation {
+    pub fn new(config: DegradationConfig, scale: usize) -> Self {
+        Self { config, scale }
+    }
+
+    /// Degrade a (batch, 3, height, width) tensor in the 0-1 range, every image
+    /// with its own random parameters. The height and width must be multiples
+    /// of the scale.
+    pub fn degrade(&self, hr: &Tensor, rng: &mut StdRng) -> Result<Tensor> {
+        let lr = (0..hr.dim(0)?)
+            .map(|i| self.degrade_one(&hr.narrow(0, i, 1)?, rng))
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::cat(&lr, 0)
+    }
+
+    fn degrade_one(&self, hr: &Tensor, rng: &mut StdRng) -> Result<Tensor> {
+        let (_, _, height, width) = hr.dims4()?;
+        let lr_size = (height / self.scale, width / self.scale);
+        let first = &self.config.first;
+        let second = &self.config.second;
+
+        let xs = self.stage(&hr.to_dtype(DType::F32)?, first, (height, width), rng)?;
+        let xs = jpeg(
+            &xs,
+            rng.random_range(first.jpeg_range.0..=first.jpeg_range.1),
+        )?;
+        let xs = self.stage(&xs, second, lr_size, rng)?;
+
+        // The final resize and sinc filter happen before or after the last
+        // JPEG compression, with equal probability
+        let quality = rng.random_range(second.jpeg_range.0..=second.jpeg_range.1);
+        let xs = if rng.random_bool(0.5) {
+            let xs = self.final_resize(&xs, lr_size, rng)?;
+            jpeg(&xs, quality)?
+        } else {
+            let xs = jpeg(&xs, quality)?;
+            self.final_resize(&xs, lr_size, rng)?
+        };
+        // Resizing and the sinc filter can overshoot after the JPEG round
+        (xs * 255.)?.round()?.clamp(0f32, 255f32)? / 255.
+    }
+
+    /// Blur, resize relative to `base` and add noise
+    fn stage(
+        &self,
+        xs: &Tensor,
+        stage: &Stage,
+        base: (usize, usize),
+        rng: &mut StdRng,
+    ) -> Result<Tensor> {
+        let mut xs = xs.clone();
+        if rng.random_bool(stage.blur_prob) {
+            let kernel = random_kernel(stage, rng);
+            xs = filter2d(&xs, &kernel)?;
+        }
+
+        let factor = match choose(&stage.resize_probs, rng) {
+            0 => rng.random_range(1f64..=stage.resize_range.1),
+            1 => rng.random_range(stage.resize_range.0..=1.),
+            _ => 1.,
+        };
+        let height = ((base.0 as f64 * factor) as usize).max(1);
+        let width = ((base.1 as f64 * factor) as usize).max(1);
+        xs = resize(&xs, height, width, random_filter(rng))?;
+
+        xs = if rng.random_bool(stage.gaussian_noise_prob) {
+            gaussian_noise(&xs, stage, rng)?
+        } else {
+            poisson_noise(&xs, stage, rng)?
+        };
+        xs.clamp(0f32, 1f32)
+    }
+
+    fn final_resize(&self, xs: &Tensor, size: (usize, usize), rng: &mut StdRng) -> Result<Tensor> {
+        let xs = resize(xs, size.0, size.1, random_filter(rng))?;
+        if !rng.random_bool(self.config.final_sinc_prob) {
+            return Ok(xs);
+        }
+        let sizes = &self.config.second.kernel_sizes;
+        let kernel_size = sizes[rng.random_range(0..sizes.len())];
+        let cutoff = rng.random_range(PI / 3.0..=PI);
+        filter2d(&xs, &Kernel::sinc(kernel_size, cutoff))
+    }
+}
+
+/// Index picked with the given probabilities
+fn choose(probs: &[f64], rng: &mut StdRng) -> usize {
+    let mut x = rng.random::<f64>() * probs.iter().sum::<f64>();
+    for (i, p) in probs.iter().enumerate() {
+        if x < *p {
+            return i;
+        }
+        x -= p;
+    }
+    return probs.len() - 1;
+}
+
+// basicsr picks between area, bilinear and bicubic interpolation. Unlike
+// PyTorch's interpolate, the resize filters here also antialias when
+// downscaling.
+fn random_filter(rng: &mut StdRng) -> ResizeFilter {
+    let filters = [
+        ResizeFilter::Area,
+        ResizeFilter::Bilinear,
+        ResizeFilter::Bicubic,
+    ];
+    return filters[rng.random_range(0..filters.len())];
+}
+
+/// Square convolution kernel, normalised to sum to one
+struct Kernel {
+    size: usize,
+    data: Vec<f64>,
+}
+
+impl Kernel {
+    fn from_fn(size: usize, f: impl Fn(f64, f64) -> f64) -> Self {
+        let r = (size / 2) as f64;
+        let mut data = Vec::with_capacity(size * size);
+        for y in 0..size {
+            for x in 0..size {
+                data.push(f(x as f64 - r, y as f64 - r));
+            }
+        }
+        let total: f64 = data.iter().sum();
+        Self {
+            size,
+            data: data.into_iter().map(|v| v / total).collect(),
+        }
+    }
+
+    /// Mahalanobis distance of every offset for a Gaussian with standard
+    /// deviations `sigma` along axes rotated by `theta`, shaped by `f`
+    fn bivariate(size: usize, sigma: (f64, f64), theta: f64, f: impl Fn(f64) -> f64) -> Self {
+        let (sin, cos) = theta.sin_cos();
+        Self::from_fn(size, |x, y| {
+            let u = x * cos + y * sin;
+            let v = -x * sin + y * cos;
+            f(u * u / (sigma.0 * sigma.0) + v * v / (sigma.1 * sigma.1))
+        })
+    }
+
+    /// Circular low-pass filter with cutoff frequency `cutoff`, which rings
+    /// like the artifacts of sharpened or badly resized images
+    fn sinc(size: usize, cutoff: f64) -> Self {
+        Self::from_fn(size, |x, y| {
+            let r = (x * x + y * y).sqrt();
+            if r == 0. {
+                cutoff * cutoff / (4. * PI)
+            } else {
+                cutoff * bessel_j1(cutoff * r) / (2. * PI * r)
+            }
+        })
+    }
+}
+
+fn random_kernel(stage: &Stage, rng: &mut StdRng) -> Kernel {
+    let size = stage.kernel_sizes[rng.random_range(0..stage.kernel_sizes.len())];
+    if rng.random_bool(stage.sinc_prob) {
+        let min_cutoff = if size < 13 { PI / 3. } else { PI / 5. };
+        return Kernel::sinc(size, rng.random_range(min_cutoff..=PI));
+    }
+
+    let kind = choose(&stage.kernel_probs, rng);
+    let (s0, s1) = stage.blur_sigma;
+    let isotropic = kind % 2 == 0;
+    let sigma_x = rng.random_range(s0..=s1);
+    let (sigma, theta) = if isotropic {
+        ((sigma_x, sigma_x), 0.)
+    } else {
+        (
+            (sigma_x, rng.random_range(s0..=s1)),
+            rng.random_range(-PI..=PI),
+        )
+    };
+    // Shape parameters are as likely to be below one as above it
+    let mut beta = |range: (f64, f64)| {
+        if rng.random_bool(0.5) {
+            rng.random_range(range.0..=1.)
+        } else {
+            rng.random_range(1f64..=range.1)
+        }
+    };
+    return match kind / 2 {
+        0 => Kernel::bivariate(size, sigma, theta, |d| (-0.5 * d).exp()),
+        1 => {
+            let beta = beta(stage.betag_range);
+            Kernel::bivariate(size, sigma, theta, |d| (-0.5 * d.powf(beta)).exp())
+        }
+        _ => {
+            let beta = beta(stage.betap_range);
+            Kernel::bivariate(size, sigma, theta, |d| 1. / (d.powf(beta) + 1.))
+        }
+    };
+}
+
+/// Bessel function of the first kind of order one (Numerical Recipes)
+fn bessel_j1(x: f64) -> f64 {
+    let ax = x.abs();
+    if ax < 8. {
+        let y = x * x;
+        let p = x
+            * (72362614232.
+                + y * (-7895059235.
+                    + y * (242396853.1
+                        + y * (-2972611.439 + y * (15704.48260 + y * -30.16036606)))));
+        let q = 144725228442.
+            + y * (2300535178. + y * (18583304.74 + y * (99447.43394 + y * (376.9991397 + y))));
+        return p / q;
+    }
+    let z = 8. / ax;
+    let y = z * z;
+    let xx = ax - 2.356194491;
+    let p = 1.
+        + y * (0.183105e-2 + y * (-0.3516396496e-4 + y * (0.2457520174e-5 + y * -0.240337019e-6)));
+    let q = 0.04687499995
+        + y * (-0.2002690873e-3
+            + y * (0.8449199096e-5 + y * (-0.88228987e-6 + y * 0.105787412e-6)));
+    let ans = (FRAC_2_PI / ax).sqrt() * (xx.cos() * p - z * xx.sin() * q);
+    return if x < 0. { -ans } else { ans };
+}
+
+// Reflect padding, folding back and forth for pads larger than the image
+fn pad_reflect(xs: &Tensor, dim: usize, pad: usize) -> Result<Tensor> {
+    let size = xs.dim(dim)? as i64;
+    let period = (2 * (size - 1)).max(1);
+    let index: Vec<u32> = (-(pad as i64)..size + pad as i64)
+        .map(|i| {
+            let i = i.rem_euclid(period);
+            (if i < size { i } else { period - i }) as u32
+        })
+        .collect();
+    let index = Tensor::new(index, xs.device())?;
+    xs.index_select(&index, dim)
+}
+
+/// Convolve every channel with the kernel, with reflect padding
+fn filter2d(xs: &Tensor, kernel: &Kernel) -> Result<Tensor> {
+    let channels = xs.dim(1)?;
+    let pad = kernel.size / 2;
+    let data: Vec<f32> = kernel.data.iter().map(|&v| v as f32).collect();
+    let kernel = Tensor::from_vec(data, (1, 1, kernel.size, kernel.size), xs.device())?
+        .repeat((channels, 1, 1, 1))?;
+    let xs = pad_reflect(&pad_reflect(xs, 2, pad)?, 3, pad)?;
+    xs.conv2d(&kernel, 0, 1, 1, channels)
+}
+
+/// Samples for every pixel, shared by the channels with `gray`
+fn sample_noise(xs: &Tensor, gray: bool, mut sample: impl FnMut(usize) -> f32) -> Result<Tensor> {
+    let (b_size, channels, height, width) = xs.dims4()?;
+    let plane = height * width;
+    let data: Vec<f32> = if gray {
+        let data: Vec<f32> = (0..plane).map(&mut sample).collect();
+        data.repeat(channels)
+    } else {
+        (0..channels * plane).map(sample).collect()
+    };
+    Tensor::from_vec(data, (b_size, channels, height, width), xs.device())
+}
+
+fn gaussian_noise(xs: &Tensor, stage: &Stage, rng: &mut StdRng) -> Result<Tensor> {
+    let sigma = rng.random_range(stage.noise_range.0..=stage.noise_range.1) / 255.;
+    let gray = rng.random_bool(stage.gray_noise_prob);
+    let normal = Normal::new(0., sigma as f32).map_err(Error::wrap)?;
+    let noise = sample_noise(xs, gray, |_| normal.sample(rng))?;
+    xs + noise
+}
+
+fn poisson_noise(xs: &Tensor, stage: &Stage, rng: &mut StdRng) -> Result<Tensor> {
+    let (s0, s1) = stage.poisson_scale_range;
+    let scale = rng.random_range(s0..=s1);
+    let gray = rng.random_bool(stage.gray_noise_prob);
+    // Photon counts are quantised to the number of distinct 8-bit levels in
+    // the image, rounded up to a power of two
+    let mut levels = [false; 256];
+    for v in (xs * 255.)?
+        .round()?
+        .clamp(0f32, 255f32)?
+        .flatten_all()?
+        .to_vec1::<f32>()?
+    {
+        levels[v as usize] = true;
+    }
+    let distinct = levels.iter().filter(|&&l| l).count();
+    let vals = (distinct as f32).log2().ceil().exp2();
+
+    let source = if gray {
+        // BT.601 luma, as in torchvision's rgb_to_grayscale
+        let weights = Tensor::new(&[0.299f32, 0.587, 0.114], xs.device())?.reshape((1, 3, 1, 1))?;
+        xs.broadcast_mul(&weights)?.sum_keepdim(1)?
+    } else {
+        xs.clone()
+    };
+    let values = source.flatten_all()?.to_vec1::<f32>()?;
+    let noise = sample_noise(xs, gray, |i| {
+        let lambda = values[i] * vals;
+        let count = if lambda > 0. {
+            Poisson::new(lambda).unwrap().sample(rng)
+        } else {
+            0.
+        };
+        count / vals - values[i]
+    })?;
+    xs + (noise * scale)?
+}
+
+/// Encode the image as a JPEG of the given quality and decode it again
+fn jpeg(xs: &Tensor, quality: u8) -> Result<Tensor> {
+    let (_, channels, height, width) = xs.dims4()?;
+    assert_eq!(channels, 3, "JPEG compression needs RGB images");
+    let data = (xs.squeeze(0)?.permute((1, 2, 0))? * 255.)?
+        .round()?
+        .clamp(0f32, 255f32)?
+        .to_dtype(DType::U8)?
+        .flatten_all()?
+        .to_vec1::<u8>()?;
+    let mut buffer = vec![];
+    JpegEncoder::new_with_quality(&mut buffer, quality)
+        .encode(&data, width as u32, height as u32, ColorType::Rgb8)
+        .map_err(Error::wrap)?;
+    let decoded = image::load_from_memory_with_format(&buffer, image::ImageFormat::Jpeg)
+        .map_err(Error::wrap)?
+        .to_rgb8();
+    let xs = Tensor::from_vec(decoded.into_raw(), (height, width, 3), xs.device())?
+        .permute((2, 0, 1))?
+        .unsqueeze(0)?
+        .to_dtype(DType::F32)?;
+    xs / 255.
+}
@@ -0,0 +1,120 @@
+use std::path::{Path, PathBuf};
+
+use candle_core::{DType, Device};
+use clap::builder::RangedU64ValueParser;
+use esrgan_candle_rs::degradation::{Degradation, DegradationConfig};
+use image::{DynamicImage, RgbImage};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::train::sample_patch;
+use crate::{exit_with_error, img2tensor, tensor2img};
+
+/// Write LR/HR training pairs made with the Real-ESRGAN degradations
+#[derive(clap::Args, Debug)]
+pub struct DegradeArgs {
+    /// Folder with the high-resolution images
+    #[arg(short, long)]
+    input: String,
+
+    /// Folder the pairs are written to, in its `hr` and `lr` subfolders
+    #[arg(short, long)]
+    output: String,
+
+    /// Device to run the degradations on
+    /// -1 for CPU, 0 for GPU 0, 1 for GPU 1, etc.
+    #[arg(short, long, default_value = "-1")]
+    device: i32,
+
+    /// Downscaling factor between the HR and LR images
+    #[arg(short, long, default_value = "4", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    scale: usize,
+
+    /// JSON file overriding the default RealESRGAN_x4plus degradation settings
+    #[arg(long)]
+    config: Option<String>,
+
+    /// Seed of every random choice, the same seed and inputs give the same pairs
+    #[arg(long, default_value = "0")]
+    seed: u64,
+
+    /// Degraded versions written for every image
+    #[arg(long, default_value = "1")]
+    repeats: usize,
+
+    /// Take random square crops of this size instead of the whole images, a
+    /// multiple of the scale
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    patch_size: Option<usize>,
+}
+
+pub fn degrade(args: &DegradeArgs) {
+    if let Some(size) = args.patch_size.filter(|size| size % args.scale != 0) {
+        exit_with_error(format!(
+            "The patch size {size} isn't a multiple of the scale {}",
+            args.scale
+        ));
+    }
+    let device = match args.device {
+        -1 => Device::Cpu,
+        _ => Device::new_cuda(args.device as usize).unwrap(),
+    };
+    let config = match &args.config {
+        Some(path) => DegradationConfig::load(path),
+        None => DegradationConfig::default(),
+    };
+    let degradation = Degradation::new(config, args.scale);
+    let mut rng = StdRng::seed_from_u64(args.seed);
+
+    let hr_dir = Path::new(&args.output).join("hr");
+    let lr_dir = Path::new(&args.output).join("lr");
+    std::fs::create_dir_all(&hr_dir).unwrap();
+    std::fs::create_dir_all(&lr_dir).unwrap();
+
+    // Sorted so the pairs only depend on the seed
+    let mut files: Vec<PathBuf> = std::fs::read_dir(&args.input)
+        .unwrap()
+        .map(|file| file.unwrap().path())
+        .collect();
+    files.sort();
+
+    for path in files {
+        let img = match image::open(&path) {
+            Ok(img) => img.to_rgb8(),
+            Err(err) => {
+                eprintln!("Skipping {}: {err}", path.display());
+                continue;
+            }
+        };
+        let stem = path.file_stem().unwrap().to_string_lossy();
+        for i in 0..args.repeats {
+            let hr = match args.patch_size {
+                Some(size) if (img.width() as usize) < size || (img.height() as usize) < size => {
+                    eprintln!("Skipping {}: smaller than the patch size", path.display());
+                    break;
+                }
+                Some(size) => sample_patch(&img, size, &mut rng),
+                None => crop_to_multiple(&img, args.scale),
+            };
+            let hr_t = img2tensor(DynamicImage::ImageRgb8(hr.clone()), &device, DType::F32);
+            let lr_t = degradation.degrade(&hr_t, &mut rng).unwrap();
+            let lr = tensor2img((lr_t.squeeze(0).unwrap() * 255.).unwrap().round().unwrap());
+
+            let name = if args.repeats == 1 {
+                format!("{stem}.png")
+            } else {
+                format!("{stem}_{i}.png")
+            };
+            hr.save(hr_dir.join(&name)).unwrap();
+            lr.save(lr_dir.join(&name)).unwrap();
+            println!("Saved {name}");
+        }
+    }
+}
+
+/// Crop the bottom and right edges so both sides are multiples of `scale`
+fn crop_to_multiple(img: &RgbImage, scale: usize) -> RgbImage {
+    let scale = scale as u32;
+    let (width, height) = (img.width() / scale * scale, img.height() / scale * scale);
+    return image::imageops::crop_imm(img, 0, 0, width, height).to_image();
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Tensor};
+
+// What code calling a model has to know before running it: the size and
+// channels of the output, and how to cut the input into tiles that stitch
+// back together without seams.
+
+/// Buffers that checkpoints store next to the weights
+const BUFFERS: [&str; 6] = [
+    "running_mean",
+    "running_var",
+    "num_batches_tracked",
+    "attn_mask",
+    "relative_position_index",
+    "rpe_biases",
+];
+
+/// Scale, channels and tiling hints of a loaded model
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelDescriptor {
+    /// Architecture, with the variant for those that have several (hat-m,
+    /// dat-light...)
+    pub arch: String,
+    pub scale: usize,
+    pub in_nc: usize,
+    pub out_nc: usize,
+    /// Input pixels on each side of a pixel that its output depends on, None
+    /// when that is the whole image (attention, global pooling) or unknown
+    pub receptive_field: Option<usize>,
+    /// The height and width of the input have to be a multiple of this. Models
+    /// that pad their input themselves take any size.
+    pub input_multiple: usize,
+    /// Dtypes the model runs in on the device it was loaded on
+    pub dtypes: Vec<DType>,
+    /// Number of weights, without the buffers of the checkpoint
+    pub params: usize,
+}
+
+impl ModelDescriptor {
+    /// A model taking inputs of any size in any float dtype `device` has
+    /// kernels for, whose receptive field isn't known and whose parameters are
+    /// counted from `state_dict`
+    pub fn new(
+        arch: &str,
+        scale: usize,
+        in_nc: usize,
+        out_nc: usize,
+        state_dict: &HashMap<String, Tensor>,
+        device: &Device,
+    ) -> Self {
+        let mut dtypes = vec![DType::F32, DType::F64, DType::F16];
+        // No bf16 matmul on the CPU
+        if !device.is_cpu() {
+            dtypes.push(DType::BF16);
+        }
+        Self {
+            arch: arch.to_string(),
+            scale,
+            in_nc,
+            out_nc,
+            receptive_field: None,
+            input_multiple: 1,
+            dtypes,
+            params: count_params(state_dict),
+        }
+    }
+}
+
+/// Number of weights in `state_dict`, leaving out integer tensors and the
+/// buffers of BatchNorm and attention layers
+pub fn count_params(state_dict: &HashMap<String, Tensor>) -> usize {
+    state_dict
+        .iter()
+        .filter(|(name, tensor)| {
+            let last = name.rsplit('.').next().unwrap();
+            let float = matches!(
+                tensor.dtype(),
+                DType::F16 | DType::BF16 | DType::F32 | DType::F64
+            );
+            float && !BUFFERS.iter().any(|b| last.starts_with(b))
+        })
+        .map(|(_, tensor)| tensor.elem_count())
+        .sum()
+}
@@ -0,0 +1,283 @@
+use std::cell::RefCell;
+
+use candle_core::{Module, ModuleT, Result, Tensor};
+use candle_nn as nn;
+
+/// Conv without bias whose weight is divided by its largest singular value,
+/// estimated with one power iteration per forward pass. Uses the keys of
+/// PyTorch's `spectral_norm` (`weight_orig`, `weight_u`, `weight_v`).
+#[derive(Debug)]
+struct SpectralNormConv {
+    name: String,
+    weight_orig: Tensor,
+    // Power iteration state, updated without gradients on every forward
+    u: RefCell<Tensor>,
+    v: RefCell<Tensor>,
+    stride: usize,
+    padding: usize,
+}
+
+fn normalize(xs: &Tensor) -> Result<Tensor> {
+    let norm = (xs.sqr()?.sum_all()?.sqrt()? + 1e-12)?;
+    xs.broadcast_div(&norm)
+}
+
+impl SpectralNormConv {
+    fn load(
+        vb: nn::VarBuilder,
+        c_in: usize,
+        c_out: usize,
+        kernel_size: usize,
+        stride: usize,
+        padding: usize,
+    ) -> Result<Self> {
+        let bound = 1. / ((c_in * kernel_size * kernel_size) as f64).sqrt();
+        let weight_orig = vb.get_with_hints(
+            (c_out, c_in, kernel_size, kernel_size),
+            "weight_orig",
+            nn::Init::Uniform {
+                lo: -bound,
+                up: bound,
+            },
+        )?;
+        let randn = nn::Init::Randn {
+            mean: 0.,
+            stdev: 1.,
+        };
+        let u = normalize(&vb.get_with_hints(c_out, "weight_u", randn)?)?;
+        let v =
+            normalize(&vb.get_with_hints(c_in * kernel_size * kernel_size, "weight_v", randn)?)?;
+        Ok(Self {
+            name: vb.prefix(),
+            weight_orig,
+            u: RefCell::new(u),
+            v: RefCell::new(v),
+            stride,
+            padding,
+        })
+    }
+}
+
+impl Module for SpectralNormConv {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let c_out = self.weight_orig.dim(0)?;
+        let weight_mat = self.weight_orig.reshape((c_out, ()))?;
+        let w = weight_mat.detach()?;
+        let v = normalize(&w.t()?.matmul(&self.u.borrow().unsqueeze(1)?)?.squeeze(1)?)?;
+        let u = normalize(&w.matmul(&v.unsqueeze(1)?)?.squeeze(1)?)?;
+        let sigma = u
+            .unsqueeze(0)?
+            .matmul(&weight_mat.matmul(&v.unsqueeze(1)?)?)?
+            .squeeze(1)?
+            .squeeze(0)?;
+        *self.u.borrow_mut() = u;
+        *self.v.borrow_mut() = v;
+        let weight = self.weight_orig.broadcast_div(&sigma)?;
+        xs.conv2d(&weight, self.padding, self.stride, 1, 1)
+    }
+}
+
+/// x2 bilinear upsampling with `align_corners=False`, written as two matrix
+/// products so it can be backpropagated through
+fn upsample_bilinear2x(xs: &Tensor) -> Result<Tensor> {
+    let (_b_size, _channels, h, w) = xs.dims4()?;
+    let weights = |n: usize| {
+        let mut m = vec![0f32; 2 * n * n];
+        for i in 0..n {
+            m[2 * i * n + i] += 0.75;
+            m[2 * i * n + i.saturating_sub(1)] += 0.25;
+            m[(2 * i + 1) * n + i] += 0.75;
+            m[(2 * i + 1) * n + usize::min(i + 1, n - 1)] += 0.25;
+        }
+        Tensor::from_vec(m, (2 * n, n), xs.device())
+    };
+    let weights_h = weights(h)?.to_dtype(xs.dtype())?;
+    let weights_w = weights(w)?.to_dtype(xs.dtype())?.t()?;
+    let out = xs.contiguous()?.broadcast_matmul(&weights_w)?;
+    weights_h.broadcast_matmul(&out)
+}
+
+/// U-Net discriminator with spectral normalisation from Real-ESRGAN. Gives a
+/// realness score for every pixel.
+#[derive(Debug)]
+pub struct UNetDiscriminatorSN {
+    conv0: nn::Conv2d,
+    down: Vec<SpectralNormConv>,
+    up: Vec<SpectralNormConv>,
+    conv7: SpectralNormConv,
+    conv8: SpectralNormConv,
+    conv9: nn::Conv2d,
+    skip_connection: bool,
+    lrelu: nn::Activation,
+}
+
+impl UNetDiscriminatorSN {
+    pub fn load(
+        vb: nn::VarBuilder,
+        num_in_ch: usize,
+        num_feat: usize,
+        skip_connection: bool,
+    ) -> Result<Self> {
+        let config = nn::Conv2dConfig {
+            padding: 1,
+            stride: 1,
+            dilation: 1,
+            groups: 1,
+        };
+        let nf = num_feat;
+        let conv0 = nn::conv2d(num_in_ch, nf, 3, config, vb.pp("conv0"))?;
+        let down = vec![
+            SpectralNormConv::load(vb.pp("conv1"), nf, nf * 2, 4, 2, 1)?,
+            SpectralNormConv::load(vb.pp("conv2"), nf * 2, nf * 4, 4, 2, 1)?,
+            SpectralNormConv::load(vb.pp("conv3"), nf * 4, nf * 8, 4, 2, 1)?,
+        ];
+        let up = vec![
+            SpectralNormConv::load(vb.pp("conv4"), nf * 8, nf * 4, 3, 1, 1)?,
+            SpectralNormConv::load(vb.pp("conv5"), nf * 4, nf * 2, 3, 1, 1)?,
+            SpectralNormConv::load(vb.pp("conv6"), nf * 2, nf, 3, 1, 1)?,
+        ];
+        let conv7 = SpectralNormConv::load(vb.pp("conv7"), nf, nf, 3, 1, 1)?;
+        let conv8 = SpectralNormConv::load(vb.pp("conv8"), nf, nf, 3, 1, 1)?;
+        let conv9 = nn::conv2d(nf, 1, 3, config, vb.pp("conv9"))?;
+        Ok(Self {
+            conv0,
+            down,
+            up,
+            conv7,
+            conv8,
+            conv9,
+            skip_connection,
+            lrelu: nn::Activation::LeakyRelu(0.2),
+        })
+    }
+
+    /// Power iteration vectors by state dict key, to be stored with the weights
+    pub fn spectral_buffers(&self) -> Vec<(String, Tensor)> {
+        let mut buffers = vec![];
+        for conv in self
+            .down
+            .iter()
+            .chain(&self.up)
+            .chain([&self.conv7, &self.conv8])
+        {
+            buffers.push((format!("{}.weight_u", conv.name), conv.u.borrow().clone()));
+            buffers.push((format!("{}.weight_v", conv.name), conv.v.borrow().clone()));
+        }
+        buffers
+    }
+}
+
+impl Module for UNetDiscriminatorSN {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let x0 = self.lrelu.forward(&self.conv0.forward(xs)?)?;
+        // Outputs of every level, finest first
+        let mut skips = vec![x0];
+        for conv in &self.down {
+            let x = self.lrelu.forward(&conv.forward(skips.last().unwrap())?)?;
+            skips.push(x);
+        }
+        let mut xs = skips.pop().unwrap();
+        for conv in &self.up {
+            xs = upsample_bilinear2x(&xs)?;
+            xs = self.lrelu.forward(&conv.forward(&xs)?)?;
+            let skip = skips.pop().unwrap();
+            if self.skip_connection {
+                xs = (xs + skip)?;
+            }
+        }
+        let xs = self.lrelu.forward(&self.conv7.forward(&xs)?)?;
+        let xs = self.lrelu.forward(&self.conv8.forward(&xs)?)?;
+        self.conv9.forward(&xs)
+    }
+}
+
+#[derive(Debug)]
+struct ConvBn {
+    conv: nn::Conv2d,
+    bn: nn::BatchNorm,
+}
+
+/// VGG-style discriminator of the original ESRGAN, for 128x128 or 256x256
+/// inputs. Gives one realness score per image. The batch norms always use the
+/// statistics of the current batch, so it is only meant for training.
+#[derive(Debug)]
+pub struct VGGStyleDiscriminator {
+    conv0_0: nn::Conv2d,
+    blocks: Vec<ConvBn>,
+    linear1: nn::Linear,
+    linear2: nn::Linear,
+    lrelu: nn::Activation,
+}
+
+impl VGGStyleDiscriminator {
+    pub fn load(
+        vb: nn::VarBuilder,
+        num_in_ch: usize,
+        num_feat: usize,
+        input_size: usize,
+    ) -> Result<Self> {
+        assert!(
+            input_size == 128 || input_size == 256,
+            "the VGG-style discriminator takes 128x128 or 256x256 inputs, got {input_size}"
+        );
+        let config = nn::Conv2dConfig {
+            padding: 1,
+            stride: 1,
+            dilation: 1,
+            groups: 1,
+        };
+        let down_config = nn::Conv2dConfig {
+            padding: 1,
+            stride: 2,
+            dilation: 1,
+            groups: 1,
+        };
+        let conv0_0 = nn::conv2d(num_in_ch, num_feat, 3, config, vb.pp("conv0_0"))?;
+
+        // Every level halves the size, the features double up to 8 * num_feat
+        let levels = if input_size == 128 { 5 } else { 6 };
+        let mut blocks = vec![];
+        let mut c_in = num_feat;
+        for level in 0..levels {
+            let c_out = num_feat << usize::min(level, 3);
+            if level > 0 {
+                let conv =
+                    nn::conv2d_no_bias(c_in, c_out, 3, config, vb.pp(format!("conv{level}_0")))?;
+                let bn = nn::batch_norm(c_out, 1e-5, vb.pp(format!("bn{level}_0")))?;
+                blocks.push(ConvBn { conv, bn });
+            }
+            let conv = nn::conv2d_no_bias(
+                c_out,
+                c_out,
+                4,
+                down_config,
+                vb.pp(format!("conv{level}_1")),
+            )?;
+            let bn = nn::batch_norm(c_out, 1e-5, vb.pp(format!("bn{level}_1")))?;
+            blocks.push(ConvBn { conv, bn });
+            c_in = c_out;
+        }
+        let linear1 = nn::linear(num_feat * 8 * 4 * 4, 100, vb.pp("linear1"))?;
+        let linear2 = nn::linear(100, 1, vb.pp("linear2"))?;
+        Ok(Self {
+            conv0_0,
+            blocks,
+            linear1,
+            linear2,
+            lrelu: nn::Activation::LeakyRelu(0.2),
+        })
+    }
+}
+
+impl Module for VGGStyleDiscriminator {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut xs = self.lrelu.forward(&self.conv0_0.forward(xs)?)?;
+        for block in &self.blocks {
+            let x = block.bn.forward_t(&block.conv.forward(&xs)?, true)?;
+            xs = self.lrelu.forward(&x)?;
+        }
+        let xs = xs.flatten_from(1)?;
+        let xs = self.lrelu.forward(&self.linear1.forward(&xs)?)?;
+        self.linear2.forward(&xs)
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use candle_core::Device;
+use clap::builder::RangedU64ValueParser;
+use clap::ValueEnum;
+use esrgan_candle_rs::metrics::{ms_ssim, psnr, ssim};
+use image::imageops;
+use serde_json::json;
+
+use crate::{exit_with_error, load_model, process, ModelArgs, Precision, ProcessOptions};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    Table,
+    Json,
+}
+
+/// Upscale a validation set and compare the results to the ground truth
+#[derive(clap::Args, Debug)]
+pub struct EvalArgs {
+    /// Path to the model file in safetensors format
+    #[arg(short, long)]
+    model: String,
+
+    /// Folder with the low-resolution inputs
+    #[arg(long)]
+    lr: String,
+
+    /// Folder with the high-resolution ground truth, matched to the inputs by file
+    /// name without extension
+    #[arg(long)]
+    hr: String,
+
+    /// Device to run the model on
+    /// -1 for CPU, 0 for GPU 0, 1 for GPU 1, etc.
+    #[arg(short, long, default_value = "-1")]
+    device: i32,
+
+    #[command(flatten)]
+    model_args: ModelArgs,
+
+    /// Precision the model runs in. Outputs with NaN or Inf values, which f16
+    /// gives on some models, are computed again in f32.
+    #[arg(long, value_enum, default_value = "f32")]
+    dtype: Precision,
+
+    /// Precision the weights are kept in, --dtype by default. The convs cast
+    /// them to --dtype as they run.
+    #[arg(long, value_enum)]
+    weight_dtype: Option<Precision>,
+
+    /// Split images into tiles of at most this many pixels per side to save memory
+    #[arg(short, long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    tile: Option<usize>,
+
+    /// Compare the Y channel of YCbCr instead of RGB
+    #[arg(long)]
+    y_channel: bool,
+
+    /// Pixels ignored on every side of the images. Defaults to the model's scale.
+    #[arg(long)]
+    crop_border: Option<usize>,
+
+    /// Also compute MS-SSIM, which needs images of at least 176 pixels per side
+    #[arg(long)]
+    ms_ssim: bool,
+
+    #[arg(long, value_enum, default_value = "table")]
+    format: ReportFormat,
+}
+
+struct Scores {
+    name: String,
+    psnr: f64,
+    ssim: Option<f64>,
+    ms_ssim: Option<f64>,
+}
+
+pub fn eval(args: &EvalArgs) {
+    let device = match args.device {
+        -1 => Device::Cpu,
+        _ => Device::new_cuda(args.device as usize).unwrap(),
+    };
+
+    let dtype = args.dtype.compute_dtype(&device);
+    let weight_dtype = args.weight_dtype.unwrap_or(args.dtype).dtype();
+    let model = load_model(&args.model, &args.model_args, &device, weight_dtype);
+    let crop_border = args.crop_border.unwrap_or(model.descriptor.scale);
+
+    let options = ProcessOptions {
+        dtype,
+        tile: args.tile,
+        resize: None,
+        color_fix: None,
+        faces: None,
+    };
+
+    let hr_files: HashMap<String, PathBuf> = std::fs::read_dir(&args.hr)
+        .unwrap()
+        .map(|file| file.unwrap().path())
+        .map(|path| {
+            (
+                path.file_stem().unwrap().to_string_lossy().into_owned(),
+                path,
+            )
+        })
+        .collect();
+
+    let mut lr_files: Vec<PathBuf> = std::fs::read_dir(&args.lr)
+        .unwrap()
+        .map(|file| file.unwrap().path())
+        .collect();
+    lr_files.sort();
+
+    let mut scores = vec![];
+    for lr_path in lr_files {
+        let name = lr_path.file_stem().unwrap().to_string_lossy().into_owned();
+        let Some(hr_path) = hr_files.get(&name) else {
+            eprintln!("No ground truth for {}, skipping", lr_path.display());
+            continue;
+        };
+        let open = |path: &Path| {
+            image::open(path)
+                .map_err(|err| eprintln!("Failed to read {}: {}, skipping", path.display(), err))
+        };
+        let (Ok(lr), Ok(hr)) = (open(&lr_path), open(hr_path)) else {
+            continue;
+        };
+        let sr = process(&model, lr, &device, &options);
+        let mut hr = hr.to_rgb8();
+
+        // Ground truth that isn't a multiple of the scale is cropped to the output
+        let (width, height) = (sr.width().min(hr.width()), sr.height().min(hr.height()));
+        let sr = imageops::crop_imm(&sr, 0, 0, width, height).to_image();
+        let hr = imageops::crop(&mut hr, 0, 0, width, height).to_image();
+
+        let Some(psnr) = psnr(&sr, &hr, crop_border, args.y_channel) else {
+            eprintln!(
+                "{} is {}x{}, nothing is left after cropping {} pixels, skipping",
+                name, width, height, crop_border
+            );
+            continue;
+        };
+        scores.push(Scores {
+            name,
+            psnr,
+            ssim: ssim(&sr, &hr, crop_border, args.y_channel),
+            ms_ssim: if args.ms_ssim {
+                ms_ssim(&sr, &hr, crop_border, args.y_channel)
+            } else {
+                None
+            },
+        });
+    }
+
+    if scores.is_empty() {
+        exit_with_error(format!(
+            "No image of {} could be compared to {}",
+            args.lr, args.hr
+        ));
+    }
+    let average = Scores {
+        name: "average".to_string(),
+        psnr: scores.iter().map(|s| s.psnr).sum::<f64>() / scores.len() as f64,
+        ssim: mean(scores.iter().filter_map(|s| s.ssim)),
+        ms_ssim: mean(scores.iter().filter_map(|s| s.ms_ssim)),
+    };
+
+    match args.format {
+        ReportFormat::Table => {
+            print!("{:<32} {:>9} {:>8}", "image", "PSNR", "SSIM");
+            if args.ms_ssim {
+                print!(" {:>8}", "MS-SSIM");
+            }
+            println!();
+            for s in scores.iter().chain([&average]) {
+                print!("{:<32} {:>9.4}", s.name, s.psnr);
+                match s.ssim {
+                    Some(value) => print!(" {:>8.4}", value),
+                    None => print!(" {:>8}", "-"),
+                }
+                if args.ms_ssim {
+                    match s.ms_ssim {
+                        Some(value) => print!(" {:>8.4}", value),
+                        None => print!(" {:>8}", "-"),
+                    }
+                }
+                println!();
+            }
+        }
+        ReportFormat::Json => {
+            let to_json = |s: &Scores| {
+                json!({
+                    "name": s.name,
+                    "psnr": s.psnr,
+                    "ssim": s.ssim,
+                    "ms_ssim": s.ms_ssim,
+                })
+            };
+            let report = json!({
+                "y_channel": args.y_channel,
+                "crop_border": crop_border,
+                "images": scores.iter().map(to_json).collect::<Vec<_>>(),
+                "average": to_json(&average),
+            });
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        }
+    }
+}
+
+/// Average of the images that have a value, `None` when none has
+fn mean(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = values.fold((0., 0), |(sum, count), v| (sum + v, count + 1));
+    if count == 0 {
+        None
+    } else {
+        Some(sum / count as f64)
+    }
+}
@@ -0,0 +1,331 @@
+use candle_core::{DType, Device, Module, Result, Tensor};
+
+use crate::gfpgan::GFPGAN;
+use crate::retinaface::{Face, RetinaFace};
+
+// Face restoration over an upscaled image, the way GFPGAN's `GFPGANer` does
+// it with facexlib: detect the faces of the original image, warp each one
+// onto the FFHQ landmarks, restore it, and blend it back into the upscaled
+// image through a feathered mask.
+
+/// Landmarks of facexlib's FFHQ template for 512 pixel faces
+pub const FACE_TEMPLATE: [[f32; 2]; 5] = [
+    [192.98138, 239.94708],
+    [318.90277, 240.1936],
+    [256.63416, 314.01935],
+    [201.26117, 371.41043],
+    [313.08905, 371.15118],
+];
+/// Grey the crops are filled with outside of the image, (135, 133, 132) in
+/// the BGR of OpenCV
+const BORDER_RGB: [f32; 3] = [132. / 255., 133. / 255., 135. / 255.];
+const CONF_THRESHOLD: f32 = 0.8;
+const NMS_THRESHOLD: f32 = 0.4;
+/// Faces whose eyes are closer than this many pixels are left alone
+const EYE_DIST_THRESHOLD: f32 = 5.;
+
+/// 2x3 affine transform, from source to destination pixels
+pub type Affine = [[f32; 3]; 2];
+
+/// Restoration model run on the aligned faces
+#[derive(Debug)]
+pub enum FaceModel {
+    GFPGAN(GFPGAN),
+}
+
+impl FaceModel {
+    /// Side of the faces the model takes and returns
+    pub fn face_size(&self) -> usize {
+        match self {
+            FaceModel::GFPGAN(model) => model.config().out_size,
+        }
+    }
+}
+
+impl Module for FaceModel {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            FaceModel::GFPGAN(model) => model.forward(xs),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FaceRestorer {
+    detector: RetinaFace,
+    model: FaceModel,
+}
+
+impl FaceRestorer {
+    pub fn new(detector: RetinaFace, model: FaceModel) -> Self {
+        Self { detector, model }
+    }
+
+    /// Faces of `img`, an RGB image (1, 3, H, W) in [0, 1], big enough to be
+    /// restored
+    pub fn detect(&self, img: &Tensor) -> Result<Vec<Face>> {
+        let faces = self.detector.detect(img, CONF_THRESHOLD, NMS_THRESHOLD)?;
+        Ok(faces
+            .into_iter()
+            .filter(|face| {
+                let [left, right] = [face.landmarks[0], face.landmarks[1]];
+                (left[0] - right[0]).hypot(left[1] - right[1]) >= EYE_DIST_THRESHOLD
+            })
+            .collect())
+    }
+
+    /// Restore the faces of `img`, an RGB image (1, 3, H, W) in [0, 1], and
+    /// paste them over `upscaled`, the same image at any size
+    pub fn restore(&self, img: &Tensor, upscaled: &Tensor) -> Result<Tensor> {
+        let (_, _, h, w) = img.dims4()?;
+        let (_, _, up_h, up_w) = upscaled.dims4()?;
+        let scale = (up_w as f32 / w as f32, up_h as f32 / h as f32);
+        let size = self.model.face_size();
+        let template = FACE_TEMPLATE.map(|[x, y]| [x * size as f32 / 512., y * size as f32 / 512.]);
+
+        let img = img.to_dtype(DType::F32)?;
+        let mut out = upscaled.to_dtype(DType::F32)?;
+        for face in self.detect(&img)? {
+            let affine = similarity_transform(&face.landmarks, &template);
+            let crop = warp_affine(&img, &affine, size, size, &BORDER_RGB)?;
+            let restored = self.model.forward(&crop.affine(2., -1.)?)?;
+            let restored = restored.clamp(-1., 1.)?.affine(0.5, 0.5)?;
+            out = paste_face(&out, &restored, &affine, scale)?;
+        }
+        out.to_dtype(upscaled.dtype())
+    }
+}
+
+/// Least squares rotation, uniform scale and translation taking `src` to
+/// `dst`, which OpenCV's `estimateAffinePartial2D` approaches on clean
+/// landmarks
+pub fn similarity_transform(src: &[[f32; 2]; 5], dst: &[[f32; 2]; 5]) -> Affine {
+    let n = src.len() as f32;
+    let mean = |points: &[[f32; 2]; 5], k: usize| points.iter().map(|p| p[k]).sum::<f32>() / n;
+    let (sx, sy) = (mean(src, 0), mean(src, 1));
+    let (dx, dy) = (mean(dst, 0), mean(dst, 1));
+    let (mut norm, mut a, mut b) = (0., 0., 0.);
+    for (s, d) in src.iter().zip(dst) {
+        let (x, y) = (s[0] - sx, s[1] - sy);
+        let (u, v) = (d[0] - dx, d[1] - dy);
+        norm += x * x + y * y;
+        a += x * u + y * v;
+        b += x * v - y * u;
+    }
+    let (a, b) = (a / norm, b / norm);
+    [
+        [a, -b, dx - (a * sx - b * sy)],
+        [b, a, dy - (b * sx + a * sy)],
+    ]
+}
+
+pub fn invert_affine(m: &Affine) -> Affine {
+    let det = m[0][0] * m[1][1] - m[0][1] * m[1][0];
+    let (a, b) = (m[1][1] / det, -m[0][1] / det);
+    let (c, d) = (-m[1][0] / det, m[0][0] / det);
+    [
+        [a, b, -(a * m[0][2] + b * m[1][2])],
+        [c, d, -(c * m[0][2] + d * m[1][2])],
+    ]
+}
+
+/// `cv2.warpAffine` with bilinear interpolation: the (1, C, out_h, out_w)
+/// image `affine` takes `img` to, with `border` wherever the source pixels are
+/// outside of `img`
+pub fn warp_affine(
+    img: &Tensor,
+    affine: &Affine,
+    out_h: usize,
+    out_w: usize,
+    border: &[f32],
+) -> Result<Tensor> {
+    let (_, channels, h, w) = img.dims4()?;
+    let device = img.device();
+    // A one pixel frame of the border colour takes every tap outside the image
+    let border = Tensor::new(border, device)?.reshape((1, channels, 1, 1))?;
+    let padded = img
+        .broadcast_sub(&border)?
+        .pad_with_zeros(2, 1, 1)?
+        .pad_with_zeros(3, 1, 1)?
+        .broadcast_add(&border)?
+        .reshape((channels, (h + 2) * (w + 2)))?;
+
+    let inv = invert_affine(affine);
+    let mut indices = vec![Vec::with_capacity(out_h * out_w); 4];
+    let mut weights = vec![Vec::with_capacity(out_h * out_w); 4];
+    for y in 0..out_h {
+        for x in 0..out_w {
+            let (x, y) = (x as f32, y as f32);
+            let sx = inv[0][0] * x + inv[0][1] * y + inv[0][2];
+            let sy = inv[1][0] * x + inv[1][1] * y + inv[1][2];
+            let (x0, y0) = (sx.floor(), sy.floor());
+            let (fx, fy) = (sx - x0, sy - y0);
+            let taps = [
+                (x0, y0, (1. - fx) * (1. - fy)),
+                (x0 + 1., y0, fx * (1. - fy)),
+                (x0, y0 + 1., (1. - fx) * fy),
+                (x0 + 1., y0 + 1., fx * fy),
+            ];
+            for (k, (tx, ty, weight)) in taps.into_iter().enumerate() {
+                let tx = (tx.clamp(-1., w as f32) + 1.) as usize;
+                let ty = (ty.clamp(-1., h as f32) + 1.) as usize;
+                indices[k].push((ty * (w + 2) + tx) as u32);
+                weights[k].push(weight);
+            }
+        }
+    }
+
+    let mut out = Tensor::zeros((channels, out_h * out_w), DType::F32, device)?;
+    for (indices, weights) in indices.into_iter().zip(weights) {
+        let indices = Tensor::from_vec(indices, out_h * out_w, device)?;
+        let weights = Tensor::from_vec(weights, (1, out_h * out_w), device)?;
+        let taps = padded.index_select(&indices, 1)?;
+        out = (out + taps.broadcast_mul(&weights)?)?;
+    }
+    out.reshape((1, channels, out_h, out_w))
+}
+
+/// `cv2.erode` with a k by k square: the minimum over the window, ignoring
+/// what's outside of the image. Like OpenCV, an empty kernel is 3 by 3.
+fn erode(xs: &Tensor, k: usize) -> Result<Tensor> {
+    let k = if k == 0 { 3 } else { k };
+    // The erosion of x is 1 minus the dilation of 1 - x, whose zero padding
+    // can't win the maximum
+    let (before, after) = (k / 2, k - 1 - k / 2);
+    let inverted = xs.affine(-1., 1.)?;
+    let rows = inverted
+        .pad_with_zeros(2, before, after)?
+        .max_pool2d_with_stride((k, 1), (1, 1))?;
+    let dilated = rows
+        .pad_with_zeros(3, before, after)?
+        .max_pool2d_with_stride((1, k), (1, 1))?;
+    dilated.affine(-1., 1.)
+}
+
+/// Weights of `cv2.getGaussianKernel(ksize, 0)`, which has fixed kernels up
+/// to 7 taps and derives the sigma from the size past that
+fn gaussian_kernel(ksize: usize) -> Vec<f32> {
+    match ksize {
+        1 => return vec![1.],
+        3 => return vec![0.25, 0.5, 0.25],
+        5 => return vec![0.0625, 0.25, 0.375, 0.25, 0.0625],
+        7 => {
+            return vec![
+                0.03125, 0.109375, 0.21875, 0.28125, 0.21875, 0.109375, 0.03125,
+            ]
+        }
+        _ => {}
+    }
+    let sigma = 0.3 * ((ksize as f64 - 1.) * 0.5 - 1.) + 0.8;
+    let center = (ksize as f64 - 1.) / 2.;
+    let weights: Vec<f64> = (0..ksize)
+        .map(|i| (-(i as f64 - center).powi(2) / (2. * sigma * sigma)).exp())
+        .collect();
+    let total: f64 = weights.iter().sum();
+    weights.iter().map(|w| (w / total) as f32).collect()
+}
+
+/// Indices of a dimension of `size` padded by `pad` on both sides with
+/// OpenCV's default border, the reflection without the edge pixel
+fn reflect_101(size: usize, pad: usize) -> Vec<u32> {
+    (0..size + 2 * pad)
+        .map(|i| {
+            if size == 1 {
+                return 0;
+            }
+            let period = 2 * (size as i64 - 1);
+            let i = (i as i64 - pad as i64).rem_euclid(period);
+            (if i < size as i64 { i } else { period - i }) as u32
+        })
+        .collect()
+}
+
+/// `cv2.GaussianBlur` of a (1, 1, H, W) mask with a ksize by ksize kernel
+fn gaussian_blur(xs: &Tensor, ksize: usize) -> Result<Tensor> {
+    let (_, _, h, w) = xs.dims4()?;
+    let pad = ksize / 2;
+    let device = xs.device();
+    let kernel = Tensor::from_vec(gaussian_kernel(ksize), (1, 1, 1, ksize), device)?;
+    let rows = Tensor::from_vec(reflect_101(h, pad), h + 2 * pad, device)?;
+    let cols = Tensor::from_vec(reflect_101(w, pad), w + 2 * pad, device)?;
+    let xs = xs.index_select(&cols, 3)?.conv2d(&kernel, 0, 1, 1, 1)?;
+    let kernel = kernel.reshape((1, 1, ksize, 1))?;
+    xs.index_select(&rows, 2)?.conv2d(&kernel, 0, 1, 1, 1)
+}
+
+/// Warp `face` back from the aligned crop `affine` made, for an image scaled
+/// by `scale`, and blend it over `img` like facexlib's
+/// `paste_faces_to_input_image`. Only the rectangle around the face is
+/// computed.
+pub fn paste_face(
+    img: &Tensor,
+    face: &Tensor,
+    affine: &Affine,
+    scale: (f32, f32),
+) -> Result<Tensor> {
+    let (_, channels, h, w) = img.dims4()?;
+    let size = face.dim(3)?;
+    // From the crop to the scaled image, half a pixel further like facexlib
+    let mut inverse = invert_affine(affine);
+    for (row, s) in inverse.iter_mut().zip([scale.0, scale.1]) {
+        let offset = if s > 1. { 0.5 * s } else { 0. };
+        *row = [row[0] * s, row[1] * s, row[2] * s + offset];
+    }
+
+    let corners = [
+        [0., 0.],
+        [size as f32, 0.],
+        [0., size as f32],
+        [size as f32, size as f32],
+    ];
+    let mapped = corners.map(|[x, y]| {
+        [
+            inverse[0][0] * x + inverse[0][1] * y + inverse[0][2],
+            inverse[1][0] * x + inverse[1][1] * y + inverse[1][2],
+        ]
+    });
+    let bound =
+        |k: usize, f: fn(f32, f32) -> f32, init: f32| mapped.iter().map(|p| p[k]).fold(init, f);
+    let x0 = (bound(0, f32::min, f32::MAX).floor() - 1.).clamp(0., w as f32) as usize;
+    let y0 = (bound(1, f32::min, f32::MAX).floor() - 1.).clamp(0., h as f32) as usize;
+    let x1 = (bound(0, f32::max, f32::MIN).ceil() + 2.).clamp(0., w as f32) as usize;
+    let y1 = (bound(1, f32::max, f32::MIN).ceil() + 2.).clamp(0., h as f32) as usize;
+    if x1 <= x0 || y1 <= y0 {
+        return Ok(img.clone());
+    }
+    let (rect_w, rect_h) = (x1 - x0, y1 - y0);
+    inverse[0][2] -= x0 as f32;
+    inverse[1][2] -= y0 as f32;
+
+    // The face and a mask of ones warped together, with black around
+    let ones = Tensor::ones((1, 1, size, size), DType::F32, face.device())?;
+    let face_mask = Tensor::cat(&[&face.to_dtype(DType::F32)?, &ones], 1)?;
+    let warped = warp_affine(
+        &face_mask,
+        &inverse,
+        rect_h,
+        rect_w,
+        &vec![0.; channels + 1],
+    )?;
+    let restored = warped.narrow(1, 0, channels)?;
+    let mask = warped.narrow(1, channels, 1)?;
+
+    // Remove the black borders, then feather the edge by an amount that
+    // grows with the face
+    let mask = erode(&mask, (2. * scale.0) as usize)?;
+    let pasted = restored.broadcast_mul(&mask)?;
+    let area = mask
+        .sum_all()?
+        .to_device(&Device::Cpu)?
+        .to_scalar::<f32>()?;
+    let w_edge = (area.sqrt() as usize) / 20;
+    let center = erode(&mask, w_edge * 2)?;
+    let soft_mask = gaussian_blur(&center, w_edge * 2 + 1)?;
+
+    let background = img.narrow(2, y0, rect_h)?.narrow(3, x0, rect_w)?;
+    let delta = (pasted - &background)?.broadcast_mul(&soft_mask)?;
+    let delta = delta
+        .pad_with_zeros(2, y0, h - y1)?
+        .pad_with_zeros(3, x0, w - x1)?;
+    img + delta
+}
@@ -0,0 +1,492 @@
+use candle_core::{Module, Result, Tensor};
+use candle_nn as nn;
+
+use crate::conv::{conv2d, conv2d_no_bias, Conv2d};
+use crate::layers::{linear, upsample_bilinear, Linear};
+
+// GFPGAN, in the "clean" version of v1.3 and v1.4 that needs no custom CUDA
+// ops: a U-Net encodes the degraded face into the style codes of a StyleGAN2
+// decoder, and into scales and shifts that modulate the decoder features
+// (spatial feature transform, SFT) at every resolution from 8 pixels up. The
+// keys are those of `GFPGANv1Clean`, its unused `toRGB` convs and style MLP
+// are left out. Inputs and outputs are RGB faces in [-1, 1].
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GFPGANConfig {
+    /// Side of the input and output faces, a power of two
+    pub out_size: usize,
+    /// Channels of the style codes
+    pub num_style_feat: usize,
+    pub channel_multiplier: usize,
+    /// Width of the decoder, as a multiple of the StyleGAN2 one. The U-Net
+    /// has half as many channels.
+    pub narrow: f64,
+    /// Whether the SFT only modulates half of the decoder channels
+    pub sft_half: bool,
+    /// Whether every layer of the decoder has its own style code
+    pub different_w: bool,
+}
+
+impl Default for GFPGANConfig {
+    /// GFPGAN v1.3 and v1.4
+    fn default() -> Self {
+        Self {
+            out_size: 512,
+            num_style_feat: 512,
+            channel_multiplier: 2,
+            narrow: 1.,
+            sft_half: true,
+            different_w: true,
+        }
+    }
+}
+
+impl GFPGANConfig {
+    /// Channels of the StyleGAN2 decoder at `resolution`, truncated like the
+    /// `int()` of the original
+    fn channels(&self, resolution: usize, narrow: f64) -> usize {
+        let cm = self.channel_multiplier;
+        let base = match resolution {
+            4 | 8 | 16 | 32 => 512,
+            64 => 256 * cm,
+            128 => 128 * cm,
+            256 => 64 * cm,
+            512 => 32 * cm,
+            _ => 16 * cm,
+        };
+        (base as f64 * narrow) as usize
+    }
+
+    pub(crate) fn decoder_channels(&self, resolution: usize) -> usize {
+        self.channels(resolution, self.narrow)
+    }
+
+    pub(crate) fn unet_channels(&self, resolution: usize) -> usize {
+        self.channels(resolution, self.narrow * 0.5)
+    }
+
+    pub(crate) fn log_size(&self) -> usize {
+        self.out_size.ilog2() as usize
+    }
+
+    /// Number of style codes of the decoder, one per modulated conv
+    pub(crate) fn num_latent(&self) -> usize {
+        self.log_size() * 2 - 2
+    }
+}
+
+fn conv(c_in: usize, c_out: usize, k: usize, vb: nn::VarBuilder) -> Result<Conv2d> {
+    let config = nn::Conv2dConfig {
+        padding: k / 2,
+        stride: 1,
+        dilation: 1,
+        groups: 1,
+    };
+    conv2d(c_in, c_out, k, config, vb)
+}
+
+/// Resize by `scale`, 0.5 or 2, keeping the corners like `F.interpolate`
+/// with a scale factor does
+fn rescale(xs: &Tensor, scale: f64) -> Result<Tensor> {
+    let (_, _, h, w) = xs.dims4()?;
+    let out_h = (h as f64 * scale) as usize;
+    let out_w = (w as f64 * scale) as usize;
+    upsample_bilinear(xs, out_h, out_w)
+}
+
+/// Residual block of the U-Net, resizing by `scale` between its convs and on
+/// its 1x1 skip conv
+#[derive(Debug)]
+struct ResBlock {
+    conv1: Conv2d,
+    conv2: Conv2d,
+    skip: Conv2d,
+    scale: f64,
+    lrelu: nn::Activation,
+}
+
+impl ResBlock {
+    fn load(vb: nn::VarBuilder, c_in: usize, c_out: usize, scale: f64) -> Result<Self> {
+        let skip_config = nn::Conv2dConfig {
+            padding: 0,
+            stride: 1,
+            dilation: 1,
+            groups: 1,
+        };
+        Ok(Self {
+            conv1: conv(c_in, c_in, 3, vb.pp("conv1"))?,
+            conv2: conv(c_in, c_out, 3, vb.pp("conv2"))?,
+            skip: conv2d_no_bias(c_in, c_out, 1, skip_config, vb.pp("skip"))?,
+            scale,
+            lrelu: nn::Activation::LeakyRelu(0.2),
+        })
+    }
+}
+
+impl Module for ResBlock {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let out = self.lrelu.forward(&self.conv1.forward(xs)?)?;
+        let out = rescale(&out, self.scale)?;
+        let out = self.lrelu.forward(&self.conv2.forward(&out)?)?;
+        let skip = self.skip.forward(&rescale(xs, self.scale)?)?;
+        out + skip
+    }
+}
+
+/// StyleGAN2 conv whose weight is scaled by the style code for every input
+/// channel, then normalised back to unit variance for every output channel.
+/// Scaling the input channels of the weight is the same as scaling the input,
+/// which lets the whole batch share a single conv.
+#[derive(Debug)]
+struct ModulatedConv2d {
+    conv: Conv2d,
+    /// Sums of the squared weights over the kernel, (out, in)
+    weight_sq: Tensor,
+    modulation: Linear,
+    demodulate: bool,
+    upsample: bool,
+}
+
+impl ModulatedConv2d {
+    fn load(
+        vb: nn::VarBuilder,
+        c_in: usize,
+        c_out: usize,
+        k: usize,
+        num_style_feat: usize,
+        demodulate: bool,
+        upsample: bool,
+    ) -> Result<Self> {
+        let weight = vb.get((1, c_out, c_in, k, k), "weight")?.squeeze(0)?;
+        let weight_sq = weight.sqr()?.sum((2, 3))?;
+        let config = nn::Conv2dConfig {
+            padding: k / 2,
+            stride: 1,
+            dilation: 1,
+            groups: 1,
+        };
+        Ok(Self {
+            conv: Conv2d::new(nn::Conv2d::new(weight, None, config)),
+            weight_sq,
+            modulation: linear(num_style_feat, c_in, vb.pp("modulation"))?,
+            demodulate,
+            upsample,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, style: &Tensor) -> Result<Tensor> {
+        let style = self.modulation.forward(style)?;
+        let (b, c_in) = style.dims2()?;
+        let mut xs = xs.broadcast_mul(&style.reshape((b, c_in, 1, 1))?)?;
+        if self.upsample {
+            xs = rescale(&xs, 2.)?;
+        }
+        let out = self.conv.forward(&xs)?;
+        if !self.demodulate {
+            return Ok(out);
+        }
+        let weight_sq = self.weight_sq.to_dtype(style.dtype())?;
+        let demod = style
+            .sqr()?
+            .matmul(&weight_sq.t()?)?
+            .affine(1., 1e-8)?
+            .sqrt()?
+            .recip()?;
+        let c_out = demod.dim(1)?;
+        out.broadcast_mul(&demod.reshape((b, c_out, 1, 1))?)
+    }
+}
+
+/// Modulated 3x3 conv, bias and leaky ReLU. The noise injection is left out,
+/// which makes the output deterministic.
+#[derive(Debug)]
+struct StyleConv {
+    modulated_conv: ModulatedConv2d,
+    bias: Tensor,
+    lrelu: nn::Activation,
+}
+
+impl StyleConv {
+    fn load(
+        vb: nn::VarBuilder,
+        c_in: usize,
+        c_out: usize,
+        num_style_feat: usize,
+        upsample: bool,
+    ) -> Result<Self> {
+        Ok(Self {
+            modulated_conv: ModulatedConv2d::load(
+                vb.pp("modulated_conv"),
+                c_in,
+                c_out,
+                3,
+                num_style_feat,
+                true,
+                upsample,
+            )?,
+            bias: vb.get((1, c_out, 1, 1), "bias")?,
+            lrelu: nn::Activation::LeakyRelu(0.2),
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, style: &Tensor) -> Result<Tensor> {
+        let out = (self.modulated_conv.forward(xs, style)? * 2f64.sqrt())?;
+        let out = out.broadcast_add(&self.bias.to_dtype(out.dtype())?)?;
+        self.lrelu.forward(&out)
+    }
+}
+
+/// Modulated 1x1 conv to RGB, added to the upsampled RGB of the level below
+#[derive(Debug)]
+struct ToRGB {
+    modulated_conv: ModulatedConv2d,
+    bias: Tensor,
+}
+
+impl ToRGB {
+    fn load(vb: nn::VarBuilder, c_in: usize, num_style_feat: usize) -> Result<Self> {
+        Ok(Self {
+            modulated_conv: ModulatedConv2d::load(
+                vb.pp("modulated_conv"),
+                c_in,
+                3,
+                1,
+                num_style_feat,
+                false,
+                false,
+            )?,
+            bias: vb.get((1, 3, 1, 1), "bias")?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, style: &Tensor, skip: Option<&Tensor>) -> Result<Tensor> {
+        let out = self.modulated_conv.forward(xs, style)?;
+        let out = out.broadcast_add(&self.bias.to_dtype(out.dtype())?)?;
+        match skip {
+            Some(skip) => out + rescale(skip, 2.)?,
+            None => Ok(out),
+        }
+    }
+}
+
+/// The two convs predicting the SFT scale or shift of a level
+#[derive(Debug)]
+struct Condition {
+    conv1: Conv2d,
+    conv2: Conv2d,
+    lrelu: nn::Activation,
+}
+
+impl Condition {
+    fn load(vb: nn::VarBuilder, c_in: usize, c_out: usize) -> Result<Self> {
+        Ok(Self {
+            conv1: conv(c_in, c_in, 3, vb.pp("0"))?,
+            conv2: conv(c_in, c_out, 3, vb.pp("2"))?,
+            lrelu: nn::Activation::LeakyRelu(0.2),
+        })
+    }
+}
+
+impl Module for Condition {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.conv2
+            .forward(&self.lrelu.forward(&self.conv1.forward(xs)?)?)
+    }
+}
+
+/// `StyleGAN2GeneratorCSFT`, the decoder taking the style codes as latents
+#[derive(Debug)]
+struct Decoder {
+    constant_input: Tensor,
+    style_conv1: StyleConv,
+    to_rgb1: ToRGB,
+    style_convs: Vec<StyleConv>,
+    to_rgbs: Vec<ToRGB>,
+    sft_half: bool,
+}
+
+impl Decoder {
+    fn load(vb: nn::VarBuilder, config: &GFPGANConfig) -> Result<Self> {
+        let s = config.num_style_feat;
+        let c4 = config.decoder_channels(4);
+        let mut style_convs = vec![];
+        let mut to_rgbs = vec![];
+        let mut c_in = c4;
+        for i in 3..=config.log_size() {
+            let c_out = config.decoder_channels(1 << i);
+            let j = i - 3;
+            style_convs.push(StyleConv::load(
+                vb.pp(format!("style_convs.{}", 2 * j)),
+                c_in,
+                c_out,
+                s,
+                true,
+            )?);
+            style_convs.push(StyleConv::load(
+                vb.pp(format!("style_convs.{}", 2 * j + 1)),
+                c_out,
+                c_out,
+                s,
+                false,
+            )?);
+            to_rgbs.push(ToRGB::load(vb.pp(format!("to_rgbs.{j}")), c_out, s)?);
+            c_in = c_out;
+        }
+        Ok(Self {
+            constant_input: vb.get((1, c4, 4, 4), "constant_input.weight")?,
+            style_conv1: StyleConv::load(vb.pp("style_conv1"), c4, c4, s, false)?,
+            to_rgb1: ToRGB::load(vb.pp("to_rgb1"), c4, s)?,
+            style_convs,
+            to_rgbs,
+            sft_half: config.sft_half,
+        })
+    }
+
+    /// `latents` are (B, num_latent, num_style_feat) and `conditions` the
+    /// scale and shift of every level
+    fn forward(&self, latents: &Tensor, conditions: &[(Tensor, Tensor)]) -> Result<Tensor> {
+        let b = latents.dim(0)?;
+        let latent = |i: usize| latents.narrow(1, i, 1)?.squeeze(1);
+        let (_, c, h, w) = self.constant_input.dims4()?;
+        let mut out = self
+            .constant_input
+            .to_dtype(latents.dtype())?
+            .broadcast_as((b, c, h, w))?
+            .contiguous()?;
+        out = self.style_conv1.forward(&out, &latent(0)?)?;
+        let mut skip = self.to_rgb1.forward(&out, &latent(1)?, None)?;
+        for (j, to_rgb) in self.to_rgbs.iter().enumerate() {
+            let i = 2 * j + 1;
+            out = self.style_convs[2 * j].forward(&out, &latent(i)?)?;
+            let (scale, shift) = &conditions[j];
+            out = if self.sft_half {
+                let half = out.dim(1)? / 2;
+                let same = out.narrow(1, 0, half)?;
+                let sft = out.narrow(1, half, out.dim(1)? - half)?;
+                Tensor::cat(&[&same, &((sft * scale)? + shift)?], 1)?
+            } else {
+                ((out * scale)? + shift)?
+            };
+            out = self.style_convs[2 * j + 1].forward(&out, &latent(i + 1)?)?;
+            skip = to_rgb.forward(&out, &latent(i + 2)?, Some(&skip))?;
+        }
+        Ok(skip)
+    }
+}
+
+#[derive(Debug)]
+pub struct GFPGAN {
+    config: GFPGANConfig,
+    conv_body_first: Conv2d,
+    conv_body_down: Vec<ResBlock>,
+    final_conv: Conv2d,
+    final_linear: Linear,
+    conv_body_up: Vec<ResBlock>,
+    condition_scale: Vec<Condition>,
+    condition_shift: Vec<Condition>,
+    decoder: Decoder,
+    lrelu: nn::Activation,
+}
+
+impl GFPGAN {
+    pub fn load(vb: nn::VarBuilder, config: &GFPGANConfig) -> Result<Self> {
+        let log_size = config.log_size();
+        let first = config.unet_channels(config.out_size);
+
+        let mut conv_body_down = vec![];
+        let mut c_in = first;
+        for (j, i) in (3..=log_size).rev().enumerate() {
+            let c_out = config.unet_channels(1 << (i - 1));
+            conv_body_down.push(ResBlock::load(
+                vb.pp(format!("conv_body_down.{j}")),
+                c_in,
+                c_out,
+                0.5,
+            )?);
+            c_in = c_out;
+        }
+        let c4 = config.unet_channels(4);
+        let final_conv = conv(c_in, c4, 3, vb.pp("final_conv"))?;
+
+        let mut conv_body_up = vec![];
+        let mut condition_scale = vec![];
+        let mut condition_shift = vec![];
+        let mut c_in = c4;
+        for (j, i) in (3..=log_size).enumerate() {
+            let c_out = config.unet_channels(1 << i);
+            conv_body_up.push(ResBlock::load(
+                vb.pp(format!("conv_body_up.{j}")),
+                c_in,
+                c_out,
+                2.,
+            )?);
+            let c_sft = if config.sft_half { c_out } else { 2 * c_out };
+            condition_scale.push(Condition::load(
+                vb.pp(format!("condition_scale.{j}")),
+                c_out,
+                c_sft,
+            )?);
+            condition_shift.push(Condition::load(
+                vb.pp(format!("condition_shift.{j}")),
+                c_out,
+                c_sft,
+            )?);
+            c_in = c_out;
+        }
+
+        let linear_out = match config.different_w {
+            true => config.num_latent() * config.num_style_feat,
+            false => config.num_style_feat,
+        };
+        Ok(Self {
+            config: config.clone(),
+            conv_body_first: conv(3, first, 1, vb.pp("conv_body_first"))?,
+            conv_body_down,
+            final_conv,
+            final_linear: linear(c4 * 4 * 4, linear_out, vb.pp("final_linear"))?,
+            conv_body_up,
+            condition_scale,
+            condition_shift,
+            decoder: Decoder::load(vb.pp("stylegan_decoder"), config)?,
+            lrelu: nn::Activation::LeakyRelu(0.2),
+        })
+    }
+
+    pub fn config(&self) -> &GFPGANConfig {
+        &self.config
+    }
+}
+
+impl Module for GFPGAN {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut feat = self.lrelu.forward(&self.conv_body_first.forward(xs)?)?;
+        let mut unet_skips = vec![];
+        for block in &self.conv_body_down {
+            feat = block.forward(&feat)?;
+            unet_skips.push(feat.clone());
+        }
+        let mut feat = self.lrelu.forward(&self.final_conv.forward(&feat)?)?;
+
+        let b = feat.dim(0)?;
+        let style_code = self.final_linear.forward(&feat.flatten_from(1)?)?;
+        let (num_latent, s) = (self.config.num_latent(), self.config.num_style_feat);
+        let latents = match self.config.different_w {
+            true => style_code.reshape((b, num_latent, s))?,
+            false => style_code
+                .unsqueeze(1)?
+                .broadcast_as((b, num_latent, s))?
+                .contiguous()?,
+        };
+
+        let mut conditions = vec![];
+        for (i, block) in self.conv_body_up.iter().enumerate() {
+            feat = (feat + &unet_skips[unet_skips.len() - 1 - i])?;
+            feat = block.forward(&feat)?;
+            conditions.push((
+                self.condition_scale[i].forward(&feat)?,
+                self.condition_shift[i].forward(&feat)?,
+            ));
+        }
+        self.decoder.forward(&latents, &conditions)
+    }
+}
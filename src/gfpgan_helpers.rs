@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+
+use candle_core::Tensor;
+
+// Every GFPGANv1Clean release (v1.2 to v1.4) uses the defaults of
+// `GFPGANConfig`, but all of them can be read back from the shapes
+
+pub fn get_out_size(state_dict: &HashMap<String, Tensor>) -> usize {
+    // The U-Net halves the face down to 4 pixels
+    let highest_block_num = state_dict
+        .keys()
+        .filter(|x| x.starts_with("conv_body_down."))
+        .filter_map(|x| x.split('.').nth(1)?.parse::<u32>().ok())
+        .max();
+    return match highest_block_num {
+        Some(x) => usize::pow(2, x + 3),
+        None => 512,
+    };
+}
+
+pub fn get_num_style_feat(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("stylegan_decoder.style_conv1.modulated_conv.modulation.weight") {
+        Some(x) => x.shape().dims()[1],
+        None => 512,
+    };
+}
+
+/// Width of the decoder relative to StyleGAN2, which has 512 channels at 4 pixels
+pub fn get_narrow(state_dict: &HashMap<String, Tensor>) -> f64 {
+    return match state_dict.get("stylegan_decoder.constant_input.weight") {
+        Some(x) => x.shape().dims()[1] as f64 / 512.,
+        None => 1.,
+    };
+}
+
+pub fn get_channel_multiplier(state_dict: &HashMap<String, Tensor>) -> usize {
+    // The decoder has 256 * channel_multiplier * narrow channels at 64 pixels,
+    // the input of its fourth RGB conv
+    return match state_dict.get("stylegan_decoder.to_rgbs.3.modulated_conv.modulation.weight") {
+        Some(x) => {
+            let channels = x.shape().dims()[0] as f64 / get_narrow(state_dict);
+            (channels / 256.).round() as usize
+        }
+        None => 2,
+    };
+}
+
+/// Whether the SFT convs output as many channels as the U-Net has, which
+/// modulate half of the decoder ones
+pub fn get_sft_half(state_dict: &HashMap<String, Tensor>) -> bool {
+    let sft = state_dict.get("condition_scale.0.2.weight");
+    let unet = state_dict.get("conv_body_up.0.conv2.weight");
+    return match (sft, unet) {
+        (Some(sft), Some(unet)) => sft.shape().dims()[0] == unet.shape().dims()[0],
+        _ => true,
+    };
+}
+
+/// Whether the style code has a vector for every layer of the decoder
+pub fn get_different_w(state_dict: &HashMap<String, Tensor>) -> bool {
+    return match state_dict.get("final_linear.weight") {
+        Some(x) => x.shape().dims()[0] > get_num_style_feat(state_dict),
+        None => true,
+    };
+}
@@ -0,0 +1,470 @@
+use candle_core::{Device, Module, Result, Tensor};
+use candle_nn as nn;
+
+use crate::conv::{conv2d, Conv2d};
+use crate::layers::{layer_norm, linear, LayerNorm, Linear};
+use crate::profile::Profiler;
+use crate::swinir::{
+    embed, in_shifted_windows, input_mean, pad_to_multiple, shifted_window_mask, unembed,
+    window_partition, window_reverse, Mlp, Reconstruction, ResiConnection, ResiConv, Upsampler,
+    WindowAttention,
+};
+
+// Hybrid Attention Transformer: SwinIR with a channel attention branch next to
+// every window attention and an overlapping cross-attention block after every
+// group of them.
+
+#[derive(Debug, Clone)]
+pub struct HATConfig {
+    pub in_nc: usize,
+    pub out_nc: usize,
+    pub scale: usize,
+    pub embed_dim: usize,
+    /// Number of hybrid attention blocks of every residual group
+    pub depths: Vec<usize>,
+    /// Number of attention heads of every residual group
+    pub num_heads: Vec<usize>,
+    pub window_size: usize,
+    /// Channels of the channel attention branch are embed_dim / compress_ratio
+    pub compress_ratio: usize,
+    /// Channels of its squeeze-excitation are embed_dim / squeeze_factor
+    pub squeeze_factor: usize,
+    /// Weight of the channel attention branch
+    pub conv_scale: f64,
+    /// How much the windows of the keys of the cross-attention overlap
+    pub overlap_ratio: f64,
+    pub mlp_ratio: f64,
+    pub upsampler: Upsampler,
+    pub num_feat: usize,
+    pub resi_connection: ResiConnection,
+    pub patch_norm: bool,
+}
+
+impl Default for HATConfig {
+    /// HAT-M x4 of the paper
+    fn default() -> Self {
+        Self {
+            in_nc: 3,
+            out_nc: 3,
+            scale: 4,
+            embed_dim: 180,
+            depths: vec![6; 6],
+            num_heads: vec![6; 6],
+            window_size: 16,
+            compress_ratio: 3,
+            squeeze_factor: 30,
+            conv_scale: 0.01,
+            overlap_ratio: 0.5,
+            mlp_ratio: 2.,
+            upsampler: Upsampler::PixelShuffle,
+            num_feat: 64,
+            resi_connection: ResiConnection::OneConv,
+            patch_norm: true,
+        }
+    }
+}
+
+impl HATConfig {
+    /// Name of the variant of the paper the config matches, S, M or L
+    pub fn variant(&self) -> &'static str {
+        match (self.embed_dim, self.depths.len(), self.compress_ratio) {
+            (144, 6, 24) => "hat-s",
+            (180, 6, 3) => "hat-m",
+            (180, 12, 3) => "hat-l",
+            _ => "hat",
+        }
+    }
+
+    /// Side of the windows of the keys and values of the cross-attention
+    fn overlap_window_size(&self) -> usize {
+        (self.window_size as f64 * self.overlap_ratio) as usize + self.window_size
+    }
+}
+
+fn conv(c_in: usize, c_out: usize, k: usize, vb: nn::VarBuilder) -> Result<Conv2d> {
+    let config = nn::Conv2dConfig {
+        padding: k / 2,
+        stride: 1,
+        dilation: 1,
+        groups: 1,
+    };
+    conv2d(c_in, c_out, k, config, vb)
+}
+
+/// Channel attention block: two convs and a squeeze-excitation
+#[derive(Debug)]
+struct CAB {
+    conv1: Conv2d,
+    conv2: Conv2d,
+    squeeze: Conv2d,
+    excite: Conv2d,
+}
+
+impl CAB {
+    fn load(
+        vb: nn::VarBuilder,
+        dim: usize,
+        compress_ratio: usize,
+        squeeze_factor: usize,
+    ) -> Result<Self> {
+        Ok(Self {
+            conv1: conv(dim, dim / compress_ratio, 3, vb.pp("cab.0"))?,
+            conv2: conv(dim / compress_ratio, dim, 3, vb.pp("cab.2"))?,
+            squeeze: conv(dim, dim / squeeze_factor, 1, vb.pp("cab.3.attention.1"))?,
+            excite: conv(dim / squeeze_factor, dim, 1, vb.pp("cab.3.attention.3"))?,
+        })
+    }
+}
+
+impl Module for CAB {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let out = self.conv2.forward(&self.conv1.forward(xs)?.gelu_erf()?)?;
+        let attention = out.mean_keepdim(2)?.mean_keepdim(3)?;
+        let attention = self.squeeze.forward(&attention)?.relu()?;
+        let attention = nn::ops::sigmoid(&self.excite.forward(&attention)?)?;
+        out.broadcast_mul(&attention)
+    }
+}
+
+/// Hybrid attention block: a Swin transformer block with a channel attention
+/// branch added to the window attention
+#[derive(Debug)]
+struct HAB {
+    norm1: LayerNorm,
+    attn: WindowAttention,
+    conv_block: CAB,
+    norm2: LayerNorm,
+    mlp: Mlp,
+    window_size: usize,
+    shift_size: usize,
+    conv_scale: f64,
+}
+
+impl HAB {
+    fn load(
+        vb: nn::VarBuilder,
+        dim: usize,
+        num_heads: usize,
+        shift_size: usize,
+        config: &HATConfig,
+    ) -> Result<Self> {
+        let window_size = config.window_size;
+        let conv_block = CAB::load(
+            vb.pp("conv_block"),
+            dim,
+            config.compress_ratio,
+            config.squeeze_factor,
+        )?;
+        Ok(Self {
+            norm1: layer_norm(dim, vb.pp("norm1"))?,
+            attn: WindowAttention::load(vb.pp("attn"), dim, window_size, num_heads)?,
+            conv_block,
+            norm2: layer_norm(dim, vb.pp("norm2"))?,
+            mlp: Mlp::load(vb.pp("mlp"), dim, (dim as f64 * config.mlp_ratio) as usize)?,
+            window_size,
+            shift_size,
+            conv_scale: config.conv_scale,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, (h, w): (usize, usize), mask: &Tensor) -> Result<Tensor> {
+        let (b, l, c) = xs.dims3()?;
+        let x = self.norm1.forward(xs)?;
+        let conv_x = embed(&self.conv_block.forward(&unembed(&x, (h, w))?)?)?;
+
+        let mask = if self.shift_size > 0 {
+            Some(mask)
+        } else {
+            None
+        };
+        let window = (self.window_size, self.window_size);
+        let shift = (self.shift_size, self.shift_size);
+        let attn_x = in_shifted_windows(&x.reshape((b, h, w, c))?, window, shift, |windows| {
+            self.attn.forward(windows, mask)
+        })?;
+        let x = ((xs + attn_x.reshape((b, l, c))?)? + (conv_x * self.conv_scale)?)?;
+        &x + self.mlp.forward(&self.norm2.forward(&x)?)?
+    }
+}
+
+/// Index of the relative position of every pixel of a window in the bias
+/// table of the cross-attention, for the keys of the enlarged window around it,
+/// (window_size², overlap_window_size²)
+fn overlap_position_index(
+    window_size: usize,
+    overlap_size: usize,
+    device: &Device,
+) -> Result<Tensor> {
+    let (ws, os) = (window_size as i64, overlap_size as i64);
+    let side = ws + os - 1;
+    let mut index = vec![];
+    for i in 0..ws * ws {
+        for j in 0..os * os {
+            // Like the original, the offsets don't start from 0 and the negative
+            // indices wrap around the table
+            let dy = j / os - i / ws + ws - os + 1;
+            let dx = j % os - i % ws + ws - os + 1;
+            index.push((dy * side + dx).rem_euclid(side * side) as u32);
+        }
+    }
+    Tensor::from_vec(index, ((ws * ws) as usize, (os * os) as usize), device)
+}
+
+/// Overlapping cross-attention block: the queries of every window attend to
+/// the keys of a larger window centred on it
+#[derive(Debug)]
+struct OCAB {
+    norm1: LayerNorm,
+    qkv: Linear,
+    proj: Linear,
+    relative_position_bias_table: Tensor,
+    relative_position_index: Tensor,
+    norm2: LayerNorm,
+    mlp: Mlp,
+    num_heads: usize,
+    window_size: usize,
+    overlap_size: usize,
+}
+
+impl OCAB {
+    fn load(vb: nn::VarBuilder, dim: usize, num_heads: usize, config: &HATConfig) -> Result<Self> {
+        let window_size = config.window_size;
+        let overlap_size = config.overlap_window_size();
+        let table_side = window_size + overlap_size - 1;
+        Ok(Self {
+            norm1: layer_norm(dim, vb.pp("norm1"))?,
+            qkv: linear(dim, dim * 3, vb.pp("qkv"))?,
+            proj: linear(dim, dim, vb.pp("proj"))?,
+            relative_position_bias_table: vb.get(
+                (table_side * table_side, num_heads),
+                "relative_position_bias_table",
+            )?,
+            relative_position_index: overlap_position_index(
+                window_size,
+                overlap_size,
+                vb.device(),
+            )?,
+            norm2: layer_norm(dim, vb.pp("norm2"))?,
+            mlp: Mlp::load(vb.pp("mlp"), dim, (dim as f64 * config.mlp_ratio) as usize)?,
+            num_heads,
+            window_size,
+            overlap_size,
+        })
+    }
+
+    /// The overlapping windows of (B, H, W, C), zero-padded around the image
+    /// like `nn.Unfold`, (B * windows, overlap_size², C)
+    fn overlapping_windows(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b, h, w, c) = xs.dims4()?;
+        let (ws, os) = (self.window_size, self.overlap_size);
+        let pad = (os - ws) / 2;
+        let xs = xs
+            .pad_with_zeros(1, pad, pad)?
+            .pad_with_zeros(2, pad, pad)?;
+        let index = |size: usize| {
+            let index: Vec<u32> = (0..size / ws)
+                .flat_map(|i| (i * ws..i * ws + os).map(|j| j as u32))
+                .collect();
+            Tensor::new(index.as_slice(), xs.device())
+        };
+        xs.index_select(&index(h)?, 1)?
+            .index_select(&index(w)?, 2)?
+            .reshape((b, h / ws, os, w / ws, os, c))?
+            .permute([0, 1, 3, 2, 4, 5])?
+            .reshape((b * (h / ws) * (w / ws), os * os, c))
+    }
+
+    fn forward(&self, xs: &Tensor, (h, w): (usize, usize)) -> Result<Tensor> {
+        let (b, l, c) = xs.dims3()?;
+        let head_dim = c / self.num_heads;
+        let ws = self.window_size;
+        let qkv = self
+            .qkv
+            .forward(&self.norm1.forward(xs)?)?
+            .reshape((b, h, w, 3, c))?;
+        let heads = |xs: Tensor| {
+            let (n, tokens, _) = xs.dims3()?;
+            xs.reshape((n, tokens, self.num_heads, head_dim))?
+                .transpose(1, 2)?
+                .contiguous()
+        };
+        let q = window_partition(&qkv.narrow(3, 0, 1)?.squeeze(3)?, (ws, ws))?;
+        let k = self.overlapping_windows(&qkv.narrow(3, 1, 1)?.squeeze(3)?)?;
+        let v = self.overlapping_windows(&qkv.narrow(3, 2, 1)?.squeeze(3)?)?;
+        let (n, nq, _) = q.dims3()?;
+        let q = (heads(q)? * (head_dim as f64).powf(-0.5))?;
+        let (k, v) = (heads(k)?, heads(v)?);
+        let attn = q.matmul(&k.t()?)?;
+
+        let bias = self
+            .relative_position_bias_table
+            .index_select(&self.relative_position_index.flatten_all()?, 0)?
+            .reshape((nq, self.overlap_size * self.overlap_size, self.num_heads))?
+            .permute((2, 0, 1))?
+            .to_dtype(xs.dtype())?;
+        let attn = nn::ops::softmax_last_dim(&attn.broadcast_add(&bias.unsqueeze(0)?)?)?;
+        let out = attn.matmul(&v)?.transpose(1, 2)?.reshape((n, nq, c))?;
+        let out = window_reverse(&out, (ws, ws), h, w)?.reshape((b, l, c))?;
+
+        let x = (self.proj.forward(&out)? + xs)?;
+        &x + self.mlp.forward(&self.norm2.forward(&x)?)?
+    }
+}
+
+/// Residual hybrid attention group: hybrid attention blocks, a cross-attention
+/// block and a conv, with a residual connection around them
+#[derive(Debug)]
+struct RHAG {
+    blocks: Vec<HAB>,
+    overlap_attn: OCAB,
+    conv: ResiConv,
+}
+
+impl RHAG {
+    fn load(
+        vb: nn::VarBuilder,
+        dim: usize,
+        depth: usize,
+        num_heads: usize,
+        config: &HATConfig,
+    ) -> Result<Self> {
+        let mut blocks = vec![];
+        for i in 0..depth {
+            let shift_size = if i % 2 == 0 {
+                0
+            } else {
+                config.window_size / 2
+            };
+            blocks.push(HAB::load(
+                vb.pp(format!("residual_group.blocks.{i}")),
+                dim,
+                num_heads,
+                shift_size,
+                config,
+            )?);
+        }
+        Ok(Self {
+            blocks,
+            overlap_attn: OCAB::load(vb.pp("residual_group.overlap_attn"), dim, num_heads, config)?,
+            conv: ResiConv::load(vb.pp("conv"), dim, config.resi_connection)?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, size: (usize, usize), mask: &Tensor) -> Result<Tensor> {
+        let mut out = xs.clone();
+        for block in self.blocks.iter() {
+            out = block.forward(&out, size, mask)?;
+        }
+        let out = self.overlap_attn.forward(&out, size)?;
+        embed(&self.conv.forward(&unembed(&out, size)?)?)? + xs
+    }
+}
+
+#[derive(Debug)]
+pub struct HAT {
+    conv_first: Conv2d,
+    patch_norm: Option<LayerNorm>,
+    layers: Vec<RHAG>,
+    norm: LayerNorm,
+    conv_after_body: ResiConv,
+    reconstruction: Reconstruction,
+    in_nc: usize,
+    scale: usize,
+    window_size: usize,
+    variant: &'static str,
+}
+
+impl HAT {
+    pub fn load(vb: nn::VarBuilder, config: &HATConfig) -> Result<Self> {
+        let dim = config.embed_dim;
+        let conv_first = conv(config.in_nc, dim, 3, vb.pp("conv_first"))?;
+        let patch_norm = match config.patch_norm {
+            true => Some(layer_norm(dim, vb.pp("patch_embed.norm"))?),
+            false => None,
+        };
+        let mut layers = vec![];
+        for (i, (depth, num_heads)) in config.depths.iter().zip(&config.num_heads).enumerate() {
+            layers.push(RHAG::load(
+                vb.pp(format!("layers.{i}")),
+                dim,
+                *depth,
+                *num_heads,
+                config,
+            )?);
+        }
+        Ok(Self {
+            conv_first,
+            patch_norm,
+            layers,
+            norm: layer_norm(dim, vb.pp("norm"))?,
+            conv_after_body: ResiConv::load(vb.pp("conv_after_body"), dim, config.resi_connection)?,
+            reconstruction: Reconstruction::load(
+                vb,
+                config.upsampler,
+                dim,
+                config.num_feat,
+                config.out_nc,
+                config.scale,
+            )?,
+            in_nc: config.in_nc,
+            scale: config.scale,
+            window_size: config.window_size,
+            variant: config.variant(),
+        })
+    }
+
+    /// See `HATConfig::variant`
+    pub fn variant(&self) -> &'static str {
+        self.variant
+    }
+
+    /// The convs outside of the attention blocks, for `conv::quantize`, see
+    /// `SwinIR::convs_mut`
+    pub fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = vec![&mut self.conv_first];
+        for layer in self.layers.iter_mut() {
+            convs.extend(layer.conv.convs_mut());
+        }
+        convs.extend(self.conv_after_body.convs_mut());
+        convs.extend(self.reconstruction.convs_mut());
+        convs
+    }
+
+    /// `forward`, recording the time of every module in `profiler`
+    pub fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        let (_, _, h, w) = xs.dims4()?;
+        let xs = pad_to_multiple(xs, self.window_size)?;
+        let mean = input_mean(self.in_nc, &xs)?;
+        let xs = xs.broadcast_sub(&mean)?;
+        let size = (xs.dim(2)?, xs.dim(3)?);
+
+        let feat = self.conv_first.forward(&xs)?;
+        profiler.record("conv_first", &feat)?;
+        let mut out = embed(&feat)?;
+        if let Some(norm) = &self.patch_norm {
+            out = norm.forward(&out)?;
+        }
+        let half = self.window_size / 2;
+        let window = (self.window_size, self.window_size);
+        let mask = shifted_window_mask(size.0, size.1, window, (half, half), xs.device())?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            out = layer.forward(&out, size, &mask)?;
+            profiler.record(&format!("layers.{i}"), &out)?;
+        }
+        let out = unembed(&self.norm.forward(&out)?, size)?;
+        let out = (self.conv_after_body.forward(&out)? + feat)?;
+        profiler.record("conv_after_body", &out)?;
+
+        let out = self.reconstruction.forward(&out, &xs)?;
+        profiler.record("reconstruction", &out)?;
+        out.broadcast_add(&mean)?
+            .narrow(2, 0, h * self.scale)?
+            .narrow(3, 0, w * self.scale)
+    }
+}
+
+impl nn::Module for HAT {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.forward_profiled(xs, &mut Profiler::disabled())
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+
+use candle_core::Tensor;
+
+use crate::hat::HATConfig;
+use crate::swinir_helpers;
+
+// HAT shares the key names of SwinIR outside of its attention blocks, see
+// `swinir_helpers` for those
+
+fn dim(state_dict: &HashMap<String, Tensor>, key: &str, default: usize) -> usize {
+    return match state_dict.get(key) {
+        Some(x) => x.shape().dims()[0],
+        None => default,
+    };
+}
+
+pub fn get_compress_ratio(state_dict: &HashMap<String, Tensor>) -> usize {
+    let key = "layers.0.residual_group.blocks.0.conv_block.cab.0.weight";
+    let embed_dim = swinir_helpers::get_embed_dim(state_dict);
+    return embed_dim / dim(state_dict, key, embed_dim / 3);
+}
+
+pub fn get_squeeze_factor(state_dict: &HashMap<String, Tensor>) -> usize {
+    let key = "layers.0.residual_group.blocks.0.conv_block.cab.3.attention.1.weight";
+    let embed_dim = swinir_helpers::get_embed_dim(state_dict);
+    return embed_dim / dim(state_dict, key, embed_dim / 30);
+}
+
+pub fn get_overlap_ratio(state_dict: &HashMap<String, Tensor>) -> f64 {
+    // The bias table of the cross-attention has a row for every one of the
+    // (window_size + overlap_window_size - 1)² relative positions
+    let key = "layers.0.residual_group.overlap_attn.relative_position_bias_table";
+    let window_size = swinir_helpers::get_window_size(state_dict);
+    return match state_dict.get(key) {
+        Some(x) => {
+            let side = (x.shape().dims()[0] as f64).sqrt().round() as usize;
+            let overlap_size = side + 1 - window_size;
+            (overlap_size - window_size) as f64 / window_size as f64
+        }
+        None => 0.5,
+    };
+}
+
+/// Every hyperparameter of the model, `HATConfig::variant` tells which of
+/// the paper it is
+pub fn get_config(state_dict: &HashMap<String, Tensor>) -> HATConfig {
+    return HATConfig {
+        in_nc: swinir_helpers::get_in_nc(state_dict),
+        out_nc: swinir_helpers::get_out_nc(state_dict),
+        scale: swinir_helpers::get_scale(state_dict),
+        embed_dim: swinir_helpers::get_embed_dim(state_dict),
+        depths: swinir_helpers::get_depths(state_dict),
+        num_heads: swinir_helpers::get_num_heads(state_dict),
+        window_size: swinir_helpers::get_window_size(state_dict),
+        compress_ratio: get_compress_ratio(state_dict),
+        squeeze_factor: get_squeeze_factor(state_dict),
+        conv_scale: 0.01,
+        overlap_ratio: get_overlap_ratio(state_dict),
+        mlp_ratio: swinir_helpers::get_mlp_ratio(state_dict),
+        upsampler: swinir_helpers::get_upsampler(state_dict),
+        num_feat: swinir_helpers::get_num_feat(state_dict),
+        resi_connection: swinir_helpers::get_resi_connection(state_dict),
+        patch_norm: state_dict.contains_key("patch_embed.norm.weight"),
+    };
+}
@@ -0,0 +1,67 @@
+use candle_core::{DType, Device};
+use esrgan_candle_rs::descriptor::ModelDescriptor;
+use serde_json::{json, Value};
+
+use crate::eval::ReportFormat;
+use crate::{load_model, ModelArgs};
+
+/// Print the descriptor of a model without running it
+#[derive(clap::Args, Debug)]
+pub struct InfoArgs {
+    /// Path to the model file in safetensors format
+    #[arg(short, long)]
+    model: String,
+
+    /// Device to report the dtypes of the model for
+    /// -1 for CPU, 0 for GPU 0, 1 for GPU 1, etc.
+    #[arg(short, long, default_value = "-1")]
+    device: i32,
+
+    #[command(flatten)]
+    model_args: ModelArgs,
+
+    #[arg(long, value_enum, default_value = "table")]
+    format: ReportFormat,
+}
+
+pub fn info(args: &InfoArgs) {
+    let device = match args.device {
+        -1 => Device::Cpu,
+        _ => Device::new_cuda(args.device as usize).unwrap(),
+    };
+    let model = load_model(&args.model, &args.model_args, &device, DType::F32);
+    let descriptor = &model.descriptor;
+    match args.format {
+        ReportFormat::Table => {
+            let receptive_field = match descriptor.receptive_field {
+                Some(pixels) => format!("{pixels} px"),
+                None => "unbounded".to_string(),
+            };
+            let dtypes: Vec<&str> = descriptor.dtypes.iter().map(|d| d.as_str()).collect();
+            println!("{:<20} {}", "architecture", descriptor.arch);
+            println!("{:<20} {}x", "scale", descriptor.scale);
+            println!("{:<20} {}", "input channels", descriptor.in_nc);
+            println!("{:<20} {}", "output channels", descriptor.out_nc);
+            println!("{:<20} {}", "receptive field", receptive_field);
+            println!("{:<20} {}", "input multiple", descriptor.input_multiple);
+            println!("{:<20} {}", "dtypes", dtypes.join(", "));
+            println!("{:<20} {}", "parameters", descriptor.params);
+        }
+        ReportFormat::Json => println!("{}", descriptor_json(descriptor)),
+    }
+}
+
+/// `descriptor` as `info` and the server's model list report it
+pub fn descriptor_json(descriptor: &ModelDescriptor) -> Value {
+    let dtypes: Vec<&str> = descriptor.dtypes.iter().map(|d| d.as_str()).collect();
+    json!({
+        "arch": descriptor.arch,
+        "scale": descriptor.scale,
+        "in_nc": descriptor.in_nc,
+        "out_nc": descriptor.out_nc,
+        "receptive_field": descriptor.receptive_field,
+        "input_multiple": descriptor.input_multiple,
+        "dtypes": dtypes,
+        "params": descriptor.params,
+    })
+}
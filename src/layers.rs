@@ -0,0 +1,144 @@
+use candle_core::{DType, Module, Result, Tensor};
+use candle_nn as nn;
+
+// Layers of the transformer architectures. Like `conv::Conv2d`, they cast
+// their weights to the dtype of the activations when the two differ.
+
+fn cast(weight: &Tensor, xs: &Tensor) -> Result<Tensor> {
+    weight.to_dtype(xs.dtype())
+}
+
+#[derive(Debug, Clone)]
+pub struct Linear(nn::Linear);
+
+impl Module for Linear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let linear = &self.0;
+        if linear.weight().dtype() == xs.dtype() {
+            return linear.forward(xs);
+        }
+        let bias = match linear.bias() {
+            Some(bias) => Some(cast(bias, xs)?),
+            None => None,
+        };
+        nn::Linear::new(cast(linear.weight(), xs)?, bias).forward(xs)
+    }
+}
+
+/// Same as `candle_nn::linear`, wrapped in a `Linear`
+pub fn linear(in_dim: usize, out_dim: usize, vb: nn::VarBuilder) -> Result<Linear> {
+    Ok(Linear(nn::linear(in_dim, out_dim, vb)?))
+}
+
+/// Same as `candle_nn::linear_b`, wrapped in a `Linear`
+pub fn linear_b(in_dim: usize, out_dim: usize, bias: bool, vb: nn::VarBuilder) -> Result<Linear> {
+    Ok(Linear(nn::linear_b(in_dim, out_dim, bias, vb)?))
+}
+
+/// Layer norm with the default epsilon of PyTorch
+#[derive(Debug, Clone)]
+pub struct LayerNorm(nn::LayerNorm);
+
+const LAYER_NORM_EPS: f64 = 1e-5;
+
+impl Module for LayerNorm {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let norm = &self.0;
+        if norm.weight().dtype() == xs.dtype() {
+            return norm.forward(xs);
+        }
+        let weight = cast(norm.weight(), xs)?;
+        let norm = match norm.bias() {
+            Some(bias) => nn::LayerNorm::new(weight, cast(bias, xs)?, LAYER_NORM_EPS),
+            None => nn::LayerNorm::new_no_bias(weight, LAYER_NORM_EPS),
+        };
+        norm.forward(xs)
+    }
+}
+
+pub fn layer_norm(size: usize, vb: nn::VarBuilder) -> Result<LayerNorm> {
+    Ok(LayerNorm(nn::layer_norm(size, LAYER_NORM_EPS, vb)?))
+}
+
+/// Layer norm over the channels of (B, C, H, W), the "channels first" layer
+/// norm of the conv networks. The statistics are computed in f32.
+#[derive(Debug, Clone)]
+pub struct LayerNorm2d {
+    weight: Tensor,
+    bias: Tensor,
+    eps: f64,
+}
+
+impl Module for LayerNorm2d {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let shape = (1, self.weight.dim(0)?, 1, 1);
+        let x = xs.to_dtype(DType::F32)?;
+        let x = x.broadcast_sub(&x.mean_keepdim(1)?)?;
+        let var = x.sqr()?.mean_keepdim(1)?;
+        let x = x.broadcast_div(&(var + self.eps)?.sqrt()?)?;
+        x.broadcast_mul(&self.weight.to_dtype(DType::F32)?.reshape(shape)?)?
+            .broadcast_add(&self.bias.to_dtype(DType::F32)?.reshape(shape)?)?
+            .to_dtype(xs.dtype())
+    }
+}
+
+pub fn layer_norm_2d(size: usize, eps: f64, vb: nn::VarBuilder) -> Result<LayerNorm2d> {
+    Ok(LayerNorm2d {
+        weight: vb.get(size, "weight")?,
+        bias: vb.get(size, "bias")?,
+        eps,
+    })
+}
+
+/// Batch norm of (B, C, H, W) with the running statistics, as PyTorch models
+/// use it for inference
+#[derive(Debug, Clone)]
+pub struct BatchNorm {
+    weight: Tensor,
+    bias: Tensor,
+    running_mean: Tensor,
+    running_var: Tensor,
+}
+
+const BATCH_NORM_EPS: f64 = 1e-5;
+
+impl Module for BatchNorm {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let shape = (1, self.weight.dim(0)?, 1, 1);
+        let scale = (&self.weight / (&self.running_var + BATCH_NORM_EPS)?.sqrt()?)?;
+        let shift = (&self.bias - (&self.running_mean * &scale)?)?;
+        xs.broadcast_mul(&cast(&scale, xs)?.reshape(shape)?)?
+            .broadcast_add(&cast(&shift, xs)?.reshape(shape)?)
+    }
+}
+
+pub fn batch_norm(size: usize, vb: nn::VarBuilder) -> Result<BatchNorm> {
+    Ok(BatchNorm {
+        weight: vb.get(size, "weight")?,
+        bias: vb.get(size, "bias")?,
+        running_mean: vb.get(size, "running_mean")?,
+        running_var: vb.get(size, "running_var")?,
+    })
+}
+
+/// Bilinear resize with `align_corners=False`, written as two matrix products
+pub fn upsample_bilinear(xs: &Tensor, out_h: usize, out_w: usize) -> Result<Tensor> {
+    let (_, _, h, w) = xs.dims4()?;
+    let weights = |size: usize, out: usize| {
+        let scale = size as f64 / out as f64;
+        let mut m = vec![0f32; out * size];
+        for i in 0..out {
+            let src = ((i as f64 + 0.5) * scale - 0.5).max(0.);
+            let i0 = (src as usize).min(size - 1);
+            let i1 = usize::min(i0 + 1, size - 1);
+            let l1 = (src - i0 as f64) as f32;
+            m[i * size + i0] += 1. - l1;
+            m[i * size + i1] += l1;
+        }
+        Tensor::from_vec(m, (out, size), xs.device())
+    };
+    let weights_h = weights(h, out_h)?.to_dtype(xs.dtype())?;
+    let weights_w = weights(w, out_w)?.to_dtype(xs.dtype())?.t()?;
+    let out = xs.contiguous()?.broadcast_matmul(&weights_w)?;
+    weights_h.broadcast_matmul(&out)
+}
@@ -0,0 +1,44 @@
+pub mod animation;
+pub mod color_fix;
+pub mod compact;
+pub mod compact_helpers;
+pub mod conv;
+pub mod cugan;
+pub mod cugan_helpers;
+pub mod dat;
+pub mod dat_helpers;
+pub mod degradation;
+pub mod descriptor;
+pub mod discriminator;
+pub mod face;
+pub mod gfpgan;
+pub mod gfpgan_helpers;
+pub mod hat;
+pub mod hat_helpers;
+pub mod layers;
+pub mod losses;
+pub mod metrics;
+pub mod new_arch;
+pub mod new_arch_helpers;
+pub mod old_arch;
+pub mod old_arch_helpers;
+pub mod omnisr;
+pub mod omnisr_helpers;
+pub mod profile;
+pub mod registry;
+pub mod resize;
+pub mod retinaface;
+pub mod retinaface_helpers;
+pub mod safmn;
+pub mod safmn_helpers;
+pub mod span;
+pub mod span_helpers;
+pub mod spsr;
+pub mod spsr_helpers;
+pub mod srformer;
+pub mod srformer_helpers;
+pub mod swinir;
+pub mod swinir_helpers;
+pub mod synthetic;
+pub mod vgg;
+pub mod y4m;
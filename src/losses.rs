@@ -0,0 +1,39 @@
+// Losses for fine-tuning with a discriminator. The GAN losses are the
+// relativistic average ones of ESRGAN: the discriminator estimates how much
+// more realistic the real images are than the average fake one, and the
+// other way around.
+
+use candle_core::{Result, Tensor};
+
+/// Mean absolute error
+pub fn l1(output: &Tensor, target: &Tensor) -> Result<Tensor> {
+    (output - target)?.abs()?.mean_all()
+}
+
+/// Mean binary cross-entropy between `sigmoid(logits)` and a constant label
+pub fn bce_with_logits(logits: &Tensor, target: f64) -> Result<Tensor> {
+    // max(x, 0) - x * y + log(1 + exp(-|x|)), which never overflows
+    let softplus = (logits.abs()?.neg()?.exp()? + 1.)?.log()?;
+    (logits.relu()? - (logits * target)?)?
+        .add(&softplus)?
+        .mean_all()
+}
+
+/// Loss of the discriminator given its scores for real and generated images.
+/// The generated images should be detached before scoring them, so only the
+/// discriminator gets gradients.
+pub fn relativistic_discriminator_loss(real: &Tensor, fake: &Tensor) -> Result<Tensor> {
+    // Each term only backpropagates through its own scores
+    let real_loss = bce_with_logits(&real.broadcast_sub(&fake.detach()?.mean_all()?)?, 1.)?;
+    let fake_loss = bce_with_logits(&fake.broadcast_sub(&real.detach()?.mean_all()?)?, 0.)?;
+    (real_loss + fake_loss)? * 0.5
+}
+
+/// Loss of the generator given the discriminator's scores for real and
+/// generated images
+pub fn relativistic_generator_loss(real: &Tensor, fake: &Tensor) -> Result<Tensor> {
+    let real = real.detach()?;
+    let real_loss = bce_with_logits(&real.broadcast_sub(&fake.mean_all()?)?, 0.)?;
+    let fake_loss = bce_with_logits(&fake.broadcast_sub(&real.mean_all()?)?, 1.)?;
+    (real_loss + fake_loss)? * 0.5
+}
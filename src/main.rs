@@ -1,46 +1,161 @@
+use std::collections::HashMap;
 use std::time::Instant;
 
-use candle_core::{pickle, DType, Device, Tensor};
-use candle_nn::{Module, VarBuilder};
-mod new_arch;
-mod old_arch;
 use candle_core::safetensors::load;
-use clap::ValueEnum;
-use compact::SRVGGNetCompact as Compact;
+use candle_core::{pickle, DType, Device, Error, Result, Tensor};
+use candle_nn::VarBuilder;
+use esrgan_candle_rs::animation;
+use esrgan_candle_rs::conv::{self, Quantization};
+use esrgan_candle_rs::descriptor::ModelDescriptor;
+use esrgan_candle_rs::face::{FaceModel, FaceRestorer};
+use esrgan_candle_rs::gfpgan::{GFPGANConfig, GFPGAN};
+use esrgan_candle_rs::registry::{Registry, Upscaler};
+use esrgan_candle_rs::retinaface::{RetinaFace, RetinaFaceConfig};
+use esrgan_candle_rs::swinir::pad_to_multiple;
+use esrgan_candle_rs::y4m;
+use esrgan_candle_rs::{gfpgan_helpers, retinaface_helpers};
 use image::DynamicImage;
 use image::RgbImage;
-use new_arch::RRDBNet as RealESRGAN;
-use old_arch::RRDBNet as OldESRGAN;
 use std::path::Path;
-mod old_arch_helpers;
-use old_arch_helpers::{get_in_nc, get_nb, get_nf, get_out_nc, get_scale};
-mod compact;
+mod bench;
+mod convert;
+mod degrade;
+mod eval;
+mod info;
+mod server;
+mod tile;
+mod train;
+mod watch;
+use esrgan_candle_rs::color_fix::{self, ColorFix};
+use esrgan_candle_rs::resize::{self, OutputSize, Resize, ResizeFilter};
+
+use clap::builder::{PossibleValuesParser, RangedU64ValueParser};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 
-use clap::Parser;
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
-enum ModelType {
-    /// Old-arch ESRGAN
-    Old,
-    /// New-arch ESRGAN (RealESRGAN)
-    New,
-    // RealESRGANv2 aka Compact
-    Compact,
+    #[command(flatten)]
+    args: Option<Args>,
 }
 
-/// Simple program to greet a person
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Load models once and serve them over HTTP
+    Serve(server::ServeArgs),
+    /// Keep a model loaded and upscale every image dropped into a folder
+    Watch(watch::WatchArgs),
+    /// Measure the latency and throughput of a model
+    Bench(bench::BenchArgs),
+    /// Score a model on a validation set with PSNR and SSIM
+    Eval(eval::EvalArgs),
+    /// Print the scale, channels and tiling hints of a model
+    Info(info::InfoArgs),
+    /// Fine-tune a model on high-resolution images
+    Train(train::TrainArgs),
+    /// Make LR/HR training pairs with realistic degradations
+    Degrade(degrade::DegradeArgs),
+    /// Convert a model to safetensors, or to GGUF with quantised weights
+    Convert(convert::ConvertArgs),
+}
+
+/// Names `--arch` takes, those of the architectures of the registry
+fn arch_names() -> PossibleValuesParser {
+    PossibleValuesParser::new(Registry::builtin().names())
+}
+
+/// Exit with `message` like clap does for invalid arguments, for models that
+/// don't match what the command line says about them or fail to load
+fn exit_with_error(message: impl std::fmt::Display) -> ! {
+    Cli::command()
+        .error(ErrorKind::InvalidValue, message)
+        .exit()
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Precision {
+    F32,
+    F16,
+    Bf16,
+    F64,
+}
+
+impl Precision {
+    pub fn dtype(&self) -> DType {
+        match self {
+            Precision::F32 => DType::F32,
+            Precision::F16 => DType::F16,
+            Precision::Bf16 => DType::BF16,
+            Precision::F64 => DType::F64,
+        }
+    }
+
+    /// Dtype to run the model in on `device`
+    pub fn compute_dtype(&self, device: &Device) -> DType {
+        // candle has no bf16 matmul on the CPU, only on CUDA
+        if device.is_cpu() && *self == Precision::Bf16 {
+            exit_with_error(
+                "bf16 only runs on GPUs, use --dtype f32 --weight-dtype bf16 to keep the weights in bf16 on the CPU",
+            );
+        }
+        self.dtype()
+    }
+}
+
+/// Overrides for the automatically detected model parameters
+// `Args` repeats these instead of flattening them, clap can't tell whether an
+// optional flattened group was given when it contains another flattened group.
+#[derive(clap::Args, Debug, Clone)]
+struct ModelArgs {
+    /// Architecture of the model, detected from its weights by default
+    #[arg(short, long, value_parser = arch_names())]
+    arch: Option<String>,
+
+    /// Number of input channels. Dependent on the model used.
+    #[arg(long)]
+    in_channels: Option<usize>,
+
+    /// Number of output channels. Dependent on the model used.
+    #[arg(long)]
+    out_channels: Option<usize>,
+
+    /// Number of RRDB blocks. Dependent on the model used.
+    #[arg(long)]
+    num_blocks: Option<usize>,
+
+    /// Number of features. Dependent on the model used.
+    #[arg(long)]
+    num_features: Option<usize>,
+
+    /// Scale of the model. Dependent on the model used.
+    #[arg(short, long)]
+    scale: Option<usize>,
+
+    /// Quantise the conv weights at load time, q8_0 (int8) loses the least.
+    /// Models converted to GGUF are quantised already.
+    #[arg(long, value_enum)]
+    quantize: Option<Quantization>,
+}
+
+/// Upscale a folder of images
+#[derive(clap::Args, Debug)]
 struct Args {
     /// Path to the model file in safetensors format
     #[arg(short, long)]
     model: String,
 
-    /// Folder path containing images to upscale
+    /// Folder path containing images to upscale.
+    /// Use - to read a YUV4MPEG2 video stream from stdin instead.
     #[arg(short, long)]
     input: String,
 
-    /// Folder path to save upscaled images
+    /// Folder path to save upscaled images.
+    /// With `--input -`, the file to write the upscaled stream to, or - for stdout.
     #[arg(short, long)]
     output: String,
 
@@ -49,9 +164,9 @@ struct Args {
     #[arg(short, long, default_value = "-1")]
     device: i32,
 
-    /// Architecture revision (old or new). Dependent on the model used.
-    #[arg(short, long, value_enum)]
-    arch: Option<ModelType>,
+    /// Architecture of the model, detected from its weights by default
+    #[arg(short, long, value_parser = arch_names())]
+    arch: Option<String>,
 
     /// Number of input channels. Dependent on the model used.
     #[arg(long)]
@@ -73,12 +188,92 @@ struct Args {
     #[arg(short, long)]
     scale: Option<usize>,
 
-    /// Run the model with half precision (fp16)
+    /// Quantise the conv weights at load time, q8_0 (int8) loses the least.
+    /// Models converted to GGUF are quantised already.
+    #[arg(long, value_enum)]
+    quantize: Option<Quantization>,
+
+    /// Precision the model runs in. Outputs with NaN or Inf values, which f16
+    /// gives on some models, are computed again in f32.
+    #[arg(long, value_enum, default_value = "f32")]
+    dtype: Precision,
+
+    /// Precision the weights are kept in, --dtype by default. The convs cast
+    /// them to --dtype as they run.
+    #[arg(long, value_enum)]
+    weight_dtype: Option<Precision>,
+
+    /// Split images into tiles of at most this many pixels per side to save memory
+    #[arg(short, long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    tile: Option<usize>,
+
+    /// Scale factor of the final output, independent of the model's scale.
+    /// The model is run as many times as needed and the result is resized.
+    #[arg(long, conflicts_with_all = ["width", "height"])]
+    output_scale: Option<f64>,
+
+    /// Width of the final output. Keeps the aspect ratio if no height is given.
+    #[arg(long)]
+    width: Option<usize>,
+
+    /// Height of the final output. Keeps the aspect ratio if no width is given.
     #[arg(long)]
-    half: bool,
+    height: Option<usize>,
+
+    /// Fit the output inside --width x --height instead of stretching to it
+    #[arg(long, requires_all = ["width", "height"])]
+    fit: bool,
+
+    /// Filter used to resize the model output to the final size
+    #[arg(long, value_enum, default_value = "lanczos")]
+    resize_filter: ResizeFilter,
+
+    /// Correct colour shifts of the model by matching the output to the input
+    #[arg(long, value_enum)]
+    color_fix: Option<ColorFix>,
+
+    /// GFPGAN model restoring the faces of the output, pasted over the
+    /// upscaled image
+    #[arg(long, requires = "face_detector")]
+    face_model: Option<String>,
+
+    /// RetinaFace model (facexlib's detection_Resnet50_Final) finding the
+    /// faces for --face-model
+    #[arg(long, requires = "face_model")]
+    face_detector: Option<String>,
 }
 
-fn img2tensor(img: DynamicImage, device: &Device, half: bool) -> Tensor {
+impl Args {
+    fn model_args(&self) -> ModelArgs {
+        ModelArgs {
+            arch: self.arch.clone(),
+            in_channels: self.in_channels,
+            out_channels: self.out_channels,
+            num_blocks: self.num_blocks,
+            num_features: self.num_features,
+            scale: self.scale,
+            quantize: self.quantize,
+        }
+    }
+}
+
+fn output_size(
+    output_scale: Option<f64>,
+    width: Option<usize>,
+    height: Option<usize>,
+    fit: bool,
+) -> Option<OutputSize> {
+    match (output_scale, width, height) {
+        (Some(scale), _, _) => Some(OutputSize::Scale(scale)),
+        (None, Some(w), Some(h)) if fit => Some(OutputSize::Fit(w, h)),
+        (None, Some(w), Some(h)) => Some(OutputSize::Exact(w, h)),
+        (None, Some(w), None) => Some(OutputSize::Width(w)),
+        (None, None, Some(h)) => Some(OutputSize::Height(h)),
+        (None, None, None) => None,
+    }
+}
+
+fn img2tensor(img: DynamicImage, device: &Device, dtype: DType) -> Tensor {
     let height: usize = img.height() as usize;
     let width: usize = img.width() as usize;
     let data = img.to_rgb8().into_raw();
@@ -86,13 +281,7 @@ fn img2tensor(img: DynamicImage, device: &Device, half: bool) -> Tensor {
         .unwrap()
         .permute((2, 0, 1))
         .unwrap();
-    let image_t = (tensor
-        .unsqueeze(0)
-        .unwrap()
-        .to_dtype(if half { DType::F16 } else { DType::F32 })
-        .unwrap()
-        / 255.)
-        .unwrap();
+    let image_t = (tensor.unsqueeze(0).unwrap().to_dtype(dtype).unwrap() / 255.).unwrap();
     return image_t;
 }
 
@@ -118,22 +307,209 @@ fn tensor2img(tensor: Tensor) -> RgbImage {
     out_img
 }
 
-enum ModelVariant {
-    Old(OldESRGAN),
-    New(RealESRGAN),
-    Compact(Compact),
+/// A model with what is known about it from its state dict
+struct LoadedModel {
+    upscaler: Box<dyn Upscaler>,
+    descriptor: ModelDescriptor,
 }
 
-fn process(model: &ModelVariant, img: DynamicImage, device: &Device, half: bool) -> RgbImage {
-    let img_t = img2tensor(img, &device, half);
+fn read_state_dict(path: &str, device: &Device) -> HashMap<String, Tensor> {
+    let path_extension = Path::new(path).extension().unwrap().to_str().unwrap();
 
-    let now = Instant::now();
-    let result = match model {
-        ModelVariant::Old(model) => model.forward(&img_t).unwrap(),
-        ModelVariant::New(model) => model.forward(&img_t).unwrap(),
-        ModelVariant::Compact(model) => model.forward(&img_t).unwrap(),
+    return match path_extension {
+        "safetensors" => load(path, device).unwrap(),
+        "pth" => pickle::read_all(path).unwrap().into_iter().collect(),
+        "gguf" => convert::read_gguf(path, device),
+        _ => panic!("Invalid model file extension"),
+    };
+}
+
+/// State dict of a face model, without the prefixes of basicsr checkpoints
+/// and of DataParallel
+fn read_face_state_dict(path: &str, device: &Device) -> HashMap<String, Tensor> {
+    return read_state_dict(path, device)
+        .into_iter()
+        .map(|(k, v)| {
+            let k = k.strip_prefix("params_ema.").unwrap_or(&k);
+            let k = k.strip_prefix("module.").unwrap_or(k);
+            (k.to_string(), v)
+        })
+        .collect();
+}
+
+/// Load the detector and the restoration model of the face pass. They run in
+/// f32 whatever the precision of the upscaling model.
+fn load_face_restorer(model_path: &str, detector_path: &str, device: &Device) -> FaceRestorer {
+    let state_dict = read_face_state_dict(detector_path, device);
+    let config = RetinaFaceConfig {
+        width: retinaface_helpers::get_width(&state_dict),
+        layers: retinaface_helpers::get_layers(&state_dict),
+        out_channels: retinaface_helpers::get_out_channels(&state_dict),
+    };
+    let vb = VarBuilder::from_tensors(state_dict, DType::F32, device);
+    let detector = RetinaFace::load(vb, &config).unwrap();
+
+    let state_dict = read_face_state_dict(model_path, device);
+    let config = GFPGANConfig {
+        out_size: gfpgan_helpers::get_out_size(&state_dict),
+        num_style_feat: gfpgan_helpers::get_num_style_feat(&state_dict),
+        channel_multiplier: gfpgan_helpers::get_channel_multiplier(&state_dict),
+        narrow: gfpgan_helpers::get_narrow(&state_dict),
+        sft_half: gfpgan_helpers::get_sft_half(&state_dict),
+        different_w: gfpgan_helpers::get_different_w(&state_dict),
     };
-    println!("Model took {:?}", now.elapsed());
+    let vb = VarBuilder::from_tensors(state_dict, DType::F32, device);
+    let model = GFPGAN::load(vb, &config).unwrap();
+    return FaceRestorer::new(detector, FaceModel::GFPGAN(model));
+}
+
+fn load_model(path: &str, model_args: &ModelArgs, device: &Device, dtype: DType) -> LoadedModel {
+    let state_dict = read_state_dict(path, device);
+    let mut model_args = model_args.clone();
+    // GGUF files are read dequantised, the model quantises them back
+    if model_args.quantize.is_none() && path.ends_with(".gguf") {
+        model_args.quantize = convert::stored_quantization(path);
+    }
+    return model_from_state_dict(state_dict, &model_args, device, dtype)
+        .unwrap_or_else(|err| exit_with_error(err));
+}
+
+fn model_from_state_dict(
+    state_dict: HashMap<String, Tensor>,
+    model_args: &ModelArgs,
+    device: &Device,
+    dtype: DType,
+) -> Result<LoadedModel> {
+    let vb = VarBuilder::from_tensors(state_dict.clone(), dtype, device);
+
+    let mut model = build_model(&state_dict, model_args, vb)?;
+    if let Some(quantization) = model_args.quantize {
+        conv::quantize(model.upscaler.convs_mut(), quantization)?;
+    }
+    return Ok(model);
+}
+
+/// Construct the model described by `state_dict`, taking its weights from `vb`
+fn build_model(
+    state_dict: &HashMap<String, Tensor>,
+    model_args: &ModelArgs,
+    vb: VarBuilder,
+) -> Result<LoadedModel> {
+    let registry = Registry::builtin();
+    let (arch, mut params) = match &model_args.arch {
+        // `arch_names` only lets through the registered names
+        Some(name) => {
+            let arch = registry.get(name).unwrap();
+            match arch.detect(state_dict) {
+                Some(params) => (arch, params),
+                None => return Err(Error::Msg(format!("The model isn't a {name} model"))),
+            }
+        }
+        None => registry.detect(state_dict).ok_or_else(|| {
+            Error::Msg("Couldn't detect the architecture of the model".to_string())
+        })?,
+    };
+    params.in_nc = model_args.in_channels.unwrap_or(params.in_nc);
+    params.out_nc = model_args.out_channels.unwrap_or(params.out_nc);
+    params.scale = model_args.scale.unwrap_or(params.scale);
+    params.num_features = model_args.num_features.unwrap_or(params.num_features);
+    params.num_blocks = model_args.num_blocks.unwrap_or(params.num_blocks);
+    return Ok(LoadedModel {
+        descriptor: arch.descriptor(state_dict, &params, vb.device()),
+        upscaler: arch.load(vb, state_dict, &params)?,
+    });
+}
+
+fn forward(model: &LoadedModel, img_t: &Tensor, tile: Option<usize>) -> Tensor {
+    let forward = |xs: &Tensor| model.upscaler.forward(xs);
+    let descriptor = &model.descriptor;
+    let run = |xs: &Tensor| {
+        // Sizes the model can't take are padded and cropped back from the output
+        let (_b_size, _channels, h, w) = xs.dims4().unwrap();
+        let padded = pad_to_multiple(xs, descriptor.input_multiple).unwrap();
+        let output = match tile {
+            Some(tile_size) => tile::tiled(&padded, tile_size, descriptor, forward).unwrap(),
+            None => forward(&padded).unwrap(),
+        };
+        if padded.dims() == xs.dims() {
+            return output;
+        }
+        output
+            .narrow(2, 0, h * descriptor.scale)
+            .unwrap()
+            .narrow(3, 0, w * descriptor.scale)
+            .unwrap()
+    };
+    let output = run(img_t);
+    let dtype = img_t.dtype();
+    if !matches!(dtype, DType::F16 | DType::BF16) || is_finite(&output) {
+        return output;
+    }
+    // Activations of some models overflow the range of half precision
+    eprintln!("The output has NaN or Inf values in {dtype:?}, running the model again in f32");
+    return run(&img_t.to_dtype(DType::F32).unwrap())
+        .to_dtype(dtype)
+        .unwrap();
+}
+
+/// Whether `xs` has no NaN or Inf values
+fn is_finite(xs: &Tensor) -> bool {
+    // 0 * x is 0 for finite values and NaN for the others
+    let sum = (xs * 0.).unwrap().sum_all().unwrap();
+    return sum
+        .to_dtype(DType::F32)
+        .unwrap()
+        .to_scalar::<f32>()
+        .unwrap()
+        == 0.;
+}
+
+/// Everything besides the model and the image that `process` needs
+#[derive(Debug, Clone, Copy)]
+struct ProcessOptions<'a> {
+    dtype: DType,
+    tile: Option<usize>,
+    resize: Option<Resize>,
+    color_fix: Option<ColorFix>,
+    /// Restores the faces of the input over the upscaled image
+    faces: Option<&'a FaceRestorer>,
+}
+
+fn process(
+    model: &LoadedModel,
+    img: DynamicImage,
+    device: &Device,
+    options: &ProcessOptions<'_>,
+) -> RgbImage {
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let img_t = img2tensor(img, &device, options.dtype);
+
+    let now = Instant::now();
+    let mut result = forward(model, &img_t, options.tile);
+    if let Some(resize) = options.resize {
+        // Run the model again until the output is at least as big as the target
+        let (target_w, target_h) = resize.size.target(width, height);
+        loop {
+            let (_b_size, _channels, h, w) = result.dims4().unwrap();
+            if w >= target_w && h >= target_h {
+                break;
+            }
+            let next = forward(model, &result.clamp(0., 1.).unwrap(), options.tile);
+            if next.dims4().unwrap().3 <= w {
+                // Scale 1 model, more passes won't get any closer
+                break;
+            }
+            result = next;
+        }
+        result = resize::resize(&result, target_h, target_w, resize.filter).unwrap();
+    }
+    if let Some(faces) = options.faces {
+        result = faces.restore(&img_t, &result).unwrap();
+    }
+    if let Some(method) = options.color_fix {
+        result = color_fix::color_fix(&result, &img_t, method).unwrap();
+    }
+    eprintln!("Model took {:?}", now.elapsed());
 
     let result = (result.squeeze(0).unwrap().clamp(0., 1.).unwrap() * 255.).unwrap();
 
@@ -141,79 +517,83 @@ fn process(model: &ModelVariant, img: DynamicImage, device: &Device, half: bool)
     return out_img;
 }
 
+/// Upscale a YUV4MPEG2 stream from stdin frame by frame, so video can be piped
+/// through without temporary files.
+fn process_y4m(
+    model: &LoadedModel,
+    device: &Device,
+    output: &str,
+    options: &ProcessOptions<'_>,
+) -> std::io::Result<()> {
+    let mut reader = y4m::Reader::new(std::io::stdin().lock())?;
+    let output: Box<dyn std::io::Write> = match output {
+        "-" => Box::new(std::io::stdout().lock()),
+        path => Box::new(std::fs::File::create(path)?),
+    };
+    let mut output = Some(std::io::BufWriter::new(output));
+    let mut writer = None;
+    let mut frames = 0;
+    while let Some(frame) = reader.next_frame()? {
+        let out_img = process(model, DynamicImage::ImageRgb8(frame), device, options);
+        // The output size is only known once the first frame went through the model
+        if writer.is_none() {
+            let header = reader
+                .header
+                .resized(out_img.width() as usize, out_img.height() as usize);
+            writer = Some(y4m::Writer::new(output.take().unwrap(), header)?);
+        }
+        writer.as_mut().unwrap().write_frame(&out_img)?;
+        frames += 1;
+    }
+    eprintln!("Upscaled {frames} frames");
+    Ok(())
+}
+
 fn main() {
-    let args: Args = Args::parse();
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Serve(serve_args)) => server::serve(&serve_args),
+        Some(Command::Watch(watch_args)) => watch::watch(&watch_args),
+        Some(Command::Bench(bench_args)) => bench::bench(&bench_args),
+        Some(Command::Eval(eval_args)) => eval::eval(&eval_args),
+        Some(Command::Info(info_args)) => info::info(&info_args),
+        Some(Command::Train(train_args)) => train::train(&train_args),
+        Some(Command::Degrade(degrade_args)) => degrade::degrade(&degrade_args),
+        Some(Command::Convert(convert_args)) => convert::convert(&convert_args),
+        None => upscale(cli.args.unwrap()),
+    }
+}
 
+fn upscale(args: Args) {
     let device = match args.device {
         -1 => Device::Cpu,
         _ => Device::new_cuda(args.device as usize).unwrap(),
     };
 
-    let path_extension = Path::new(&args.model)
-        .extension()
-        .unwrap()
-        .to_str()
-        .unwrap();
-
-    let state_dict = match path_extension {
-        "safetensors" => load(&args.model, &device).unwrap(),
-        "pth" => pickle::read_all(&args.model).unwrap().into_iter().collect(),
-        _ => panic!("Invalid model file extension"),
+    let weight_dtype = args.weight_dtype.unwrap_or(args.dtype).dtype();
+    let model = load_model(&args.model, &args.model_args(), &device, weight_dtype);
+    let faces = match (&args.face_model, &args.face_detector) {
+        (Some(model), Some(detector)) => Some(load_face_restorer(model, detector, &device)),
+        _ => None,
     };
 
-    let vb = {
-        VarBuilder::from_tensors(
-            state_dict.clone(),
-            if args.half { DType::F16 } else { DType::F32 },
-            &device,
-        )
+    let options = ProcessOptions {
+        dtype: args.dtype.compute_dtype(&device),
+        tile: args.tile,
+        resize: output_size(args.output_scale, args.width, args.height, args.fit).map(|size| {
+            Resize {
+                size,
+                filter: args.resize_filter,
+            }
+        }),
+        color_fix: args.color_fix,
+        faces: faces.as_ref(),
     };
 
-    let model_arch =
-        args.arch
-            .unwrap_or(if state_dict.keys().any(|x| x.contains("model.0.weight")) {
-                ModelType::Old
-            } else {
-                ModelType::New
-            });
-
-    let model: ModelVariant = match model_arch {
-        ModelType::Old => ModelVariant::Old(
-            OldESRGAN::load(
-                vb,
-                args.in_channels.unwrap_or(get_in_nc(&state_dict)),
-                args.out_channels.unwrap_or(get_out_nc(&state_dict)),
-                args.scale.unwrap_or(get_scale(&state_dict)),
-                args.num_features.unwrap_or(get_nf(&state_dict)),
-                args.num_blocks.unwrap_or(get_nb(&state_dict)),
-                32,
-            )
-            .unwrap(),
-        ),
-        ModelType::New => ModelVariant::New(
-            RealESRGAN::load(
-                vb,
-                args.in_channels.unwrap_or(3),
-                args.out_channels.unwrap_or(3),
-                args.scale.unwrap_or(4),
-                args.num_features.unwrap_or(64),
-                args.num_blocks.unwrap_or(23),
-                32,
-            )
-            .unwrap(),
-        ),
-        ModelType::Compact => ModelVariant::Compact(
-            Compact::load(
-                vb,
-                args.in_channels.unwrap_or(3),
-                args.out_channels.unwrap_or(3),
-                args.num_features.unwrap_or(64),
-                args.num_blocks.unwrap_or(23),
-                args.scale.unwrap_or(4),
-            )
-            .unwrap(),
-        ),
-    };
+    if args.input == "-" {
+        process_y4m(&model, &device, &args.output, &options).unwrap();
+        return;
+    }
 
     let images_dir = args.input;
     let out_dir = args.output;
@@ -226,14 +606,58 @@ fn main() {
     let now = Instant::now();
     files.into_iter().for_each(|file| {
         let file = file.unwrap();
-        let path = file.path();
-        let img = image::open(path).unwrap();
-
-        let out_img = process(&model, img, &device, args.half);
-
         let out_path = format!("{}/{}", out_dir, file.file_name().into_string().unwrap());
-        out_img.save(out_path).unwrap();
+        upscale_file(
+            &model,
+            &device,
+            &file.path(),
+            Path::new(&out_path),
+            &options,
+            args.resize_filter,
+        )
+        .unwrap();
         println!("Saved {}", file.file_name().into_string().unwrap());
     });
     println!("Time taken: {:?}", now.elapsed());
 }
+
+/// Upscale one image or animation from `path` and save it to `out_path`.
+/// `filter` resizes animation frames to the exact size of the scaled animation.
+fn upscale_file(
+    model: &LoadedModel,
+    device: &Device,
+    path: &Path,
+    out_path: &Path,
+    options: &ProcessOptions<'_>,
+    filter: ResizeFilter,
+) -> image::ImageResult<()> {
+    if let Some(format) = animation::format(path) {
+        let anim = animation::read(path, format)?;
+        let (width, height) = match &options.resize {
+            Some(resize) => resize
+                .size
+                .target(anim.width as usize, anim.height as usize),
+            None => {
+                let scale = model.descriptor.scale;
+                (anim.width as usize * scale, anim.height as usize * scale)
+            }
+        };
+        let anim = anim.scaled(width as u32, height as u32, |img, w, h| {
+            let frame_options = ProcessOptions {
+                resize: Some(Resize {
+                    size: OutputSize::Exact(w as usize, h as usize),
+                    filter,
+                }),
+                ..*options
+            };
+            process(model, img, device, &frame_options)
+        });
+        return animation::write(&anim, out_path);
+    }
+
+    let img = image::open(path)?;
+
+    let out_img = process(model, img, device, options);
+
+    return out_img.save(out_path);
+}
@@ -0,0 +1,234 @@
+// Full-reference image quality metrics, following the basicsr conventions:
+// values are computed on the 0-255 range, `crop_border` pixels are dropped on
+// every side first, and the Y channel is the BT.601 studio-range luma.
+
+use image::RgbImage;
+
+/// One channel of an image as floats in the 0-255 range
+struct Plane {
+    width: usize,
+    height: usize,
+    data: Vec<f64>,
+}
+
+impl Plane {
+    fn get(&self, x: usize, y: usize) -> f64 {
+        self.data[y * self.width + x]
+    }
+
+    fn map2(&self, other: &Plane, f: impl Fn(f64, f64) -> f64) -> Plane {
+        Plane {
+            width: self.width,
+            height: self.height,
+            data: self
+                .data
+                .iter()
+                .zip(&other.data)
+                .map(|(&a, &b)| f(a, b))
+                .collect(),
+        }
+    }
+
+    fn mean(&self) -> f64 {
+        self.data.iter().sum::<f64>() / self.data.len() as f64
+    }
+
+    /// Average every 2x2 block, dropping the last row or column if odd
+    fn downsample(&self) -> Plane {
+        let (width, height) = (self.width / 2, self.height / 2);
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let sum = self.get(2 * x, 2 * y)
+                    + self.get(2 * x + 1, 2 * y)
+                    + self.get(2 * x, 2 * y + 1)
+                    + self.get(2 * x + 1, 2 * y + 1);
+                data.push(sum / 4.);
+            }
+        }
+        Plane {
+            width,
+            height,
+            data,
+        }
+    }
+
+    /// 11x11 Gaussian blur with sigma 1.5, keeping only the fully covered pixels
+    fn gaussian(&self) -> Plane {
+        let kernel = gaussian_kernel();
+        let r = kernel.len() / 2;
+        let (width, height) = (self.width - 2 * r, self.height - 2 * r);
+        let mut rows: Vec<f64> = Vec::with_capacity(width * self.height);
+        for y in 0..self.height {
+            for x in 0..width {
+                rows.push(
+                    (0..kernel.len())
+                        .map(|i| kernel[i] * self.get(x + i, y))
+                        .sum(),
+                );
+            }
+        }
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                data.push(
+                    (0..kernel.len())
+                        .map(|i| kernel[i] * rows[(y + i) * width + x])
+                        .sum(),
+                );
+            }
+        }
+        Plane {
+            width,
+            height,
+            data,
+        }
+    }
+}
+
+fn gaussian_kernel() -> [f64; 11] {
+    let mut kernel = [0.; 11];
+    for (i, k) in kernel.iter_mut().enumerate() {
+        let x = i as f64 - 5.;
+        *k = (-x * x / (2. * 1.5 * 1.5)).exp();
+    }
+    let total: f64 = kernel.iter().sum();
+    kernel.map(|k| k / total)
+}
+
+/// The channels to compare, `None` when cropping leaves nothing
+fn planes(img: &RgbImage, crop_border: usize, y_channel: bool) -> Option<Vec<Plane>> {
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    if w <= 2 * crop_border || h <= 2 * crop_border {
+        return None;
+    }
+    let (width, height) = (w - 2 * crop_border, h - 2 * crop_border);
+    let pixels = || {
+        (0..height).flat_map(move |y| {
+            (0..width).map(move |x| {
+                img.get_pixel((x + crop_border) as u32, (y + crop_border) as u32)
+                    .0
+                    .map(|c| c as f64)
+            })
+        })
+    };
+    let plane = |data: Vec<f64>| Plane {
+        width,
+        height,
+        data,
+    };
+    let planes = if y_channel {
+        vec![plane(
+            pixels()
+                .map(|[r, g, b]| (65.481 * r + 128.553 * g + 24.966 * b) / 255. + 16.)
+                .collect(),
+        )]
+    } else {
+        (0..3)
+            .map(|c| plane(pixels().map(|p| p[c]).collect()))
+            .collect()
+    };
+    Some(planes)
+}
+
+fn check_sizes(a: &RgbImage, b: &RgbImage) {
+    assert_eq!(
+        a.dimensions(),
+        b.dimensions(),
+        "images to compare have different sizes"
+    );
+}
+
+/// Peak signal-to-noise ratio in dB, infinite for identical images, `None`
+/// when the image is no bigger than the cropped border
+pub fn psnr(a: &RgbImage, b: &RgbImage, crop_border: usize, y_channel: bool) -> Option<f64> {
+    check_sizes(a, b);
+    let (a, b) = (
+        planes(a, crop_border, y_channel)?,
+        planes(b, crop_border, y_channel)?,
+    );
+    let mse = a
+        .iter()
+        .zip(&b)
+        .map(|(a, b)| a.map2(b, |x, y| (x - y) * (x - y)).mean())
+        .sum::<f64>()
+        / a.len() as f64;
+    Some(10. * (255. * 255. / mse).log10())
+}
+
+const C1: f64 = (0.01 * 255.) * (0.01 * 255.);
+const C2: f64 = (0.03 * 255.) * (0.03 * 255.);
+
+/// Mean SSIM and mean contrast-structure term of one channel
+fn ssim_plane(a: &Plane, b: &Plane) -> (f64, f64) {
+    let mu_a = a.gaussian();
+    let mu_b = b.gaussian();
+    let var_a = a.map2(a, |x, y| x * y).gaussian();
+    let var_b = b.map2(b, |x, y| x * y).gaussian();
+    let cov = a.map2(b, |x, y| x * y).gaussian();
+    let mut ssim = 0.;
+    let mut cs = 0.;
+    for i in 0..mu_a.data.len() {
+        let (ma, mb) = (mu_a.data[i], mu_b.data[i]);
+        let sa = var_a.data[i] - ma * ma;
+        let sb = var_b.data[i] - mb * mb;
+        let sab = cov.data[i] - ma * mb;
+        let contrast = (2. * sab + C2) / (sa + sb + C2);
+        ssim += (2. * ma * mb + C1) / (ma * ma + mb * mb + C1) * contrast;
+        cs += contrast;
+    }
+    let n = mu_a.data.len() as f64;
+    (ssim / n, cs / n)
+}
+
+/// Structural similarity, averaged over the channels, `None` when the image is
+/// smaller than the 11x11 window after cropping
+pub fn ssim(a: &RgbImage, b: &RgbImage, crop_border: usize, y_channel: bool) -> Option<f64> {
+    check_sizes(a, b);
+    let (a, b) = (
+        planes(a, crop_border, y_channel)?,
+        planes(b, crop_border, y_channel)?,
+    );
+    if a[0].width < 11 || a[0].height < 11 {
+        return None;
+    }
+    let total = a
+        .iter()
+        .zip(&b)
+        .map(|(a, b)| ssim_plane(a, b).0)
+        .sum::<f64>();
+    Some(total / a.len() as f64)
+}
+
+const MS_SSIM_WEIGHTS: [f64; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+
+/// Multi-scale SSIM over 5 scales, `None` when the image is too small for the
+/// 11x11 window at the coarsest scale (176 pixels after cropping)
+pub fn ms_ssim(a: &RgbImage, b: &RgbImage, crop_border: usize, y_channel: bool) -> Option<f64> {
+    check_sizes(a, b);
+    let (a, b) = (
+        planes(a, crop_border, y_channel)?,
+        planes(b, crop_border, y_channel)?,
+    );
+    if a[0].width < 11 << 4 || a[0].height < 11 << 4 {
+        return None;
+    }
+    let channels = a.len() as f64;
+    let mut total = 0.;
+    for (a, b) in a.into_iter().zip(b) {
+        let (mut a, mut b) = (a, b);
+        let mut value = 1.;
+        for (level, weight) in MS_SSIM_WEIGHTS.iter().enumerate() {
+            let (ssim, cs) = ssim_plane(&a, &b);
+            if level == MS_SSIM_WEIGHTS.len() - 1 {
+                value *= ssim.max(0.).powf(*weight);
+            } else {
+                value *= cs.max(0.).powf(*weight);
+                a = a.downsample();
+                b = b.downsample();
+            }
+        }
+        total += value;
+    }
+    Some(total / channels)
+}
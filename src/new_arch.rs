@@ -1,6 +1,9 @@
-use candle_core::{Module, Result, Tensor};
+use candle_core::{Error, Module, Result, Tensor};
 use candle_nn as nn;
 
+use crate::conv::{conv2d, Conv2d};
+use crate::profile::Profiler;
+
 #[derive(Debug)]
 struct Upsample {
     scale_factor: usize,
@@ -21,11 +24,11 @@ impl nn::Module for Upsample {
 
 #[derive(Debug)]
 struct ResidualDenseBlock {
-    conv1: nn::Conv2d,
-    conv2: nn::Conv2d,
-    conv3: nn::Conv2d,
-    conv4: nn::Conv2d,
-    conv5: nn::Conv2d,
+    conv1: Conv2d,
+    conv2: Conv2d,
+    conv3: Conv2d,
+    conv4: Conv2d,
+    conv5: Conv2d,
     lrelu: nn::Activation,
 }
 
@@ -37,29 +40,29 @@ impl ResidualDenseBlock {
             dilation: 1,
             groups: 1,
         };
-        let conv1 = nn::conv2d(num_feat, num_grow_ch, 3, config, vb.pp("conv1"));
-        let conv2 = nn::conv2d(
+        let conv1 = conv2d(num_feat, num_grow_ch, 3, config, vb.pp("conv1"));
+        let conv2 = conv2d(
             num_feat + num_grow_ch,
             num_grow_ch,
             3,
             config,
             vb.pp("conv2"),
         );
-        let conv3 = nn::conv2d(
+        let conv3 = conv2d(
             num_feat + 2 * num_grow_ch,
             num_grow_ch,
             3,
             config,
             vb.pp("conv3"),
         );
-        let conv4 = nn::conv2d(
+        let conv4 = conv2d(
             num_feat + 3 * num_grow_ch,
             num_grow_ch,
             3,
             config,
             vb.pp("conv4"),
         );
-        let conv5 = nn::conv2d(
+        let conv5 = conv2d(
             num_feat + 4 * num_grow_ch,
             num_feat,
             3,
@@ -76,6 +79,16 @@ impl ResidualDenseBlock {
             lrelu,
         })
     }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        vec![
+            &mut self.conv1,
+            &mut self.conv2,
+            &mut self.conv3,
+            &mut self.conv4,
+            &mut self.conv5,
+        ]
+    }
 }
 
 impl nn::Module for ResidualDenseBlock {
@@ -115,6 +128,13 @@ impl RRDB {
             rdb3: rdb3.unwrap(),
         })
     }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = self.rdb1.convs_mut();
+        convs.extend(self.rdb2.convs_mut());
+        convs.extend(self.rdb3.convs_mut());
+        convs
+    }
 }
 
 impl nn::Module for RRDB {
@@ -188,13 +208,14 @@ impl<T: Module> Module for Sequential<T> {
 
 #[derive(Debug)]
 pub struct RRDBNet {
-    conv_first: nn::Conv2d,
+    scale: usize,
+    conv_first: Conv2d,
     body: Sequential<RRDB>,
-    conv_body: nn::Conv2d,
-    conv_up1: nn::Conv2d,
-    conv_up2: nn::Conv2d,
-    conv_hr: nn::Conv2d,
-    conv_last: nn::Conv2d,
+    conv_body: Conv2d,
+    conv_up1: Conv2d,
+    conv_up2: Conv2d,
+    conv_hr: Conv2d,
+    conv_last: Conv2d,
     lrelu: nn::Activation,
 }
 
@@ -214,7 +235,18 @@ impl RRDBNet {
             dilation: 1,
             groups: 1,
         };
-        let conv_first = nn::conv2d(num_in_ch, num_feat, 3, config, vb.pp("conv_first"));
+        // x1 and x2 models pixel-unshuffle the input so the body always runs at 1/4 size
+        if ![1, 2, 4].contains(&scale) {
+            return Err(Error::Msg(format!("New-arch ESRGAN has no x{scale} models")));
+        }
+        let unshuffle = 4 / scale;
+        let conv_first = conv2d(
+            num_in_ch * unshuffle * unshuffle,
+            num_feat,
+            3,
+            config,
+            vb.pp("conv_first"),
+        );
         let mut body = seq();
         for i in 0..num_blocks {
             body.add(RRDB::load(
@@ -223,13 +255,14 @@ impl RRDBNet {
                 num_grow_ch,
             )?)
         }
-        let conv_body = nn::conv2d(num_feat, num_feat, 3, config, vb.pp("conv_body"));
-        let conv_up1 = nn::conv2d(num_feat, num_feat, 3, config, vb.pp("conv_up1"));
-        let conv_up2 = nn::conv2d(num_feat, num_feat, 3, config, vb.pp("conv_up2"));
-        let conv_hr = nn::conv2d(num_feat, num_feat, 3, config, vb.pp("conv_hr"));
-        let conv_last = nn::conv2d(num_feat, num_out_ch, 3, config, vb.pp("conv_last"));
+        let conv_body = conv2d(num_feat, num_feat, 3, config, vb.pp("conv_body"));
+        let conv_up1 = conv2d(num_feat, num_feat, 3, config, vb.pp("conv_up1"));
+        let conv_up2 = conv2d(num_feat, num_feat, 3, config, vb.pp("conv_up2"));
+        let conv_hr = conv2d(num_feat, num_feat, 3, config, vb.pp("conv_hr"));
+        let conv_last = conv2d(num_feat, num_out_ch, 3, config, vb.pp("conv_last"));
         let lrelu = nn::Activation::LeakyRelu(0.2);
         Ok(Self {
+            scale,
             conv_first: conv_first.unwrap(),
             body,
             conv_body: conv_body.unwrap(),
@@ -240,22 +273,58 @@ impl RRDBNet {
             lrelu,
         })
     }
+
+    /// Every conv of the model, for `conv::quantize`
+    pub fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = vec![&mut self.conv_first];
+        for block in self.body.layers.iter_mut() {
+            convs.extend(block.convs_mut());
+        }
+        convs.extend([
+            &mut self.conv_body,
+            &mut self.conv_up1,
+            &mut self.conv_up2,
+            &mut self.conv_hr,
+            &mut self.conv_last,
+        ]);
+        convs
+    }
 }
 
-impl nn::Module for RRDBNet {
-    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
-        let mut feat = self.conv_first.forward(xs)?;
-        let body_feat = self.conv_body.forward(&self.body.forward(&feat)?)?;
+impl RRDBNet {
+    /// `forward`, recording the time of every module in `profiler`
+    pub fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        let xs = match self.scale {
+            4 => xs.clone(),
+            scale => nn::ops::pixel_unshuffle(xs, 4 / scale)?,
+        };
+        let mut feat = self.conv_first.forward(&xs)?;
+        profiler.record("conv_first", &feat)?;
+        let mut body_feat = feat.clone();
+        for (i, block) in self.body.layers.iter().enumerate() {
+            body_feat = block.forward(&body_feat)?;
+            profiler.record(&format!("body.{i}"), &body_feat)?;
+        }
+        let body_feat = self.conv_body.forward(&body_feat)?;
         feat = (feat + body_feat)?;
+        profiler.record("conv_body", &feat)?;
         feat = self
             .lrelu
             .forward(&self.conv_up1.forward(&Upsample::new(2)?.forward(&feat)?)?)?;
         feat = self
             .lrelu
             .forward(&self.conv_up2.forward(&Upsample::new(2)?.forward(&feat)?)?)?;
-        let out = self
-            .conv_last
-            .forward(&self.lrelu.forward(&self.conv_hr.forward(&feat)?)?)?;
+        profiler.record("upsampling", &feat)?;
+        let feat = self.lrelu.forward(&self.conv_hr.forward(&feat)?)?;
+        profiler.record("conv_hr", &feat)?;
+        let out = self.conv_last.forward(&feat)?;
+        profiler.record("conv_last", &out)?;
         Ok(out)
     }
 }
+
+impl nn::Module for RRDBNet {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.forward_profiled(xs, &mut Profiler::disabled())
+    }
+}
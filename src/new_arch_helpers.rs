@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+
+use candle_core::Tensor;
+
+pub fn get_scale(state_dict: &HashMap<String, Tensor>) -> usize {
+    // x1 and x2 models pixel-unshuffle the input by 4 and 2 before conv_first,
+    // which multiplies its input channels by 16 and 4.
+    let ratio = match (
+        state_dict.get("conv_first.weight"),
+        state_dict.get("conv_last.weight"),
+    ) {
+        (Some(first), Some(last)) => first.shape().dims()[1] / last.shape().dims()[0],
+        _ => 1,
+    };
+    return match ratio {
+        16 => 1,
+        4 => 2,
+        _ => 4,
+    };
+}
+
+pub fn get_in_nc(state_dict: &HashMap<String, Tensor>) -> usize {
+    let unshuffle = 4 / get_scale(state_dict);
+    return match state_dict.get("conv_first.weight") {
+        Some(x) => x.shape().dims()[1] / (unshuffle * unshuffle),
+        None => 3,
+    };
+}
+
+pub fn get_out_nc(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("conv_last.weight") {
+        Some(x) => x.shape().dims()[0],
+        None => 3,
+    };
+}
+
+pub fn get_nf(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("conv_first.weight") {
+        Some(x) => x.shape().dims()[0],
+        None => 64,
+    };
+}
+
+pub fn get_nb(state_dict: &HashMap<String, Tensor>) -> usize {
+    let highest_block_num = state_dict
+        .keys()
+        .filter(|x| x.starts_with("body."))
+        .map(|x| x.split(".").collect::<Vec<&str>>()[1])
+        .map(|y| y.parse::<usize>().unwrap())
+        .max();
+    return match highest_block_num {
+        Some(x) => x + 1,
+        None => 23,
+    };
+}
+
+pub fn get_gc(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("body.0.rdb1.conv1.weight") {
+        Some(x) => x.shape().dims()[0],
+        None => 32,
+    };
+}
@@ -1,6 +1,9 @@
 use candle_core::{Module, Result, Tensor};
 use candle_nn as nn;
 
+use crate::conv::{conv2d, conv2d_no_bias, Conv2d};
+use crate::profile::Profiler;
+
 #[derive(Debug)]
 struct Upsample {
     scale_factor: usize,
@@ -21,51 +24,71 @@ impl nn::Module for Upsample {
 
 #[derive(Debug)]
 struct ResidualDenseBlock {
-    conv1: nn::Conv2d,
-    conv2: nn::Conv2d,
-    conv3: nn::Conv2d,
-    conv4: nn::Conv2d,
-    conv5: nn::Conv2d,
+    conv1: Conv2d,
+    conv2: Conv2d,
+    conv3: Conv2d,
+    conv4: Conv2d,
+    conv5: Conv2d,
+    /// ESRGAN+ only, a residual from the input to the second conv, which then
+    /// also goes to the fourth one
+    conv1x1: Option<Conv2d>,
     lrelu: nn::Activation,
 }
 
 impl ResidualDenseBlock {
-    fn load(vb: nn::VarBuilder, num_feat: usize, num_grow_ch: usize) -> Result<Self> {
+    fn load(vb: nn::VarBuilder, num_feat: usize, num_grow_ch: usize, plus: bool) -> Result<Self> {
         let config = nn::Conv2dConfig {
             padding: 1,
             stride: 1,
             dilation: 1,
             groups: 1,
         };
-        let conv1 = nn::conv2d(num_feat, num_grow_ch, 3, config, vb.pp("conv1.0"));
-        let conv2 = nn::conv2d(
+        let conv1 = conv2d(num_feat, num_grow_ch, 3, config, vb.pp("conv1.0"));
+        let conv2 = conv2d(
             num_feat + num_grow_ch,
             num_grow_ch,
             3,
             config,
             vb.pp("conv2.0"),
         );
-        let conv3 = nn::conv2d(
+        let conv3 = conv2d(
             num_feat + 2 * num_grow_ch,
             num_grow_ch,
             3,
             config,
             vb.pp("conv3.0"),
         );
-        let conv4 = nn::conv2d(
+        let conv4 = conv2d(
             num_feat + 3 * num_grow_ch,
             num_grow_ch,
             3,
             config,
             vb.pp("conv4.0"),
         );
-        let conv5 = nn::conv2d(
+        let conv5 = conv2d(
             num_feat + 4 * num_grow_ch,
             num_feat,
             3,
             config,
             vb.pp("conv5.0"),
         );
+        let conv1x1 = if plus {
+            let config = nn::Conv2dConfig {
+                padding: 0,
+                stride: 1,
+                dilation: 1,
+                groups: 1,
+            };
+            Some(conv2d_no_bias(
+                num_feat,
+                num_grow_ch,
+                1,
+                config,
+                vb.pp("conv1x1"),
+            )?)
+        } else {
+            None
+        };
         let lrelu = nn::Activation::LeakyRelu(0.2);
         Ok(Self {
             conv1: conv1.unwrap(),
@@ -73,48 +96,84 @@ impl ResidualDenseBlock {
             conv3: conv3.unwrap(),
             conv4: conv4.unwrap(),
             conv5: conv5.unwrap(),
+            conv1x1,
             lrelu,
         })
     }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = vec![
+            &mut self.conv1,
+            &mut self.conv2,
+            &mut self.conv3,
+            &mut self.conv4,
+            &mut self.conv5,
+        ];
+        if let Some(conv1x1) = &mut self.conv1x1 {
+            convs.push(conv1x1);
+        }
+        convs
+    }
 }
 
 impl nn::Module for ResidualDenseBlock {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
         let x1 = self.lrelu.forward(&self.conv1.forward(xs)?)?;
-        let x2 = self
+        let mut x2 = self
             .lrelu
             .forward(&self.conv2.forward(&Tensor::cat(&[xs, &x1], 1)?)?)?;
+        if let Some(conv1x1) = &self.conv1x1 {
+            x2 = (x2 + conv1x1.forward(xs)?)?;
+        }
         let x3 = self
             .lrelu
             .forward(&self.conv3.forward(&Tensor::cat(&[xs, &x1, &x2], 1)?)?)?;
-        let x4 = self
+        let mut x4 = self
             .lrelu
             .forward(&self.conv4.forward(&Tensor::cat(&[xs, &x1, &x2, &x3], 1)?)?)?;
+        if self.conv1x1.is_some() {
+            x4 = (x4 + &x2)?;
+        }
         let x5 = self
             .conv5
             .forward(&Tensor::cat(&[xs, &x1, &x2, &x3, &x4], 1)?)?;
+        // ESRGAN+ adds Gaussian noise here and after every RRDB, but only while
+        // training, so there is nothing to do for inference
         Ok((x5 * 0.2 + xs)?)
     }
 }
 
+/// Also the blocks of both branches of SPSR
 #[derive(Debug)]
-struct RRDB {
+pub(crate) struct RRDB {
     rdb1: ResidualDenseBlock,
     rdb2: ResidualDenseBlock,
     rdb3: ResidualDenseBlock,
 }
 
 impl RRDB {
-    fn load(vb: nn::VarBuilder, num_feat: usize, num_grow_ch: usize) -> Result<Self> {
-        let rdb1 = ResidualDenseBlock::load(vb.pp("RDB1"), num_feat, num_grow_ch);
-        let rdb2 = ResidualDenseBlock::load(vb.pp("RDB2"), num_feat, num_grow_ch);
-        let rdb3 = ResidualDenseBlock::load(vb.pp("RDB3"), num_feat, num_grow_ch);
+    pub(crate) fn load(
+        vb: nn::VarBuilder,
+        num_feat: usize,
+        num_grow_ch: usize,
+        plus: bool,
+    ) -> Result<Self> {
+        let rdb1 = ResidualDenseBlock::load(vb.pp("RDB1"), num_feat, num_grow_ch, plus);
+        let rdb2 = ResidualDenseBlock::load(vb.pp("RDB2"), num_feat, num_grow_ch, plus);
+        let rdb3 = ResidualDenseBlock::load(vb.pp("RDB3"), num_feat, num_grow_ch, plus);
         Ok(Self {
             rdb1: rdb1.unwrap(),
             rdb2: rdb2.unwrap(),
             rdb3: rdb3.unwrap(),
         })
     }
+
+    pub(crate) fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = self.rdb1.convs_mut();
+        convs.extend(self.rdb2.convs_mut());
+        convs.extend(self.rdb3.convs_mut());
+        convs
+    }
 }
 
 impl nn::Module for RRDB {
@@ -188,16 +247,19 @@ impl<T: Module> Module for Sequential<T> {
 
 #[derive(Debug)]
 pub struct RRDBNet {
-    conv_first: nn::Conv2d,
+    conv_first: Conv2d,
     body: Sequential<RRDB>,
-    conv_body: nn::Conv2d,
-    conv_ups: Vec<nn::Conv2d>,
-    conv_hr: nn::Conv2d,
-    conv_last: nn::Conv2d,
+    conv_body: Conv2d,
+    conv_ups: Vec<Conv2d>,
+    conv_hr: Conv2d,
+    conv_last: Conv2d,
     lrelu: nn::Activation,
 }
 
 impl RRDBNet {
+    /// `plus` is for the ESRGAN+ models, whose dense blocks have an extra 1x1
+    /// conv, see `old_arch_helpers::get_plus`
+    #[allow(clippy::too_many_arguments)]
     pub fn load(
         vb: nn::VarBuilder,
         num_in_ch: usize,
@@ -206,6 +268,7 @@ impl RRDBNet {
         num_feat: usize,
         num_blocks: usize,
         num_grow_ch: usize,
+        plus: bool,
     ) -> Result<Self> {
         let config = nn::Conv2dConfig {
             padding: 1,
@@ -213,16 +276,17 @@ impl RRDBNet {
             dilation: 1,
             groups: 1,
         };
-        let conv_first = nn::conv2d(num_in_ch, num_feat, 3, config, vb.pp("model.0"));
+        let conv_first = conv2d(num_in_ch, num_feat, 3, config, vb.pp("model.0"));
         let mut body = seq();
         for i in 0..num_blocks {
             body.add(RRDB::load(
                 vb.pp(format!("model.1.sub.{i}")),
                 num_feat,
                 num_grow_ch,
+                plus,
             )?)
         }
-        let conv_body = nn::conv2d(
+        let conv_body = conv2d(
             num_feat,
             num_feat,
             3,
@@ -231,12 +295,12 @@ impl RRDBNet {
         );
         let mut layer_start_num = 0;
         let num_ups = (scale as f32).log2() as usize;
-        let mut conv_ups: Vec<nn::Conv2d> = vec![];
+        let mut conv_ups: Vec<Conv2d> = vec![];
 
         (0..num_ups).for_each(|_| {
             layer_start_num += 3;
             conv_ups.push(
-                nn::conv2d(
+                conv2d(
                     num_feat,
                     num_feat,
                     3,
@@ -246,14 +310,14 @@ impl RRDBNet {
                 .unwrap(),
             );
         });
-        let conv_hr = nn::conv2d(
+        let conv_hr = conv2d(
             num_feat,
             num_feat,
             3,
             config,
             vb.pp(format!("model.{i}", i = layer_start_num + 2)),
         );
-        let conv_last = nn::conv2d(
+        let conv_last = conv2d(
             num_feat,
             num_out_ch,
             3,
@@ -271,21 +335,50 @@ impl RRDBNet {
             lrelu,
         })
     }
+
+    /// Every conv of the model, for `conv::quantize`
+    pub fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = vec![&mut self.conv_first];
+        for block in self.body.layers.iter_mut() {
+            convs.extend(block.convs_mut());
+        }
+        convs.push(&mut self.conv_body);
+        convs.extend(self.conv_ups.iter_mut());
+        convs.push(&mut self.conv_hr);
+        convs.push(&mut self.conv_last);
+        convs
+    }
 }
 
-impl nn::Module for RRDBNet {
-    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+impl RRDBNet {
+    /// `forward`, recording the time of every module in `profiler`
+    pub fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
         let mut feat = self.conv_first.forward(xs)?;
-        let body_feat = self.conv_body.forward(&self.body.forward(&feat)?)?;
+        profiler.record("conv_first", &feat)?;
+        let mut body_feat = feat.clone();
+        for (i, block) in self.body.layers.iter().enumerate() {
+            body_feat = block.forward(&body_feat)?;
+            profiler.record(&format!("body.{i}"), &body_feat)?;
+        }
+        let body_feat = self.conv_body.forward(&body_feat)?;
         feat = (feat + body_feat)?;
+        profiler.record("conv_body", &feat)?;
         for conv_up in &self.conv_ups {
             feat = self
                 .lrelu
                 .forward(&conv_up.forward(&Upsample::new(2)?.forward(&feat)?)?)?;
+            profiler.record("upsampling", &feat)?;
         }
-        let out = self
-            .conv_last
-            .forward(&self.lrelu.forward(&self.conv_hr.forward(&feat)?)?)?;
+        let feat = self.lrelu.forward(&self.conv_hr.forward(&feat)?)?;
+        profiler.record("conv_hr", &feat)?;
+        let out = self.conv_last.forward(&feat)?;
+        profiler.record("conv_last", &out)?;
         Ok(out)
     }
 }
+
+impl nn::Module for RRDBNet {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.forward_profiled(xs, &mut Profiler::disabled())
+    }
+}
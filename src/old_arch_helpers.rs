@@ -60,3 +60,15 @@ pub fn get_nb(state_dict: &HashMap<String, Tensor>) -> usize {
         None => 23,
     };
 }
+
+pub fn get_gc(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("model.1.sub.0.RDB1.conv1.0.weight") {
+        Some(x) => x.shape().dims()[0],
+        None => 32,
+    };
+}
+
+/// Whether the model is an ESRGAN+ one, with a 1x1 conv in every dense block
+pub fn get_plus(state_dict: &HashMap<String, Tensor>) -> bool {
+    return state_dict.keys().any(|x| x.contains(".conv1x1."));
+}
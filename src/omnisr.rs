@@ -0,0 +1,573 @@
+use candle_core::{Module, Result, Tensor};
+use candle_nn as nn;
+
+use crate::conv::{conv2d, conv2d_no_bias, Conv2d};
+use crate::dat::normalize;
+use crate::layers::{
+    layer_norm, layer_norm_2d, linear_b, upsample_bilinear, LayerNorm, LayerNorm2d, Linear,
+};
+use crate::profile::Profiler;
+use crate::swinir::{relative_position_index, window_partition, window_reverse};
+
+// Omni-SR: groups of omni self-attention blocks, each attending across the
+// pixels of local windows, across the pixels of a sparse grid spanning the
+// whole image and across the channels of both, with gated depthwise conv
+// feed-forward networks in between.
+
+/// Heads of every attention, whatever the number of channels
+const HEADS: usize = 4;
+const LAYER_NORM_2D_EPS: f64 = 1e-6;
+
+#[derive(Debug, Clone)]
+pub struct OmniSRConfig {
+    pub in_nc: usize,
+    pub out_nc: usize,
+    pub scale: usize,
+    pub num_feat: usize,
+    /// Number of omni self-attention groups
+    pub res_num: usize,
+    /// Number of omni self-attention blocks of every group
+    pub block_num: usize,
+    /// Side of the windows and of the grid cells, the input is padded to a
+    /// multiple of it
+    pub window_size: usize,
+    /// Whether the attention across pixels has a relative position bias
+    pub pe: bool,
+    /// Whether the convs outside of the blocks have a bias
+    pub bias: bool,
+}
+
+impl Default for OmniSRConfig {
+    /// Omni-SR of the paper
+    fn default() -> Self {
+        Self {
+            in_nc: 3,
+            out_nc: 3,
+            scale: 4,
+            num_feat: 64,
+            res_num: 5,
+            block_num: 1,
+            window_size: 8,
+            pe: true,
+            bias: true,
+        }
+    }
+}
+
+fn conv(
+    c_in: usize,
+    c_out: usize,
+    k: usize,
+    groups: usize,
+    bias: bool,
+    vb: nn::VarBuilder,
+) -> Result<Conv2d> {
+    let config = nn::Conv2dConfig {
+        padding: k / 2,
+        stride: 1,
+        dilation: 1,
+        groups,
+    };
+    if bias {
+        conv2d(c_in, c_out, k, config, vb)
+    } else {
+        conv2d_no_bias(c_in, c_out, k, config, vb)
+    }
+}
+
+/// Split (B, H, W, C) into the (B * windows, window * window, C) cells of a
+/// grid: cell (i, j) holds every pixel whose row is i and column is j modulo
+/// the number of cells
+fn grid_partition(xs: &Tensor, window: usize) -> Result<Tensor> {
+    let (b, h, w, c) = xs.dims4()?;
+    xs.reshape((b, window, h / window, window, w / window, c))?
+        .permute([0, 2, 4, 1, 3, 5])?
+        .reshape((b * (h / window) * (w / window), window * window, c))
+}
+
+/// Inverse of `grid_partition`
+fn grid_reverse(windows: &Tensor, window: usize, h: usize, w: usize) -> Result<Tensor> {
+    let (n, _, c) = windows.dims3()?;
+    let b = n / (h / window * (w / window));
+    windows
+        .reshape((b, h / window, w / window, window, window, c))?
+        .permute([0, 3, 1, 4, 2, 5])?
+        .reshape((b, h, w, c))
+}
+
+/// Inverted bottleneck with a squeeze-excitation, the expansion rate is 1
+#[derive(Debug)]
+struct MBConv {
+    expand: Conv2d,
+    depthwise: Conv2d,
+    gate: [Linear; 2],
+    project: Conv2d,
+}
+
+impl MBConv {
+    fn load(vb: nn::VarBuilder, dim: usize) -> Result<Self> {
+        let vb = vb.pp("fn");
+        let hidden_dim = dim / 4;
+        Ok(Self {
+            expand: conv(dim, dim, 1, 1, true, vb.pp("0"))?,
+            depthwise: conv(dim, dim, 3, dim, true, vb.pp("2"))?,
+            gate: [
+                linear_b(dim, hidden_dim, false, vb.pp("4.gate.1"))?,
+                linear_b(hidden_dim, dim, false, vb.pp("4.gate.3"))?,
+            ],
+            project: conv(dim, dim, 1, 1, true, vb.pp("5"))?,
+        })
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        vec![&mut self.expand, &mut self.depthwise, &mut self.project]
+    }
+}
+
+impl Module for MBConv {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b, c, _, _) = xs.dims4()?;
+        let out = self.expand.forward(xs)?.gelu_erf()?;
+        let out = self.depthwise.forward(&out)?.gelu_erf()?;
+        let gate = self.gate[0].forward(&out.mean((2, 3))?)?.silu()?;
+        let gate = nn::ops::sigmoid(&self.gate[1].forward(&gate)?)?;
+        let out = out.broadcast_mul(&gate.reshape((b, c, 1, 1))?)?;
+        self.project.forward(&out)? + xs
+    }
+}
+
+/// Attention across the pixels of every window, or of every grid cell, after
+/// a layer norm and with a residual connection
+#[derive(Debug)]
+struct Attention {
+    norm: LayerNorm,
+    to_qkv: Linear,
+    to_out: Linear,
+    rel_pos_bias: Option<Tensor>,
+    rel_pos_index: Tensor,
+    window_size: usize,
+    grid: bool,
+}
+
+impl Attention {
+    fn load(
+        vb: nn::VarBuilder,
+        dim: usize,
+        window_size: usize,
+        pe: bool,
+        grid: bool,
+    ) -> Result<Self> {
+        let norm = layer_norm(dim, vb.pp("norm"))?;
+        let vb = vb.pp("fn");
+        let table_size = (2 * window_size - 1) * (2 * window_size - 1);
+        let rel_pos_bias = match pe {
+            true => Some(vb.get((table_size, HEADS), "rel_pos_bias.weight")?),
+            false => None,
+        };
+        Ok(Self {
+            norm,
+            to_qkv: linear_b(dim, dim * 3, false, vb.pp("to_qkv"))?,
+            to_out: linear_b(dim, dim, false, vb.pp("to_out.0"))?,
+            rel_pos_bias,
+            rel_pos_index: relative_position_index((window_size, window_size), vb.device())?,
+            window_size,
+            grid,
+        })
+    }
+
+    /// Attention over `xs` of (B * windows, N, C)
+    fn attention(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b, n, c) = xs.dims3()?;
+        let head_dim = c / HEADS;
+        let qkv = self
+            .to_qkv
+            .forward(xs)?
+            .reshape((b, n, 3, HEADS, head_dim))?
+            .permute((2, 0, 3, 1, 4))?;
+        let q = (qkv.get(0)? * (head_dim as f64).powf(-0.5))?.contiguous()?;
+        let k = qkv.get(1)?.contiguous()?;
+        let v = qkv.get(2)?.contiguous()?;
+        let attn = q.matmul(&k.t()?)?;
+        let attn = match &self.rel_pos_bias {
+            Some(table) => {
+                let bias = table
+                    .index_select(&self.rel_pos_index.flatten_all()?, 0)?
+                    .reshape((n, n, HEADS))?
+                    .permute((2, 0, 1))?
+                    .to_dtype(xs.dtype())?;
+                attn.broadcast_add(&bias.unsqueeze(0)?)?
+            }
+            None => attn,
+        };
+        let attn = nn::ops::softmax_last_dim(&attn)?;
+        let out = attn.matmul(&v)?.transpose(1, 2)?.reshape((b, n, c))?;
+        self.to_out.forward(&out)
+    }
+}
+
+impl Module for Attention {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (_, _, h, w) = xs.dims4()?;
+        let ws = self.window_size;
+        let xs = xs.permute((0, 2, 3, 1))?;
+        let windows = match self.grid {
+            true => grid_partition(&xs, ws)?,
+            false => window_partition(&xs, (ws, ws))?,
+        };
+        let out = (self.attention(&self.norm.forward(&windows)?)? + windows)?;
+        let out = match self.grid {
+            true => grid_reverse(&out, ws, h, w)?,
+            false => window_reverse(&out, (ws, ws), h, w)?,
+        };
+        out.permute((0, 3, 1, 2))
+    }
+}
+
+/// Attention between the channels of every head inside every window, or grid
+/// cell, after a layer norm and with a residual connection
+#[derive(Debug)]
+struct ChannelAttention {
+    norm: LayerNorm2d,
+    temperature: Tensor,
+    qkv: Conv2d,
+    qkv_dwconv: Conv2d,
+    project_out: Conv2d,
+    window_size: usize,
+    grid: bool,
+}
+
+impl ChannelAttention {
+    fn load(vb: nn::VarBuilder, dim: usize, window_size: usize, grid: bool) -> Result<Self> {
+        let norm = layer_norm_2d(dim, LAYER_NORM_2D_EPS, vb.pp("norm"))?;
+        let vb = vb.pp("fn");
+        Ok(Self {
+            norm,
+            temperature: vb.get((HEADS, 1, 1), "temperature")?,
+            qkv: conv(dim, dim * 3, 1, 1, false, vb.pp("qkv"))?,
+            qkv_dwconv: conv(dim * 3, dim * 3, 3, dim * 3, false, vb.pp("qkv_dwconv"))?,
+            project_out: conv(dim, dim, 1, 1, false, vb.pp("project_out"))?,
+            window_size,
+            grid,
+        })
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        vec![&mut self.qkv, &mut self.qkv_dwconv, &mut self.project_out]
+    }
+
+    fn attention(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b, c, h, w) = xs.dims4()?;
+        let ps = self.window_size;
+        let (nh, nw) = (h / ps, w / ps);
+        let head_dim = c / HEADS;
+        // (B * heads, windows, head_dim, ps * ps), candle's matmul takes at
+        // most two batch dimensions
+        let split = |t: &Tensor| -> Result<Tensor> {
+            let t = match self.grid {
+                true => t
+                    .reshape((b * c, ps, nh, ps, nw))?
+                    .permute((0, 2, 4, 1, 3))?,
+                false => t
+                    .reshape((b * c, nh, ps, nw, ps))?
+                    .permute((0, 1, 3, 2, 4))?,
+            };
+            t.reshape((b * HEADS, head_dim, nh * nw, ps * ps))?
+                .permute((0, 2, 1, 3))?
+                .contiguous()
+        };
+        let qkv = self.qkv_dwconv.forward(&self.qkv.forward(xs)?)?;
+        let qkv = qkv.chunk(3, 1)?;
+        let q = normalize(&split(&qkv[0])?)?;
+        let k = normalize(&split(&qkv[1])?)?;
+        let v = split(&qkv[2])?;
+        let temperature = self
+            .temperature
+            .to_dtype(xs.dtype())?
+            .reshape((1, HEADS, 1, 1))?;
+        let attn = q
+            .matmul(&k.t()?)?
+            .reshape((b, HEADS, nh * nw * head_dim, head_dim))?
+            .broadcast_mul(&temperature)?
+            .reshape((b * HEADS, nh * nw, head_dim, head_dim))?;
+        let attn = nn::ops::softmax_last_dim(&attn)?;
+        let out = attn
+            .matmul(&v)?
+            .permute((0, 2, 1, 3))?
+            .reshape((b * c, nh, nw, ps, ps))?;
+        let out = match self.grid {
+            true => out.permute((0, 3, 1, 4, 2))?,
+            false => out.permute((0, 1, 3, 2, 4))?,
+        };
+        self.project_out.forward(&out.reshape((b, c, h, w))?)
+    }
+}
+
+impl Module for ChannelAttention {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.attention(&self.norm.forward(xs)?)? + xs
+    }
+}
+
+/// Feed-forward network where half of the channels, through a depthwise conv,
+/// gate the other half, after a layer norm and with a residual connection
+#[derive(Debug)]
+struct GatedConvFeedForward {
+    norm: LayerNorm2d,
+    project_in: Conv2d,
+    dwconv: Conv2d,
+    project_out: Conv2d,
+}
+
+impl GatedConvFeedForward {
+    fn load(vb: nn::VarBuilder, dim: usize) -> Result<Self> {
+        let norm = layer_norm_2d(dim, LAYER_NORM_2D_EPS, vb.pp("norm"))?;
+        let vb = vb.pp("fn");
+        Ok(Self {
+            norm,
+            project_in: conv(dim, dim * 2, 1, 1, false, vb.pp("project_in"))?,
+            dwconv: conv(dim * 2, dim * 2, 3, dim * 2, false, vb.pp("dwconv"))?,
+            project_out: conv(dim, dim, 1, 1, false, vb.pp("project_out"))?,
+        })
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        vec![
+            &mut self.project_in,
+            &mut self.dwconv,
+            &mut self.project_out,
+        ]
+    }
+}
+
+impl Module for GatedConvFeedForward {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let out = self.project_in.forward(&self.norm.forward(xs)?)?;
+        let out = self.dwconv.forward(&out)?.chunk(2, 1)?;
+        self.project_out
+            .forward(&out[0].gelu_erf()?.mul(&out[1])?)?
+            + xs
+    }
+}
+
+/// Omni self-attention block: an MBConv, then attention across the windows
+/// and across the grid, each followed by a feed-forward network, attention
+/// across the channels and another feed-forward network
+#[derive(Debug)]
+struct OSABlock {
+    mbconv: MBConv,
+    window_attn: Attention,
+    window_channel_attn: ChannelAttention,
+    grid_attn: Attention,
+    grid_channel_attn: ChannelAttention,
+    /// The feed-forward networks after each of the four attentions
+    ffn: Vec<GatedConvFeedForward>,
+}
+
+impl OSABlock {
+    fn load(vb: nn::VarBuilder, dim: usize, window_size: usize, pe: bool) -> Result<Self> {
+        let vb = vb.pp("layer");
+        let mut ffn = vec![];
+        for i in [4, 6, 10, 12] {
+            ffn.push(GatedConvFeedForward::load(vb.pp(i), dim)?);
+        }
+        Ok(Self {
+            mbconv: MBConv::load(vb.pp("0"), dim)?,
+            window_attn: Attention::load(vb.pp("2"), dim, window_size, pe, false)?,
+            window_channel_attn: ChannelAttention::load(vb.pp("5"), dim, window_size, false)?,
+            grid_attn: Attention::load(vb.pp("8"), dim, window_size, pe, true)?,
+            grid_channel_attn: ChannelAttention::load(vb.pp("11"), dim, window_size, true)?,
+            ffn,
+        })
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = self.mbconv.convs_mut();
+        convs.extend(self.window_channel_attn.convs_mut());
+        convs.extend(self.grid_channel_attn.convs_mut());
+        for ffn in self.ffn.iter_mut() {
+            convs.extend(ffn.convs_mut());
+        }
+        convs
+    }
+}
+
+impl Module for OSABlock {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let out = self.mbconv.forward(xs)?;
+        let out = self.ffn[0].forward(&self.window_attn.forward(&out)?)?;
+        let out = self.ffn[1].forward(&self.window_channel_attn.forward(&out)?)?;
+        let out = self.ffn[2].forward(&self.grid_attn.forward(&out)?)?;
+        self.ffn[3].forward(&self.grid_channel_attn.forward(&out)?)
+    }
+}
+
+/// Enhanced spatial attention: a mask computed at a much lower resolution
+/// and upsampled back weights the features
+#[derive(Debug)]
+struct ESA {
+    conv1: Conv2d,
+    conv_f: Conv2d,
+    conv2: Conv2d,
+    conv3: Conv2d,
+    conv4: Conv2d,
+}
+
+impl ESA {
+    fn load(vb: nn::VarBuilder, esa_channels: usize, n_feats: usize) -> Result<Self> {
+        let f = esa_channels;
+        let strided = nn::Conv2dConfig {
+            padding: 0,
+            stride: 2,
+            dilation: 1,
+            groups: 1,
+        };
+        Ok(Self {
+            conv1: conv(n_feats, f, 1, 1, true, vb.pp("conv1"))?,
+            conv_f: conv(f, f, 1, 1, true, vb.pp("conv_f"))?,
+            conv2: conv2d(f, f, 3, strided, vb.pp("conv2"))?,
+            conv3: conv(f, f, 3, 1, true, vb.pp("conv3"))?,
+            conv4: conv(f, n_feats, 1, 1, true, vb.pp("conv4"))?,
+        })
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        vec![
+            &mut self.conv1,
+            &mut self.conv_f,
+            &mut self.conv2,
+            &mut self.conv3,
+            &mut self.conv4,
+        ]
+    }
+}
+
+impl Module for ESA {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (_, _, h, w) = xs.dims4()?;
+        let c1_ = self.conv1.forward(xs)?;
+        let c1 = self.conv2.forward(&c1_)?;
+        let v_max = c1.max_pool2d_with_stride(7, 3)?;
+        let c3 = upsample_bilinear(&self.conv3.forward(&v_max)?, h, w)?;
+        let cf = self.conv_f.forward(&c1_)?;
+        let c4 = self.conv4.forward(&(c3 + cf)?)?;
+        xs.mul(&nn::ops::sigmoid(&c4)?)
+    }
+}
+
+/// Omni self-attention group: blocks, a 1x1 conv and a residual connection,
+/// then an enhanced spatial attention
+#[derive(Debug)]
+struct OSAG {
+    blocks: Vec<OSABlock>,
+    conv: Conv2d,
+    esa: ESA,
+}
+
+impl OSAG {
+    fn load(vb: nn::VarBuilder, config: &OmniSRConfig) -> Result<Self> {
+        let dim = config.num_feat;
+        let mut blocks = vec![];
+        for i in 0..config.block_num {
+            let vb = vb.pp(format!("residual_layer.{i}"));
+            blocks.push(OSABlock::load(vb, dim, config.window_size, config.pe)?);
+        }
+        let conv_vb = vb.pp(format!("residual_layer.{}", config.block_num));
+        Ok(Self {
+            blocks,
+            conv: conv(dim, dim, 1, 1, config.bias, conv_vb)?,
+            esa: ESA::load(vb.pp("esa"), usize::max(dim / 4, 16), dim)?,
+        })
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = Vec::new();
+        for block in self.blocks.iter_mut() {
+            convs.extend(block.convs_mut());
+        }
+        convs.push(&mut self.conv);
+        convs.extend(self.esa.convs_mut());
+        convs
+    }
+}
+
+impl Module for OSAG {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut out = xs.clone();
+        for block in self.blocks.iter() {
+            out = block.forward(&out)?;
+        }
+        self.esa.forward(&(self.conv.forward(&out)? + xs)?)
+    }
+}
+
+#[derive(Debug)]
+pub struct OmniSR {
+    input: Conv2d,
+    residual_layer: Vec<OSAG>,
+    output: Conv2d,
+    up: Conv2d,
+    window_size: usize,
+    scale: usize,
+}
+
+impl OmniSR {
+    pub fn load(vb: nn::VarBuilder, config: &OmniSRConfig) -> Result<Self> {
+        let nf = config.num_feat;
+        let mut residual_layer = vec![];
+        for i in 0..config.res_num {
+            residual_layer.push(OSAG::load(vb.pp(format!("residual_layer.{i}")), config)?);
+        }
+        let up_channels = config.out_nc * config.scale * config.scale;
+        Ok(Self {
+            input: conv(config.in_nc, nf, 3, 1, config.bias, vb.pp("input"))?,
+            residual_layer,
+            output: conv(nf, nf, 3, 1, config.bias, vb.pp("output"))?,
+            up: conv(nf, up_channels, 3, 1, config.bias, vb.pp("up.0"))?,
+            window_size: config.window_size,
+            scale: config.scale,
+        })
+    }
+
+    /// Every conv of the model, for `conv::quantize`. The linear layers aren't
+    /// among them and stay in full precision.
+    pub fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = vec![&mut self.input];
+        for group in self.residual_layer.iter_mut() {
+            convs.extend(group.convs_mut());
+        }
+        convs.push(&mut self.output);
+        convs.push(&mut self.up);
+        convs
+    }
+
+    /// `forward`, recording the time of every module in `profiler`
+    pub fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        let (_, _, h, w) = xs.dims4()?;
+        // The windows and the grid cells tile the image, it is padded with
+        // zeros to a multiple of their size and the output cropped back
+        let ws = self.window_size;
+        let xs = xs
+            .pad_with_zeros(2, 0, h.next_multiple_of(ws) - h)?
+            .pad_with_zeros(3, 0, w.next_multiple_of(ws) - w)?;
+        let residual = self.input.forward(&xs)?;
+        profiler.record("input", &residual)?;
+        let mut out = residual.clone();
+        for (i, group) in self.residual_layer.iter().enumerate() {
+            out = group.forward(&out)?;
+            profiler.record(&format!("residual_layer.{i}"), &out)?;
+        }
+        let out = (self.output.forward(&out)? + residual)?;
+        profiler.record("output", &out)?;
+        let out = nn::ops::pixel_shuffle(&self.up.forward(&out)?, self.scale)?
+            .narrow(2, 0, h * self.scale)?
+            .narrow(3, 0, w * self.scale)?;
+        profiler.record("up", &out)?;
+        Ok(out)
+    }
+}
+
+impl nn::Module for OmniSR {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.forward_profiled(xs, &mut Profiler::disabled())
+    }
+}
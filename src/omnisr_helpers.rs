@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use candle_core::Tensor;
+
+use crate::omnisr::OmniSRConfig;
+
+// Omni-SR groups are `residual_layer.{i}`, and their blocks
+// `residual_layer.{i}.residual_layer.{j}` followed by the 1x1 conv of the
+// group at the next index
+
+const FIRST_BLOCK: &str = "residual_layer.0.residual_layer.0.layer";
+
+pub fn get_in_nc(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("input.weight") {
+        Some(x) => x.shape().dims()[1],
+        None => 3,
+    };
+}
+
+pub fn get_num_feat(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("input.weight") {
+        Some(x) => x.shape().dims()[0],
+        None => 64,
+    };
+}
+
+pub fn get_scale(state_dict: &HashMap<String, Tensor>) -> usize {
+    // The last conv outputs in_nc * scale * scale channels for the pixel shuffle
+    return match state_dict.get("up.0.weight") {
+        Some(x) => {
+            let channels = x.shape().dims()[0] / get_in_nc(state_dict);
+            (channels as f64).sqrt().round() as usize
+        }
+        None => 4,
+    };
+}
+
+pub fn get_res_num(state_dict: &HashMap<String, Tensor>) -> usize {
+    let highest_group_num = state_dict
+        .keys()
+        .filter(|x| x.starts_with("residual_layer.") && x.contains(".esa."))
+        .filter_map(|x| x.split('.').nth(1)?.parse::<usize>().ok())
+        .max();
+    return match highest_group_num {
+        Some(x) => x + 1,
+        None => 5,
+    };
+}
+
+pub fn get_block_num(state_dict: &HashMap<String, Tensor>) -> usize {
+    // The 1x1 conv closing the group has no `layer` in its key
+    let highest_block_num = state_dict
+        .keys()
+        .filter(|x| x.starts_with("residual_layer.0.residual_layer.") && x.contains(".layer."))
+        .filter_map(|x| x.split('.').nth(3)?.parse::<usize>().ok())
+        .max();
+    return match highest_block_num {
+        Some(x) => x + 1,
+        None => 1,
+    };
+}
+
+/// Whether the attention across pixels has a relative position bias
+pub fn get_pe(state_dict: &HashMap<String, Tensor>) -> bool {
+    return state_dict.contains_key(&format!("{FIRST_BLOCK}.2.fn.rel_pos_bias.weight"));
+}
+
+pub fn get_window_size(state_dict: &HashMap<String, Tensor>) -> usize {
+    // The bias table has a row for every one of the (2 * window_size - 1)²
+    // relative positions. Without it nothing depends on the window size and
+    // the one of the paper is assumed.
+    return match state_dict.get(&format!("{FIRST_BLOCK}.2.fn.rel_pos_bias.weight")) {
+        Some(x) => {
+            let side = (x.shape().dims()[0] as f64).sqrt().round() as usize;
+            side.div_ceil(2)
+        }
+        None => 8,
+    };
+}
+
+/// Every hyperparameter of the model
+pub fn get_config(state_dict: &HashMap<String, Tensor>) -> OmniSRConfig {
+    let in_nc = get_in_nc(state_dict);
+    return OmniSRConfig {
+        in_nc,
+        out_nc: in_nc,
+        scale: get_scale(state_dict),
+        num_feat: get_num_feat(state_dict),
+        res_num: get_res_num(state_dict),
+        block_num: get_block_num(state_dict),
+        window_size: get_window_size(state_dict),
+        pe: get_pe(state_dict),
+        bias: state_dict.contains_key("input.bias"),
+    };
+}
@@ -0,0 +1,78 @@
+use std::time::{Duration, Instant};
+
+use candle_core::{DType, Result, Tensor};
+
+/// Collects the time spent in each part of a forward pass.
+/// A disabled profiler does nothing, so models can always take one.
+pub struct Profiler {
+    enabled: bool,
+    last: Instant,
+    timings: Vec<(String, Duration)>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            enabled: true,
+            last: Instant::now(),
+            timings: vec![],
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            ..Self::new()
+        }
+    }
+
+    /// Start timing from now, call right before the forward pass
+    pub fn start(&mut self) {
+        self.last = Instant::now();
+    }
+
+    /// Wait for `xs` to be computed and charge the time since the previous
+    /// record to `name`
+    pub fn record(&mut self, name: &str, xs: &Tensor) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        synchronize(xs)?;
+        let now = Instant::now();
+        self.timings.push((name.to_string(), now - self.last));
+        self.last = now;
+        Ok(())
+    }
+
+    /// Time of every part in the order they ran, summed when a name repeats
+    pub fn timings(&self) -> Vec<(String, Duration)> {
+        let mut totals: Vec<(String, Duration)> = vec![];
+        for (name, time) in &self.timings {
+            match totals.iter_mut().find(|(n, _)| n == name) {
+                Some((_, total)) => *total += *time,
+                None => totals.push((name.clone(), *time)),
+            }
+        }
+        totals
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Block until `xs` is computed. GPU kernels run asynchronously, so timings
+/// are meaningless without this.
+pub fn synchronize(xs: &Tensor) -> Result<()> {
+    if xs.device().is_cpu() {
+        return Ok(());
+    }
+    // Copying anything back to the host waits for the queued kernels
+    xs.flatten_all()?
+        .narrow(0, 0, 1)?
+        .to_dtype(DType::F32)?
+        .to_vec1::<f32>()?;
+    Ok(())
+}
@@ -0,0 +1,1022 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn as nn;
+
+use crate::conv::Conv2d;
+use crate::dat::DATConfig;
+use crate::descriptor::ModelDescriptor;
+use crate::hat::HATConfig;
+use crate::omnisr::OmniSRConfig;
+use crate::profile::Profiler;
+use crate::swinir::SwinIRConfig;
+use crate::{
+    compact, compact_helpers, cugan, cugan_helpers, dat, dat_helpers, hat, hat_helpers, new_arch,
+    new_arch_helpers, old_arch, old_arch_helpers, omnisr, omnisr_helpers, safmn, safmn_helpers,
+    span, span_helpers, spsr, spsr_helpers, srformer, srformer_helpers, swinir, swinir_helpers,
+};
+
+// Architectures behind one interface: each recognises its own state dicts,
+// reads their hyperparameters and loads them, so code picking a model from a
+// file doesn't have to know every model type, and other crates can add theirs
+// to a `Registry` without touching this one.
+
+/// Hyperparameters of a model, as read from its state dict
+#[derive(Debug, Clone, PartialEq)]
+pub struct Params {
+    pub in_nc: usize,
+    pub out_nc: usize,
+    pub scale: usize,
+    pub num_features: usize,
+    pub num_blocks: usize,
+    /// Whatever else the architecture needs, by name
+    pub extra: HashMap<String, usize>,
+}
+
+/// A model loaded by an `Architecture`
+pub trait Upscaler: Send + Sync {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor>;
+
+    /// `forward`, recording the time spent in every part of the model. Only
+    /// the whole pass by default.
+    fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        let out = self.forward(xs)?;
+        profiler.record("forward", &out)?;
+        Ok(out)
+    }
+
+    /// The convs of the model, for `conv::quantize`. None by default, the
+    /// model is then left as it is.
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        Vec::new()
+    }
+}
+
+pub trait Architecture: Send + Sync {
+    /// Name the architecture is registered under, the same as `--arch`
+    fn name(&self) -> &'static str;
+
+    /// The hyperparameters of `state_dict` if it is a model of this
+    /// architecture, None otherwise
+    fn detect(&self, state_dict: &HashMap<String, Tensor>) -> Option<Params>;
+
+    /// Load the model with `params`, which may differ from the detected ones.
+    /// `state_dict` has whatever `Params` can't hold, like the depths of
+    /// the transformers.
+    fn load(
+        &self,
+        vb: nn::VarBuilder,
+        state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+    ) -> Result<Box<dyn Upscaler>>;
+
+    /// Scale, channels and tiling hints of the model `load` makes out of
+    /// `state_dict` with `params`, and the dtypes it runs in on `device`.
+    /// Without a receptive field by default, tiles then overlap by a fixed
+    /// margin.
+    fn descriptor(
+        &self,
+        state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+        device: &Device,
+    ) -> ModelDescriptor {
+        ModelDescriptor::new(
+            self.name(),
+            params.scale,
+            params.in_nc,
+            params.out_nc,
+            state_dict,
+            device,
+        )
+    }
+}
+
+/// Architectures to detect and load models with, tried in the order they
+/// were registered
+#[derive(Default)]
+pub struct Registry {
+    architectures: Vec<Box<dyn Architecture>>,
+}
+
+impl Registry {
+    /// Every upscaling architecture of this crate
+    pub fn builtin() -> Self {
+        let mut registry = Self::default();
+        registry.register(OldArch);
+        registry.register(NewArch);
+        registry.register(Compact);
+        registry.register(Span);
+        registry.register(SwinIR);
+        registry.register(HAT);
+        registry.register(DAT);
+        registry.register(SRFormer);
+        registry.register(CUGAN);
+        registry.register(OmniSR);
+        registry.register(SAFMN);
+        registry.register(SPSR);
+        registry
+    }
+
+    pub fn register(&mut self, architecture: impl Architecture + 'static) {
+        self.architectures.push(Box::new(architecture));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Architecture> {
+        self.architectures
+            .iter()
+            .find(|arch| arch.name() == name)
+            .map(|arch| arch.as_ref())
+    }
+
+    /// The first architecture recognising `state_dict`, with its
+    /// hyperparameters
+    pub fn detect(
+        &self,
+        state_dict: &HashMap<String, Tensor>,
+    ) -> Option<(&dyn Architecture, Params)> {
+        self.architectures
+            .iter()
+            .find_map(|arch| Some((arch.as_ref(), arch.detect(state_dict)?)))
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.architectures.iter().map(|arch| arch.name()).collect()
+    }
+}
+
+/// Old-arch ESRGAN and ESRGAN+, `gc` and `plus` (0 or 1) in the extra
+/// params
+pub struct OldArch;
+
+impl Architecture for OldArch {
+    fn name(&self) -> &'static str {
+        "old"
+    }
+
+    fn detect(&self, state_dict: &HashMap<String, Tensor>) -> Option<Params> {
+        // The trunk of SPSR has the keys of old-arch models
+        if !state_dict.keys().any(|x| x.contains("model.0.weight"))
+            || state_dict.contains_key("b_fea_conv.0.weight")
+        {
+            return None;
+        }
+        Some(Params {
+            in_nc: old_arch_helpers::get_in_nc(state_dict),
+            out_nc: old_arch_helpers::get_out_nc(state_dict),
+            scale: old_arch_helpers::get_scale(state_dict),
+            num_features: old_arch_helpers::get_nf(state_dict),
+            num_blocks: old_arch_helpers::get_nb(state_dict),
+            extra: HashMap::from([
+                ("gc".to_string(), old_arch_helpers::get_gc(state_dict)),
+                (
+                    "plus".to_string(),
+                    old_arch_helpers::get_plus(state_dict) as usize,
+                ),
+            ]),
+        })
+    }
+
+    fn load(
+        &self,
+        vb: nn::VarBuilder,
+        _state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+    ) -> Result<Box<dyn Upscaler>> {
+        let model = old_arch::RRDBNet::load(
+            vb,
+            params.in_nc,
+            params.out_nc,
+            params.scale,
+            params.num_features,
+            params.num_blocks,
+            params.extra["gc"],
+            params.extra["plus"] != 0,
+        )?;
+        Ok(Box::new(model))
+    }
+
+    fn descriptor(
+        &self,
+        state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+        device: &Device,
+    ) -> ModelDescriptor {
+        let num_ups = (params.scale as f32).log2() as usize;
+        let mut descriptor = ModelDescriptor::new(
+            self.name(),
+            params.scale,
+            params.in_nc,
+            params.out_nc,
+            state_dict,
+            device,
+        );
+        // 15 3x3 convs in every block, counting those after the upsampling as
+        // a whole pixel of the input too
+        descriptor.receptive_field = Some(15 * params.num_blocks + num_ups + 4);
+        descriptor
+    }
+}
+
+/// New-arch ESRGAN (RealESRGAN), `gc` in the extra params
+pub struct NewArch;
+
+impl Architecture for NewArch {
+    fn name(&self) -> &'static str {
+        "new"
+    }
+
+    fn detect(&self, state_dict: &HashMap<String, Tensor>) -> Option<Params> {
+        if !state_dict.contains_key("body.0.rdb1.conv1.weight") {
+            return None;
+        }
+        Some(Params {
+            in_nc: new_arch_helpers::get_in_nc(state_dict),
+            out_nc: new_arch_helpers::get_out_nc(state_dict),
+            scale: new_arch_helpers::get_scale(state_dict),
+            num_features: new_arch_helpers::get_nf(state_dict),
+            num_blocks: new_arch_helpers::get_nb(state_dict),
+            extra: HashMap::from([("gc".to_string(), new_arch_helpers::get_gc(state_dict))]),
+        })
+    }
+
+    fn load(
+        &self,
+        vb: nn::VarBuilder,
+        _state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+    ) -> Result<Box<dyn Upscaler>> {
+        let model = new_arch::RRDBNet::load(
+            vb,
+            params.in_nc,
+            params.out_nc,
+            params.scale,
+            params.num_features,
+            params.num_blocks,
+            params.extra["gc"],
+        )?;
+        Ok(Box::new(model))
+    }
+
+    fn descriptor(
+        &self,
+        state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+        device: &Device,
+    ) -> ModelDescriptor {
+        // x2 and x1 models pixel unshuffle their input to run at a quarter of
+        // its size
+        let unshuffle = 4 / params.scale.min(4);
+        let mut descriptor = ModelDescriptor::new(
+            self.name(),
+            params.scale,
+            params.in_nc,
+            params.out_nc,
+            state_dict,
+            device,
+        );
+        descriptor.receptive_field = Some((15 * params.num_blocks + 6) * unshuffle);
+        descriptor.input_multiple = unshuffle;
+        descriptor
+    }
+}
+
+/// RealESRGANv2 aka Compact, whose number of convs is `num_blocks`
+pub struct Compact;
+
+impl Architecture for Compact {
+    fn name(&self) -> &'static str {
+        "compact"
+    }
+
+    fn detect(&self, state_dict: &HashMap<String, Tensor>) -> Option<Params> {
+        if !state_dict.contains_key("body.0.weight") {
+            return None;
+        }
+        Some(Params {
+            in_nc: compact_helpers::get_in_nc(state_dict),
+            out_nc: compact_helpers::get_in_nc(state_dict),
+            scale: compact_helpers::get_scale(state_dict),
+            num_features: compact_helpers::get_nf(state_dict),
+            num_blocks: compact_helpers::get_num_conv(state_dict),
+            extra: HashMap::new(),
+        })
+    }
+
+    fn load(
+        &self,
+        vb: nn::VarBuilder,
+        _state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+    ) -> Result<Box<dyn Upscaler>> {
+        let model = compact::SRVGGNetCompact::load(
+            vb,
+            params.in_nc,
+            params.out_nc,
+            params.num_features,
+            params.num_blocks,
+            params.scale,
+        )?;
+        Ok(Box::new(model))
+    }
+
+    fn descriptor(
+        &self,
+        state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+        device: &Device,
+    ) -> ModelDescriptor {
+        let mut descriptor = ModelDescriptor::new(
+            self.name(),
+            params.scale,
+            params.in_nc,
+            params.out_nc,
+            state_dict,
+            device,
+        );
+        // The first and last convs around the body
+        descriptor.receptive_field = Some(params.num_blocks + 2);
+        descriptor
+    }
+}
+
+/// SPAN, `norm` (0 or 1) in the extra params. Its 6 blocks are fixed.
+pub struct Span;
+
+impl Architecture for Span {
+    fn name(&self) -> &'static str {
+        "span"
+    }
+
+    fn detect(&self, state_dict: &HashMap<String, Tensor>) -> Option<Params> {
+        if !state_dict.contains_key("block_1.c1_r.sk.weight") {
+            return None;
+        }
+        Some(Params {
+            in_nc: span_helpers::get_in_nc(state_dict),
+            out_nc: span_helpers::get_in_nc(state_dict),
+            scale: span_helpers::get_scale(state_dict),
+            num_features: span_helpers::get_nf(state_dict),
+            num_blocks: 6,
+            extra: HashMap::from([(
+                "norm".to_string(),
+                span_helpers::get_norm(state_dict) as usize,
+            )]),
+        })
+    }
+
+    fn load(
+        &self,
+        vb: nn::VarBuilder,
+        _state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+    ) -> Result<Box<dyn Upscaler>> {
+        let model = span::SPAN::load(
+            vb,
+            params.in_nc,
+            params.out_nc,
+            params.num_features,
+            params.scale,
+            params.extra["norm"] != 0,
+        )?;
+        Ok(Box::new(model))
+    }
+
+    fn descriptor(
+        &self,
+        state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+        device: &Device,
+    ) -> ModelDescriptor {
+        let mut descriptor = ModelDescriptor::new(
+            self.name(),
+            params.scale,
+            params.in_nc,
+            params.out_nc,
+            state_dict,
+            device,
+        );
+        // Three 3x3 convs in each of the 6 blocks, and three around them
+        descriptor.receptive_field = Some(21);
+        descriptor
+    }
+}
+
+/// SwinIR, whose `num_blocks` is the number of residual groups. The rest of
+/// its config is read from the state dict when loading.
+pub struct SwinIR;
+
+impl Architecture for SwinIR {
+    fn name(&self) -> &'static str {
+        "swinir"
+    }
+
+    fn detect(&self, state_dict: &HashMap<String, Tensor>) -> Option<Params> {
+        // HAT and SRFormer have the keys of SwinIR too
+        if !state_dict.contains_key("layers.0.residual_group.blocks.0.norm1.weight")
+            || state_dict.contains_key("layers.0.residual_group.blocks.0.conv_block.cab.0.weight")
+            || state_dict.contains_key("layers.0.residual_group.blocks.0.attn.q.weight")
+        {
+            return None;
+        }
+        let config = swinir_helpers::get_config(state_dict);
+        Some(Params {
+            in_nc: config.in_nc,
+            out_nc: config.out_nc,
+            scale: config.scale,
+            num_features: config.embed_dim,
+            num_blocks: config.depths.len(),
+            extra: HashMap::new(),
+        })
+    }
+
+    fn load(
+        &self,
+        vb: nn::VarBuilder,
+        state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+    ) -> Result<Box<dyn Upscaler>> {
+        let config = swinir_config(state_dict, params);
+        Ok(Box::new(swinir::SwinIR::load(vb, &config)?))
+    }
+
+    fn descriptor(
+        &self,
+        state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+        device: &Device,
+    ) -> ModelDescriptor {
+        let mut descriptor = ModelDescriptor::new(
+            self.name(),
+            params.scale,
+            params.in_nc,
+            params.out_nc,
+            state_dict,
+            device,
+        );
+        // The fused layer norm of recent candle versions has no f64 kernel
+        descriptor.dtypes.retain(|&dtype| dtype != DType::F64);
+        descriptor
+    }
+}
+
+/// HAT (S, M or L), configured like `SwinIR`
+pub struct HAT;
+
+impl Architecture for HAT {
+    fn name(&self) -> &'static str {
+        "hat"
+    }
+
+    fn detect(&self, state_dict: &HashMap<String, Tensor>) -> Option<Params> {
+        if !state_dict.contains_key("layers.0.residual_group.blocks.0.conv_block.cab.0.weight") {
+            return None;
+        }
+        let config = hat_helpers::get_config(state_dict);
+        Some(Params {
+            in_nc: config.in_nc,
+            out_nc: config.out_nc,
+            scale: config.scale,
+            num_features: config.embed_dim,
+            num_blocks: config.depths.len(),
+            extra: HashMap::new(),
+        })
+    }
+
+    fn load(
+        &self,
+        vb: nn::VarBuilder,
+        state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+    ) -> Result<Box<dyn Upscaler>> {
+        let config = hat_config(state_dict, params);
+        Ok(Box::new(hat::HAT::load(vb, &config)?))
+    }
+
+    fn descriptor(
+        &self,
+        state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+        device: &Device,
+    ) -> ModelDescriptor {
+        let mut descriptor = ModelDescriptor::new(
+            hat_config(state_dict, params).variant(),
+            params.scale,
+            params.in_nc,
+            params.out_nc,
+            state_dict,
+            device,
+        );
+        // Layer norms, see `SwinIR::descriptor`
+        descriptor.dtypes.retain(|&dtype| dtype != DType::F64);
+        descriptor
+    }
+}
+
+/// DAT (S, base, 2 or light), configured like `SwinIR`
+pub struct DAT;
+
+impl Architecture for DAT {
+    fn name(&self) -> &'static str {
+        "dat"
+    }
+
+    fn detect(&self, state_dict: &HashMap<String, Tensor>) -> Option<Params> {
+        if !state_dict.contains_key("before_RG.1.weight") {
+            return None;
+        }
+        let config = dat_helpers::get_config(state_dict);
+        Some(Params {
+            in_nc: config.in_nc,
+            out_nc: config.out_nc,
+            scale: config.scale,
+            num_features: config.embed_dim,
+            num_blocks: config.depths.len(),
+            extra: HashMap::new(),
+        })
+    }
+
+    fn load(
+        &self,
+        vb: nn::VarBuilder,
+        state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+    ) -> Result<Box<dyn Upscaler>> {
+        let config = dat_config(state_dict, params);
+        Ok(Box::new(dat::DAT::load(vb, &config)?))
+    }
+
+    fn descriptor(
+        &self,
+        state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+        device: &Device,
+    ) -> ModelDescriptor {
+        let mut descriptor = ModelDescriptor::new(
+            dat_config(state_dict, params).variant(),
+            params.scale,
+            params.in_nc,
+            params.out_nc,
+            state_dict,
+            device,
+        );
+        // Layer norms, see `SwinIR::descriptor`
+        descriptor.dtypes.retain(|&dtype| dtype != DType::F64);
+        descriptor
+    }
+}
+
+/// SRFormer (base or light), configured like `SwinIR`
+pub struct SRFormer;
+
+impl Architecture for SRFormer {
+    fn name(&self) -> &'static str {
+        "srformer"
+    }
+
+    fn detect(&self, state_dict: &HashMap<String, Tensor>) -> Option<Params> {
+        if !state_dict.contains_key("layers.0.residual_group.blocks.0.attn.q.weight") {
+            return None;
+        }
+        let config = srformer_helpers::get_config(state_dict);
+        Some(Params {
+            in_nc: config.in_nc,
+            out_nc: config.out_nc,
+            scale: config.scale,
+            num_features: config.embed_dim,
+            num_blocks: config.depths.len(),
+            extra: HashMap::new(),
+        })
+    }
+
+    fn load(
+        &self,
+        vb: nn::VarBuilder,
+        state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+    ) -> Result<Box<dyn Upscaler>> {
+        let config = srformer_config(state_dict, params);
+        Ok(Box::new(srformer::SRFormer::load(vb, &config)?))
+    }
+
+    fn descriptor(
+        &self,
+        state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+        device: &Device,
+    ) -> ModelDescriptor {
+        let mut descriptor = ModelDescriptor::new(
+            srformer::variant(&srformer_config(state_dict, params)),
+            params.scale,
+            params.in_nc,
+            params.out_nc,
+            state_dict,
+            device,
+        );
+        // Layer norms, see `SwinIR::descriptor`
+        descriptor.dtypes.retain(|&dtype| dtype != DType::F64);
+        descriptor
+    }
+}
+
+/// Real-CUGAN, `pro` (0 or 1) in the extra params. Its UNets are fixed.
+pub struct CUGAN;
+
+impl Architecture for CUGAN {
+    fn name(&self) -> &'static str {
+        "cugan"
+    }
+
+    fn detect(&self, state_dict: &HashMap<String, Tensor>) -> Option<Params> {
+        if !state_dict.contains_key("unet1.conv1.conv.0.weight") {
+            return None;
+        }
+        Some(Params {
+            in_nc: cugan_helpers::get_in_nc(state_dict),
+            out_nc: cugan_helpers::get_out_nc(state_dict),
+            scale: cugan_helpers::get_scale(state_dict),
+            num_features: cugan_helpers::get_nf(state_dict),
+            num_blocks: 0,
+            extra: HashMap::from([(
+                "pro".to_string(),
+                cugan_helpers::get_pro(state_dict) as usize,
+            )]),
+        })
+    }
+
+    fn load(
+        &self,
+        vb: nn::VarBuilder,
+        _state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+    ) -> Result<Box<dyn Upscaler>> {
+        let model = cugan::UpCunet::load(
+            vb,
+            params.in_nc,
+            params.out_nc,
+            params.scale,
+            params.num_features,
+            params.extra["pro"] != 0,
+        )?;
+        Ok(Box::new(model))
+    }
+}
+
+/// Omni-SR, whose `num_blocks` is the number of self-attention groups
+pub struct OmniSR;
+
+impl Architecture for OmniSR {
+    fn name(&self) -> &'static str {
+        "omnisr"
+    }
+
+    fn detect(&self, state_dict: &HashMap<String, Tensor>) -> Option<Params> {
+        if !state_dict.contains_key("residual_layer.0.residual_layer.0.layer.0.fn.0.weight") {
+            return None;
+        }
+        let config = omnisr_helpers::get_config(state_dict);
+        Some(Params {
+            in_nc: config.in_nc,
+            out_nc: config.out_nc,
+            scale: config.scale,
+            num_features: config.num_feat,
+            num_blocks: config.res_num,
+            extra: HashMap::new(),
+        })
+    }
+
+    fn load(
+        &self,
+        vb: nn::VarBuilder,
+        state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+    ) -> Result<Box<dyn Upscaler>> {
+        let config = omnisr_config(state_dict, params);
+        Ok(Box::new(omnisr::OmniSR::load(vb, &config)?))
+    }
+
+    fn descriptor(
+        &self,
+        state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+        device: &Device,
+    ) -> ModelDescriptor {
+        let mut descriptor = ModelDescriptor::new(
+            self.name(),
+            params.scale,
+            params.in_nc,
+            params.out_nc,
+            state_dict,
+            device,
+        );
+        // Layer norms, see `SwinIR::descriptor`
+        descriptor.dtypes.retain(|&dtype| dtype != DType::F64);
+        descriptor
+    }
+}
+
+/// SAFMN, whose feed-forward expansion is read from the state dict when
+/// loading
+pub struct SAFMN;
+
+impl Architecture for SAFMN {
+    fn name(&self) -> &'static str {
+        "safmn"
+    }
+
+    fn detect(&self, state_dict: &HashMap<String, Tensor>) -> Option<Params> {
+        if !state_dict.contains_key("feats.0.safm.mfr.0.weight") {
+            return None;
+        }
+        Some(Params {
+            in_nc: safmn_helpers::get_in_nc(state_dict),
+            out_nc: safmn_helpers::get_in_nc(state_dict),
+            scale: safmn_helpers::get_scale(state_dict),
+            num_features: safmn_helpers::get_dim(state_dict),
+            num_blocks: safmn_helpers::get_n_blocks(state_dict),
+            extra: HashMap::new(),
+        })
+    }
+
+    fn load(
+        &self,
+        vb: nn::VarBuilder,
+        state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+    ) -> Result<Box<dyn Upscaler>> {
+        let model = safmn::SAFMN::load(
+            vb,
+            params.in_nc,
+            params.out_nc,
+            params.num_features,
+            params.num_blocks,
+            safmn_helpers::get_ffn_scale(state_dict),
+            params.scale,
+        )?;
+        Ok(Box::new(model))
+    }
+}
+
+/// SPSR, old-arch ESRGAN with a gradient branch, `gc` in the extra params
+pub struct SPSR;
+
+impl Architecture for SPSR {
+    fn name(&self) -> &'static str {
+        "spsr"
+    }
+
+    fn detect(&self, state_dict: &HashMap<String, Tensor>) -> Option<Params> {
+        if !state_dict.contains_key("b_fea_conv.0.weight")
+            || !state_dict.contains_key("f_HR_conv1.0.weight")
+        {
+            return None;
+        }
+        Some(Params {
+            in_nc: old_arch_helpers::get_in_nc(state_dict),
+            out_nc: spsr_helpers::get_out_nc(state_dict),
+            scale: spsr_helpers::get_scale(state_dict),
+            num_features: old_arch_helpers::get_nf(state_dict),
+            num_blocks: old_arch_helpers::get_nb(state_dict),
+            extra: HashMap::from([("gc".to_string(), old_arch_helpers::get_gc(state_dict))]),
+        })
+    }
+
+    fn load(
+        &self,
+        vb: nn::VarBuilder,
+        _state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+    ) -> Result<Box<dyn Upscaler>> {
+        let model = spsr::SPSRNet::load(
+            vb,
+            params.in_nc,
+            params.out_nc,
+            params.scale,
+            params.num_features,
+            params.num_blocks,
+            params.extra["gc"],
+        )?;
+        Ok(Box::new(model))
+    }
+}
+
+// The configs of the transformers and of Omni-SR, read from the state dict
+// with `params` on top
+
+fn swinir_config(state_dict: &HashMap<String, Tensor>, params: &Params) -> SwinIRConfig {
+    SwinIRConfig {
+        in_nc: params.in_nc,
+        out_nc: params.out_nc,
+        embed_dim: params.num_features,
+        scale: params.scale,
+        ..swinir_helpers::get_config(state_dict)
+    }
+}
+
+fn hat_config(state_dict: &HashMap<String, Tensor>, params: &Params) -> HATConfig {
+    HATConfig {
+        in_nc: params.in_nc,
+        out_nc: params.out_nc,
+        embed_dim: params.num_features,
+        scale: params.scale,
+        ..hat_helpers::get_config(state_dict)
+    }
+}
+
+fn dat_config(state_dict: &HashMap<String, Tensor>, params: &Params) -> DATConfig {
+    DATConfig {
+        in_nc: params.in_nc,
+        out_nc: params.out_nc,
+        embed_dim: params.num_features,
+        scale: params.scale,
+        ..dat_helpers::get_config(state_dict)
+    }
+}
+
+fn srformer_config(state_dict: &HashMap<String, Tensor>, params: &Params) -> SwinIRConfig {
+    SwinIRConfig {
+        in_nc: params.in_nc,
+        out_nc: params.out_nc,
+        embed_dim: params.num_features,
+        scale: params.scale,
+        ..srformer_helpers::get_config(state_dict)
+    }
+}
+
+fn omnisr_config(state_dict: &HashMap<String, Tensor>, params: &Params) -> OmniSRConfig {
+    OmniSRConfig {
+        in_nc: params.in_nc,
+        out_nc: params.out_nc,
+        num_feat: params.num_features,
+        res_num: params.num_blocks,
+        scale: params.scale,
+        ..omnisr_helpers::get_config(state_dict)
+    }
+}
+
+impl Upscaler for old_arch::RRDBNet {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        nn::Module::forward(self, xs)
+    }
+
+    fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        self.forward_profiled(xs, profiler)
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        self.convs_mut()
+    }
+}
+
+impl Upscaler for new_arch::RRDBNet {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        nn::Module::forward(self, xs)
+    }
+
+    fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        self.forward_profiled(xs, profiler)
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        self.convs_mut()
+    }
+}
+
+impl Upscaler for compact::SRVGGNetCompact {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        nn::Module::forward(self, xs)
+    }
+
+    fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        self.forward_profiled(xs, profiler)
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        self.convs_mut()
+    }
+}
+
+impl Upscaler for span::SPAN {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        nn::Module::forward(self, xs)
+    }
+
+    fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        self.forward_profiled(xs, profiler)
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        self.convs_mut()
+    }
+}
+
+impl Upscaler for swinir::SwinIR {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        nn::Module::forward(self, xs)
+    }
+
+    fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        self.forward_profiled(xs, profiler)
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        self.convs_mut()
+    }
+}
+
+impl Upscaler for hat::HAT {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        nn::Module::forward(self, xs)
+    }
+
+    fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        self.forward_profiled(xs, profiler)
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        self.convs_mut()
+    }
+}
+
+impl Upscaler for dat::DAT {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        nn::Module::forward(self, xs)
+    }
+
+    fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        self.forward_profiled(xs, profiler)
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        self.convs_mut()
+    }
+}
+
+impl Upscaler for srformer::SRFormer {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        nn::Module::forward(self, xs)
+    }
+
+    fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        self.forward_profiled(xs, profiler)
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        self.convs_mut()
+    }
+}
+
+impl Upscaler for cugan::UpCunet {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        nn::Module::forward(self, xs)
+    }
+
+    fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        self.forward_profiled(xs, profiler)
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        self.convs_mut()
+    }
+}
+
+impl Upscaler for omnisr::OmniSR {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        nn::Module::forward(self, xs)
+    }
+
+    fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        self.forward_profiled(xs, profiler)
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        self.convs_mut()
+    }
+}
+
+impl Upscaler for safmn::SAFMN {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        nn::Module::forward(self, xs)
+    }
+
+    fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        self.forward_profiled(xs, profiler)
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        self.convs_mut()
+    }
+}
+
+impl Upscaler for spsr::SPSRNet {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        nn::Module::forward(self, xs)
+    }
+
+    fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        self.forward_profiled(xs, profiler)
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        self.convs_mut()
+    }
+}
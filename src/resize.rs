@@ -0,0 +1,151 @@
+use candle_core::{Result, Tensor};
+use clap::ValueEnum;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum ResizeFilter {
+    /// Lanczos with a 3-lobe window
+    Lanczos,
+    /// Bicubic (Keys, a = -0.5)
+    Bicubic,
+    /// Bilinear (triangle filter)
+    Bilinear,
+    /// Area averaging (box filter)
+    Area,
+}
+
+impl ResizeFilter {
+    fn support(&self) -> f64 {
+        match self {
+            ResizeFilter::Lanczos => 3.,
+            ResizeFilter::Bicubic => 2.,
+            ResizeFilter::Bilinear => 1.,
+            ResizeFilter::Area => 0.5,
+        }
+    }
+
+    fn eval(&self, x: f64) -> f64 {
+        match self {
+            ResizeFilter::Lanczos => {
+                if x.abs() < 3. {
+                    sinc(x) * sinc(x / 3.)
+                } else {
+                    0.
+                }
+            }
+            ResizeFilter::Bicubic => {
+                let a = -0.5;
+                let x = x.abs();
+                if x < 1. {
+                    ((a + 2.) * x - (a + 3.)) * x * x + 1.
+                } else if x < 2. {
+                    (((x - 5.) * x + 8.) * x - 4.) * a
+                } else {
+                    0.
+                }
+            }
+            ResizeFilter::Bilinear => f64::max(1. - x.abs(), 0.),
+            ResizeFilter::Area => {
+                // Half open like Pillow's box, so that a pixel centre falling on
+                // an input pixel boundary still takes one of them
+                if x > -0.5 && x <= 0.5 {
+                    1.
+                } else {
+                    0.
+                }
+            }
+        }
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0. {
+        1.
+    } else {
+        let x = x * std::f64::consts::PI;
+        x.sin() / x
+    }
+}
+
+/// The requested size of the final output image.
+#[derive(Debug, Clone, Copy)]
+pub enum OutputSize {
+    /// Multiply the input size by a factor
+    Scale(f64),
+    /// Exact width and height, ignoring the aspect ratio
+    Exact(usize, usize),
+    /// Largest size that fits inside the box while keeping the aspect ratio
+    Fit(usize, usize),
+    /// Fixed width, height follows the aspect ratio
+    Width(usize),
+    /// Fixed height, width follows the aspect ratio
+    Height(usize),
+}
+
+impl OutputSize {
+    /// Target (width, height) for an input of the given size.
+    pub fn target(&self, width: usize, height: usize) -> (usize, usize) {
+        let (w, h) = (width as f64, height as f64);
+        let (tw, th) = match *self {
+            OutputSize::Scale(s) => (w * s, h * s),
+            OutputSize::Exact(tw, th) => (tw as f64, th as f64),
+            OutputSize::Fit(tw, th) => {
+                let s = f64::min(tw as f64 / w, th as f64 / h);
+                (w * s, h * s)
+            }
+            OutputSize::Width(tw) => (tw as f64, h * tw as f64 / w),
+            OutputSize::Height(th) => (w * th as f64 / h, th as f64),
+        };
+        (
+            usize::max(tw.round() as usize, 1),
+            usize::max(th.round() as usize, 1),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Resize {
+    pub size: OutputSize,
+    pub filter: ResizeFilter,
+}
+
+// Row i holds the contribution of every input pixel to output pixel i.
+// Follows Pillow's convolution resampling, widening the filter when
+// downscaling so it also acts as an antialiasing filter.
+fn weights(in_size: usize, out_size: usize, filter: ResizeFilter) -> Vec<f32> {
+    let scale = in_size as f64 / out_size as f64;
+    let filter_scale = f64::max(scale, 1.);
+    let support = filter.support() * filter_scale;
+    let mut weights = vec![0f32; out_size * in_size];
+    for i in 0..out_size {
+        let center = (i as f64 + 0.5) * scale;
+        let min = (center - support + 0.5).floor().max(0.) as usize;
+        let max = usize::min((center + support + 0.5).floor() as usize, in_size);
+        let row: Vec<f64> = (min..max)
+            .map(|j| filter.eval((j as f64 - center + 0.5) / filter_scale))
+            .collect();
+        let total: f64 = row.iter().sum();
+        for (j, w) in (min..max).zip(row) {
+            weights[i * in_size + j] = if total == 0. { 0. } else { (w / total) as f32 };
+        }
+    }
+    weights
+}
+
+/// Resize a (batch, channels, height, width) tensor on its own device.
+///
+/// The filter is separable, so the resize is two matrix multiplications with
+/// precomputed weight matrices and never leaves the device.
+pub fn resize(xs: &Tensor, height: usize, width: usize, filter: ResizeFilter) -> Result<Tensor> {
+    let (_b_size, _channels, h, w) = xs.dims4()?;
+    if h == height && w == width {
+        return Ok(xs.clone());
+    }
+    let device = xs.device();
+    let weights_h =
+        Tensor::from_vec(weights(h, height, filter), (height, h), device)?.to_dtype(xs.dtype())?;
+    let weights_w = Tensor::from_vec(weights(w, width, filter), (width, w), device)?
+        .to_dtype(xs.dtype())?
+        .t()?;
+    let out = xs.contiguous()?.broadcast_matmul(&weights_w)?;
+    weights_h.broadcast_matmul(&out)
+}
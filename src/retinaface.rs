@@ -0,0 +1,433 @@
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_nn as nn;
+
+use crate::conv::{conv2d, conv2d_no_bias, Conv2d};
+use crate::layers::{batch_norm, BatchNorm};
+
+// RetinaFace as facexlib runs it for GFPGAN: a ResNet-50 backbone, a feature
+// pyramid over its last three stages, an SSH context module on every level
+// and 1x1 conv heads predicting, for two anchors per pixel, a face score, a
+// box and five landmarks relative to the anchor. Every conv besides the
+// heads is followed by a batch norm.
+
+/// Channels of the BGR mean facexlib subtracts from its 0-255 inputs
+const MEAN_BGR: [f32; 3] = [104., 117., 123.];
+/// Side of the anchors of every pyramid level, in pixels
+const MIN_SIZES: [[f32; 2]; 3] = [[16., 32.], [64., 128.], [256., 512.]];
+/// Strides of the pyramid levels
+const STEPS: [usize; 3] = [8, 16, 32];
+/// Scales of the offsets of the centres and of the log sizes
+const VARIANCE: [f32; 2] = [0.1, 0.2];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetinaFaceConfig {
+    /// Channels of the stem of the ResNet, its stages have 1, 2, 4 and 8
+    /// times as many in their bottlenecks and 4 times that in their outputs
+    pub width: usize,
+    /// Bottlenecks of every stage of the ResNet
+    pub layers: [usize; 4],
+    /// Channels of the feature pyramid
+    pub out_channels: usize,
+}
+
+impl Default for RetinaFaceConfig {
+    /// `detection_Resnet50_Final`, the detector of GFPGAN
+    fn default() -> Self {
+        Self {
+            width: 64,
+            layers: [3, 4, 6, 3],
+            out_channels: 256,
+        }
+    }
+}
+
+/// A face found by the detector, in pixels of the input image
+#[derive(Debug, Clone, PartialEq)]
+pub struct Face {
+    /// Left, top, right and bottom
+    pub bbox: [f32; 4],
+    pub score: f32,
+    /// Eyes, nose and the corners of the mouth, from the left of the image
+    pub landmarks: [[f32; 2]; 5],
+}
+
+/// Conv without a bias followed by a batch norm and, unless it's None, an
+/// activation
+#[derive(Debug)]
+struct ConvBn {
+    conv: Conv2d,
+    bn: BatchNorm,
+    activation: Option<nn::Activation>,
+}
+
+impl ConvBn {
+    fn load(
+        vb_conv: nn::VarBuilder,
+        vb_bn: nn::VarBuilder,
+        c_in: usize,
+        c_out: usize,
+        k: usize,
+        stride: usize,
+        activation: Option<nn::Activation>,
+    ) -> Result<Self> {
+        let config = nn::Conv2dConfig {
+            padding: k / 2,
+            stride,
+            dilation: 1,
+            groups: 1,
+        };
+        Ok(Self {
+            conv: conv2d_no_bias(c_in, c_out, k, config, vb_conv)?,
+            bn: batch_norm(c_out, vb_bn)?,
+            activation,
+        })
+    }
+
+    /// The `conv_bn` Sequentials of RetinaFace, a conv at `.0` and its batch
+    /// norm at `.1`
+    fn load_seq(
+        vb: nn::VarBuilder,
+        c_in: usize,
+        c_out: usize,
+        k: usize,
+        activation: Option<nn::Activation>,
+    ) -> Result<Self> {
+        Self::load(vb.pp("0"), vb.pp("1"), c_in, c_out, k, 1, activation)
+    }
+}
+
+impl Module for ConvBn {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = self.bn.forward(&self.conv.forward(xs)?)?;
+        match &self.activation {
+            Some(activation) => activation.forward(&xs),
+            None => Ok(xs),
+        }
+    }
+}
+
+/// torchvision's ResNet bottleneck, with the stride on its 3x3 conv
+#[derive(Debug)]
+struct Bottleneck {
+    conv1: ConvBn,
+    conv2: ConvBn,
+    conv3: ConvBn,
+    downsample: Option<ConvBn>,
+}
+
+impl Bottleneck {
+    fn load(vb: nn::VarBuilder, c_in: usize, planes: usize, stride: usize) -> Result<Self> {
+        let relu = Some(nn::Activation::Relu);
+        let c_out = planes * 4;
+        let downsample = match stride != 1 || c_in != c_out {
+            true => Some(ConvBn::load(
+                vb.pp("downsample.0"),
+                vb.pp("downsample.1"),
+                c_in,
+                c_out,
+                1,
+                stride,
+                None,
+            )?),
+            false => None,
+        };
+        Ok(Self {
+            conv1: ConvBn::load(vb.pp("conv1"), vb.pp("bn1"), c_in, planes, 1, 1, relu)?,
+            conv2: ConvBn::load(
+                vb.pp("conv2"),
+                vb.pp("bn2"),
+                planes,
+                planes,
+                3,
+                stride,
+                relu,
+            )?,
+            conv3: ConvBn::load(vb.pp("conv3"), vb.pp("bn3"), planes, c_out, 1, 1, None)?,
+            downsample,
+        })
+    }
+}
+
+impl Module for Bottleneck {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let out = self
+            .conv3
+            .forward(&self.conv2.forward(&self.conv1.forward(xs)?)?)?;
+        let identity = match &self.downsample {
+            Some(downsample) => downsample.forward(xs)?,
+            None => xs.clone(),
+        };
+        (out + identity)?.relu()
+    }
+}
+
+/// Single stage headless face detector context module: 3x3, 5x5 and 7x7
+/// receptive fields made of stacked 3x3 convs, concatenated
+#[derive(Debug)]
+struct SSH {
+    conv3x3: ConvBn,
+    conv5x5_1: ConvBn,
+    conv5x5_2: ConvBn,
+    conv7x7_2: ConvBn,
+    conv7x7_3: ConvBn,
+}
+
+impl SSH {
+    fn load(vb: nn::VarBuilder, c: usize, leaky: nn::Activation) -> Result<Self> {
+        Ok(Self {
+            conv3x3: ConvBn::load_seq(vb.pp("conv3X3"), c, c / 2, 3, None)?,
+            conv5x5_1: ConvBn::load_seq(vb.pp("conv5X5_1"), c, c / 4, 3, Some(leaky))?,
+            conv5x5_2: ConvBn::load_seq(vb.pp("conv5X5_2"), c / 4, c / 4, 3, None)?,
+            conv7x7_2: ConvBn::load_seq(vb.pp("conv7X7_2"), c / 4, c / 4, 3, Some(leaky))?,
+            conv7x7_3: ConvBn::load_seq(vb.pp("conv7x7_3"), c / 4, c / 4, 3, None)?,
+        })
+    }
+}
+
+impl Module for SSH {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let conv3x3 = self.conv3x3.forward(xs)?;
+        let conv5x5_1 = self.conv5x5_1.forward(xs)?;
+        let conv5x5 = self.conv5x5_2.forward(&conv5x5_1)?;
+        let conv7x7 = self
+            .conv7x7_3
+            .forward(&self.conv7x7_2.forward(&conv5x5_1)?)?;
+        Tensor::cat(&[conv3x3, conv5x5, conv7x7], 1)?.relu()
+    }
+}
+
+#[derive(Debug)]
+pub struct RetinaFace {
+    stem: ConvBn,
+    stages: Vec<Vec<Bottleneck>>,
+    fpn_outputs: Vec<ConvBn>,
+    fpn_merges: Vec<ConvBn>,
+    ssh: Vec<SSH>,
+    class_heads: Vec<Conv2d>,
+    bbox_heads: Vec<Conv2d>,
+    landmark_heads: Vec<Conv2d>,
+}
+
+impl RetinaFace {
+    pub fn load(vb: nn::VarBuilder, config: &RetinaFaceConfig) -> Result<Self> {
+        let body = vb.pp("body");
+        let w = config.width;
+        let relu = Some(nn::Activation::Relu);
+        let stem = ConvBn::load(body.pp("conv1"), body.pp("bn1"), 3, w, 7, 2, relu)?;
+        let mut stages = vec![];
+        let mut c_in = w;
+        for (i, &num_blocks) in config.layers.iter().enumerate() {
+            let planes = w << i;
+            let mut blocks = vec![];
+            for j in 0..num_blocks {
+                let stride = if i > 0 && j == 0 { 2 } else { 1 };
+                blocks.push(Bottleneck::load(
+                    body.pp(format!("layer{}.{j}", i + 1)),
+                    c_in,
+                    planes,
+                    stride,
+                )?);
+                c_in = planes * 4;
+            }
+            stages.push(blocks);
+        }
+
+        // The narrow MobileNet variants use leaky ReLUs, the ResNet ones ReLUs
+        let c = config.out_channels;
+        let leaky = nn::Activation::LeakyRelu(if c <= 64 { 0.1 } else { 0. });
+        let mut fpn_outputs = vec![];
+        let mut ssh = vec![];
+        let mut class_heads = vec![];
+        let mut bbox_heads = vec![];
+        let mut landmark_heads = vec![];
+        let head = |name: &str, i: usize, k: usize| {
+            let config = nn::Conv2dConfig {
+                padding: 0,
+                stride: 1,
+                dilation: 1,
+                groups: 1,
+            };
+            let anchors = MIN_SIZES[i].len();
+            conv2d(
+                c,
+                anchors * k,
+                1,
+                config,
+                vb.pp(format!("{name}.{i}.conv1x1")),
+            )
+        };
+        for i in 0..3 {
+            let c_stage = (w << (i + 1)) * 4;
+            fpn_outputs.push(ConvBn::load_seq(
+                vb.pp(format!("fpn.output{}", i + 1)),
+                c_stage,
+                c,
+                1,
+                Some(leaky),
+            )?);
+            ssh.push(SSH::load(vb.pp(format!("ssh{}", i + 1)), c, leaky)?);
+            class_heads.push(head("ClassHead", i, 2)?);
+            bbox_heads.push(head("BboxHead", i, 4)?);
+            landmark_heads.push(head("LandmarkHead", i, 10)?);
+        }
+        let fpn_merges = vec![
+            ConvBn::load_seq(vb.pp("fpn.merge1"), c, c, 3, Some(leaky))?,
+            ConvBn::load_seq(vb.pp("fpn.merge2"), c, c, 3, Some(leaky))?,
+        ];
+
+        Ok(Self {
+            stem,
+            stages,
+            fpn_outputs,
+            fpn_merges,
+            ssh,
+            class_heads,
+            bbox_heads,
+            landmark_heads,
+        })
+    }
+
+    /// Box offsets (B, anchors, 4), face probabilities (B, anchors, 2) and
+    /// landmark offsets (B, anchors, 10) for a BGR input with the mean
+    /// subtracted, the anchors in the order of `priors`
+    pub fn forward_raw(&self, xs: &Tensor) -> Result<(Tensor, Tensor, Tensor)> {
+        // Zero padding for the max pool is the same as -inf after a ReLU
+        let xs = self.stem.forward(xs)?;
+        let mut xs = xs
+            .pad_with_zeros(2, 1, 1)?
+            .pad_with_zeros(3, 1, 1)?
+            .max_pool2d_with_stride(3, 2)?;
+        let mut features = vec![];
+        for (i, stage) in self.stages.iter().enumerate() {
+            for block in stage {
+                xs = block.forward(&xs)?;
+            }
+            if i > 0 {
+                features.push(xs.clone());
+            }
+        }
+
+        let out3 = self.fpn_outputs[2].forward(&features[2])?;
+        let out2 = self.fpn_outputs[1].forward(&features[1])?;
+        let (_, _, h2, w2) = out2.dims4()?;
+        let out2 = (out2 + out3.upsample_nearest2d(h2, w2)?)?;
+        let out2 = self.fpn_merges[1].forward(&out2)?;
+        let out1 = self.fpn_outputs[0].forward(&features[0])?;
+        let (_, _, h1, w1) = out1.dims4()?;
+        let out1 = (out1 + out2.upsample_nearest2d(h1, w1)?)?;
+        let out1 = self.fpn_merges[0].forward(&out1)?;
+
+        let mut loc = vec![];
+        let mut conf = vec![];
+        let mut landmarks = vec![];
+        for (i, feature) in [out1, out2, out3].iter().enumerate() {
+            let feature = self.ssh[i].forward(feature)?;
+            let head = |conv: &Conv2d, k: usize| {
+                let out = conv.forward(&feature)?.permute((0, 2, 3, 1))?;
+                let (b, h, w, c) = out.dims4()?;
+                out.contiguous()?.reshape((b, h * w * c / k, k))
+            };
+            loc.push(head(&self.bbox_heads[i], 4)?);
+            conf.push(head(&self.class_heads[i], 2)?);
+            landmarks.push(head(&self.landmark_heads[i], 10)?);
+        }
+        let conf = nn::ops::softmax(&Tensor::cat(&conf, 1)?, 2)?;
+        Ok((Tensor::cat(&loc, 1)?, conf, Tensor::cat(&landmarks, 1)?))
+    }
+
+    /// Faces of an RGB image (1, 3, H, W) in [0, 1] with a score over
+    /// `conf_threshold`, best first, without those overlapping a better one
+    /// by more than `nms_threshold`
+    pub fn detect(
+        &self,
+        img: &Tensor,
+        conf_threshold: f32,
+        nms_threshold: f32,
+    ) -> Result<Vec<Face>> {
+        let (_, _, h, w) = img.dims4()?;
+        let device = img.device();
+        let mean = Tensor::new(&MEAN_BGR, device)?.reshape((1, 3, 1, 1))?;
+        let bgr = Tensor::new(&[2u32, 1, 0], device)?;
+        let xs = (img.to_dtype(DType::F32)?.index_select(&bgr, 1)? * 255.)?;
+        let xs = xs.broadcast_sub(&mean)?;
+
+        let (loc, conf, landmarks) = self.forward_raw(&xs)?;
+        let loc = loc.squeeze(0)?.to_device(&Device::Cpu)?.to_vec2::<f32>()?;
+        let conf = conf.squeeze(0)?.to_device(&Device::Cpu)?.to_vec2::<f32>()?;
+        let landmarks = landmarks
+            .squeeze(0)?
+            .to_device(&Device::Cpu)?
+            .to_vec2::<f32>()?;
+
+        let (wf, hf) = (w as f32, h as f32);
+        let mut faces = vec![];
+        for (i, prior) in priors(h, w).iter().enumerate() {
+            let score = conf[i][1];
+            if score <= conf_threshold {
+                continue;
+            }
+            let decode = |dx: f32, dy: f32| {
+                [
+                    (prior[0] + dx * VARIANCE[0] * prior[2]) * wf,
+                    (prior[1] + dy * VARIANCE[0] * prior[3]) * hf,
+                ]
+            };
+            let [cx, cy] = decode(loc[i][0], loc[i][1]);
+            let bw = prior[2] * (loc[i][2] * VARIANCE[1]).exp() * wf;
+            let bh = prior[3] * (loc[i][3] * VARIANCE[1]).exp() * hf;
+            let mut points = [[0f32; 2]; 5];
+            for (k, point) in points.iter_mut().enumerate() {
+                *point = decode(landmarks[i][2 * k], landmarks[i][2 * k + 1]);
+            }
+            faces.push(Face {
+                bbox: [cx - bw / 2., cy - bh / 2., cx + bw / 2., cy + bh / 2.],
+                score,
+                landmarks: points,
+            });
+        }
+        faces.sort_by(|a, b| b.score.total_cmp(&a.score));
+        Ok(nms(faces, nms_threshold))
+    }
+}
+
+/// Anchors (centre x, centre y, width, height) relative to the image size,
+/// for every pixel of every pyramid level, row by row
+pub fn priors(h: usize, w: usize) -> Vec<[f32; 4]> {
+    let mut anchors = vec![];
+    for (min_sizes, step) in MIN_SIZES.iter().zip(STEPS) {
+        for i in 0..h.div_ceil(step) {
+            for j in 0..w.div_ceil(step) {
+                for min_size in min_sizes {
+                    anchors.push([
+                        (j as f32 + 0.5) * step as f32 / w as f32,
+                        (i as f32 + 0.5) * step as f32 / h as f32,
+                        min_size / w as f32,
+                        min_size / h as f32,
+                    ]);
+                }
+            }
+        }
+    }
+    anchors
+}
+
+/// Greedy non-maximum suppression of faces sorted by score, with the pixel
+/// inclusive areas of facexlib's `py_cpu_nms`
+fn nms(faces: Vec<Face>, threshold: f32) -> Vec<Face> {
+    let area = |b: &[f32; 4]| (b[2] - b[0] + 1.) * (b[3] - b[1] + 1.);
+    let mut keep: Vec<Face> = vec![];
+    for face in faces {
+        let a = &face.bbox;
+        let overlaps = keep.iter().any(|kept| {
+            let b = &kept.bbox;
+            let iw = (a[2].min(b[2]) - a[0].max(b[0]) + 1.).max(0.);
+            let ih = (a[3].min(b[3]) - a[1].max(b[1]) + 1.).max(0.);
+            let inter = iw * ih;
+            inter / (area(a) + area(b) - inter) > threshold
+        });
+        if !overlaps {
+            keep.push(face);
+        }
+    }
+    keep
+}
@@ -0,0 +1,37 @@
+use std::collections::HashMap;
+
+use candle_core::Tensor;
+
+// The ResNet-50 detector of facexlib has fixed sizes, reading them from the
+// shapes lets the same code load narrower test models
+
+pub fn get_width(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("body.conv1.weight") {
+        Some(x) => x.shape().dims()[0],
+        None => 64,
+    };
+}
+
+/// Number of bottlenecks of every stage of the ResNet
+pub fn get_layers(state_dict: &HashMap<String, Tensor>) -> [usize; 4] {
+    let mut layers = [3, 4, 6, 3];
+    for (i, layer) in layers.iter_mut().enumerate() {
+        let prefix = format!("body.layer{}.", i + 1);
+        let highest_block_num = state_dict
+            .keys()
+            .filter_map(|x| x.strip_prefix(&prefix))
+            .filter_map(|x| x.split('.').next()?.parse::<usize>().ok())
+            .max();
+        if let Some(x) = highest_block_num {
+            *layer = x + 1;
+        }
+    }
+    return layers;
+}
+
+pub fn get_out_channels(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("fpn.output1.0.weight") {
+        Some(x) => x.shape().dims()[0],
+        None => 256,
+    };
+}
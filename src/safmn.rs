@@ -0,0 +1,204 @@
+use candle_core::{Module, Result, Tensor};
+use candle_nn as nn;
+
+use crate::conv::{conv2d, Conv2d};
+use crate::layers::{layer_norm_2d, LayerNorm2d};
+use crate::profile::Profiler;
+
+// Spatially-adaptive feature modulation network: a lightweight conv network
+// whose blocks weight the features with maps computed from them at four
+// resolutions, followed by a small feed-forward conv.
+
+/// Number of resolutions of the feature modulation, each of them gets a
+/// quarter of the channels
+const N_LEVELS: usize = 4;
+const LAYER_NORM_EPS: f64 = 1e-6;
+
+fn conv(c_in: usize, c_out: usize, k: usize, groups: usize, vb: nn::VarBuilder) -> Result<Conv2d> {
+    let config = nn::Conv2dConfig {
+        padding: k / 2,
+        stride: 1,
+        dilation: 1,
+        groups,
+    };
+    conv2d(c_in, c_out, k, config, vb)
+}
+
+/// `F.adaptive_max_pool2d`: output pixel i covers the input pixels from
+/// floor(i * size / out) to ceil((i + 1) * size / out), which is a plain max
+/// pool when the size is a multiple of the output one
+fn adaptive_max_pool2d(xs: &Tensor, (oh, ow): (usize, usize)) -> Result<Tensor> {
+    let (_, _, h, w) = xs.dims4()?;
+    if h % oh == 0 && w % ow == 0 {
+        return xs.max_pool2d_with_stride((h / oh, w / ow), (h / oh, w / ow));
+    }
+    // The max over a rectangle is the max over its rows of the max over its
+    // columns, so the two dimensions are pooled one after the other
+    let pool = |xs: &Tensor, dim: usize, size: usize, out: usize| -> Result<Tensor> {
+        let cells = (0..out)
+            .map(|i| {
+                let start = i * size / out;
+                let end = ((i + 1) * size).div_ceil(out);
+                xs.narrow(dim, start, end - start)?.max_keepdim(dim)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::cat(&cells, dim)
+    };
+    pool(&pool(xs, 2, h, oh)?, 3, w, ow)
+}
+
+/// Spatially-adaptive feature modulation: every quarter of the channels goes
+/// through a depthwise conv at 1, 1/2, 1/4 and 1/8 of the resolution, and
+/// their aggregation weights the input
+#[derive(Debug)]
+struct SAFM {
+    mfr: Vec<Conv2d>,
+    aggr: Conv2d,
+}
+
+impl SAFM {
+    fn load(vb: nn::VarBuilder, dim: usize) -> Result<Self> {
+        let chunk_dim = dim / N_LEVELS;
+        let mut mfr = vec![];
+        for i in 0..N_LEVELS {
+            mfr.push(conv(
+                chunk_dim,
+                chunk_dim,
+                3,
+                chunk_dim,
+                vb.pp(format!("mfr.{i}")),
+            )?);
+        }
+        Ok(Self {
+            mfr,
+            aggr: conv(dim, dim, 1, 1, vb.pp("aggr"))?,
+        })
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs: Vec<&mut Conv2d> = self.mfr.iter_mut().collect();
+        convs.push(&mut self.aggr);
+        convs
+    }
+}
+
+impl Module for SAFM {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (_, _, h, w) = xs.dims4()?;
+        let mut out = vec![];
+        for (i, (chunk, mfr)) in xs.chunk(N_LEVELS, 1)?.iter().zip(&self.mfr).enumerate() {
+            if i == 0 {
+                out.push(mfr.forward(chunk)?);
+            } else {
+                let s = adaptive_max_pool2d(chunk, (h >> i, w >> i))?;
+                out.push(mfr.forward(&s)?.upsample_nearest2d(h, w)?);
+            }
+        }
+        let out = self.aggr.forward(&Tensor::cat(&out, 1)?)?;
+        out.gelu_erf()?.mul(xs)
+    }
+}
+
+/// Feature modulation and convolutional channel mixer, each after a layer
+/// norm and with a residual connection
+#[derive(Debug)]
+struct AttBlock {
+    norm1: LayerNorm2d,
+    norm2: LayerNorm2d,
+    safm: SAFM,
+    ccm: [Conv2d; 2],
+}
+
+impl AttBlock {
+    fn load(vb: nn::VarBuilder, dim: usize, hidden_dim: usize) -> Result<Self> {
+        Ok(Self {
+            norm1: layer_norm_2d(dim, LAYER_NORM_EPS, vb.pp("norm1"))?,
+            norm2: layer_norm_2d(dim, LAYER_NORM_EPS, vb.pp("norm2"))?,
+            safm: SAFM::load(vb.pp("safm"), dim)?,
+            ccm: [
+                conv(dim, hidden_dim, 3, 1, vb.pp("ccm.ccm.0"))?,
+                conv(hidden_dim, dim, 1, 1, vb.pp("ccm.ccm.2"))?,
+            ],
+        })
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = self.safm.convs_mut();
+        convs.extend(self.ccm.iter_mut());
+        convs
+    }
+}
+
+impl Module for AttBlock {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = (self.safm.forward(&self.norm1.forward(xs)?)? + xs)?;
+        let out = self.ccm[0].forward(&self.norm2.forward(&xs)?)?.gelu_erf()?;
+        self.ccm[1].forward(&out)? + xs
+    }
+}
+
+#[derive(Debug)]
+pub struct SAFMN {
+    to_feat: Conv2d,
+    feats: Vec<AttBlock>,
+    to_img: Conv2d,
+    upscale: usize,
+}
+
+impl SAFMN {
+    pub fn load(
+        vb: nn::VarBuilder,
+        num_in_ch: usize,
+        num_out_ch: usize,
+        dim: usize,
+        n_blocks: usize,
+        ffn_scale: f64,
+        upscale: usize,
+    ) -> Result<Self> {
+        let hidden_dim = (dim as f64 * ffn_scale) as usize;
+        let mut feats = vec![];
+        for i in 0..n_blocks {
+            feats.push(AttBlock::load(
+                vb.pp(format!("feats.{i}")),
+                dim,
+                hidden_dim,
+            )?);
+        }
+        Ok(Self {
+            to_feat: conv(num_in_ch, dim, 3, 1, vb.pp("to_feat"))?,
+            feats,
+            to_img: conv(dim, num_out_ch * upscale * upscale, 3, 1, vb.pp("to_img.0"))?,
+            upscale,
+        })
+    }
+
+    /// Every conv of the model, for `conv::quantize`
+    pub fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = vec![&mut self.to_feat];
+        for block in self.feats.iter_mut() {
+            convs.extend(block.convs_mut());
+        }
+        convs.push(&mut self.to_img);
+        convs
+    }
+
+    /// `forward`, recording the time of every module in `profiler`
+    pub fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        let feat = self.to_feat.forward(xs)?;
+        profiler.record("to_feat", &feat)?;
+        let mut out = feat.clone();
+        for (i, block) in self.feats.iter().enumerate() {
+            out = block.forward(&out)?;
+            profiler.record(&format!("feats.{i}"), &out)?;
+        }
+        let out = nn::ops::pixel_shuffle(&self.to_img.forward(&(out + feat)?)?, self.upscale)?;
+        profiler.record("to_img", &out)?;
+        Ok(out)
+    }
+}
+
+impl nn::Module for SAFMN {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.forward_profiled(xs, &mut Profiler::disabled())
+    }
+}
@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+
+use candle_core::Tensor;
+
+// SAFMN models are a stack of identical blocks between two convs, so only the
+// channel counts, the number of blocks and the scale vary
+
+pub fn get_in_nc(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("to_feat.weight") {
+        Some(x) => x.shape().dims()[1],
+        None => 3,
+    };
+}
+
+pub fn get_dim(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("to_feat.weight") {
+        Some(x) => x.shape().dims()[0],
+        None => 36,
+    };
+}
+
+pub fn get_n_blocks(state_dict: &HashMap<String, Tensor>) -> usize {
+    let highest_block_num = state_dict
+        .keys()
+        .filter(|x| x.starts_with("feats."))
+        .filter_map(|x| x.split('.').nth(1)?.parse::<usize>().ok())
+        .max();
+    return match highest_block_num {
+        Some(x) => x + 1,
+        None => 8,
+    };
+}
+
+/// Hidden channels of the channel mixers, as a multiple of the features
+pub fn get_ffn_scale(state_dict: &HashMap<String, Tensor>) -> f64 {
+    return match state_dict.get("feats.0.ccm.ccm.0.weight") {
+        Some(x) => x.shape().dims()[0] as f64 / get_dim(state_dict) as f64,
+        None => 2.,
+    };
+}
+
+pub fn get_scale(state_dict: &HashMap<String, Tensor>) -> usize {
+    // Like SPAN, the last conv outputs in_nc * scale * scale channels for the
+    // pixel shuffle
+    return match state_dict.get("to_img.0.weight") {
+        Some(x) => {
+            let channels = x.shape().dims()[0] / get_in_nc(state_dict);
+            (channels as f64).sqrt().round() as usize
+        }
+        None => 4,
+    };
+}
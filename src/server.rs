@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+
+use candle_core::{DType, Device};
+use clap::ValueEnum;
+use image::{DynamicImage, ImageFormat, RgbImage};
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::info::descriptor_json;
+use crate::{load_model, output_size, process, LoadedModel, ModelArgs, Precision, ProcessOptions};
+use esrgan_candle_rs::color_fix::ColorFix;
+use esrgan_candle_rs::resize::{OutputSize, Resize, ResizeFilter};
+
+/// Serve models over HTTP
+#[derive(clap::Args, Debug)]
+pub struct ServeArgs {
+    /// Model to serve, as `name=path` or just a path, in which case the file
+    /// name is used. Can be given several times.
+    #[arg(short, long, required = true)]
+    model: Vec<String>,
+
+    /// Device to run the models on
+    /// -1 for CPU, 0 for GPU 0, 1 for GPU 1, etc.
+    #[arg(short, long, default_value = "-1")]
+    device: i32,
+
+    #[command(flatten)]
+    model_args: ModelArgs,
+
+    /// Precision the models run in. Outputs with NaN or Inf values, which f16
+    /// gives on some models, are computed again in f32.
+    #[arg(long, value_enum, default_value = "f32")]
+    dtype: Precision,
+
+    /// Precision the weights are kept in, --dtype by default. The convs cast
+    /// them to --dtype as they run.
+    #[arg(long, value_enum)]
+    weight_dtype: Option<Precision>,
+
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+
+    /// Port to listen on
+    #[arg(short, long, default_value = "8080")]
+    port: u16,
+
+    /// Number of requests that can wait for the device before new ones are rejected
+    #[arg(long, default_value = "16")]
+    queue_size: usize,
+
+    /// Number of threads accepting connections
+    #[arg(long, default_value = "4")]
+    threads: usize,
+
+    /// Largest request body accepted, in megabytes
+    #[arg(long, default_value = "50")]
+    max_body_mb: usize,
+
+    /// Largest input image accepted, in megapixels
+    #[arg(long, default_value = "16")]
+    max_input_megapixels: usize,
+
+    /// Largest image a request can make the server allocate, the upscaled
+    /// image or the resized output, in megapixels
+    #[arg(long, default_value = "64")]
+    max_output_megapixels: usize,
+}
+
+/// What a request is allowed to make the server read and allocate, in bytes
+/// and pixels
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    body: usize,
+    input_pixels: usize,
+    output_pixels: usize,
+}
+
+struct NamedModel {
+    name: String,
+    model: LoadedModel,
+}
+
+/// An upscale request waiting for the device
+struct Job {
+    request: Request,
+    model: usize,
+    image: DynamicImage,
+    format: ImageFormat,
+    options: ProcessOptions<'static>,
+}
+
+/// Load every model, then serve requests until the process is killed.
+///
+/// Connections are handled by a few threads that parse and validate requests,
+/// while this thread owns the device and runs the upscales one at a time.
+pub fn serve(args: &ServeArgs) {
+    let device = match args.device {
+        -1 => Device::Cpu,
+        _ => Device::new_cuda(args.device as usize).unwrap(),
+    };
+
+    let dtype = args.dtype.compute_dtype(&device);
+    let weight_dtype = args.weight_dtype.unwrap_or(args.dtype).dtype();
+    let models: Vec<NamedModel> = args
+        .model
+        .iter()
+        .map(|spec| {
+            let (name, path) = match spec.split_once('=') {
+                Some((name, path)) => (name.to_string(), path),
+                None => (
+                    Path::new(spec)
+                        .file_stem()
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned(),
+                    spec.as_str(),
+                ),
+            };
+            let model = load_model(path, &args.model_args, &device, weight_dtype);
+            let descriptor = &model.descriptor;
+            println!(
+                "Loaded {} ({}, {}x, {} parameters)",
+                name, descriptor.arch, descriptor.scale, descriptor.params
+            );
+            NamedModel { name, model }
+        })
+        .collect();
+
+    let model_list = json!(models
+        .iter()
+        .map(|m| {
+            let mut entry = descriptor_json(&m.model.descriptor);
+            entry["name"] = json!(m.name);
+            entry
+        })
+        .collect::<Vec<_>>())
+    .to_string();
+    let names: Vec<String> = models.iter().map(|m| m.name.clone()).collect();
+    let scales: Vec<usize> = models.iter().map(|m| m.model.descriptor.scale).collect();
+    let limits = Limits {
+        body: args.max_body_mb * 1_000_000,
+        input_pixels: args.max_input_megapixels * 1_000_000,
+        output_pixels: args.max_output_megapixels * 1_000_000,
+    };
+
+    let server = Arc::new(Server::http((args.host.as_str(), args.port)).unwrap());
+    println!("Listening on http://{}:{}", args.host, args.port);
+
+    let (jobs, queue) = sync_channel::<Job>(args.queue_size);
+    for _ in 0..args.threads {
+        let server = server.clone();
+        let jobs = jobs.clone();
+        let model_list = model_list.clone();
+        let names = names.clone();
+        let scales = scales.clone();
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                handle(request, &jobs, &model_list, &names, &scales, limits, dtype);
+            }
+        });
+    }
+    drop(jobs);
+
+    run_jobs(queue, &models, &device);
+}
+
+fn run_jobs(queue: Receiver<Job>, models: &[NamedModel], device: &Device) {
+    for job in queue {
+        let model = &models[job.model].model;
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            process(model, job.image, device, &job.options)
+        }));
+        let response = match result {
+            Ok(img) => match encode(&img, job.format) {
+                Ok(bytes) => Response::from_data(bytes).with_header(content_type(job.format)),
+                Err(err) => error(500, &err),
+            },
+            Err(_) => error(500, "upscaling failed"),
+        };
+        let _ = job.request.respond(response);
+    }
+}
+
+fn handle(
+    mut request: Request,
+    jobs: &SyncSender<Job>,
+    model_list: &str,
+    names: &[String],
+    scales: &[usize],
+    limits: Limits,
+    dtype: DType,
+) {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let method = request.method().clone();
+    let response = match (method, path) {
+        (Method::Get, "/health") => json_response(200, r#"{"status":"ok"}"#.to_string()),
+        (Method::Get, "/models") => json_response(200, model_list.to_string()),
+        (Method::Post, "/upscale") => {
+            let too_large = || error(413, &format!("bodies are limited to {} bytes", limits.body));
+            if request.body_length().is_some_and(|len| len > limits.body) {
+                let _ = request.respond(too_large());
+                return;
+            }
+            // The length isn't always announced, read one byte more than the
+            // limit to tell
+            let mut body = vec![];
+            let read = request
+                .as_reader()
+                .take(limits.body as u64 + 1)
+                .read_to_end(&mut body);
+            if body.len() > limits.body {
+                let _ = request.respond(too_large());
+                return;
+            }
+            let job = read.map_err(|err| err.to_string()).and_then(|_| {
+                let params = parse_query(query)?;
+                upscale_job(&params, &body, names, scales, limits, dtype)
+            });
+            match job {
+                Ok((model, image, format, options)) => {
+                    let job = Job {
+                        request,
+                        model,
+                        image,
+                        format,
+                        options,
+                    };
+                    // Reject right away rather than letting clients pile up
+                    // behind a busy device
+                    let (job, message) = match jobs.try_send(job) {
+                        Ok(()) => return,
+                        Err(TrySendError::Full(job)) => (job, "queue is full"),
+                        Err(TrySendError::Disconnected(job)) => (job, "server is shutting down"),
+                    };
+                    let _ = job.request.respond(error(503, message));
+                    return;
+                }
+                Err(err) => error(400, &err),
+            }
+        }
+        _ => error(404, "not found"),
+    };
+    let _ = request.respond(response);
+}
+
+/// Validate the parameters of an upscale request and decode its image
+fn upscale_job(
+    params: &HashMap<String, String>,
+    body: &[u8],
+    names: &[String],
+    scales: &[usize],
+    limits: Limits,
+    dtype: DType,
+) -> Result<(usize, DynamicImage, ImageFormat, ProcessOptions<'static>), String> {
+    let model = match params.get("model") {
+        Some(name) => names
+            .iter()
+            .position(|n| n == name)
+            .ok_or(format!("unknown model {name}"))?,
+        None if names.len() == 1 => 0,
+        None => return Err("several models are loaded, pick one with ?model=".to_string()),
+    };
+
+    // Check the size in the header before decoding anything
+    let (width, height) = image::io::Reader::new(Cursor::new(body))
+        .with_guessed_format()
+        .map_err(|err| err.to_string())?
+        .into_dimensions()
+        .map_err(|err| err.to_string())?;
+    let (width, height) = (width as usize, height as usize);
+    if width * height > limits.input_pixels {
+        return Err(format!(
+            "the image is {width}x{height}, more than the {} pixels allowed",
+            limits.input_pixels
+        ));
+    }
+    let image = image::load_from_memory(body).map_err(|err| err.to_string())?;
+    let format = match params.get("format") {
+        Some(ext) => {
+            ImageFormat::from_extension(ext).ok_or(format!("unknown output format {ext}"))?
+        }
+        None => image::guess_format(body).unwrap_or(ImageFormat::Png),
+    };
+    if !format.can_write() {
+        return Err(format!("can't write {format:?} images"));
+    }
+
+    let size = output_size(
+        parse(params, "output_scale")?,
+        parse(params, "width")?,
+        parse(params, "height")?,
+        parse(params, "fit")?.unwrap_or(false),
+    );
+    if largest_output(width, height, scales[model], size) > limits.output_pixels {
+        return Err(format!(
+            "the output would be more than the {} pixels allowed",
+            limits.output_pixels
+        ));
+    }
+    let tile = parse(params, "tile")?;
+    if tile == Some(0) {
+        return Err("tile must be at least 1".to_string());
+    }
+    let filter = match params.get("filter") {
+        Some(s) => ResizeFilter::from_str(s, true)?,
+        None => ResizeFilter::Lanczos,
+    };
+    let color_fix = match params.get("color_fix") {
+        Some(s) => Some(ColorFix::from_str(s, true)?),
+        None => None,
+    };
+
+    let options = ProcessOptions {
+        dtype,
+        tile,
+        resize: size.map(|size| Resize { size, filter }),
+        color_fix,
+        faces: None,
+    };
+    Ok((model, image, format, options))
+}
+
+/// Pixels of the biggest image `process` makes for a `width` x `height`
+/// input: the last pass of the model, or the image it is resized to
+fn largest_output(width: usize, height: usize, scale: usize, size: Option<OutputSize>) -> usize {
+    let (mut w, mut h) = (width.saturating_mul(scale), height.saturating_mul(scale));
+    let Some(size) = size else {
+        return w.saturating_mul(h);
+    };
+    // The model runs again until its output is at least the target size
+    let (target_w, target_h) = size.target(width, height);
+    while scale > 1 && (w < target_w || h < target_h) {
+        w = w.saturating_mul(scale);
+        h = h.saturating_mul(scale);
+    }
+    usize::max(w.saturating_mul(h), target_w.saturating_mul(target_h))
+}
+
+fn parse<T: std::str::FromStr>(
+    params: &HashMap<String, String>,
+    key: &str,
+) -> Result<Option<T>, String> {
+    match params.get(key) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("invalid value for {key}: {value}")),
+        None => Ok(None),
+    }
+}
+
+fn parse_query(query: &str) -> Result<HashMap<String, String>, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, "true"));
+            Ok((percent_decode(key)?, percent_decode(value)?))
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> Result<String, String> {
+    let mut bytes = vec![];
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex: Vec<u8> = iter.by_ref().take(2).collect();
+                let hex = std::str::from_utf8(&hex).map_err(|err| err.to_string())?;
+                let byte = u8::from_str_radix(hex, 16)
+                    .map_err(|_| format!("invalid escape in query: %{hex}"))?;
+                bytes.push(byte);
+            }
+            b'+' => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).map_err(|err| err.to_string())
+}
+
+fn encode(img: &RgbImage, format: ImageFormat) -> Result<Vec<u8>, String> {
+    let mut bytes = Cursor::new(vec![]);
+    img.write_to(&mut bytes, format)
+        .map_err(|err| err.to_string())?;
+    Ok(bytes.into_inner())
+}
+
+fn content_type(format: ImageFormat) -> Header {
+    let mime = match format {
+        ImageFormat::Png => "image/png",
+        ImageFormat::Jpeg => "image/jpeg",
+        ImageFormat::WebP => "image/webp",
+        ImageFormat::Gif => "image/gif",
+        ImageFormat::Bmp => "image/bmp",
+        ImageFormat::Tiff => "image/tiff",
+        ImageFormat::Tga => "image/x-tga",
+        ImageFormat::Ico => "image/x-icon",
+        ImageFormat::Avif => "image/avif",
+        _ => "application/octet-stream",
+    };
+    Header::from_bytes("Content-Type", mime).unwrap()
+}
+
+fn json_response(status: u16, body: String) -> Response<Cursor<Vec<u8>>> {
+    Response::from_string(body)
+        .with_status_code(status)
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
+}
+
+fn error(status: u16, message: &str) -> Response<Cursor<Vec<u8>>> {
+    json_response(status, json!({ "error": message }).to_string())
+}
@@ -0,0 +1,256 @@
+use candle_core::{DType, Module, Result, Tensor};
+use candle_nn as nn;
+
+use crate::conv::{conv2d, Conv2d};
+use crate::profile::Profiler;
+
+const RGB_MEAN: [f32; 3] = [0.4488, 0.4371, 0.4040];
+const IMG_RANGE: f64 = 255.;
+
+/// Conv3XC of SPAN: a 1x1 -> 3x3 -> 1x1 chain of convs next to a 1x1 skip conv.
+/// They are all linear, so for inference they are reparameterised into the
+/// single 3x3 conv they add up to.
+#[derive(Debug)]
+struct Conv3XC {
+    sk: nn::Conv2d,
+    conv: [nn::Conv2d; 3],
+    eval_conv: Conv2d,
+}
+
+impl Conv3XC {
+    fn load(vb: nn::VarBuilder, c_in: usize, c_out: usize, gain: usize) -> Result<Self> {
+        let config = nn::Conv2dConfig {
+            padding: 0,
+            stride: 1,
+            dilation: 1,
+            groups: 1,
+        };
+        let sk = nn::conv2d(c_in, c_out, 1, config, vb.pp("sk"))?;
+        let conv = [
+            nn::conv2d(c_in, c_in * gain, 1, config, vb.pp("conv.0"))?,
+            nn::conv2d(c_in * gain, c_out * gain, 3, config, vb.pp("conv.1"))?,
+            nn::conv2d(c_out * gain, c_out, 1, config, vb.pp("conv.2"))?,
+        ];
+        let eval_conv = Conv2d::new(Self::reparameterize(&sk, &conv)?);
+        Ok(Self {
+            sk,
+            conv,
+            eval_conv,
+        })
+    }
+
+    /// The 3x3 conv equal to the sum of the two branches, computed like
+    /// `update_params` of the PyTorch module
+    fn reparameterize(sk: &nn::Conv2d, conv: &[nn::Conv2d; 3]) -> Result<nn::Conv2d> {
+        let dtype = sk.weight().dtype();
+        let weight = |conv: &nn::Conv2d| conv.weight().to_dtype(DType::F32);
+        let bias = |conv: &nn::Conv2d| conv.bias().unwrap().to_dtype(DType::F32);
+        let [conv1, conv2, conv3] = conv;
+        let (c_mid, c_in, _, _) = conv1.weight().dims4()?;
+        let (c_mid2, _, k, _) = conv2.weight().dims4()?;
+        let c_out = conv3.weight().dim(0)?;
+
+        // The 1x1 convs are matrices, multiplied into every tap of the 3x3 one
+        let w1 = weight(conv1)?.reshape((c_mid, c_in))?;
+        let w2 = weight(conv2)?;
+        let w3 = weight(conv3)?.reshape((c_out, c_mid2))?;
+        let taps = w2.permute((2, 3, 0, 1))?.reshape((k * k, c_mid2, c_mid))?;
+        let w = w3
+            .broadcast_matmul(&taps.broadcast_matmul(&w1)?)?
+            .reshape((k, k, c_out, c_in))?
+            .permute((2, 3, 0, 1))?;
+        // The first bias goes through every tap of the 3x3 conv
+        let b = (w2
+            .broadcast_mul(&bias(conv1)?.reshape((1, c_mid, 1, 1))?)?
+            .sum((1, 2, 3))?
+            + bias(conv2)?)?;
+        let b = (w3.matmul(&b.unsqueeze(1)?)?.squeeze(1)? + bias(conv3)?)?;
+
+        // The skip conv is the centre tap
+        let w = (w + weight(sk)?
+            .pad_with_zeros(2, k / 2, k / 2)?
+            .pad_with_zeros(3, k / 2, k / 2)?)?
+        .to_dtype(dtype)?;
+        let b = (b + bias(sk)?)?.to_dtype(dtype)?;
+        let config = nn::Conv2dConfig {
+            padding: k / 2,
+            ..*sk.config()
+        };
+        Ok(nn::Conv2d::new(w, Some(b), config))
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        vec![&mut self.eval_conv]
+    }
+}
+
+impl nn::Module for Conv3XC {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        // While training, the branches change at every step and the merged
+        // conv is derived from them each time, so gradients reach them
+        if self.sk.weight().is_variable() {
+            return Self::reparameterize(&self.sk, &self.conv)?.forward(xs);
+        }
+        self.eval_conv.forward(xs)
+    }
+}
+
+/// Swift parameter-free attention block
+#[derive(Debug)]
+struct SPAB {
+    c1_r: Conv3XC,
+    c2_r: Conv3XC,
+    c3_r: Conv3XC,
+}
+
+impl SPAB {
+    fn load(vb: nn::VarBuilder, channels: usize) -> Result<Self> {
+        Ok(Self {
+            c1_r: Conv3XC::load(vb.pp("c1_r"), channels, channels, 2)?,
+            c2_r: Conv3XC::load(vb.pp("c2_r"), channels, channels, 2)?,
+            c3_r: Conv3XC::load(vb.pp("c3_r"), channels, channels, 2)?,
+        })
+    }
+
+    fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = self.c1_r.convs_mut();
+        convs.extend(self.c2_r.convs_mut());
+        convs.extend(self.c3_r.convs_mut());
+        convs
+    }
+
+    /// The output of the block and the one of its first conv
+    fn forward(&self, xs: &Tensor) -> Result<(Tensor, Tensor)> {
+        let out1 = self.c1_r.forward(xs)?;
+        let out2 = self.c2_r.forward(&out1.silu()?)?;
+        let out3 = self.c3_r.forward(&out2.silu()?)?;
+        // The attention map comes from the features themselves, without parameters
+        let sim_att = (nn::ops::sigmoid(&out3)? - 0.5)?;
+        let out = (out3 + xs)?.mul(&sim_att)?;
+        Ok((out, out1))
+    }
+}
+
+#[derive(Debug)]
+pub struct SPAN {
+    conv_1: Conv3XC,
+    blocks: Vec<SPAB>,
+    conv_cat: Conv2d,
+    conv_2: Conv3XC,
+    upsampler: Conv2d,
+    upscale: usize,
+    norm: bool,
+}
+
+impl SPAN {
+    pub fn load(
+        vb: nn::VarBuilder,
+        num_in_ch: usize,
+        num_out_ch: usize,
+        feature_channels: usize,
+        upscale: usize,
+        norm: bool,
+    ) -> Result<Self> {
+        let conv_1 = Conv3XC::load(vb.pp("conv_1"), num_in_ch, feature_channels, 2)?;
+        let mut blocks = vec![];
+        for i in 1..=6 {
+            blocks.push(SPAB::load(vb.pp(format!("block_{i}")), feature_channels)?);
+        }
+        let conv_cat = conv2d(
+            feature_channels * 4,
+            feature_channels,
+            1,
+            nn::Conv2dConfig {
+                padding: 0,
+                stride: 1,
+                dilation: 1,
+                groups: 1,
+            },
+            vb.pp("conv_cat"),
+        )?;
+        let conv_2 = Conv3XC::load(vb.pp("conv_2"), feature_channels, feature_channels, 2)?;
+        let upsampler = conv2d(
+            feature_channels,
+            num_out_ch * upscale * upscale,
+            3,
+            nn::Conv2dConfig {
+                padding: 1,
+                stride: 1,
+                dilation: 1,
+                groups: 1,
+            },
+            vb.pp("upsampler.0"),
+        )?;
+        if !norm {
+            // Only there to mark the model as not normalising its input. Loaded
+            // so that it is kept in the checkpoints of `train`.
+            vb.get(1, "no_norm")?;
+        }
+        Ok(Self {
+            conv_1,
+            blocks,
+            conv_cat,
+            conv_2,
+            upsampler,
+            upscale,
+            norm,
+        })
+    }
+
+    /// Every conv of the model, for `conv::quantize`
+    pub fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = self.conv_1.convs_mut();
+        for block in self.blocks.iter_mut() {
+            convs.extend(block.convs_mut());
+        }
+        convs.extend(self.conv_2.convs_mut());
+        convs.push(&mut self.conv_cat);
+        convs.push(&mut self.upsampler);
+        convs
+    }
+}
+
+impl SPAN {
+    /// `forward`, recording the time of every module in `profiler`
+    pub fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        let xs = if self.norm {
+            let mean = Tensor::new(&RGB_MEAN, xs.device())?
+                .to_dtype(xs.dtype())?
+                .reshape((1, 3, 1, 1))?;
+            (xs.broadcast_sub(&mean)? * IMG_RANGE)?
+        } else {
+            xs.clone()
+        };
+        let out_feature = self.conv_1.forward(&xs)?;
+        profiler.record("conv_1", &out_feature)?;
+
+        let mut outputs = vec![];
+        let mut out = out_feature.clone();
+        for (i, block) in self.blocks.iter().enumerate() {
+            let (block_out, out1) = block.forward(&out)?;
+            out = block_out;
+            outputs.push((out.clone(), out1));
+            profiler.record(&format!("block_{}", i + 1), &out)?;
+        }
+        let out_b1 = &outputs[0].0;
+        // The first conv of the last block
+        let out_b5_2 = &outputs[5].1;
+
+        let out_b6 = self.conv_2.forward(&out)?;
+        profiler.record("conv_2", &out_b6)?;
+        let out = self
+            .conv_cat
+            .forward(&Tensor::cat(&[&out_feature, &out_b6, out_b1, out_b5_2], 1)?)?;
+        profiler.record("conv_cat", &out)?;
+        // Unlike the input, the output isn't denormalised
+        let out = nn::ops::pixel_shuffle(&self.upsampler.forward(&out)?, self.upscale)?;
+        profiler.record("upsampler", &out)?;
+        Ok(out)
+    }
+}
+
+impl nn::Module for SPAN {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.forward_profiled(xs, &mut Profiler::disabled())
+    }
+}
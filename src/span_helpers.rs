@@ -0,0 +1,38 @@
+use std::collections::HashMap;
+
+use candle_core::Tensor;
+
+// SPAN models have a fixed layout of six blocks between two Conv3XC, so only
+// the channel counts, the scale and the input normalisation vary
+
+pub fn get_in_nc(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("conv_1.sk.weight") {
+        Some(x) => x.shape().dims()[1],
+        None => 3,
+    };
+}
+
+pub fn get_nf(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("conv_1.sk.weight") {
+        Some(x) => x.shape().dims()[0],
+        None => 48,
+    };
+}
+
+pub fn get_scale(state_dict: &HashMap<String, Tensor>) -> usize {
+    // The upsampler outputs out_nc * scale * scale channels for the pixel shuffle,
+    // and the output channel count matches the input one.
+    return match state_dict.get("upsampler.0.weight") {
+        Some(x) => {
+            let channels = x.shape().dims()[0] / get_in_nc(state_dict);
+            (channels as f64).sqrt().round() as usize
+        }
+        None => 4,
+    };
+}
+
+/// Whether the model normalises its input with the mean of DIV2K, which models
+/// trained without it mark with a `no_norm` buffer
+pub fn get_norm(state_dict: &HashMap<String, Tensor>) -> bool {
+    return !state_dict.contains_key("no_norm");
+}
@@ -0,0 +1,230 @@
+use candle_core::{bail, Module, Result, Tensor};
+use candle_nn as nn;
+
+use crate::conv::{conv2d, Conv2d};
+use crate::old_arch::RRDB;
+use crate::profile::Profiler;
+
+// Structure-preserving SR: an old-arch ESRGAN whose trunk also feeds a branch
+// upscaling the gradient map of the input, and a last RRDB fusing the outputs
+// of both. The keys of the trunk are those of old-arch models, the convs of
+// the branch and the fusion are wrapped in a Sequential too.
+
+/// The gradient branch takes the trunk features after every BRANCH_STRIDE
+/// blocks, once for each of its NUM_BRANCH_BLOCKS RRDBs
+const BRANCH_STRIDE: usize = 5;
+const NUM_BRANCH_BLOCKS: usize = 4;
+
+fn conv(c_in: usize, c_out: usize, vb: nn::VarBuilder) -> Result<Conv2d> {
+    let config = nn::Conv2dConfig {
+        padding: 1,
+        stride: 1,
+        dilation: 1,
+        groups: 1,
+    };
+    conv2d(c_in, c_out, 3, config, vb)
+}
+
+/// `Get_gradient_nopadding`: the magnitude of the vertical and horizontal
+/// central differences of every channel, with zeros around the image
+fn gradient(xs: &Tensor) -> Result<Tensor> {
+    let (_, _, h, w) = xs.dims4()?;
+    let padded = xs.pad_with_zeros(2, 1, 1)?.pad_with_zeros(3, 1, 1)?;
+    let shifted = |dy: usize, dx: usize| padded.narrow(2, dy, h)?.narrow(3, dx, w);
+    let v = (shifted(2, 1)? - shifted(0, 1)?)?;
+    let h = (shifted(1, 2)? - shifted(1, 0)?)?;
+    (v.sqr()? + h.sqr()?)?.affine(1., 1e-6)?.sqrt()
+}
+
+#[derive(Debug)]
+pub struct SPSRNet {
+    conv_first: Conv2d,
+    body: Vec<RRDB>,
+    conv_body: Conv2d,
+    conv_ups: Vec<Conv2d>,
+    conv_hr: [Conv2d; 2],
+    b_fea_conv: Conv2d,
+    b_blocks: Vec<RRDB>,
+    b_concats: Vec<Conv2d>,
+    b_lr_conv: Conv2d,
+    b_ups: Vec<Conv2d>,
+    b_hr_conv: [Conv2d; 2],
+    f_block: RRDB,
+    f_concat: Conv2d,
+    f_hr_conv: [Conv2d; 2],
+    lrelu: nn::Activation,
+}
+
+impl SPSRNet {
+    /// The gradient branch needs at least 20 blocks in the trunk, the 23 of
+    /// the official models leave three after its last tap
+    pub fn load(
+        vb: nn::VarBuilder,
+        num_in_ch: usize,
+        num_out_ch: usize,
+        scale: usize,
+        num_feat: usize,
+        num_blocks: usize,
+        num_grow_ch: usize,
+    ) -> Result<Self> {
+        if num_blocks < BRANCH_STRIDE * NUM_BRANCH_BLOCKS {
+            bail!(
+                "SPSR models need at least {} blocks, got {num_blocks}",
+                BRANCH_STRIDE * NUM_BRANCH_BLOCKS
+            );
+        }
+        let nf = num_feat;
+        let num_ups = (scale as f32).log2() as usize;
+
+        let mut body = vec![];
+        for i in 0..num_blocks {
+            body.push(RRDB::load(
+                vb.pp(format!("model.1.sub.{i}")),
+                nf,
+                num_grow_ch,
+                false,
+            )?);
+        }
+        let mut conv_ups = vec![];
+        for i in 1..=num_ups {
+            conv_ups.push(conv(nf, nf, vb.pp(format!("model.{}", 3 * i)))?);
+        }
+
+        let mut b_blocks = vec![];
+        let mut b_concats = vec![];
+        for i in 1..=NUM_BRANCH_BLOCKS {
+            b_blocks.push(RRDB::load(
+                vb.pp(format!("b_block_{i}")),
+                2 * nf,
+                num_grow_ch,
+                false,
+            )?);
+            b_concats.push(conv(2 * nf, nf, vb.pp(format!("b_concat_{i}.0")))?);
+        }
+        let mut b_ups = vec![];
+        for i in 0..num_ups {
+            b_ups.push(conv(nf, nf, vb.pp(format!("b_module.{}", 3 * i + 1)))?);
+        }
+
+        Ok(Self {
+            conv_first: conv(num_in_ch, nf, vb.pp("model.0"))?,
+            body,
+            conv_body: conv(nf, nf, vb.pp(format!("model.1.sub.{num_blocks}")))?,
+            conv_ups,
+            conv_hr: [
+                conv(nf, nf, vb.pp(format!("model.{}", 3 * num_ups + 2)))?,
+                conv(nf, nf, vb.pp("HR_conv1_new.0"))?,
+            ],
+            b_fea_conv: conv(num_in_ch, nf, vb.pp("b_fea_conv.0"))?,
+            b_blocks,
+            b_concats,
+            b_lr_conv: conv(nf, nf, vb.pp("b_LR_conv.0"))?,
+            b_ups,
+            b_hr_conv: [
+                conv(nf, nf, vb.pp(format!("b_module.{}", 3 * num_ups)))?,
+                conv(nf, nf, vb.pp(format!("b_module.{}", 3 * num_ups + 2)))?,
+            ],
+            f_block: RRDB::load(vb.pp("f_block"), 2 * nf, num_grow_ch, false)?,
+            f_concat: conv(2 * nf, nf, vb.pp("f_concat.0"))?,
+            f_hr_conv: [
+                conv(nf, nf, vb.pp("f_HR_conv0.0"))?,
+                conv(nf, num_out_ch, vb.pp("f_HR_conv1.0"))?,
+            ],
+            lrelu: nn::Activation::LeakyRelu(0.2),
+        })
+    }
+
+    /// Every conv of the model, for `conv::quantize`
+    pub fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = Vec::new();
+        for block in self
+            .body
+            .iter_mut()
+            .chain(self.b_blocks.iter_mut())
+            .chain([&mut self.f_block])
+        {
+            convs.extend(block.convs_mut());
+        }
+        convs.extend([
+            &mut self.conv_first,
+            &mut self.conv_body,
+            &mut self.b_fea_conv,
+            &mut self.b_lr_conv,
+            &mut self.f_concat,
+        ]);
+        convs.extend(self.conv_ups.iter_mut());
+        convs.extend(self.conv_hr.iter_mut());
+        convs.extend(self.b_concats.iter_mut());
+        convs.extend(self.b_ups.iter_mut());
+        convs.extend(self.b_hr_conv.iter_mut());
+        convs.extend(self.f_hr_conv.iter_mut());
+        convs
+    }
+
+    /// Nearest upsampling by 2 and a conv for every upconv, then the two convs
+    /// at the output resolution
+    fn upscale(&self, xs: &Tensor, ups: &[Conv2d], hr_conv: &[Conv2d; 2]) -> Result<Tensor> {
+        let mut xs = xs.clone();
+        for conv in ups {
+            let (_, _, h, w) = xs.dims4()?;
+            xs = self
+                .lrelu
+                .forward(&conv.forward(&xs.upsample_nearest2d(2 * h, 2 * w)?)?)?;
+        }
+        let xs = self.lrelu.forward(&hr_conv[0].forward(&xs)?)?;
+        hr_conv[1].forward(&xs)
+    }
+
+    /// `forward`, recording the time of every module in `profiler`
+    pub fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        let x_grad = gradient(xs)?;
+
+        let feat = self.conv_first.forward(xs)?;
+        profiler.record("conv_first", &feat)?;
+        let mut body_feat = feat.clone();
+        let mut taps = vec![];
+        for (i, block) in self.body.iter().enumerate() {
+            body_feat = block.forward(&body_feat)?;
+            profiler.record(&format!("body.{i}"), &body_feat)?;
+            if (i + 1) % BRANCH_STRIDE == 0 && taps.len() < NUM_BRANCH_BLOCKS {
+                taps.push(body_feat.clone());
+            }
+        }
+        let feat = (feat + self.conv_body.forward(&body_feat)?)?;
+        profiler.record("conv_body", &feat)?;
+        let feat = self.upscale(&feat, &self.conv_ups, &self.conv_hr)?;
+        profiler.record("upsampling", &feat)?;
+
+        let b_fea = self.b_fea_conv.forward(&x_grad)?;
+        profiler.record("b_fea_conv", &b_fea)?;
+        let mut branch = b_fea.clone();
+        for (i, ((block, concat), tap)) in self
+            .b_blocks
+            .iter()
+            .zip(&self.b_concats)
+            .zip(&taps)
+            .enumerate()
+        {
+            let cat = block.forward(&Tensor::cat(&[&branch, tap], 1)?)?;
+            branch = concat.forward(&cat)?;
+            profiler.record(&format!("b_block_{}", i + 1), &branch)?;
+        }
+        let branch = (self.b_lr_conv.forward(&branch)? + b_fea)?;
+        let branch = self.upscale(&branch, &self.b_ups, &self.b_hr_conv)?;
+        profiler.record("b_module", &branch)?;
+
+        let out = self.f_block.forward(&Tensor::cat(&[&branch, &feat], 1)?)?;
+        let out = self.f_concat.forward(&out)?;
+        profiler.record("f_block", &out)?;
+        let out = self.lrelu.forward(&self.f_hr_conv[0].forward(&out)?)?;
+        let out = self.f_hr_conv[1].forward(&out)?;
+        profiler.record("f_HR_conv", &out)?;
+        Ok(out)
+    }
+}
+
+impl nn::Module for SPSRNet {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.forward_profiled(xs, &mut Profiler::disabled())
+    }
+}
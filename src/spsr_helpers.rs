@@ -0,0 +1,27 @@
+use std::collections::{HashMap, HashSet};
+
+use candle_core::Tensor;
+
+// The trunk of SPSR models is an old-arch ESRGAN, so the input channels, the
+// features, the number of blocks and the growth channels come from
+// `old_arch_helpers`. Only the end of the network differs.
+
+pub fn get_out_nc(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("f_HR_conv1.0.weight") {
+        Some(x) => x.shape().dims()[0],
+        None => 3,
+    };
+}
+
+pub fn get_scale(state_dict: &HashMap<String, Tensor>) -> usize {
+    // Like old-arch models, every upconv adds a model.# layer to model.0,
+    // model.1 and the conv at the output resolution, which is the last one
+    // of the trunk here
+    let num_unique_layers = state_dict
+        .keys()
+        .filter(|x| x.starts_with("model."))
+        .filter_map(|x| x.split('.').nth(1))
+        .collect::<HashSet<_>>()
+        .len();
+    return usize::pow(2, num_unique_layers.saturating_sub(3) as u32);
+}
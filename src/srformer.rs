@@ -0,0 +1,356 @@
+use candle_core::{Device, Module, Result, Tensor};
+use candle_nn as nn;
+
+use crate::conv::{conv2d, Conv2d};
+use crate::layers::{layer_norm, linear, LayerNorm, Linear};
+use crate::profile::Profiler;
+use crate::swinir::{
+    embed, in_shifted_windows, input_mean, pad_to_multiple, shifted_window_regions, unembed, Mlp,
+    Reconstruction, ResiConv, SwinIRConfig, Upsampler,
+};
+
+// SRFormer: SwinIR with permuted self-attention, where the keys and values of
+// a window are its pixels merged 2x2, with their channels cut by 4, so that
+// the windows can be larger for the same cost. It has the hyperparameters of
+// SwinIR, with `window_size` the side of the windows of the queries.
+
+/// Name of the variant of the paper the config matches
+pub fn variant(config: &SwinIRConfig) -> &'static str {
+    match (config.embed_dim, config.upsampler) {
+        (60, Upsampler::PixelShuffleDirect) => "srformer-light",
+        _ => "srformer",
+    }
+}
+
+fn conv(c_in: usize, c_out: usize, k: usize, vb: nn::VarBuilder) -> Result<Conv2d> {
+    let config = nn::Conv2dConfig {
+        padding: k / 2,
+        stride: 1,
+        dilation: 1,
+        groups: 1,
+    };
+    conv2d(c_in, c_out, k, config, vb)
+}
+
+/// Index of the relative position of every pixel of a window and every merged
+/// pixel in the bias table, (window_size², (window_size / 2)²)
+fn permuted_position_index(window_size: usize, device: &Device) -> Result<Tensor> {
+    let (ws, ps) = (window_size as i64, window_size as i64 / 2);
+    let mut index = vec![];
+    for i in 0..ws * ws {
+        // A pixel is at the relative position of the merged pixel it is in
+        let (yi, xi) = (i / ws / 2, i % ws / 2);
+        for j in 0..ps * ps {
+            let (yj, xj) = (j / ps, j % ps);
+            index.push(((yi - yj + ps - 1) * (2 * ps - 1) + xi - xj + ps - 1) as u32);
+        }
+    }
+    Tensor::from_vec(index, ((ws * ws) as usize, (ps * ps) as usize), device)
+}
+
+/// The attention mask of shifted windows, see `swinir::shifted_window_mask`,
+/// between the pixels and the merged pixels of every window
+fn permuted_window_mask(
+    h: usize,
+    w: usize,
+    window_size: usize,
+    shift_size: usize,
+    device: &Device,
+) -> Result<Tensor> {
+    let (ws, ps) = (window_size, window_size / 2);
+    let regions = shifted_window_regions(h, w, (ws, ws), (shift_size, shift_size));
+    // Like `-shift_size // 2` of the original, the shift of the merged pixels
+    // rounds up
+    let merged_shift = shift_size.div_ceil(2);
+    let merged_regions =
+        shifted_window_regions(h / 2, w / 2, (ps, ps), (merged_shift, merged_shift));
+    let mut mask = vec![];
+    for (window, merged) in regions.iter().zip(merged_regions.iter()) {
+        for ri in window.iter() {
+            for rj in merged.iter() {
+                mask.push(if ri == rj { 0f32 } else { -100. });
+            }
+        }
+    }
+    Tensor::from_vec(mask, (regions.len(), ws * ws, ps * ps), device)
+}
+
+/// Permuted self-attention inside windows
+#[derive(Debug)]
+struct PSA {
+    q: Linear,
+    kv: Linear,
+    proj: Linear,
+    relative_position_bias_table: Tensor,
+    relative_position_index: Tensor,
+    num_heads: usize,
+    window_size: usize,
+}
+
+impl PSA {
+    fn load(vb: nn::VarBuilder, dim: usize, window_size: usize, num_heads: usize) -> Result<Self> {
+        let table_side = window_size - 1;
+        Ok(Self {
+            q: linear(dim, dim, vb.pp("q"))?,
+            kv: linear(dim, dim / 2, vb.pp("kv"))?,
+            proj: linear(dim, dim, vb.pp("proj"))?,
+            relative_position_bias_table: vb.get(
+                (table_side * table_side, num_heads),
+                "relative_position_bias_table",
+            )?,
+            relative_position_index: permuted_position_index(window_size, vb.device())?,
+            num_heads,
+            window_size,
+        })
+    }
+
+    /// Attention over `xs` of (B * windows, N, C), `mask` is (windows, N, N / 4)
+    fn forward(&self, xs: &Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
+        let (b, n, c) = xs.dims3()?;
+        let head_dim = c / self.num_heads;
+        let ps = self.window_size / 2;
+        // The keys and values of every 2x2 pixels are concatenated into one
+        let kv = self
+            .kv
+            .forward(xs)?
+            .reshape((b * ps, 2, ps, 2, 2, c / 4))?
+            .permute([0, 2, 4, 1, 3, 5])?
+            .reshape((b, n / 4, 2, self.num_heads, head_dim))?
+            .permute((2, 0, 3, 1, 4))?;
+        let k = kv.get(0)?.contiguous()?;
+        let v = kv.get(1)?.contiguous()?;
+        let q = self
+            .q
+            .forward(xs)?
+            .reshape((b, n, self.num_heads, head_dim))?
+            .transpose(1, 2)?;
+        let q = (q * (head_dim as f64).powf(-0.5))?.contiguous()?;
+        let attn = q.matmul(&k.t()?)?;
+
+        let bias = self
+            .relative_position_bias_table
+            .index_select(&self.relative_position_index.flatten_all()?, 0)?
+            .reshape((n, n / 4, self.num_heads))?
+            .permute((2, 0, 1))?
+            .to_dtype(xs.dtype())?;
+        let attn = attn.broadcast_add(&bias.unsqueeze(0)?)?;
+        let attn = match mask {
+            Some(mask) => {
+                let windows = mask.dim(0)?;
+                attn.reshape((b / windows, windows, self.num_heads, n, n / 4))?
+                    .broadcast_add(&mask.unsqueeze(1)?.unsqueeze(0)?.to_dtype(xs.dtype())?)?
+                    .reshape((b, self.num_heads, n, n / 4))?
+            }
+            None => attn,
+        };
+        let attn = nn::ops::softmax_last_dim(&attn)?;
+        let out = attn.matmul(&v)?.transpose(1, 2)?.reshape((b, n, c))?;
+        self.proj.forward(&out)
+    }
+}
+
+/// A Swin transformer block with permuted self-attention
+#[derive(Debug)]
+struct PSABlock {
+    norm1: LayerNorm,
+    attn: PSA,
+    norm2: LayerNorm,
+    mlp: Mlp,
+    window_size: usize,
+    shift_size: usize,
+}
+
+impl PSABlock {
+    fn load(
+        vb: nn::VarBuilder,
+        dim: usize,
+        num_heads: usize,
+        shift_size: usize,
+        config: &SwinIRConfig,
+    ) -> Result<Self> {
+        let window_size = config.window_size;
+        Ok(Self {
+            norm1: layer_norm(dim, vb.pp("norm1"))?,
+            attn: PSA::load(vb.pp("attn"), dim, window_size, num_heads)?,
+            norm2: layer_norm(dim, vb.pp("norm2"))?,
+            mlp: Mlp::load(vb.pp("mlp"), dim, (dim as f64 * config.mlp_ratio) as usize)?,
+            window_size,
+            shift_size,
+        })
+    }
+
+    /// `xs` is (B, H * W, C), `mask` the one of `permuted_window_mask`
+    fn forward(&self, xs: &Tensor, (h, w): (usize, usize), mask: &Tensor) -> Result<Tensor> {
+        let (b, l, c) = xs.dims3()?;
+        let x = self.norm1.forward(xs)?.reshape((b, h, w, c))?;
+        let mask = if self.shift_size > 0 {
+            Some(mask)
+        } else {
+            None
+        };
+        let window = (self.window_size, self.window_size);
+        let shift = (self.shift_size, self.shift_size);
+        let x = in_shifted_windows(&x, window, shift, |windows| {
+            self.attn.forward(windows, mask)
+        })?;
+        let x = (xs + x.reshape((b, l, c))?)?;
+        &x + self.mlp.forward(&self.norm2.forward(&x)?)?
+    }
+}
+
+/// A group of permuted self-attention blocks followed by a conv, with a
+/// residual connection around them
+#[derive(Debug)]
+struct PSAGroup {
+    blocks: Vec<PSABlock>,
+    conv: ResiConv,
+}
+
+impl PSAGroup {
+    fn load(
+        vb: nn::VarBuilder,
+        dim: usize,
+        depth: usize,
+        num_heads: usize,
+        config: &SwinIRConfig,
+    ) -> Result<Self> {
+        let mut blocks = vec![];
+        for i in 0..depth {
+            let shift_size = if i % 2 == 0 {
+                0
+            } else {
+                config.window_size / 2
+            };
+            blocks.push(PSABlock::load(
+                vb.pp(format!("residual_group.blocks.{i}")),
+                dim,
+                num_heads,
+                shift_size,
+                config,
+            )?);
+        }
+        let conv = ResiConv::load(vb.pp("conv"), dim, config.resi_connection)?;
+        Ok(Self { blocks, conv })
+    }
+
+    fn forward(&self, xs: &Tensor, size: (usize, usize), mask: &Tensor) -> Result<Tensor> {
+        let mut out = xs.clone();
+        for block in self.blocks.iter() {
+            out = block.forward(&out, size, mask)?;
+        }
+        embed(&self.conv.forward(&unembed(&out, size)?)?)? + xs
+    }
+}
+
+#[derive(Debug)]
+pub struct SRFormer {
+    conv_first: Conv2d,
+    patch_norm: Option<LayerNorm>,
+    layers: Vec<PSAGroup>,
+    norm: LayerNorm,
+    conv_after_body: ResiConv,
+    reconstruction: Reconstruction,
+    in_nc: usize,
+    scale: usize,
+    window_size: usize,
+    variant: &'static str,
+}
+
+impl SRFormer {
+    pub fn load(vb: nn::VarBuilder, config: &SwinIRConfig) -> Result<Self> {
+        let dim = config.embed_dim;
+        let conv_first = conv(config.in_nc, dim, 3, vb.pp("conv_first"))?;
+        let patch_norm = match config.patch_norm {
+            true => Some(layer_norm(dim, vb.pp("patch_embed.norm"))?),
+            false => None,
+        };
+        let mut layers = vec![];
+        for (i, (depth, num_heads)) in config.depths.iter().zip(&config.num_heads).enumerate() {
+            layers.push(PSAGroup::load(
+                vb.pp(format!("layers.{i}")),
+                dim,
+                *depth,
+                *num_heads,
+                config,
+            )?);
+        }
+        Ok(Self {
+            conv_first,
+            patch_norm,
+            layers,
+            norm: layer_norm(dim, vb.pp("norm"))?,
+            conv_after_body: ResiConv::load(vb.pp("conv_after_body"), dim, config.resi_connection)?,
+            reconstruction: Reconstruction::load(
+                vb,
+                config.upsampler,
+                dim,
+                config.num_feat,
+                config.out_nc,
+                config.scale,
+            )?,
+            in_nc: config.in_nc,
+            scale: config.scale,
+            window_size: config.window_size,
+            variant: variant(config),
+        })
+    }
+
+    /// See `variant`
+    pub fn variant(&self) -> &'static str {
+        self.variant
+    }
+
+    /// The convs outside of the attention blocks, for `conv::quantize`, see
+    /// `SwinIR::convs_mut`
+    pub fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = vec![&mut self.conv_first];
+        for layer in self.layers.iter_mut() {
+            convs.extend(layer.conv.convs_mut());
+        }
+        convs.extend(self.conv_after_body.convs_mut());
+        convs.extend(self.reconstruction.convs_mut());
+        convs
+    }
+
+    /// `forward`, recording the time of every module in `profiler`
+    pub fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        let (_, _, h, w) = xs.dims4()?;
+        let xs = pad_to_multiple(xs, self.window_size)?;
+        let mean = input_mean(self.in_nc, &xs)?;
+        let xs = xs.broadcast_sub(&mean)?;
+        let size = (xs.dim(2)?, xs.dim(3)?);
+
+        let feat = self.conv_first.forward(&xs)?;
+        profiler.record("conv_first", &feat)?;
+        let mut out = embed(&feat)?;
+        if let Some(norm) = &self.patch_norm {
+            out = norm.forward(&out)?;
+        }
+        let mask = permuted_window_mask(
+            size.0,
+            size.1,
+            self.window_size,
+            self.window_size / 2,
+            xs.device(),
+        )?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            out = layer.forward(&out, size, &mask)?;
+            profiler.record(&format!("layers.{i}"), &out)?;
+        }
+        let out = unembed(&self.norm.forward(&out)?, size)?;
+        let out = (self.conv_after_body.forward(&out)? + feat)?;
+        profiler.record("conv_after_body", &out)?;
+
+        let out = self.reconstruction.forward(&out, &xs)?;
+        profiler.record("reconstruction", &out)?;
+        out.broadcast_add(&mean)?
+            .narrow(2, 0, h * self.scale)?
+            .narrow(3, 0, w * self.scale)
+    }
+}
+
+impl nn::Module for SRFormer {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.forward_profiled(xs, &mut Profiler::disabled())
+    }
+}
@@ -0,0 +1,28 @@
+use std::collections::HashMap;
+
+use candle_core::Tensor;
+
+use crate::swinir::SwinIRConfig;
+use crate::swinir_helpers;
+
+// SRFormer shares the key names of SwinIR, see `swinir_helpers`, but its
+// attention has queries and keys/values instead of qkv
+
+pub fn get_window_size(state_dict: &HashMap<String, Tensor>) -> usize {
+    // The bias table has a row for every relative position of the merged
+    // pixels, in windows of half the size
+    let key = "layers.0.residual_group.blocks.0.attn.relative_position_bias_table";
+    return match state_dict.get(key) {
+        Some(_) => 2 * swinir_helpers::get_window_size(state_dict),
+        None => 24,
+    };
+}
+
+/// Every hyperparameter of the model, `srformer::variant` tells which of the
+/// paper it is
+pub fn get_config(state_dict: &HashMap<String, Tensor>) -> SwinIRConfig {
+    return SwinIRConfig {
+        window_size: get_window_size(state_dict),
+        ..swinir_helpers::get_config(state_dict)
+    };
+}
@@ -0,0 +1,702 @@
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_nn as nn;
+
+use crate::conv::{conv2d, Conv2d};
+use crate::layers::{layer_norm, linear, LayerNorm, Linear};
+use crate::profile::Profiler;
+
+const RGB_MEAN: [f32; 3] = [0.4488, 0.4371, 0.4040];
+
+/// How the features are turned into the output image
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Upsampler {
+    /// A conv, then convs with pixel shuffles, then a last conv (classical SR)
+    PixelShuffle,
+    /// A single conv and pixel shuffle (lightweight SR)
+    PixelShuffleDirect,
+    /// Nearest upsampling followed by convs, like ESRGAN (real-world SR)
+    NearestConv,
+    /// A conv added to the input, for denoising and JPEG artifact removal
+    None,
+}
+
+/// The conv at the end of every residual group and of the body
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResiConnection {
+    OneConv,
+    /// 3x3, 1x1 and 3x3 convs with a quarter of the channels in between
+    ThreeConv,
+}
+
+#[derive(Debug, Clone)]
+pub struct SwinIRConfig {
+    pub in_nc: usize,
+    pub out_nc: usize,
+    pub scale: usize,
+    pub embed_dim: usize,
+    /// Number of Swin transformer blocks of every residual group
+    pub depths: Vec<usize>,
+    /// Number of attention heads of every residual group
+    pub num_heads: Vec<usize>,
+    pub window_size: usize,
+    pub mlp_ratio: f64,
+    pub upsampler: Upsampler,
+    /// Channels of the upsampler, unused by `PixelShuffleDirect` and `None`
+    pub num_feat: usize,
+    pub resi_connection: ResiConnection,
+    pub patch_norm: bool,
+}
+
+impl Default for SwinIRConfig {
+    /// The classical SR x4 model of the paper
+    fn default() -> Self {
+        Self {
+            in_nc: 3,
+            out_nc: 3,
+            scale: 4,
+            embed_dim: 180,
+            depths: vec![6; 6],
+            num_heads: vec![6; 6],
+            window_size: 8,
+            mlp_ratio: 2.,
+            upsampler: Upsampler::PixelShuffle,
+            num_feat: 64,
+            resi_connection: ResiConnection::OneConv,
+            patch_norm: true,
+        }
+    }
+}
+
+fn conv(c_in: usize, c_out: usize, k: usize, vb: nn::VarBuilder) -> Result<Conv2d> {
+    let config = nn::Conv2dConfig {
+        padding: k / 2,
+        stride: 1,
+        dilation: 1,
+        groups: 1,
+    };
+    conv2d(c_in, c_out, k, config, vb)
+}
+
+/// Split (B, H, W, C) into (B * windows, window_h * window_w, C)
+pub(crate) fn window_partition(xs: &Tensor, (wh, ww): (usize, usize)) -> Result<Tensor> {
+    let (b, h, w, c) = xs.dims4()?;
+    xs.reshape((b, h / wh, wh, w / ww, ww, c))?
+        .permute([0, 1, 3, 2, 4, 5])?
+        .reshape((b * (h / wh) * (w / ww), wh * ww, c))
+}
+
+/// Inverse of `window_partition`
+pub(crate) fn window_reverse(
+    windows: &Tensor,
+    (wh, ww): (usize, usize),
+    h: usize,
+    w: usize,
+) -> Result<Tensor> {
+    let (n, _, c) = windows.dims3()?;
+    let b = n / (h / wh * (w / ww));
+    windows
+        .reshape((b, h / wh, w / ww, wh, ww, c))?
+        .permute([0, 1, 3, 2, 4, 5])?
+        .reshape((b, h, w, c))
+}
+
+/// Run `f` on the windows of (B, H, W, C), after rolling the image up and left
+/// by `shift` and before rolling it back
+pub(crate) fn in_shifted_windows<F>(
+    xs: &Tensor,
+    window: (usize, usize),
+    (sh, sw): (usize, usize),
+    f: F,
+) -> Result<Tensor>
+where
+    F: Fn(&Tensor) -> Result<Tensor>,
+{
+    let (_, h, w, _) = xs.dims4()?;
+    let (sh, sw) = (sh as i32, sw as i32);
+    let xs = xs.roll(-sh, 1)?.roll(-sw, 2)?;
+    let windows = f(&window_partition(&xs, window)?)?;
+    window_reverse(&windows, window, h, w)?
+        .roll(sh, 1)?
+        .roll(sw, 2)
+}
+
+/// Index of the relative position of every pair of pixels of a window in the
+/// bias table, (window_h * window_w, window_h * window_w)
+pub(crate) fn relative_position_index((wh, ww): (usize, usize), device: &Device) -> Result<Tensor> {
+    let (wh, ww) = (wh as i64, ww as i64);
+    let coords: Vec<(i64, i64)> = (0..wh).flat_map(|y| (0..ww).map(move |x| (y, x))).collect();
+    let mut index = vec![];
+    for (yi, xi) in coords.iter() {
+        for (yj, xj) in coords.iter() {
+            index.push(((yi - yj + wh - 1) * (2 * ww - 1) + xi - xj + ww - 1) as u32);
+        }
+    }
+    Tensor::from_vec(index, (coords.len(), coords.len()), device)
+}
+
+/// Region of every pixel of an image rolled by `shift`, flattened by window
+/// like `window_partition`, (windows, window_h * window_w). Pixels of the
+/// same window from different regions come from opposite sides of the image.
+pub(crate) fn shifted_window_regions(
+    h: usize,
+    w: usize,
+    (wh, ww): (usize, usize),
+    (sh, sw): (usize, usize),
+) -> Vec<Vec<usize>> {
+    let region = |i: usize, size: usize, window: usize, shift: usize| {
+        if i < size - window {
+            0
+        } else if i < size - shift {
+            1
+        } else {
+            2
+        }
+    };
+    let mut regions = vec![];
+    for y in 0..h / wh {
+        for x in 0..w / ww {
+            regions.push(
+                (0..wh * ww)
+                    .map(|i| {
+                        region(y * wh + i / ww, h, wh, sh) * 3 + region(x * ww + i % ww, w, ww, sw)
+                    })
+                    .collect(),
+            );
+        }
+    }
+    regions
+}
+
+/// The attention mask of shifted windows, (windows, window_h * window_w,
+/// window_h * window_w). After the shift, the windows along the bottom and
+/// right borders hold pixels from opposite sides of the image, which must not
+/// attend to each other.
+pub(crate) fn shifted_window_mask(
+    h: usize,
+    w: usize,
+    window: (usize, usize),
+    shift: (usize, usize),
+    device: &Device,
+) -> Result<Tensor> {
+    let regions = shifted_window_regions(h, w, window, shift);
+    let n = window.0 * window.1;
+    let mut mask = vec![];
+    for window in regions.iter() {
+        for ri in window.iter() {
+            for rj in window.iter() {
+                mask.push(if ri == rj { 0f32 } else { -100. });
+            }
+        }
+    }
+    Tensor::from_vec(mask, (regions.len(), n, n), device)
+}
+
+/// Multi-head self-attention inside windows, with a learned bias for every
+/// relative position
+#[derive(Debug)]
+pub(crate) struct WindowAttention {
+    qkv: Linear,
+    proj: Linear,
+    relative_position_bias_table: Tensor,
+    relative_position_index: Tensor,
+    num_heads: usize,
+}
+
+impl WindowAttention {
+    pub(crate) fn load(
+        vb: nn::VarBuilder,
+        dim: usize,
+        window_size: usize,
+        num_heads: usize,
+    ) -> Result<Self> {
+        let table_size = (2 * window_size - 1) * (2 * window_size - 1);
+        Ok(Self {
+            qkv: linear(dim, dim * 3, vb.pp("qkv"))?,
+            proj: linear(dim, dim, vb.pp("proj"))?,
+            relative_position_bias_table: vb
+                .get((table_size, num_heads), "relative_position_bias_table")?,
+            relative_position_index: relative_position_index(
+                (window_size, window_size),
+                vb.device(),
+            )?,
+            num_heads,
+        })
+    }
+
+    /// Attention over `xs` of (B * windows, N, C), `mask` is (windows, N, N)
+    pub(crate) fn forward(&self, xs: &Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
+        let (b, n, c) = xs.dims3()?;
+        let head_dim = c / self.num_heads;
+        let qkv = self
+            .qkv
+            .forward(xs)?
+            .reshape((b, n, 3, self.num_heads, head_dim))?
+            .permute((2, 0, 3, 1, 4))?;
+        let q = (qkv.get(0)? * (head_dim as f64).powf(-0.5))?.contiguous()?;
+        let k = qkv.get(1)?.contiguous()?;
+        let v = qkv.get(2)?.contiguous()?;
+        let attn = q.matmul(&k.t()?)?;
+
+        // The table is indexed on every call so that training updates it
+        let bias = self
+            .relative_position_bias_table
+            .index_select(&self.relative_position_index.flatten_all()?, 0)?
+            .reshape((n, n, self.num_heads))?
+            .permute((2, 0, 1))?
+            .to_dtype(xs.dtype())?;
+        let attn = attn.broadcast_add(&bias.unsqueeze(0)?)?;
+        let attn = match mask {
+            Some(mask) => {
+                let windows = mask.dim(0)?;
+                attn.reshape((b / windows, windows, self.num_heads, n, n))?
+                    .broadcast_add(&mask.unsqueeze(1)?.unsqueeze(0)?.to_dtype(xs.dtype())?)?
+                    .reshape((b, self.num_heads, n, n))?
+            }
+            None => attn,
+        };
+        let attn = nn::ops::softmax_last_dim(&attn)?;
+        let out = attn.matmul(&v)?.transpose(1, 2)?.reshape((b, n, c))?;
+        self.proj.forward(&out)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Mlp {
+    fc1: Linear,
+    fc2: Linear,
+}
+
+impl Mlp {
+    pub(crate) fn load(vb: nn::VarBuilder, dim: usize, hidden_dim: usize) -> Result<Self> {
+        Ok(Self {
+            fc1: linear(dim, hidden_dim, vb.pp("fc1"))?,
+            fc2: linear(hidden_dim, dim, vb.pp("fc2"))?,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.fc2.forward(&self.fc1.forward(xs)?.gelu_erf()?)
+    }
+}
+
+#[derive(Debug)]
+struct SwinTransformerBlock {
+    norm1: LayerNorm,
+    attn: WindowAttention,
+    norm2: LayerNorm,
+    mlp: Mlp,
+    window_size: usize,
+    shift_size: usize,
+}
+
+impl SwinTransformerBlock {
+    fn load(
+        vb: nn::VarBuilder,
+        dim: usize,
+        num_heads: usize,
+        window_size: usize,
+        shift_size: usize,
+        mlp_ratio: f64,
+    ) -> Result<Self> {
+        Ok(Self {
+            norm1: layer_norm(dim, vb.pp("norm1"))?,
+            attn: WindowAttention::load(vb.pp("attn"), dim, window_size, num_heads)?,
+            norm2: layer_norm(dim, vb.pp("norm2"))?,
+            mlp: Mlp::load(vb.pp("mlp"), dim, (dim as f64 * mlp_ratio) as usize)?,
+            window_size,
+            shift_size,
+        })
+    }
+
+    /// `xs` is (B, H * W, C), `mask` the one of `shifted_window_mask`
+    fn forward(&self, xs: &Tensor, (h, w): (usize, usize), mask: &Tensor) -> Result<Tensor> {
+        let (b, l, c) = xs.dims3()?;
+        let x = self.norm1.forward(xs)?.reshape((b, h, w, c))?;
+        let mask = if self.shift_size > 0 {
+            Some(mask)
+        } else {
+            None
+        };
+        let window = (self.window_size, self.window_size);
+        let shift = (self.shift_size, self.shift_size);
+        let x = in_shifted_windows(&x, window, shift, |windows| {
+            self.attn.forward(windows, mask)
+        })?;
+        let x = (xs + x.reshape((b, l, c))?)?;
+        &x + self.mlp.forward(&self.norm2.forward(&x)?)?
+    }
+}
+
+/// Tokens (B, H * W, C) to an image (B, C, H, W)
+pub(crate) fn unembed(xs: &Tensor, (h, w): (usize, usize)) -> Result<Tensor> {
+    let (b, _, c) = xs.dims3()?;
+    xs.transpose(1, 2)?.reshape((b, c, h, w))
+}
+
+/// An image (B, C, H, W) to tokens (B, H * W, C)
+pub(crate) fn embed(xs: &Tensor) -> Result<Tensor> {
+    xs.flatten_from(2)?.transpose(1, 2)
+}
+
+/// See `ResiConnection`
+#[derive(Debug)]
+pub(crate) struct ResiConv {
+    convs: Vec<Conv2d>,
+}
+
+impl ResiConv {
+    pub(crate) fn load(
+        vb: nn::VarBuilder,
+        dim: usize,
+        resi_connection: ResiConnection,
+    ) -> Result<Self> {
+        let convs = match resi_connection {
+            ResiConnection::OneConv => vec![conv(dim, dim, 3, vb)?],
+            ResiConnection::ThreeConv => vec![
+                conv(dim, dim / 4, 3, vb.pp("0"))?,
+                conv(dim / 4, dim / 4, 1, vb.pp("2"))?,
+                conv(dim / 4, dim, 3, vb.pp("4"))?,
+            ],
+        };
+        Ok(Self { convs })
+    }
+
+    pub(crate) fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        self.convs.iter_mut().collect()
+    }
+}
+
+impl Module for ResiConv {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut out = self.convs[0].forward(xs)?;
+        for conv in self.convs[1..].iter() {
+            out = conv.forward(&nn::ops::leaky_relu(&out, 0.2)?)?;
+        }
+        Ok(out)
+    }
+}
+
+/// Residual Swin transformer block: a group of Swin transformer blocks followed
+/// by a conv, with a residual connection around them
+#[derive(Debug)]
+struct RSTB {
+    blocks: Vec<SwinTransformerBlock>,
+    conv: ResiConv,
+}
+
+impl RSTB {
+    fn load(
+        vb: nn::VarBuilder,
+        dim: usize,
+        depth: usize,
+        num_heads: usize,
+        config: &SwinIRConfig,
+    ) -> Result<Self> {
+        let window_size = config.window_size;
+        let mut blocks = vec![];
+        for i in 0..depth {
+            // Every other block shifts its windows by half a window
+            let shift_size = if i % 2 == 0 { 0 } else { window_size / 2 };
+            blocks.push(SwinTransformerBlock::load(
+                vb.pp(format!("residual_group.blocks.{i}")),
+                dim,
+                num_heads,
+                window_size,
+                shift_size,
+                config.mlp_ratio,
+            )?);
+        }
+        let conv = ResiConv::load(vb.pp("conv"), dim, config.resi_connection)?;
+        Ok(Self { blocks, conv })
+    }
+
+    fn forward(&self, xs: &Tensor, size: (usize, usize), mask: &Tensor) -> Result<Tensor> {
+        let mut out = xs.clone();
+        for block in self.blocks.iter() {
+            out = block.forward(&out, size, mask)?;
+        }
+        embed(&self.conv.forward(&unembed(&out, size)?)?)? + xs
+    }
+}
+
+/// The convs after the body, see `Upsampler`
+#[derive(Debug)]
+pub(crate) enum Reconstruction {
+    PixelShuffle {
+        conv_before_upsample: Conv2d,
+        /// Every conv is followed by a pixel shuffle of the given scale
+        upsample: Vec<(Conv2d, usize)>,
+        conv_last: Conv2d,
+    },
+    PixelShuffleDirect {
+        upsample: Conv2d,
+        scale: usize,
+    },
+    NearestConv {
+        conv_before_upsample: Conv2d,
+        conv_up: Vec<Conv2d>,
+        conv_hr: Conv2d,
+        conv_last: Conv2d,
+    },
+    None {
+        conv_last: Conv2d,
+    },
+}
+
+impl Reconstruction {
+    pub(crate) fn load(
+        vb: nn::VarBuilder,
+        upsampler: Upsampler,
+        dim: usize,
+        num_feat: usize,
+        out_nc: usize,
+        scale: usize,
+    ) -> Result<Self> {
+        Ok(match upsampler {
+            Upsampler::PixelShuffle => {
+                let mut upsample = vec![];
+                if scale == 3 {
+                    upsample.push((conv(num_feat, 9 * num_feat, 3, vb.pp("upsample.0"))?, 3));
+                } else {
+                    let num_ups = (scale as f32).log2() as usize;
+                    for i in 0..num_ups {
+                        let conv = conv(
+                            num_feat,
+                            4 * num_feat,
+                            3,
+                            vb.pp(format!("upsample.{}", 2 * i)),
+                        )?;
+                        upsample.push((conv, 2));
+                    }
+                }
+                Reconstruction::PixelShuffle {
+                    conv_before_upsample: conv(dim, num_feat, 3, vb.pp("conv_before_upsample.0"))?,
+                    upsample,
+                    conv_last: conv(num_feat, out_nc, 3, vb.pp("conv_last"))?,
+                }
+            }
+            Upsampler::PixelShuffleDirect => Reconstruction::PixelShuffleDirect {
+                upsample: conv(dim, out_nc * scale * scale, 3, vb.pp("upsample.0"))?,
+                scale,
+            },
+            Upsampler::NearestConv => {
+                let mut conv_up = vec![conv(num_feat, num_feat, 3, vb.pp("conv_up1"))?];
+                if scale == 4 {
+                    conv_up.push(conv(num_feat, num_feat, 3, vb.pp("conv_up2"))?);
+                }
+                Reconstruction::NearestConv {
+                    conv_before_upsample: conv(dim, num_feat, 3, vb.pp("conv_before_upsample.0"))?,
+                    conv_up,
+                    conv_hr: conv(num_feat, num_feat, 3, vb.pp("conv_hr"))?,
+                    conv_last: conv(num_feat, out_nc, 3, vb.pp("conv_last"))?,
+                }
+            }
+            Upsampler::None => Reconstruction::None {
+                conv_last: conv(dim, out_nc, 3, vb.pp("conv_last"))?,
+            },
+        })
+    }
+
+    pub(crate) fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        match self {
+            Reconstruction::PixelShuffle {
+                conv_before_upsample,
+                upsample,
+                conv_last,
+            } => {
+                let mut convs = vec![conv_before_upsample, conv_last];
+                convs.extend(upsample.iter_mut().map(|(conv, _)| conv));
+                convs
+            }
+            Reconstruction::PixelShuffleDirect { upsample, .. } => vec![upsample],
+            Reconstruction::NearestConv {
+                conv_before_upsample,
+                conv_up,
+                conv_hr,
+                conv_last,
+            } => {
+                let mut convs = vec![conv_before_upsample, conv_hr, conv_last];
+                convs.extend(conv_up.iter_mut());
+                convs
+            }
+            Reconstruction::None { conv_last } => vec![conv_last],
+        }
+    }
+
+    /// The output image from the features `xs` and the normalised input `input`
+    pub(crate) fn forward(&self, xs: &Tensor, input: &Tensor) -> Result<Tensor> {
+        match self {
+            Reconstruction::PixelShuffle {
+                conv_before_upsample,
+                upsample,
+                conv_last,
+            } => {
+                let mut out = nn::ops::leaky_relu(&conv_before_upsample.forward(xs)?, 0.01)?;
+                for (conv, scale) in upsample.iter() {
+                    out = nn::ops::pixel_shuffle(&conv.forward(&out)?, *scale)?;
+                }
+                conv_last.forward(&out)
+            }
+            Reconstruction::PixelShuffleDirect { upsample, scale } => {
+                nn::ops::pixel_shuffle(&upsample.forward(xs)?, *scale)
+            }
+            Reconstruction::NearestConv {
+                conv_before_upsample,
+                conv_up,
+                conv_hr,
+                conv_last,
+            } => {
+                let mut out = nn::ops::leaky_relu(&conv_before_upsample.forward(xs)?, 0.01)?;
+                for conv in conv_up.iter() {
+                    let (_, _, h, w) = out.dims4()?;
+                    out = conv.forward(&out.upsample_nearest2d(h * 2, w * 2)?)?;
+                    out = nn::ops::leaky_relu(&out, 0.2)?;
+                }
+                let out = nn::ops::leaky_relu(&conv_hr.forward(&out)?, 0.2)?;
+                conv_last.forward(&out)
+            }
+            Reconstruction::None { conv_last } => input + conv_last.forward(xs)?,
+        }
+    }
+}
+
+/// Reflect-pad the bottom and right of (B, C, H, W) to a multiple of `multiple`
+pub fn pad_to_multiple(xs: &Tensor, multiple: usize) -> Result<Tensor> {
+    let mut xs = xs.clone();
+    for dim in [2, 3] {
+        let size = xs.dim(dim)?;
+        let pad = (multiple - size % multiple) % multiple;
+        if pad == 0 {
+            continue;
+        }
+        // Mirrors the last rows without repeating the border, like PyTorch, and
+        // bounces back and forth for images smaller than the padding
+        let period = (2 * (size - 1)).max(1);
+        let index: Vec<u32> = (0..size + pad)
+            .map(|i| {
+                let i = i % period;
+                (if i < size { i } else { period - i }) as u32
+            })
+            .collect();
+        let index = Tensor::new(index.as_slice(), xs.device())?;
+        xs = xs.index_select(&index, dim)?;
+    }
+    Ok(xs)
+}
+
+/// The mean that is subtracted from the input, that of DIV2K for RGB models
+/// and zero for the others
+pub(crate) fn input_mean(in_nc: usize, xs: &Tensor) -> Result<Tensor> {
+    let mean = match in_nc {
+        3 => Tensor::new(&RGB_MEAN, xs.device())?,
+        _ => Tensor::zeros(in_nc, DType::F32, xs.device())?,
+    };
+    mean.to_dtype(xs.dtype())?.reshape((1, in_nc, 1, 1))
+}
+
+#[derive(Debug)]
+pub struct SwinIR {
+    conv_first: Conv2d,
+    patch_norm: Option<LayerNorm>,
+    layers: Vec<RSTB>,
+    norm: LayerNorm,
+    conv_after_body: ResiConv,
+    reconstruction: Reconstruction,
+    in_nc: usize,
+    scale: usize,
+    window_size: usize,
+}
+
+impl SwinIR {
+    pub fn load(vb: nn::VarBuilder, config: &SwinIRConfig) -> Result<Self> {
+        let dim = config.embed_dim;
+        let conv_first = conv(config.in_nc, dim, 3, vb.pp("conv_first"))?;
+        let patch_norm = match config.patch_norm {
+            true => Some(layer_norm(dim, vb.pp("patch_embed.norm"))?),
+            false => None,
+        };
+        let mut layers = vec![];
+        for (i, (depth, num_heads)) in config.depths.iter().zip(&config.num_heads).enumerate() {
+            layers.push(RSTB::load(
+                vb.pp(format!("layers.{i}")),
+                dim,
+                *depth,
+                *num_heads,
+                config,
+            )?);
+        }
+        Ok(Self {
+            conv_first,
+            patch_norm,
+            layers,
+            norm: layer_norm(dim, vb.pp("norm"))?,
+            conv_after_body: ResiConv::load(vb.pp("conv_after_body"), dim, config.resi_connection)?,
+            reconstruction: Reconstruction::load(
+                vb,
+                config.upsampler,
+                dim,
+                config.num_feat,
+                config.out_nc,
+                config.scale,
+            )?,
+            in_nc: config.in_nc,
+            scale: config.scale,
+            window_size: config.window_size,
+        })
+    }
+
+    /// Every conv of the model, for `conv::quantize`. The linear layers of
+    /// the transformer blocks stay in full precision.
+    pub fn convs_mut(&mut self) -> Vec<&mut Conv2d> {
+        let mut convs = vec![&mut self.conv_first];
+        for layer in self.layers.iter_mut() {
+            convs.extend(layer.conv.convs_mut());
+        }
+        convs.extend(self.conv_after_body.convs_mut());
+        convs.extend(self.reconstruction.convs_mut());
+        convs
+    }
+}
+
+impl SwinIR {
+    /// `forward`, recording the time of every module in `profiler`
+    pub fn forward_profiled(&self, xs: &Tensor, profiler: &mut Profiler) -> Result<Tensor> {
+        let (_, _, h, w) = xs.dims4()?;
+        // The windows have to tile the image
+        let xs = pad_to_multiple(xs, self.window_size)?;
+        let mean = input_mean(self.in_nc, &xs)?;
+        let xs = xs.broadcast_sub(&mean)?;
+        let size = (xs.dim(2)?, xs.dim(3)?);
+
+        let feat = self.conv_first.forward(&xs)?;
+        profiler.record("conv_first", &feat)?;
+        let mut out = embed(&feat)?;
+        if let Some(norm) = &self.patch_norm {
+            out = norm.forward(&out)?;
+        }
+        let half = self.window_size / 2;
+        let window = (self.window_size, self.window_size);
+        let mask = shifted_window_mask(size.0, size.1, window, (half, half), xs.device())?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            out = layer.forward(&out, size, &mask)?;
+            profiler.record(&format!("layers.{i}"), &out)?;
+        }
+        let out = unembed(&self.norm.forward(&out)?, size)?;
+        let out = (self.conv_after_body.forward(&out)? + feat)?;
+        profiler.record("conv_after_body", &out)?;
+
+        let out = self.reconstruction.forward(&out, &xs)?;
+        profiler.record("reconstruction", &out)?;
+        out.broadcast_add(&mean)?
+            .narrow(2, 0, h * self.scale)?
+            .narrow(3, 0, w * self.scale)
+    }
+}
+
+impl nn::Module for SwinIR {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.forward_profiled(xs, &mut Profiler::disabled())
+    }
+}
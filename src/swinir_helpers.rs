@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+
+use candle_core::Tensor;
+
+use crate::swinir::{ResiConnection, SwinIRConfig, Upsampler};
+
+// Everything is read from the shapes of the weights and the number of blocks,
+// the buffers PyTorch saves (relative_position_index, attn_mask) are not needed
+
+pub fn get_in_nc(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("conv_first.weight") {
+        Some(x) => x.shape().dims()[1],
+        None => 3,
+    };
+}
+
+pub fn get_embed_dim(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("conv_first.weight") {
+        Some(x) => x.shape().dims()[0],
+        None => 180,
+    };
+}
+
+/// Number of Swin transformer blocks of every residual group
+pub fn get_depths(state_dict: &HashMap<String, Tensor>) -> Vec<usize> {
+    let mut depths: Vec<usize> = vec![];
+    for key in state_dict.keys() {
+        // layers.{i}.residual_group.blocks.{j}.norm1.weight
+        let parts: Vec<&str> = key.split('.').collect();
+        if parts.len() < 5 || parts[0] != "layers" || parts[3] != "blocks" {
+            continue;
+        }
+        let (Ok(layer), Ok(block)) = (parts[1].parse::<usize>(), parts[4].parse::<usize>()) else {
+            continue;
+        };
+        if depths.len() <= layer {
+            depths.resize(layer + 1, 0);
+        }
+        depths[layer] = depths[layer].max(block + 1);
+    }
+    return depths;
+}
+
+/// Number of attention heads of every residual group, the columns of its bias table
+pub fn get_num_heads(state_dict: &HashMap<String, Tensor>) -> Vec<usize> {
+    return (0..get_depths(state_dict).len())
+        .map(|i| {
+            let key =
+                format!("layers.{i}.residual_group.blocks.0.attn.relative_position_bias_table");
+            state_dict[&key].shape().dims()[1]
+        })
+        .collect();
+}
+
+pub fn get_window_size(state_dict: &HashMap<String, Tensor>) -> usize {
+    // The bias table has a row for every one of the (2 * window_size - 1)²
+    // relative positions
+    let key = "layers.0.residual_group.blocks.0.attn.relative_position_bias_table";
+    return match state_dict.get(key) {
+        Some(x) => {
+            let side = (x.shape().dims()[0] as f64).sqrt().round() as usize;
+            side.div_ceil(2)
+        }
+        None => 8,
+    };
+}
+
+pub fn get_mlp_ratio(state_dict: &HashMap<String, Tensor>) -> f64 {
+    return match state_dict.get("layers.0.residual_group.blocks.0.mlp.fc1.weight") {
+        Some(x) => x.shape().dims()[0] as f64 / get_embed_dim(state_dict) as f64,
+        None => 2.,
+    };
+}
+
+pub fn get_upsampler(state_dict: &HashMap<String, Tensor>) -> Upsampler {
+    if state_dict.contains_key("conv_before_upsample.0.weight") {
+        if state_dict.contains_key("conv_up1.weight") {
+            return Upsampler::NearestConv;
+        }
+        return Upsampler::PixelShuffle;
+    }
+    if state_dict.contains_key("upsample.0.weight") {
+        return Upsampler::PixelShuffleDirect;
+    }
+    return Upsampler::None;
+}
+
+pub fn get_num_feat(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("conv_before_upsample.0.weight") {
+        Some(x) => x.shape().dims()[0],
+        None => 64,
+    };
+}
+
+pub fn get_out_nc(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match state_dict.get("conv_last.weight") {
+        Some(x) => x.shape().dims()[0],
+        // Only pixelshuffledirect models have no conv_last, their output
+        // channels match the input ones
+        None => get_in_nc(state_dict),
+    };
+}
+
+pub fn get_scale(state_dict: &HashMap<String, Tensor>) -> usize {
+    return match get_upsampler(state_dict) {
+        Upsampler::PixelShuffle => {
+            // Either a single conv followed by a pixel shuffle of 3, or one
+            // conv and pixel shuffle of 2 per doubling
+            let first = state_dict["upsample.0.weight"].shape().dims()[0];
+            if first == 9 * get_num_feat(state_dict) {
+                3
+            } else {
+                let num_ups = (0..)
+                    .take_while(|i| state_dict.contains_key(&format!("upsample.{}.weight", 2 * i)))
+                    .count();
+                1 << num_ups
+            }
+        }
+        Upsampler::PixelShuffleDirect => {
+            let channels =
+                state_dict["upsample.0.weight"].shape().dims()[0] / get_in_nc(state_dict);
+            (channels as f64).sqrt().round() as usize
+        }
+        Upsampler::NearestConv => {
+            if state_dict.contains_key("conv_up2.weight") {
+                4
+            } else {
+                2
+            }
+        }
+        Upsampler::None => 1,
+    };
+}
+
+pub fn get_resi_connection(state_dict: &HashMap<String, Tensor>) -> ResiConnection {
+    if state_dict.contains_key("conv_after_body.0.weight") {
+        return ResiConnection::ThreeConv;
+    }
+    return ResiConnection::OneConv;
+}
+
+/// Every hyperparameter of the model
+pub fn get_config(state_dict: &HashMap<String, Tensor>) -> SwinIRConfig {
+    return SwinIRConfig {
+        in_nc: get_in_nc(state_dict),
+        out_nc: get_out_nc(state_dict),
+        scale: get_scale(state_dict),
+        embed_dim: get_embed_dim(state_dict),
+        depths: get_depths(state_dict),
+        num_heads: get_num_heads(state_dict),
+        window_size: get_window_size(state_dict),
+        mlp_ratio: get_mlp_ratio(state_dict),
+        upsampler: get_upsampler(state_dict),
+        num_feat: get_num_feat(state_dict),
+        resi_connection: get_resi_connection(state_dict),
+        patch_norm: state_dict.contains_key("patch_embed.norm.weight"),
+    };
+}
@@ -0,0 +1,864 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use candle_core::{Device, Error, Result, Tensor};
+
+use crate::dat::relative_positions;
+use crate::gfpgan::GFPGANConfig;
+use crate::retinaface::RetinaFaceConfig;
+use crate::swinir::Upsampler;
+
+/// Builds state dicts with random weights, laid out exactly like real checkpoints
+/// of each architecture, so detection and loading can be tested without
+/// downloading any models.
+///
+/// ```
+/// use esrgan_candle_rs::synthetic::SyntheticModel;
+///
+/// let state_dict = SyntheticModel::new("old")
+///     .scale(2)
+///     .num_features(16)
+///     .num_blocks(2)
+///     .state_dict(&candle_core::Device::Cpu)
+///     .unwrap();
+/// assert!(state_dict.contains_key("model.1.sub.2.weight"));
+/// ```
+#[derive(Debug, Clone)]
+pub struct SyntheticModel {
+    arch: String,
+    in_nc: usize,
+    out_nc: usize,
+    scale: usize,
+    nf: usize,
+    nb: usize,
+    gc: usize,
+    norm: bool,
+    num_groups: usize,
+    num_heads: usize,
+    window_size: usize,
+    upsampler: Upsampler,
+}
+
+impl SyntheticModel {
+    /// A model with the hyperparameters of the official releases of `arch`,
+    /// named like on the command line (old, new, compact, span, swinir...)
+    pub fn new(arch: &str) -> Self {
+        Self {
+            arch: arch.to_string(),
+            in_nc: 3,
+            out_nc: 3,
+            scale: 4,
+            nf: match arch {
+                "span" => 48,
+                "cugan" => 32,
+                "safmn" => 36,
+                "swinir" | "hat" | "dat" | "srformer" => 180,
+                _ => 64,
+            },
+            nb: match arch {
+                "compact" => 16,
+                "omnisr" => 1,
+                "safmn" => 8,
+                "swinir" | "hat" | "dat" | "srformer" => 6,
+                _ => 23,
+            },
+            gc: 32,
+            norm: true,
+            num_groups: match arch {
+                "omnisr" => 5,
+                _ => 6,
+            },
+            num_heads: 6,
+            window_size: match arch {
+                "hat" => 16,
+                "srformer" => 24,
+                _ => 8,
+            },
+            upsampler: Upsampler::PixelShuffle,
+        }
+    }
+
+    pub fn in_channels(mut self, in_nc: usize) -> Self {
+        self.in_nc = in_nc;
+        self
+    }
+
+    pub fn out_channels(mut self, out_nc: usize) -> Self {
+        self.out_nc = out_nc;
+        self
+    }
+
+    /// 1, 2 or 4 for new-arch models, a power of two for old-arch and SPSR
+    /// ones, a power of two or 3 for the transformers, 2, 3 or 4 for
+    /// Real-CUGAN ones and anything for compact and SPAN ones
+    pub fn scale(mut self, scale: usize) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Real-CUGAN models need at least 4, their widths are multiples of it and
+    /// their squeeze-excitations divide them by 16. Omni-SR and SAFMN ones
+    /// need a multiple of 4.
+    pub fn num_features(mut self, nf: usize) -> Self {
+        self.nf = nf;
+        self
+    }
+
+    /// Number of RRDB blocks, of hidden conv layers for compact models or of
+    /// transformer blocks in every residual group of the transformers and
+    /// Omni-SR. SPAN models always have six blocks and SPSR ones need at
+    /// least 20.
+    pub fn num_blocks(mut self, nb: usize) -> Self {
+        self.nb = nb;
+        self
+    }
+
+    /// Growth channels of the dense blocks, unused by compact models
+    pub fn growth_channels(mut self, gc: usize) -> Self {
+        self.gc = gc;
+        self
+    }
+
+    /// Whether SPAN models normalise their input, unused by the others
+    pub fn norm(mut self, norm: bool) -> Self {
+        self.norm = norm;
+        self
+    }
+
+    /// Number of residual groups of the transformers and Omni-SR
+    pub fn num_groups(mut self, num_groups: usize) -> Self {
+        self.num_groups = num_groups;
+        self
+    }
+
+    /// Attention heads of the transformers, which must divide the number of
+    /// features. DAT models need an even number of them, and HAT and DAT ones at
+    /// least 30 and 32 features for their narrowest layers.
+    pub fn num_heads(mut self, num_heads: usize) -> Self {
+        self.num_heads = num_heads;
+        self
+    }
+
+    /// Side of the attention windows, even for SRFormer models. Those of DAT
+    /// models are window_size by 4 * window_size, like the official releases.
+    /// Omni-SR models need inputs of at least 15 pixels after the padding to
+    /// a multiple of it.
+    pub fn window_size(mut self, window_size: usize) -> Self {
+        self.window_size = window_size;
+        self
+    }
+
+    /// Upsampler of the transformers. Nearest+conv ones only upscale by 2 or 4
+    /// and ones without an upsampler by 1.
+    pub fn upsampler(mut self, upsampler: Upsampler) -> Self {
+        self.upsampler = upsampler;
+        self
+    }
+
+    pub fn state_dict(&self, device: &Device) -> Result<HashMap<String, Tensor>> {
+        let mut builder = Builder {
+            device,
+            tensors: HashMap::new(),
+        };
+        let (nf, nb, gc) = (self.nf, self.nb, self.gc);
+        match self.arch.as_str() {
+            "old" => {
+                builder.conv("model.0", self.in_nc, nf)?;
+                for i in 0..nb {
+                    builder.rrdb(&format!("model.1.sub.{i}"), nf, gc, true)?;
+                }
+                builder.conv(&format!("model.1.sub.{nb}"), nf, nf)?;
+                let num_ups = (self.scale as f32).log2() as usize;
+                for i in 1..=num_ups {
+                    builder.conv(&format!("model.{}", 3 * i), nf, nf)?;
+                }
+                builder.conv(&format!("model.{}", 3 * num_ups + 2), nf, nf)?;
+                builder.conv(&format!("model.{}", 3 * num_ups + 4), nf, self.out_nc)?;
+            }
+            "new" => {
+                let unshuffle = match self.scale {
+                    1 | 2 | 4 => 4 / self.scale,
+                    scale => {
+                        return Err(Error::Msg(format!(
+                            "New-arch ESRGAN has no x{scale} models"
+                        )))
+                    }
+                };
+                builder.conv("conv_first", self.in_nc * unshuffle * unshuffle, nf)?;
+                for i in 0..nb {
+                    builder.rrdb(&format!("body.{i}"), nf, gc, false)?;
+                }
+                for name in ["conv_body", "conv_up1", "conv_up2", "conv_hr"] {
+                    builder.conv(name, nf, nf)?;
+                }
+                builder.conv("conv_last", nf, self.out_nc)?;
+            }
+            "compact" => {
+                builder.conv("body.0", self.in_nc, nf)?;
+                builder.prelu("body.1", nf)?;
+                for i in 0..nb {
+                    builder.conv(&format!("body.{}", 2 * i + 2), nf, nf)?;
+                    builder.prelu(&format!("body.{}", 2 * i + 3), nf)?;
+                }
+                let out = self.out_nc * self.scale * self.scale;
+                builder.conv(&format!("body.{}", 2 * nb + 2), nf, out)?;
+            }
+            "span" => {
+                builder.conv3xc("conv_1", self.in_nc, nf)?;
+                for i in 1..=6 {
+                    for conv in ["c1_r", "c2_r", "c3_r"] {
+                        builder.conv3xc(&format!("block_{i}.{conv}"), nf, nf)?;
+                    }
+                }
+                builder.conv_k("conv_cat", nf * 4, nf, 1)?;
+                builder.conv3xc("conv_2", nf, nf)?;
+                let out = self.out_nc * self.scale * self.scale;
+                builder.conv("upsampler.0", nf, out)?;
+                if !self.norm {
+                    let no_norm = Tensor::zeros(1, candle_core::DType::F32, builder.device)?;
+                    builder.tensors.insert("no_norm".to_string(), no_norm);
+                }
+            }
+            "swinir" | "hat" | "srformer" => {
+                builder.conv("conv_first", self.in_nc, nf)?;
+                builder.layer_norm("patch_embed.norm", nf)?;
+                for i in 0..self.num_groups {
+                    for j in 0..nb {
+                        let prefix = format!("layers.{i}.residual_group.blocks.{j}");
+                        self.swin_block(&mut builder, &prefix)?;
+                    }
+                    if self.arch == "hat" {
+                        let prefix = format!("layers.{i}.residual_group.overlap_attn");
+                        let ws = self.window_size;
+                        let side = 2 * ws + ws / 2 - 1;
+                        builder.layer_norm(&format!("{prefix}.norm1"), nf)?;
+                        builder.linear(&format!("{prefix}.qkv"), nf, nf * 3)?;
+                        builder.linear(&format!("{prefix}.proj"), nf, nf)?;
+                        builder.table(
+                            &format!("{prefix}.relative_position_bias_table"),
+                            side * side,
+                            self.num_heads,
+                        )?;
+                        builder.layer_norm(&format!("{prefix}.norm2"), nf)?;
+                        builder.linear(&format!("{prefix}.mlp.fc1"), nf, nf * 2)?;
+                        builder.linear(&format!("{prefix}.mlp.fc2"), nf * 2, nf)?;
+                    }
+                    builder.conv(&format!("layers.{i}.conv"), nf, nf)?;
+                }
+                builder.layer_norm("norm", nf)?;
+                builder.conv("conv_after_body", nf, nf)?;
+                builder.upsampler(self.upsampler, nf, self.out_nc, self.scale)?;
+            }
+            "dat" => {
+                builder.conv("conv_first", self.in_nc, nf)?;
+                builder.layer_norm("before_RG.1", nf)?;
+                for i in 0..self.num_groups {
+                    for j in 0..nb {
+                        self.dat_block(&mut builder, &format!("layers.{i}.blocks.{j}"), j)?;
+                    }
+                    builder.conv(&format!("layers.{i}.conv"), nf, nf)?;
+                }
+                builder.layer_norm("norm", nf)?;
+                builder.conv("conv_after_body", nf, nf)?;
+                builder.upsampler(self.upsampler, nf, self.out_nc, self.scale)?;
+            }
+            "cugan" => {
+                // The U-Nets of x4 models upscale by 2 and have wider outputs
+                let c_out = if self.scale == 4 { nf * 2 } else { self.out_nc };
+                let bottom_k = if self.scale == 3 { 5 } else { 4 };
+                builder.unet_conv("unet1.conv1", self.in_nc, nf, nf * 2, false)?;
+                builder.conv_k("unet1.conv1_down", nf * 2, nf * 2, 2)?;
+                builder.unet_conv("unet1.conv2", nf * 2, nf * 4, nf * 2, true)?;
+                builder.conv_transpose("unet1.conv2_up", nf * 2, nf * 2, 2)?;
+                builder.conv("unet1.conv3", nf * 2, nf * 2)?;
+                builder.conv_transpose("unet1.conv_bottom", nf * 2, c_out, bottom_k)?;
+                builder.unet_conv("unet2.conv1", c_out, nf, nf * 2, false)?;
+                builder.conv_k("unet2.conv1_down", nf * 2, nf * 2, 2)?;
+                builder.unet_conv("unet2.conv2", nf * 2, nf * 2, nf * 4, true)?;
+                builder.conv_k("unet2.conv2_down", nf * 4, nf * 4, 2)?;
+                builder.unet_conv("unet2.conv3", nf * 4, nf * 8, nf * 4, true)?;
+                builder.conv_transpose("unet2.conv3_up", nf * 4, nf * 4, 2)?;
+                builder.unet_conv("unet2.conv4", nf * 4, nf * 2, nf * 2, true)?;
+                builder.conv_transpose("unet2.conv4_up", nf * 2, nf * 2, 2)?;
+                builder.conv("unet2.conv5", nf * 2, nf * 2)?;
+                builder.conv("unet2.conv_bottom", nf * 2, c_out)?;
+                if self.scale == 4 {
+                    builder.conv("conv_final", nf * 2, self.out_nc * 4)?;
+                }
+            }
+            "omnisr" => {
+                builder.conv("input", self.in_nc, nf)?;
+                let esa_channels = usize::max(nf / 4, 16);
+                for i in 0..self.num_groups {
+                    let group = format!("residual_layer.{i}");
+                    for j in 0..nb {
+                        self.osa_block(&mut builder, &format!("{group}.residual_layer.{j}.layer"))?;
+                    }
+                    builder.conv_k(&format!("{group}.residual_layer.{nb}"), nf, nf, 1)?;
+                    let esa = format!("{group}.esa");
+                    builder.conv_k(&format!("{esa}.conv1"), nf, esa_channels, 1)?;
+                    builder.conv_k(&format!("{esa}.conv_f"), esa_channels, esa_channels, 1)?;
+                    builder.conv(&format!("{esa}.conv2"), esa_channels, esa_channels)?;
+                    builder.conv(&format!("{esa}.conv3"), esa_channels, esa_channels)?;
+                    builder.conv_k(&format!("{esa}.conv4"), esa_channels, nf, 1)?;
+                }
+                builder.conv("output", nf, nf)?;
+                builder.conv("up.0", nf, self.out_nc * self.scale * self.scale)?;
+            }
+            "safmn" => {
+                builder.conv("to_feat", self.in_nc, nf)?;
+                for i in 0..nb {
+                    let block = format!("feats.{i}");
+                    builder.layer_norm(&format!("{block}.norm1"), nf)?;
+                    builder.layer_norm(&format!("{block}.norm2"), nf)?;
+                    for j in 0..4 {
+                        builder.conv_k(&format!("{block}.safm.mfr.{j}"), 1, nf / 4, 3)?;
+                    }
+                    builder.conv_k(&format!("{block}.safm.aggr"), nf, nf, 1)?;
+                    builder.conv(&format!("{block}.ccm.ccm.0"), nf, nf * 2)?;
+                    builder.conv_k(&format!("{block}.ccm.ccm.2"), nf * 2, nf, 1)?;
+                }
+                builder.conv("to_img.0", nf, self.out_nc * self.scale * self.scale)?;
+            }
+            "spsr" => {
+                // The trunk is an old-arch model without its last conv
+                builder.conv("model.0", self.in_nc, nf)?;
+                for i in 0..nb {
+                    builder.rrdb(&format!("model.1.sub.{i}"), nf, gc, true)?;
+                }
+                builder.conv(&format!("model.1.sub.{nb}"), nf, nf)?;
+                let num_ups = (self.scale as f32).log2() as usize;
+                for i in 1..=num_ups {
+                    builder.conv(&format!("model.{}", 3 * i), nf, nf)?;
+                }
+                builder.conv(&format!("model.{}", 3 * num_ups + 2), nf, nf)?;
+                builder.conv("HR_conv1_new.0", nf, nf)?;
+
+                builder.conv("b_fea_conv.0", self.in_nc, nf)?;
+                for i in 1..=4 {
+                    builder.rrdb(&format!("b_block_{i}"), 2 * nf, gc, true)?;
+                    builder.conv(&format!("b_concat_{i}.0"), 2 * nf, nf)?;
+                }
+                builder.conv("b_LR_conv.0", nf, nf)?;
+                for i in 0..num_ups {
+                    builder.conv(&format!("b_module.{}", 3 * i + 1), nf, nf)?;
+                }
+                builder.conv(&format!("b_module.{}", 3 * num_ups), nf, nf)?;
+                builder.conv(&format!("b_module.{}", 3 * num_ups + 2), nf, nf)?;
+                // Only used in training, to supervise the gradient branch
+                builder.conv_k("conv_w.0", nf, self.out_nc, 1)?;
+
+                builder.rrdb("f_block", 2 * nf, gc, true)?;
+                builder.conv("f_concat.0", 2 * nf, nf)?;
+                builder.conv("f_HR_conv0.0", nf, nf)?;
+                builder.conv("f_HR_conv1.0", nf, self.out_nc)?;
+            }
+            arch => return Err(Error::Msg(format!("No synthetic {arch} models"))),
+        }
+        Ok(builder.tensors)
+    }
+
+    /// A block of SwinIR, HAT or SRFormer
+    fn swin_block(&self, builder: &mut Builder, prefix: &str) -> Result<()> {
+        let (nf, ws) = (self.nf, self.window_size);
+        builder.layer_norm(&format!("{prefix}.norm1"), nf)?;
+        if self.arch == "srformer" {
+            // The bias table of the permuted self-attention is for windows of
+            // half the size
+            builder.linear(&format!("{prefix}.attn.q"), nf, nf)?;
+            builder.linear(&format!("{prefix}.attn.kv"), nf, nf / 2)?;
+            let rows = (ws - 1) * (ws - 1);
+            builder.table(
+                &format!("{prefix}.attn.relative_position_bias_table"),
+                rows,
+                self.num_heads,
+            )?;
+        } else {
+            builder.linear(&format!("{prefix}.attn.qkv"), nf, nf * 3)?;
+            let rows = (2 * ws - 1) * (2 * ws - 1);
+            builder.table(
+                &format!("{prefix}.attn.relative_position_bias_table"),
+                rows,
+                self.num_heads,
+            )?;
+        }
+        builder.linear(&format!("{prefix}.attn.proj"), nf, nf)?;
+        if self.arch == "hat" {
+            // The compress ratio and squeeze factor of HAT-M
+            builder.conv(&format!("{prefix}.conv_block.cab.0"), nf, nf / 3)?;
+            builder.conv(&format!("{prefix}.conv_block.cab.2"), nf / 3, nf)?;
+            builder.conv_k(
+                &format!("{prefix}.conv_block.cab.3.attention.1"),
+                nf,
+                nf / 30,
+                1,
+            )?;
+            builder.conv_k(
+                &format!("{prefix}.conv_block.cab.3.attention.3"),
+                nf / 30,
+                nf,
+                1,
+            )?;
+        }
+        builder.layer_norm(&format!("{prefix}.norm2"), nf)?;
+        builder.linear(&format!("{prefix}.mlp.fc1"), nf, nf * 2)?;
+        builder.linear(&format!("{prefix}.mlp.fc2"), nf * 2, nf)
+    }
+
+    /// The `index`th block of a residual group of DAT, which uses spatial
+    /// attention if it is even and channel attention otherwise
+    fn dat_block(&self, builder: &mut Builder, prefix: &str, index: usize) -> Result<()> {
+        let nf = self.nf;
+        let attn = format!("{prefix}.attn");
+        builder.layer_norm(&format!("{prefix}.norm1"), nf)?;
+        builder.linear(&format!("{attn}.qkv"), nf, nf * 3)?;
+        builder.linear(&format!("{attn}.proj"), nf, nf)?;
+        if index % 2 == 0 {
+            let ws = self.window_size;
+            let pos_dim = nf / 2 / 4 / 4;
+            for (i, window) in [(ws, 4 * ws), (4 * ws, ws)].into_iter().enumerate() {
+                let pos = format!("{attn}.attns.{i}.pos");
+                builder.linear(&format!("{pos}.pos_proj"), 2, pos_dim)?;
+                for (j, out_dim) in [pos_dim, pos_dim, self.num_heads / 2]
+                    .into_iter()
+                    .enumerate()
+                {
+                    builder.layer_norm(&format!("{pos}.pos{}.0", j + 1), pos_dim)?;
+                    builder.linear(&format!("{pos}.pos{}.2", j + 1), pos_dim, out_dim)?;
+                }
+                let positions = relative_positions(window, builder.device)?;
+                builder
+                    .tensors
+                    .insert(format!("{attn}.attns.{i}.rpe_biases"), positions);
+            }
+        } else {
+            let temperature = Tensor::ones(
+                (self.num_heads, 1, 1),
+                candle_core::DType::F32,
+                builder.device,
+            )?;
+            builder
+                .tensors
+                .insert(format!("{attn}.temperature"), temperature);
+        }
+        // Depthwise convs have a single input channel per group
+        builder.conv_k(&format!("{attn}.dwconv.0"), 1, nf, 3)?;
+        builder.batch_norm(&format!("{attn}.dwconv.1"), nf)?;
+        builder.conv_k(&format!("{attn}.channel_interaction.1"), nf, nf / 8, 1)?;
+        builder.batch_norm(&format!("{attn}.channel_interaction.2"), nf / 8)?;
+        builder.conv_k(&format!("{attn}.channel_interaction.4"), nf / 8, nf, 1)?;
+        builder.conv_k(&format!("{attn}.spatial_interaction.0"), nf, nf / 16, 1)?;
+        builder.batch_norm(&format!("{attn}.spatial_interaction.1"), nf / 16)?;
+        builder.conv_k(&format!("{attn}.spatial_interaction.3"), nf / 16, 1, 1)?;
+
+        let hidden = nf * 4;
+        builder.layer_norm(&format!("{prefix}.norm2"), nf)?;
+        builder.linear(&format!("{prefix}.ffn.fc1"), nf, hidden)?;
+        builder.layer_norm(&format!("{prefix}.ffn.sg.norm"), hidden / 2)?;
+        builder.conv_k(&format!("{prefix}.ffn.sg.conv"), 1, hidden / 2, 3)?;
+        builder.linear(&format!("{prefix}.ffn.fc2"), hidden / 2, nf)
+    }
+
+    /// An omni self-attention block of Omni-SR, whose layers are numbered like
+    /// the `nn.Sequential` they come from, rearranges included
+    fn osa_block(&self, builder: &mut Builder, prefix: &str) -> Result<()> {
+        let (nf, ws) = (self.nf, self.window_size);
+        builder.conv_k(&format!("{prefix}.0.fn.0"), nf, nf, 1)?;
+        builder.conv_k(&format!("{prefix}.0.fn.2"), 1, nf, 3)?;
+        builder.linear_no_bias(&format!("{prefix}.0.fn.4.gate.1"), nf, nf / 4)?;
+        builder.linear_no_bias(&format!("{prefix}.0.fn.4.gate.3"), nf / 4, nf)?;
+        builder.conv_k(&format!("{prefix}.0.fn.5"), nf, nf, 1)?;
+        for i in [2, 8] {
+            builder.layer_norm(&format!("{prefix}.{i}.norm"), nf)?;
+            builder.linear_no_bias(&format!("{prefix}.{i}.fn.to_qkv"), nf, nf * 3)?;
+            builder.linear_no_bias(&format!("{prefix}.{i}.fn.to_out.0"), nf, nf)?;
+            let rows = (2 * ws - 1) * (2 * ws - 1);
+            builder.table(&format!("{prefix}.{i}.fn.rel_pos_bias.weight"), rows, 4)?;
+        }
+        for i in [5, 11] {
+            builder.layer_norm(&format!("{prefix}.{i}.norm"), nf)?;
+            let temperature = Tensor::ones((4, 1, 1), candle_core::DType::F32, builder.device)?;
+            builder
+                .tensors
+                .insert(format!("{prefix}.{i}.fn.temperature"), temperature);
+            builder.conv_no_bias(&format!("{prefix}.{i}.fn.qkv"), nf, nf * 3, 1)?;
+            builder.conv_no_bias(&format!("{prefix}.{i}.fn.qkv_dwconv"), 1, nf * 3, 3)?;
+            builder.conv_no_bias(&format!("{prefix}.{i}.fn.project_out"), nf, nf, 1)?;
+        }
+        for i in [4, 6, 10, 12] {
+            builder.layer_norm(&format!("{prefix}.{i}.norm"), nf)?;
+            builder.conv_no_bias(&format!("{prefix}.{i}.fn.project_in"), nf, nf * 2, 1)?;
+            builder.conv_no_bias(&format!("{prefix}.{i}.fn.dwconv"), 1, nf * 2, 3)?;
+            builder.conv_no_bias(&format!("{prefix}.{i}.fn.project_out"), nf, nf, 1)?;
+        }
+        Ok(())
+    }
+
+    /// Write the state dict to a safetensors file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        candle_core::safetensors::save(&self.state_dict(&Device::Cpu)?, path)
+    }
+}
+
+/// State dict of a RetinaFace detector with random weights, laid out like
+/// facexlib's `detection_Resnet50_Final`. Random weights find faces anywhere
+/// or nowhere, tests set the biases of the heads to place them.
+pub fn retinaface_state_dict(
+    config: &RetinaFaceConfig,
+    device: &Device,
+) -> Result<HashMap<String, Tensor>> {
+    let mut builder = Builder {
+        device,
+        tensors: HashMap::new(),
+    };
+    let w = config.width;
+    builder.conv_no_bias("body.conv1", 3, w, 7)?;
+    builder.batch_norm("body.bn1", w)?;
+    let mut c_in = w;
+    for (i, &num_blocks) in config.layers.iter().enumerate() {
+        let planes = w << i;
+        for j in 0..num_blocks {
+            let block = format!("body.layer{}.{j}", i + 1);
+            builder.conv_no_bias(&format!("{block}.conv1"), c_in, planes, 1)?;
+            builder.batch_norm(&format!("{block}.bn1"), planes)?;
+            builder.conv_no_bias(&format!("{block}.conv2"), planes, planes, 3)?;
+            builder.batch_norm(&format!("{block}.bn2"), planes)?;
+            builder.conv_no_bias(&format!("{block}.conv3"), planes, planes * 4, 1)?;
+            builder.batch_norm(&format!("{block}.bn3"), planes * 4)?;
+            if j == 0 {
+                builder.conv_no_bias(&format!("{block}.downsample.0"), c_in, planes * 4, 1)?;
+                builder.batch_norm(&format!("{block}.downsample.1"), planes * 4)?;
+            }
+            c_in = planes * 4;
+        }
+    }
+
+    let c = config.out_channels;
+    let mut conv_bn = |prefix: &str, c_in: usize, c_out: usize, k: usize| {
+        builder.conv_no_bias(&format!("{prefix}.0"), c_in, c_out, k)?;
+        builder.batch_norm(&format!("{prefix}.1"), c_out)
+    };
+    for i in 1..=3 {
+        conv_bn(&format!("fpn.output{i}"), (w << i) * 4, c, 1)?;
+        let ssh = format!("ssh{i}");
+        conv_bn(&format!("{ssh}.conv3X3"), c, c / 2, 3)?;
+        conv_bn(&format!("{ssh}.conv5X5_1"), c, c / 4, 3)?;
+        conv_bn(&format!("{ssh}.conv5X5_2"), c / 4, c / 4, 3)?;
+        conv_bn(&format!("{ssh}.conv7X7_2"), c / 4, c / 4, 3)?;
+        conv_bn(&format!("{ssh}.conv7x7_3"), c / 4, c / 4, 3)?;
+    }
+    conv_bn("fpn.merge1", c, c, 3)?;
+    conv_bn("fpn.merge2", c, c, 3)?;
+    // Two anchors for every pixel of every level
+    for i in 0..3 {
+        builder.conv_k(&format!("ClassHead.{i}.conv1x1"), c, 2 * 2, 1)?;
+        builder.conv_k(&format!("BboxHead.{i}.conv1x1"), c, 2 * 4, 1)?;
+        builder.conv_k(&format!("LandmarkHead.{i}.conv1x1"), c, 2 * 10, 1)?;
+    }
+    Ok(builder.tensors)
+}
+
+/// State dict of a `GFPGANv1Clean` with random weights, including the style
+/// MLP, the noise buffers and the intermediate RGB convs that inference skips
+pub fn gfpgan_state_dict(
+    config: &GFPGANConfig,
+    device: &Device,
+) -> Result<HashMap<String, Tensor>> {
+    let mut builder = Builder {
+        device,
+        tensors: HashMap::new(),
+    };
+    let log_size = config.log_size();
+    let s = config.num_style_feat;
+
+    let first = config.unet_channels(config.out_size);
+    builder.conv_k("conv_body_first", 3, first, 1)?;
+    let mut c_in = first;
+    for (j, i) in (3..=log_size).rev().enumerate() {
+        let c_out = config.unet_channels(1 << (i - 1));
+        builder.res_block(&format!("conv_body_down.{j}"), c_in, c_out)?;
+        c_in = c_out;
+    }
+    let c4 = config.unet_channels(4);
+    builder.conv("final_conv", c_in, c4)?;
+    let linear_out = match config.different_w {
+        true => config.num_latent() * s,
+        false => s,
+    };
+    builder.linear("final_linear", c4 * 4 * 4, linear_out)?;
+    let mut c_in = c4;
+    for (j, i) in (3..=log_size).enumerate() {
+        let c_out = config.unet_channels(1 << i);
+        builder.res_block(&format!("conv_body_up.{j}"), c_in, c_out)?;
+        let c_sft = if config.sft_half { c_out } else { 2 * c_out };
+        for condition in ["condition_scale", "condition_shift"] {
+            builder.conv(&format!("{condition}.{j}.0"), c_out, c_out)?;
+            builder.conv(&format!("{condition}.{j}.2"), c_out, c_sft)?;
+        }
+        builder.conv_k(&format!("toRGB.{j}"), c_out, 3, 1)?;
+        c_in = c_out;
+    }
+
+    let decoder = "stylegan_decoder";
+    for i in 0..8 {
+        builder.linear(&format!("{decoder}.style_mlp.{}", 2 * i + 1), s, s)?;
+    }
+    let c4 = config.decoder_channels(4);
+    let constant = Tensor::randn(0f32, 1., (1, c4, 4, 4), device)?;
+    builder
+        .tensors
+        .insert(format!("{decoder}.constant_input.weight"), constant);
+    builder.style_conv(&format!("{decoder}.style_conv1"), c4, c4, s)?;
+    builder.to_rgb(&format!("{decoder}.to_rgb1"), c4, s)?;
+    let mut c_in = c4;
+    for (j, i) in (3..=log_size).enumerate() {
+        let c_out = config.decoder_channels(1 << i);
+        builder.style_conv(&format!("{decoder}.style_convs.{}", 2 * j), c_in, c_out, s)?;
+        builder.style_conv(
+            &format!("{decoder}.style_convs.{}", 2 * j + 1),
+            c_out,
+            c_out,
+            s,
+        )?;
+        builder.to_rgb(&format!("{decoder}.to_rgbs.{j}"), c_out, s)?;
+        c_in = c_out;
+    }
+    for i in 0..2 * (log_size - 2) + 1 {
+        let side = 1 << ((i + 5) / 2);
+        let noise = Tensor::randn(0f32, 1., (1, 1, side, side), device)?;
+        builder
+            .tensors
+            .insert(format!("{decoder}.noises.noise{i}"), noise);
+    }
+    Ok(builder.tensors)
+}
+
+struct Builder<'a> {
+    device: &'a Device,
+    tensors: HashMap<String, Tensor>,
+}
+
+impl Builder<'_> {
+    fn conv(&mut self, prefix: &str, c_in: usize, c_out: usize) -> Result<()> {
+        self.conv_k(prefix, c_in, c_out, 3)
+    }
+
+    // Same distribution as PyTorch's default conv initialisation
+    fn conv_k(&mut self, prefix: &str, c_in: usize, c_out: usize, k: usize) -> Result<()> {
+        let bound = 1. / ((c_in * k * k) as f32).sqrt();
+        let weight = Tensor::rand(-bound, bound, (c_out, c_in, k, k), self.device)?;
+        let bias = Tensor::rand(-bound, bound, c_out, self.device)?;
+        self.tensors.insert(format!("{prefix}.weight"), weight);
+        self.tensors.insert(format!("{prefix}.bias"), bias);
+        Ok(())
+    }
+
+    fn conv_no_bias(&mut self, prefix: &str, c_in: usize, c_out: usize, k: usize) -> Result<()> {
+        self.conv_k(prefix, c_in, c_out, k)?;
+        self.tensors.remove(&format!("{prefix}.bias"));
+        Ok(())
+    }
+
+    /// Transposed convs have (in, out, k, k) weights, PyTorch initialises
+    /// them from their second dimension
+    fn conv_transpose(&mut self, prefix: &str, c_in: usize, c_out: usize, k: usize) -> Result<()> {
+        let bound = 1. / ((c_out * k * k) as f32).sqrt();
+        let weight = Tensor::rand(-bound, bound, (c_in, c_out, k, k), self.device)?;
+        let bias = Tensor::rand(-bound, bound, c_out, self.device)?;
+        self.tensors.insert(format!("{prefix}.weight"), weight);
+        self.tensors.insert(format!("{prefix}.bias"), bias);
+        Ok(())
+    }
+
+    /// The two convs of a Real-CUGAN U-Net level, and its squeeze-excitation
+    fn unet_conv(
+        &mut self,
+        prefix: &str,
+        c_in: usize,
+        c_mid: usize,
+        c_out: usize,
+        se: bool,
+    ) -> Result<()> {
+        self.conv(&format!("{prefix}.conv.0"), c_in, c_mid)?;
+        self.conv(&format!("{prefix}.conv.2"), c_mid, c_out)?;
+        if se {
+            self.conv_k(&format!("{prefix}.seblock.conv1"), c_out, c_out / 8, 1)?;
+            self.conv_k(&format!("{prefix}.seblock.conv2"), c_out / 8, c_out, 1)?;
+        }
+        Ok(())
+    }
+
+    /// Both branches of a SPAN Conv3XC, and the `eval_conv` PyTorch saves with them,
+    /// which is derived from the branches when loading
+    fn conv3xc(&mut self, prefix: &str, c_in: usize, c_out: usize) -> Result<()> {
+        let gain = 2;
+        self.conv_k(&format!("{prefix}.sk"), c_in, c_out, 1)?;
+        self.conv_k(&format!("{prefix}.conv.0"), c_in, c_in * gain, 1)?;
+        self.conv_k(&format!("{prefix}.conv.1"), c_in * gain, c_out * gain, 3)?;
+        self.conv_k(&format!("{prefix}.conv.2"), c_out * gain, c_out, 1)?;
+        self.conv_k(&format!("{prefix}.eval_conv"), c_in, c_out, 3)
+    }
+
+    // Same distribution as PyTorch's default linear initialisation
+    fn linear(&mut self, prefix: &str, c_in: usize, c_out: usize) -> Result<()> {
+        let bound = 1. / (c_in as f32).sqrt();
+        let weight = Tensor::rand(-bound, bound, (c_out, c_in), self.device)?;
+        let bias = Tensor::rand(-bound, bound, c_out, self.device)?;
+        self.tensors.insert(format!("{prefix}.weight"), weight);
+        self.tensors.insert(format!("{prefix}.bias"), bias);
+        Ok(())
+    }
+
+    fn linear_no_bias(&mut self, prefix: &str, c_in: usize, c_out: usize) -> Result<()> {
+        self.linear(prefix, c_in, c_out)?;
+        self.tensors.remove(&format!("{prefix}.bias"));
+        Ok(())
+    }
+
+    fn layer_norm(&mut self, prefix: &str, channels: usize) -> Result<()> {
+        let weight = Tensor::ones(channels, candle_core::DType::F32, self.device)?;
+        let bias = Tensor::zeros(channels, candle_core::DType::F32, self.device)?;
+        self.tensors.insert(format!("{prefix}.weight"), weight);
+        self.tensors.insert(format!("{prefix}.bias"), bias);
+        Ok(())
+    }
+
+    /// Running statistics of a standard normal distribution
+    fn batch_norm(&mut self, prefix: &str, channels: usize) -> Result<()> {
+        self.layer_norm(prefix, channels)?;
+        let mean = Tensor::zeros(channels, candle_core::DType::F32, self.device)?;
+        let var = Tensor::ones(channels, candle_core::DType::F32, self.device)?;
+        self.tensors.insert(format!("{prefix}.running_mean"), mean);
+        self.tensors.insert(format!("{prefix}.running_var"), var);
+        Ok(())
+    }
+
+    /// A bias table of attention, initialised like the transformers do
+    fn table(&mut self, name: &str, rows: usize, num_heads: usize) -> Result<()> {
+        let table = (Tensor::randn(0f32, 1., (rows, num_heads), self.device)? * 0.02)?;
+        self.tensors.insert(name.to_string(), table);
+        Ok(())
+    }
+
+    /// The convs after the body of the transformers, with the 64 channels of
+    /// every official release
+    fn upsampler(
+        &mut self,
+        upsampler: Upsampler,
+        nf: usize,
+        out_nc: usize,
+        scale: usize,
+    ) -> Result<()> {
+        let num_feat = 64;
+        match upsampler {
+            Upsampler::PixelShuffle => {
+                self.conv("conv_before_upsample.0", nf, num_feat)?;
+                if scale == 3 {
+                    self.conv("upsample.0", num_feat, 9 * num_feat)?;
+                } else {
+                    let num_ups = (scale as f32).log2() as usize;
+                    for i in 0..num_ups {
+                        self.conv(&format!("upsample.{}", 2 * i), num_feat, 4 * num_feat)?;
+                    }
+                }
+                self.conv("conv_last", num_feat, out_nc)
+            }
+            Upsampler::PixelShuffleDirect => self.conv("upsample.0", nf, out_nc * scale * scale),
+            Upsampler::NearestConv => {
+                self.conv("conv_before_upsample.0", nf, num_feat)?;
+                self.conv("conv_up1", num_feat, num_feat)?;
+                if scale == 4 {
+                    self.conv("conv_up2", num_feat, num_feat)?;
+                }
+                self.conv("conv_hr", num_feat, num_feat)?;
+                self.conv("conv_last", num_feat, out_nc)
+            }
+            Upsampler::None => self.conv("conv_last", nf, out_nc),
+        }
+    }
+
+    /// Residual block of the GFPGAN U-Net
+    fn res_block(&mut self, prefix: &str, c_in: usize, c_out: usize) -> Result<()> {
+        self.conv(&format!("{prefix}.conv1"), c_in, c_in)?;
+        self.conv(&format!("{prefix}.conv2"), c_in, c_out)?;
+        self.conv_no_bias(&format!("{prefix}.skip"), c_in, c_out, 1)
+    }
+
+    /// StyleGAN2 modulated conv, its weight has a leading batch dimension and
+    /// its modulation starts with a bias of 1
+    fn modulated_conv(
+        &mut self,
+        prefix: &str,
+        c_in: usize,
+        c_out: usize,
+        k: usize,
+        num_style_feat: usize,
+    ) -> Result<()> {
+        let scale = 1. / ((c_in * k * k) as f64).sqrt();
+        let weight = (Tensor::randn(0f32, 1., (1, c_out, c_in, k, k), self.device)? * scale)?;
+        self.tensors.insert(format!("{prefix}.weight"), weight);
+        self.linear(&format!("{prefix}.modulation"), num_style_feat, c_in)?;
+        let bias = Tensor::ones(c_in, candle_core::DType::F32, self.device)?;
+        self.tensors
+            .insert(format!("{prefix}.modulation.bias"), bias);
+        Ok(())
+    }
+
+    /// StyleGAN2 conv with its noise strength and bias
+    fn style_conv(
+        &mut self,
+        prefix: &str,
+        c_in: usize,
+        c_out: usize,
+        num_style_feat: usize,
+    ) -> Result<()> {
+        self.modulated_conv(
+            &format!("{prefix}.modulated_conv"),
+            c_in,
+            c_out,
+            3,
+            num_style_feat,
+        )?;
+        let noise_weight = Tensor::zeros(1, candle_core::DType::F32, self.device)?;
+        self.tensors
+            .insert(format!("{prefix}.weight"), noise_weight);
+        let bias = Tensor::rand(-0.1f32, 0.1, (1, c_out, 1, 1), self.device)?;
+        self.tensors.insert(format!("{prefix}.bias"), bias);
+        Ok(())
+    }
+
+    fn to_rgb(&mut self, prefix: &str, c_in: usize, num_style_feat: usize) -> Result<()> {
+        self.modulated_conv(
+            &format!("{prefix}.modulated_conv"),
+            c_in,
+            3,
+            1,
+            num_style_feat,
+        )?;
+        let bias = Tensor::rand(-0.1f32, 0.1, (1, 3, 1, 1), self.device)?;
+        self.tensors.insert(format!("{prefix}.bias"), bias);
+        Ok(())
+    }
+
+    fn prelu(&mut self, prefix: &str, channels: usize) -> Result<()> {
+        let weight = Tensor::full(0.25f32, channels, self.device)?;
+        self.tensors.insert(format!("{prefix}.weight"), weight);
+        Ok(())
+    }
+
+    fn rrdb(&mut self, prefix: &str, nf: usize, gc: usize, old_arch: bool) -> Result<()> {
+        // Old-arch convs are wrapped in a Sequential
+        let (rdb, conv_suffix) = if old_arch { ("RDB", ".0") } else { ("rdb", "") };
+        for r in 1..=3 {
+            for c in 1..=5 {
+                let c_out = if c < 5 { gc } else { nf };
+                self.conv(
+                    &format!("{prefix}.{rdb}{r}.conv{c}{conv_suffix}"),
+                    nf + (c - 1) * gc,
+                    c_out,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
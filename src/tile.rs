@@ -0,0 +1,55 @@
+use candle_core::{Result, Tensor};
+use esrgan_candle_rs::descriptor::ModelDescriptor;
+
+/// Extra context around every tile so the seams don't show, for models that
+/// see further than this
+const TILE_PAD: usize = 16;
+
+/// Run `forward` over a (batch, channels, height, width) tensor in tiles of at
+/// most `tile_size` pixels, to bound the memory used by big images.
+///
+/// Each tile is padded with its neighbouring pixels, as many as the receptive
+/// field of the model up to `TILE_PAD`, and the padding is cropped from the
+/// output before the tiles are stitched back together. Tiles start and end on
+/// multiples of the input size the model needs, which the height and width of
+/// `xs` have to be a multiple of too.
+pub fn tiled(
+    xs: &Tensor,
+    tile_size: usize,
+    descriptor: &ModelDescriptor,
+    forward: impl Fn(&Tensor) -> Result<Tensor>,
+) -> Result<Tensor> {
+    let (_b_size, _channels, h, w) = xs.dims4()?;
+    if h <= tile_size && w <= tile_size {
+        return forward(xs);
+    }
+    let multiple = descriptor.input_multiple;
+    let tile_size = tile_size.next_multiple_of(multiple);
+    let pad = descriptor
+        .receptive_field
+        .map_or(TILE_PAD, |field| field.min(TILE_PAD))
+        .next_multiple_of(multiple);
+    let scale = descriptor.scale;
+    let mut rows = vec![];
+    for y in (0..h).step_by(tile_size) {
+        let tile_h = usize::min(tile_size, h - y);
+        let mut row = vec![];
+        for x in (0..w).step_by(tile_size) {
+            let tile_w = usize::min(tile_size, w - x);
+            let (top, left) = (y.saturating_sub(pad), x.saturating_sub(pad));
+            let bottom = usize::min(y + tile_h + pad, h);
+            let right = usize::min(x + tile_w + pad, w);
+            let tile = xs
+                .narrow(2, top, bottom - top)?
+                .narrow(3, left, right - left)?;
+            let out = forward(&tile)?;
+            row.push(out.narrow(2, (y - top) * scale, tile_h * scale)?.narrow(
+                3,
+                (x - left) * scale,
+                tile_w * scale,
+            )?);
+        }
+        rows.push(Tensor::cat(&row, 3)?);
+    }
+    Tensor::cat(&rows, 2)
+}
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use candle_core::{DType, Device, Module, Tensor, Var};
+use candle_nn::{AdamW, Optimizer, ParamsAdamW, VarBuilder, VarMap};
+use clap::builder::RangedU64ValueParser;
+use clap::ValueEnum;
+use esrgan_candle_rs::degradation::{Degradation, DegradationConfig};
+use esrgan_candle_rs::discriminator::{UNetDiscriminatorSN, VGGStyleDiscriminator};
+use esrgan_candle_rs::losses::{l1, relativistic_discriminator_loss, relativistic_generator_loss};
+use esrgan_candle_rs::vgg::{PerceptualLoss, Vgg19};
+use image::{imageops, DynamicImage, RgbImage};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::{build_model, exit_with_error, forward, img2tensor, read_state_dict, ModelArgs};
+use esrgan_candle_rs::resize::{resize, ResizeFilter};
+
+/// How the LR inputs are made from the HR patches
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum LrSynthesis {
+    /// Bicubic downscaling only
+    Bicubic,
+    /// Second-order Real-ESRGAN degradations: blur, resize, noise and JPEG
+    RealEsrgan,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum DiscriminatorArch {
+    /// U-Net with spectral normalisation, from Real-ESRGAN
+    Unet,
+    /// VGG-style, from ESRGAN. Needs a patch size of 128 or 256.
+    Vgg,
+}
+
+/// Fine-tune a model on a folder of high-resolution images
+#[derive(clap::Args, Debug)]
+pub struct TrainArgs {
+    /// Path to the model to start from, in safetensors or pth format
+    #[arg(short, long)]
+    model: String,
+
+    /// Folder with the high-resolution training images
+    #[arg(long)]
+    hr: String,
+
+    /// Folder the checkpoints are written to
+    #[arg(short, long)]
+    output: String,
+
+    /// Device to run the model on
+    /// -1 for CPU, 0 for GPU 0, 1 for GPU 1, etc.
+    #[arg(short, long, default_value = "-1")]
+    device: i32,
+
+    #[command(flatten)]
+    model_args: ModelArgs,
+
+    /// Side of the square high-resolution patches, must be a multiple of the scale
+    #[arg(long, default_value = "128")]
+    patch_size: usize,
+
+    /// Patches per step
+    #[arg(short, long, default_value = "4")]
+    batch_size: usize,
+
+    /// Learning rate of the Adam optimiser
+    #[arg(long, default_value = "0.0001")]
+    lr: f64,
+
+    /// Number of optimiser steps
+    #[arg(long, default_value = "10000")]
+    steps: usize,
+
+    /// Write a checkpoint every this many steps, and after the last one
+    #[arg(long, default_value = "1000", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    save_every: usize,
+
+    /// Print the average loss every this many steps
+    #[arg(long, default_value = "100", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    log_every: usize,
+
+    /// How the LR inputs are synthesised
+    #[arg(long, value_enum, default_value = "bicubic")]
+    degradation: LrSynthesis,
+
+    /// JSON file overriding the default RealESRGAN_x4plus degradation settings
+    #[arg(long)]
+    degradation_config: Option<String>,
+
+    /// Weight of the L1 loss
+    #[arg(long, default_value = "1")]
+    pixel_weight: f64,
+
+    /// Weight of the VGG19 perceptual loss, which needs `--vgg`
+    #[arg(long, default_value = "0")]
+    perceptual_weight: f64,
+
+    /// torchvision VGG19 weights, in safetensors or pth format
+    #[arg(long)]
+    vgg: Option<String>,
+
+    /// VGG19 layers compared by the perceptual loss, as NAME=WEIGHT. Defaults
+    /// to conv1_2=0.1 conv2_2=0.1 conv3_4=1 conv4_4=1 conv5_4=1
+    #[arg(long, value_parser = parse_layer)]
+    perceptual_layer: Vec<(String, f64)>,
+
+    /// Weight of the relativistic GAN loss, 0 trains without a discriminator
+    #[arg(long, default_value = "0")]
+    gan_weight: f64,
+
+    #[arg(long, value_enum, default_value = "unet")]
+    discriminator: DiscriminatorArch,
+
+    /// Discriminator weights to start from, it is randomly initialised otherwise
+    #[arg(long)]
+    discriminator_model: Option<String>,
+
+    /// Learning rate of the discriminator, the same as the model's by default
+    #[arg(long)]
+    d_lr: Option<f64>,
+
+    /// Seed for patch sampling, augmentation and degradations
+    #[arg(long, default_value = "0")]
+    seed: u64,
+}
+
+fn parse_layer(s: &str) -> Result<(String, f64), String> {
+    let (name, weight) = s
+        .split_once('=')
+        .ok_or(format!("expected NAME=WEIGHT, got {s}"))?;
+    let weight = weight
+        .parse::<f64>()
+        .map_err(|err| format!("{weight}: {err}"))?;
+    Ok((name.to_string(), weight))
+}
+
+enum Discriminator {
+    Unet(UNetDiscriminatorSN),
+    Vgg(VGGStyleDiscriminator),
+}
+
+impl Discriminator {
+    fn forward(&self, xs: &Tensor) -> Tensor {
+        match self {
+            Discriminator::Unet(net) => net.forward(xs).unwrap(),
+            Discriminator::Vgg(net) => net.forward(xs).unwrap(),
+        }
+    }
+}
+
+/// Random crop of `img`, randomly flipped and rotated
+pub fn sample_patch(img: &RgbImage, patch_size: usize, rng: &mut StdRng) -> RgbImage {
+    let size = patch_size as u32;
+    let x = rng.random_range(0..=img.width() - size);
+    let y = rng.random_range(0..=img.height() - size);
+    let mut patch = imageops::crop_imm(img, x, y, size, size).to_image();
+    if rng.random_bool(0.5) {
+        patch = imageops::flip_horizontal(&patch);
+    }
+    if rng.random_bool(0.5) {
+        patch = imageops::flip_vertical(&patch);
+    }
+    if rng.random_bool(0.5) {
+        patch = imageops::rotate90(&patch);
+    }
+    return patch;
+}
+
+/// Copy the pretrained weights over the freshly initialised variables
+fn load_weights(varmap: &VarMap, state_dict: &HashMap<String, Tensor>, device: &Device) {
+    for (name, var) in varmap.data().lock().unwrap().iter() {
+        let weight = match state_dict.get(name) {
+            Some(weight) => weight,
+            None => panic!("{name} is missing from the model file"),
+        };
+        var.set(
+            &weight
+                .to_dtype(DType::F32)
+                .unwrap()
+                .to_device(device)
+                .unwrap(),
+        )
+        .unwrap();
+    }
+}
+
+/// Put the weights in the varmap before building the model, so the model
+/// picks them up and only initialises the missing ones
+fn preload(varmap: &VarMap, state_dict: HashMap<String, Tensor>, device: &Device) {
+    let mut data = varmap.data().lock().unwrap();
+    for (name, weight) in state_dict {
+        let weight = weight
+            .to_dtype(DType::F32)
+            .unwrap()
+            .to_device(device)
+            .unwrap();
+        data.insert(name, Var::from_tensor(&weight).unwrap());
+    }
+}
+
+pub fn train(args: &TrainArgs) {
+    let device = match args.device {
+        -1 => Device::Cpu,
+        _ => Device::new_cuda(args.device as usize).unwrap(),
+    };
+
+    let state_dict = read_state_dict(&args.model, &device);
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let model =
+        build_model(&state_dict, &args.model_args, vb).unwrap_or_else(|err| exit_with_error(err));
+    load_weights(&varmap, &state_dict, &device);
+
+    let scale = model.descriptor.scale;
+    assert!(
+        args.patch_size % scale == 0,
+        "patch size {} is not a multiple of the model scale {scale}",
+        args.patch_size
+    );
+    let lr_size = args.patch_size / scale;
+    let degradation = match &args.degradation_config {
+        Some(path) => Degradation::new(DegradationConfig::load(path), scale),
+        None => Degradation::new(DegradationConfig::default(), scale),
+    };
+
+    let perceptual = if args.perceptual_weight > 0. {
+        let path = match &args.vgg {
+            Some(path) => path,
+            None => {
+                exit_with_error("The perceptual loss needs the VGG19 weights, pass them with --vgg")
+            }
+        };
+        let vb = VarBuilder::from_tensors(read_state_dict(path, &device), DType::F32, &device);
+        let layers = if args.perceptual_layer.is_empty() {
+            PerceptualLoss::DEFAULT_LAYERS
+                .iter()
+                .map(|(name, weight)| (name.to_string(), *weight))
+                .collect()
+        } else {
+            args.perceptual_layer.clone()
+        };
+        match PerceptualLoss::new(Vgg19::load(vb).unwrap(), layers) {
+            Ok(loss) => Some(loss),
+            Err(err) => exit_with_error(err),
+        }
+    } else {
+        None
+    };
+
+    let mut d_varmap = VarMap::new();
+    let discriminator = if args.gan_weight > 0. {
+        if let Some(path) = &args.discriminator_model {
+            preload(&d_varmap, read_state_dict(path, &device), &device);
+        }
+        let vb = VarBuilder::from_varmap(&d_varmap, DType::F32, &device);
+        Some(match args.discriminator {
+            DiscriminatorArch::Unet => {
+                Discriminator::Unet(UNetDiscriminatorSN::load(vb, 3, 64, true).unwrap())
+            }
+            DiscriminatorArch::Vgg => {
+                Discriminator::Vgg(VGGStyleDiscriminator::load(vb, 3, 64, args.patch_size).unwrap())
+            }
+        })
+    } else {
+        None
+    };
+
+    let mut files: Vec<PathBuf> = std::fs::read_dir(&args.hr)
+        .unwrap()
+        .map(|file| file.unwrap().path())
+        .collect();
+    files.sort();
+    let mut images = vec![];
+    for path in files {
+        let img = match image::open(&path) {
+            Ok(img) => img.to_rgb8(),
+            Err(err) => {
+                eprintln!("Skipping {}: {err}", path.display());
+                continue;
+            }
+        };
+        if (img.width() as usize) < args.patch_size || (img.height() as usize) < args.patch_size {
+            eprintln!("Skipping {}: smaller than the patch size", path.display());
+            continue;
+        }
+        images.push(img);
+    }
+    assert!(
+        !images.is_empty(),
+        "No usable training images in {}",
+        args.hr
+    );
+    println!(
+        "Fine-tuning a {}x {} model on {} images",
+        scale,
+        model.descriptor.arch,
+        images.len()
+    );
+
+    let params = ParamsAdamW {
+        lr: args.lr,
+        weight_decay: 0.,
+        ..Default::default()
+    };
+    let mut optimizer = AdamW::new(varmap.all_vars(), params).unwrap();
+    let d_params = ParamsAdamW {
+        lr: args.d_lr.unwrap_or(args.lr),
+        weight_decay: 0.,
+        ..Default::default()
+    };
+    let mut d_optimizer = AdamW::new(d_varmap.all_vars(), d_params).unwrap();
+    let mut rng = StdRng::seed_from_u64(args.seed);
+
+    std::fs::create_dir_all(&args.output).unwrap();
+    let stem = Path::new(&args.model)
+        .file_stem()
+        .unwrap()
+        .to_string_lossy();
+
+    let mut loss_sum = 0.;
+    let mut d_loss_sum = 0.;
+    let mut now = Instant::now();
+    for step in 1..=args.steps {
+        let patches: Vec<Tensor> = (0..args.batch_size)
+            .map(|_| {
+                let img = &images[rng.random_range(0..images.len())];
+                let patch = sample_patch(img, args.patch_size, &mut rng);
+                img2tensor(DynamicImage::ImageRgb8(patch), &device, DType::F32)
+            })
+            .collect();
+        let hr = Tensor::cat(&patches, 0).unwrap();
+        let lr = match args.degradation {
+            LrSynthesis::Bicubic => resize(&hr, lr_size, lr_size, ResizeFilter::Bicubic)
+                .unwrap()
+                .clamp(0f32, 1f32)
+                .unwrap(),
+            LrSynthesis::RealEsrgan => degradation.degrade(&hr, &mut rng).unwrap(),
+        };
+
+        let sr = forward(&model, &lr, None);
+        let mut loss = (l1(&sr, &hr).unwrap() * args.pixel_weight).unwrap();
+        if let Some(perceptual) = &perceptual {
+            let perceptual_loss = perceptual.forward(&sr, &hr).unwrap();
+            loss = (loss + (perceptual_loss * args.perceptual_weight).unwrap()).unwrap();
+        }
+        if let Some(discriminator) = &discriminator {
+            let real = discriminator.forward(&hr);
+            let fake = discriminator.forward(&sr);
+            let gan_loss = relativistic_generator_loss(&real, &fake).unwrap();
+            loss = (loss + (gan_loss * args.gan_weight).unwrap()).unwrap();
+        }
+        optimizer.backward_step(&loss).unwrap();
+        loss_sum += loss.to_scalar::<f32>().unwrap() as f64;
+
+        if let Some(discriminator) = &discriminator {
+            let real = discriminator.forward(&hr);
+            let fake = discriminator.forward(&sr.detach().unwrap());
+            let d_loss = relativistic_discriminator_loss(&real, &fake).unwrap();
+            d_optimizer.backward_step(&d_loss).unwrap();
+            d_loss_sum += d_loss.to_scalar::<f32>().unwrap() as f64;
+        }
+
+        if step % args.log_every == 0 {
+            print!("step {step}: loss {:.5}", loss_sum / args.log_every as f64);
+            if discriminator.is_some() {
+                print!(
+                    ", discriminator loss {:.5}",
+                    d_loss_sum / args.log_every as f64
+                );
+            }
+            println!(
+                " ({:.2}s/step)",
+                now.elapsed().as_secs_f64() / args.log_every as f64
+            );
+            loss_sum = 0.;
+            d_loss_sum = 0.;
+            now = Instant::now();
+        }
+
+        if step % args.save_every == 0 || step == args.steps {
+            let path = Path::new(&args.output).join(format!("{stem}_{step}.safetensors"));
+            varmap.save(&path).unwrap();
+            println!("Saved {}", path.display());
+            if let Some(discriminator) = &discriminator {
+                if let Discriminator::Unet(net) = discriminator {
+                    for (name, buffer) in net.spectral_buffers() {
+                        d_varmap.set_one(name, buffer).unwrap();
+                    }
+                }
+                let path = Path::new(&args.output).join(format!("{stem}_{step}_d.safetensors"));
+                d_varmap.save(&path).unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use candle_core::{Error, Module, Result, Tensor};
+use candle_nn as nn;
+
+// Number of convs in each of the five blocks of VGG19
+const BLOCKS: [usize; 5] = [2, 2, 4, 4, 4];
+
+/// Convolutional part of VGG19, with the keys of torchvision's `vgg19`
+/// checkpoint (`features.{i}.weight`)
+#[derive(Debug)]
+pub struct Vgg19 {
+    // (name, conv), in order, e.g. ("conv3_4", ..)
+    convs: Vec<(String, nn::Conv2d)>,
+}
+
+impl Vgg19 {
+    pub fn load(vb: nn::VarBuilder) -> Result<Self> {
+        let config = nn::Conv2dConfig {
+            padding: 1,
+            stride: 1,
+            dilation: 1,
+            groups: 1,
+        };
+        let mut convs = vec![];
+        let mut index = 0;
+        let mut c_in = 3;
+        for (block, &num_convs) in BLOCKS.iter().enumerate() {
+            let c_out = usize::min(64 << block, 512);
+            for i in 0..num_convs {
+                let conv = nn::conv2d(c_in, c_out, 3, config, vb.pp(format!("features.{index}")))?;
+                convs.push((format!("conv{}_{}", block + 1, i + 1), conv));
+                c_in = c_out;
+                // conv, relu
+                index += 2;
+            }
+            // max pool
+            index += 1;
+        }
+        Ok(Self { convs })
+    }
+
+    /// Whether `name` is one of the convs, e.g. `conv3_4`
+    pub fn has_layer(&self, name: &str) -> bool {
+        self.convs.iter().any(|(conv, _)| conv == name)
+    }
+
+    /// Outputs of the named convs, before their ReLU. Stops after the last
+    /// requested layer.
+    pub fn features(&self, xs: &Tensor, layers: &[&str]) -> Result<Vec<Tensor>> {
+        if let Some(layer) = layers.iter().find(|layer| !self.has_layer(layer)) {
+            return Err(Error::Msg(format!("VGG19 has no layer named {layer}")));
+        }
+        let mut outputs: Vec<Option<Tensor>> = vec![None; layers.len()];
+        let mut xs = xs.clone();
+        let mut remaining = layers.len();
+        for (name, conv) in &self.convs {
+            if remaining == 0 {
+                break;
+            }
+            // Pool at the start of every block but the first
+            if name.ends_with("_1") && name != "conv1_1" {
+                xs = xs.max_pool2d(2)?;
+            }
+            xs = conv.forward(&xs)?;
+            for (i, layer) in layers.iter().enumerate() {
+                if layer == name {
+                    outputs[i] = Some(xs.clone());
+                    remaining -= 1;
+                }
+            }
+            xs = xs.relu()?;
+        }
+        Ok(outputs.into_iter().flatten().collect())
+    }
+}
+
+/// Weighted L1 distance between VGG19 features of the output and the target,
+/// as in ESRGAN and Real-ESRGAN
+#[derive(Debug)]
+pub struct PerceptualLoss {
+    vgg: Vgg19,
+    layers: Vec<(String, f64)>,
+}
+
+impl PerceptualLoss {
+    /// Layer weights of Real-ESRGAN
+    pub const DEFAULT_LAYERS: [(&'static str, f64); 5] = [
+        ("conv1_2", 0.1),
+        ("conv2_2", 0.1),
+        ("conv3_4", 1.),
+        ("conv4_4", 1.),
+        ("conv5_4", 1.),
+    ];
+
+    /// Fails if one of the layers isn't a conv of VGG19
+    pub fn new(vgg: Vgg19, layers: Vec<(String, f64)>) -> Result<Self> {
+        if let Some((layer, _)) = layers.iter().find(|(layer, _)| !vgg.has_layer(layer)) {
+            return Err(Error::Msg(format!("VGG19 has no layer named {layer}")));
+        }
+        Ok(Self { vgg, layers })
+    }
+
+    /// Normalise images in the 0-1 range with the ImageNet statistics
+    fn normalize(xs: &Tensor) -> Result<Tensor> {
+        let device = xs.device();
+        let mean = Tensor::new(&[0.485f32, 0.456, 0.406], device)?.reshape((1, 3, 1, 1))?;
+        let std = Tensor::new(&[0.229f32, 0.224, 0.225], device)?.reshape((1, 3, 1, 1))?;
+        xs.broadcast_sub(&mean)?.broadcast_div(&std)
+    }
+
+    pub fn forward(&self, output: &Tensor, target: &Tensor) -> Result<Tensor> {
+        let names: Vec<&str> = self.layers.iter().map(|(name, _)| name.as_str()).collect();
+        let output = self.vgg.features(&Self::normalize(output)?, &names)?;
+        let target = self
+            .vgg
+            .features(&Self::normalize(&target.detach()?)?, &names)?;
+        let mut loss = Tensor::zeros((), output[0].dtype(), output[0].device())?;
+        for ((o, t), (_, weight)) in output.iter().zip(&target).zip(&self.layers) {
+            loss = (loss + ((o - t)?.abs()?.mean_all()? * *weight)?)?;
+        }
+        Ok(loss)
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use candle_core::Device;
+use clap::builder::RangedU64ValueParser;
+use image::ImageFormat;
+
+use crate::{load_model, upscale_file, ModelArgs, Precision, ProcessOptions};
+use esrgan_candle_rs::color_fix::ColorFix;
+use esrgan_candle_rs::resize::ResizeFilter;
+
+/// Keep a model loaded and upscale every image that shows up in a folder
+#[derive(clap::Args, Debug)]
+pub struct WatchArgs {
+    /// Path to the model file in safetensors format
+    #[arg(short, long)]
+    model: String,
+
+    /// Folder to watch for new or changed images
+    #[arg(short, long)]
+    input: String,
+
+    /// Folder to save upscaled images
+    #[arg(short, long)]
+    output: String,
+
+    /// Folder for the images that failed, next to a .txt file with the error.
+    /// Defaults to an `errors` folder inside the output folder.
+    #[arg(short, long)]
+    errors: Option<String>,
+
+    /// Device to run the model on
+    /// -1 for CPU, 0 for GPU 0, 1 for GPU 1, etc.
+    #[arg(short, long, default_value = "-1")]
+    device: i32,
+
+    #[command(flatten)]
+    model_args: ModelArgs,
+
+    /// Precision the model runs in. Outputs with NaN or Inf values, which f16
+    /// gives on some models, are computed again in f32.
+    #[arg(long, value_enum, default_value = "f32")]
+    dtype: Precision,
+
+    /// Precision the weights are kept in, --dtype by default. The convs cast
+    /// them to --dtype as they run.
+    #[arg(long, value_enum)]
+    weight_dtype: Option<Precision>,
+
+    /// Split images into tiles of at most this many pixels per side to save memory
+    #[arg(short, long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    tile: Option<usize>,
+
+    /// Correct colour shifts of the model by matching the output to the input
+    #[arg(long, value_enum)]
+    color_fix: Option<ColorFix>,
+
+    /// Milliseconds between two scans of the input folder. A file is only picked
+    /// up once its size and modification time stayed the same for a whole scan.
+    #[arg(long, default_value = "1000")]
+    interval: u64,
+}
+
+/// Size and modification time, which stop changing once a copy is done
+type Stamp = (u64, SystemTime);
+
+fn stamp(path: &Path) -> Option<Stamp> {
+    let metadata = fs::metadata(path).ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown error".to_string()
+    }
+}
+
+pub fn watch(args: &WatchArgs) {
+    let device = match args.device {
+        -1 => Device::Cpu,
+        _ => Device::new_cuda(args.device as usize).unwrap(),
+    };
+
+    let weight_dtype = args.weight_dtype.unwrap_or(args.dtype).dtype();
+    let model = load_model(&args.model, &args.model_args, &device, weight_dtype);
+
+    let options = ProcessOptions {
+        dtype: args.dtype.compute_dtype(&device),
+        tile: args.tile,
+        resize: None,
+        color_fix: args.color_fix,
+        faces: None,
+    };
+
+    let out_dir = PathBuf::from(&args.output);
+    let errors_dir = match &args.errors {
+        Some(errors) => PathBuf::from(errors),
+        None => out_dir.join("errors"),
+    };
+    fs::create_dir_all(&out_dir).unwrap();
+    fs::create_dir_all(&errors_dir).unwrap();
+
+    // Files still being written, with the stamp they had at the last scan
+    let mut pending: HashMap<PathBuf, Stamp> = HashMap::new();
+    // Stamp of every file at the time it was upscaled or failed
+    let mut done: HashMap<PathBuf, Stamp> = HashMap::new();
+
+    println!("Watching {}", args.input);
+    loop {
+        // The folder can go away or be unreadable for a while (network
+        // shares, removable drives), try again at the next scan
+        let entries = fs::read_dir(&args.input)
+            .map_err(|err| eprintln!("Failed to read {}: {}", args.input, err));
+        for entry in entries.into_iter().flatten() {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(err) => {
+                    eprintln!("Failed to read {}: {}", args.input, err);
+                    break;
+                }
+            };
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            // Hidden files and anything without an image extension, like the
+            // temporary files of copy tools
+            if name.starts_with('.') || ImageFormat::from_path(&path).is_err() {
+                continue;
+            }
+            let Some(current) = stamp(&path) else {
+                continue;
+            };
+            if done.get(&path) == Some(&current) {
+                continue;
+            }
+            let out_path = out_dir.join(&name);
+            if let Entry::Vacant(vacant) = done.entry(path.clone()) {
+                // Already upscaled, or already failed, before a restart
+                let error_path = errors_dir.join(format!("{name}.txt"));
+                let handled = [&out_path, &error_path]
+                    .iter()
+                    .filter_map(|p| stamp(p))
+                    .any(|(_, modified)| modified >= current.1);
+                if handled {
+                    vacant.insert(current);
+                    continue;
+                }
+            }
+            if pending.insert(path.clone(), current) != Some(current) {
+                // New or still growing, look again at the next scan
+                continue;
+            }
+            pending.remove(&path);
+
+            let result = catch_unwind(AssertUnwindSafe(|| {
+                upscale_file(
+                    &model,
+                    &device,
+                    &path,
+                    &out_path,
+                    &options,
+                    ResizeFilter::Lanczos,
+                )
+            }));
+            let error = match result {
+                Ok(Ok(())) => None,
+                Ok(Err(err)) => Some(err.to_string()),
+                Err(payload) => Some(panic_message(payload)),
+            };
+            match error {
+                None => println!("Saved {}", name),
+                Some(error) => {
+                    eprintln!("Failed {}: {}", name, error);
+                    let _ = fs::copy(&path, errors_dir.join(&name));
+                    let _ = fs::write(errors_dir.join(format!("{name}.txt")), error);
+                }
+            }
+            // Only upscale it again if it changes
+            done.insert(path, current);
+        }
+        thread::sleep(Duration::from_millis(args.interval));
+    }
+}
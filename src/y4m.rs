@@ -0,0 +1,248 @@
+use std::io::{self, BufRead, Write};
+
+use image::RgbImage;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Chroma {
+    C420,
+    C422,
+    C444,
+    Mono,
+}
+
+impl Chroma {
+    fn parse(tag: &str) -> io::Result<Self> {
+        match tag {
+            "420" | "420jpeg" | "420paldv" | "420mpeg2" => Ok(Chroma::C420),
+            "422" => Ok(Chroma::C422),
+            "444" => Ok(Chroma::C444),
+            "mono" => Ok(Chroma::Mono),
+            _ => Err(invalid(&format!(
+                "unsupported Y4M colour space C{tag}, only 8-bit is supported"
+            ))),
+        }
+    }
+
+    /// Size of one chroma plane for a luma plane of `width` x `height`
+    fn plane_size(&self, width: usize, height: usize) -> (usize, usize) {
+        match self {
+            Chroma::C420 => ((width + 1) / 2, (height + 1) / 2),
+            Chroma::C422 => ((width + 1) / 2, height),
+            Chroma::C444 => (width, height),
+            Chroma::Mono => (0, 0),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub width: usize,
+    pub height: usize,
+    pub chroma: Chroma,
+    /// Full range (0-255) instead of the usual studio range (16-235)
+    pub full_range: bool,
+    /// All tags but the size (colour space, frame rate, interlacing, aspect, ...),
+    /// passed through untouched
+    tags: Vec<String>,
+}
+
+impl Header {
+    fn parse(line: &str) -> io::Result<Self> {
+        let mut fields = line.split_ascii_whitespace();
+        if fields.next() != Some("YUV4MPEG2") {
+            return Err(invalid("not a YUV4MPEG2 stream"));
+        }
+        let mut header = Header {
+            width: 0,
+            height: 0,
+            chroma: Chroma::C420,
+            full_range: false,
+            tags: vec![],
+        };
+        for field in fields {
+            // The tag is a single ASCII letter, others don't split anywhere
+            let tag_len = field.chars().next().unwrap().len_utf8();
+            let (tag, value) = field.split_at(tag_len);
+            match tag {
+                "W" => header.width = value.parse().map_err(|_| invalid("bad width"))?,
+                "H" => header.height = value.parse().map_err(|_| invalid("bad height"))?,
+                _ => {
+                    if tag == "C" {
+                        header.chroma = Chroma::parse(value)?;
+                    }
+                    if field == "XCOLORRANGE=FULL" {
+                        header.full_range = true;
+                    }
+                    header.tags.push(field.to_string());
+                }
+            }
+        }
+        if header.width == 0 || header.height == 0 {
+            return Err(invalid("missing frame size"));
+        }
+        Ok(header)
+    }
+
+    /// The same stream with a different frame size
+    pub fn resized(&self, width: usize, height: usize) -> Header {
+        Header {
+            width,
+            height,
+            ..self.clone()
+        }
+    }
+
+    fn frame_size(&self) -> usize {
+        let (cw, ch) = self.chroma.plane_size(self.width, self.height);
+        self.width * self.height + 2 * cw * ch
+    }
+
+    // Rec. 709 for HD material and Rec. 601 below, which is what players
+    // assume when the stream doesn't say.
+    fn coefficients(&self) -> (f32, f32) {
+        if self.height >= 720 {
+            (0.2126, 0.0722)
+        } else {
+            (0.299, 0.114)
+        }
+    }
+}
+
+pub struct Reader<R: BufRead> {
+    pub header: Header,
+    inner: R,
+    buf: Vec<u8>,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut line = String::new();
+        inner.read_line(&mut line)?;
+        let header = Header::parse(line.trim_end())?;
+        let buf = vec![0; header.frame_size()];
+        Ok(Self { header, inner, buf })
+    }
+
+    /// Reads the next frame as RGB, `None` at the end of the stream.
+    pub fn next_frame(&mut self) -> io::Result<Option<RgbImage>> {
+        let mut line = vec![];
+        if self.inner.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        if !line.starts_with(b"FRAME") {
+            return Err(invalid("expected FRAME marker"));
+        }
+        self.inner.read_exact(&mut self.buf)?;
+        Ok(Some(yuv2rgb(&self.header, &self.buf)))
+    }
+}
+
+pub struct Writer<W: Write> {
+    header: Header,
+    inner: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut inner: W, header: Header) -> io::Result<Self> {
+        write!(inner, "YUV4MPEG2 W{} H{}", header.width, header.height)?;
+        for tag in &header.tags {
+            write!(inner, " {tag}")?;
+        }
+        writeln!(inner)?;
+        Ok(Self { header, inner })
+    }
+
+    pub fn write_frame(&mut self, img: &RgbImage) -> io::Result<()> {
+        if img.width() as usize != self.header.width || img.height() as usize != self.header.height
+        {
+            return Err(invalid("frame size doesn't match the stream header"));
+        }
+        self.inner.write_all(b"FRAME\n")?;
+        self.inner.write_all(&rgb2yuv(&self.header, img))?;
+        // Keep the pipe moving, the consumer is usually an encoder
+        self.inner.flush()
+    }
+}
+
+fn yuv2rgb(header: &Header, data: &[u8]) -> RgbImage {
+    let (width, height) = (header.width, header.height);
+    let (cw, ch) = header.chroma.plane_size(width, height);
+    let (sx, sy) = (
+        if cw == 0 { 0 } else { (width + cw - 1) / cw },
+        if ch == 0 { 0 } else { (height + ch - 1) / ch },
+    );
+    let (kr, kb) = header.coefficients();
+    let kg = 1. - kr - kb;
+    let (y_plane, chroma) = data.split_at(width * height);
+    let (u_plane, v_plane) = chroma.split_at(cw * ch);
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            let mut luma = y_plane[y * width + x] as f32;
+            let (mut u, mut v) = if cw == 0 {
+                (128., 128.)
+            } else {
+                let i = (y / sy) * cw + x / sx;
+                (u_plane[i] as f32, v_plane[i] as f32)
+            };
+            if !header.full_range {
+                luma = (luma - 16.) * 255. / 219.;
+                u = (u - 128.) * 255. / 224. + 128.;
+                v = (v - 128.) * 255. / 224. + 128.;
+            }
+            let (pb, pr) = (u - 128., v - 128.);
+            let r = luma + 2. * (1. - kr) * pr;
+            let b = luma + 2. * (1. - kb) * pb;
+            let g = (luma - kr * r - kb * b) / kg;
+            rgb.extend([r, g, b].map(|c| c.round().clamp(0., 255.) as u8));
+        }
+    }
+    RgbImage::from_vec(width as u32, height as u32, rgb).unwrap()
+}
+
+fn rgb2yuv(header: &Header, img: &RgbImage) -> Vec<u8> {
+    let (width, height) = (header.width, header.height);
+    let (cw, ch) = header.chroma.plane_size(width, height);
+    let (kr, kb) = header.coefficients();
+    let kg = 1. - kr - kb;
+    let mut y_plane = Vec::with_capacity(width * height);
+    let mut pb = vec![0f32; cw * ch];
+    let mut pr = vec![0f32; cw * ch];
+    let mut count = vec![0f32; cw * ch];
+    for (x, y, pixel) in img.enumerate_pixels() {
+        let [r, g, b] = pixel.0.map(|c| c as f32);
+        let luma = kr * r + kg * g + kb * b;
+        y_plane.push(luma);
+        if cw > 0 {
+            // Box filter every luma pixel into its chroma sample
+            let i = (y as usize * ch / height) * cw + x as usize * cw / width;
+            pb[i] += (b - luma) / (2. * (1. - kb));
+            pr[i] += (r - luma) / (2. * (1. - kr));
+            count[i] += 1.;
+        }
+    }
+    let (luma_scale, luma_offset, chroma_scale) = if header.full_range {
+        (1., 0., 1.)
+    } else {
+        (219. / 255., 16., 224. / 255.)
+    };
+    let mut out = Vec::with_capacity(header.frame_size());
+    out.extend(
+        y_plane
+            .iter()
+            .map(|&l| (l * luma_scale + luma_offset).round().clamp(0., 255.) as u8),
+    );
+    for plane in [&pb, &pr] {
+        out.extend(
+            plane
+                .iter()
+                .zip(&count)
+                .map(|(&c, &n)| (c / n * chroma_scale + 128.).round().clamp(0., 255.) as u8),
+        );
+    }
+    out
+}
@@ -0,0 +1,99 @@
+// Animations written and read back keep their frames, delays and loop count,
+// scaling them copes with frames that don't fit GIF or the canvas, and
+// truncated files are errors.
+
+use std::path::PathBuf;
+
+use esrgan_candle_rs::animation::{self, Animation, Blend, Disposal, Format, Frame};
+use image::{DynamicImage, RgbImage, RgbaImage};
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("esrgan-candle-rs-{}-{name}", std::process::id()))
+}
+
+fn frame(width: u32, height: u32, left: u32, top: u32, delay: (u32, u32)) -> Frame {
+    Frame {
+        image: RgbaImage::from_fn(width, height, |x, y| {
+            image::Rgba([(x * 20) as u8, (y * 20) as u8, 128, 255])
+        }),
+        left,
+        top,
+        delay,
+        disposal: Disposal::None,
+        blend: Blend::Over,
+    }
+}
+
+/// Two frames in the time units of `format`, played 3 times
+fn animation(format: Format, delays: [(u32, u32); 2]) -> Animation {
+    Animation {
+        format,
+        width: 8,
+        height: 6,
+        plays: 3,
+        background: [0; 4],
+        frames: vec![frame(8, 6, 0, 0, delays[0]), frame(4, 2, 2, 4, delays[1])],
+    }
+}
+
+#[test]
+fn round_trips() {
+    let cases = [
+        (Format::Gif, "gif", [(10, 100), (25, 100)]),
+        (Format::Apng, "png", [(1, 10), (3, 40)]),
+        (Format::WebP, "webp", [(120, 1000), (40, 1000)]),
+    ];
+    for (format, extension, delays) in cases {
+        let path = temp_path(&format!("round-trip.{extension}"));
+        animation::write(&animation(format, delays), &path).unwrap();
+        assert_eq!(animation::format(&path), Some(format));
+        let read = animation::read(&path, format).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((read.width, read.height), (8, 6), "{format:?}");
+        assert_eq!(read.plays, 3, "{format:?}");
+        assert_eq!(read.frames.len(), 2, "{format:?}");
+        for (frame, delay) in read.frames.iter().zip(delays) {
+            assert_eq!(frame.delay, delay, "{format:?}");
+        }
+        let second = &read.frames[1];
+        assert_eq!((second.left, second.top), (2, 4), "{format:?}");
+        assert_eq!(second.image.dimensions(), (4, 2), "{format:?}");
+    }
+}
+
+fn upscale(img: DynamicImage, w: u32, h: u32) -> RgbImage {
+    image::imageops::resize(&img.to_rgb8(), w, h, image::imageops::FilterType::Nearest)
+}
+
+#[test]
+fn frames_outside_of_the_canvas_keep_a_pixel() {
+    let mut anim = animation(Format::Apng, [(1, 10); 2]);
+    anim.frames.push(frame(2, 2, 9, 7, (1, 10)));
+    let scaled = anim.scaled(16, 12, upscale);
+    let outside = &scaled.frames[2];
+    assert_eq!((outside.left, outside.top), (18, 14));
+    assert_eq!(outside.image.dimensions(), (1, 1));
+}
+
+#[test]
+fn gif_sizes_are_limited_to_u16() {
+    let path = temp_path("too-big.gif");
+    let anim = animation(Format::Gif, [(10, 100); 2]).scaled(70_000, 4, upscale);
+    assert!(animation::write(&anim, &path).is_err());
+    assert!(!path.exists());
+}
+
+#[test]
+fn truncated_webp_files_are_errors() {
+    let path = temp_path("truncated.webp");
+    animation::write(&animation(Format::WebP, [(120, 1000); 2]), &path).unwrap();
+    let data = std::fs::read(&path).unwrap();
+    // Every prefix reads without panicking, and up to the end of VP8X they fail
+    for len in 0..data.len() {
+        std::fs::write(&path, &data[..len]).unwrap();
+        let read = animation::read(&path, Format::WebP);
+        assert!(len >= 30 || read.is_err(), "{len} bytes");
+    }
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,60 @@
+// The colour corrections match the statistics of the reference, and leave an
+// image alone when it is its own reference.
+
+use candle_core::{Device, Tensor, D};
+use esrgan_candle_rs::color_fix::{color_fix, ColorFix};
+
+fn max_abs_diff(a: &Tensor, b: &Tensor) -> f32 {
+    (a - b)
+        .unwrap()
+        .abs()
+        .unwrap()
+        .flatten_all()
+        .unwrap()
+        .max(0)
+        .unwrap()
+        .to_scalar::<f32>()
+        .unwrap()
+}
+
+/// Per-channel mean and standard deviation of (1, C, H, W)
+fn stats(xs: &Tensor) -> (Vec<f32>, Vec<f32>) {
+    let xs = xs.squeeze(0).unwrap().flatten_from(1).unwrap();
+    let mean = xs.mean_keepdim(D::Minus1).unwrap();
+    let std = xs
+        .broadcast_sub(&mean)
+        .unwrap()
+        .sqr()
+        .unwrap()
+        .mean(D::Minus1)
+        .unwrap()
+        .sqrt()
+        .unwrap();
+    (
+        mean.squeeze(1).unwrap().to_vec1().unwrap(),
+        std.to_vec1().unwrap(),
+    )
+}
+
+#[test]
+fn mean_std_matches_the_reference() {
+    let input = Tensor::rand(0.2f32, 0.6, (1, 3, 12, 17), &Device::Cpu).unwrap();
+    let output = Tensor::rand(0f32, 1., (1, 3, 24, 34), &Device::Cpu).unwrap();
+    let fixed = color_fix(&output, &input, ColorFix::MeanStd).unwrap();
+    assert_eq!(fixed.dims(), output.dims());
+    let (mean, std) = stats(&fixed);
+    let (expected_mean, expected_std) = stats(&input);
+    for c in 0..3 {
+        assert!((mean[c] - expected_mean[c]).abs() < 1e-4, "{mean:?}");
+        assert!((std[c] - expected_std[c]).abs() < 1e-3, "{std:?}");
+    }
+}
+
+#[test]
+fn frequency_methods_keep_an_image_fixed_with_itself() {
+    let xs = Tensor::rand(0f32, 1., (1, 3, 12, 17), &Device::Cpu).unwrap();
+    for method in [ColorFix::Wavelet, ColorFix::Gaussian] {
+        let fixed = color_fix(&xs, &xs, method).unwrap();
+        assert!(max_abs_diff(&fixed, &xs) < 1e-5, "{method:?}");
+    }
+}
@@ -0,0 +1,125 @@
+// Quantised and mixed precision convs against the full precision ones they
+// are made from.
+
+use candle_core::{DType, Device, Module, Tensor};
+use candle_nn::{Conv2dConfig, VarBuilder, VarMap};
+use esrgan_candle_rs::conv::{conv2d, Conv2d, Quantization};
+
+fn random_conv(c_in: usize, c_out: usize, config: Conv2dConfig) -> Conv2d {
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    conv2d(c_in, c_out, 3, config, vb.pp("conv_first")).unwrap()
+}
+
+fn relative_error(actual: &Tensor, expected: &Tensor) -> f32 {
+    let error = (actual - expected)
+        .unwrap()
+        .sqr()
+        .unwrap()
+        .sum_all()
+        .unwrap();
+    let norm = expected.sqr().unwrap().sum_all().unwrap();
+    (error.to_scalar::<f32>().unwrap() / norm.to_scalar::<f32>().unwrap()).sqrt()
+}
+
+#[test]
+fn quantized_conv_matches_float() {
+    for dilation in [1, 2] {
+        let config = Conv2dConfig {
+            padding: dilation,
+            stride: 1,
+            dilation,
+            groups: 1,
+        };
+        let conv = random_conv(32, 16, config);
+        let xs = Tensor::rand(0f32, 1., (2, 32, 11, 13), &Device::Cpu).unwrap();
+        let expected = conv.forward(&xs).unwrap();
+
+        let quantized = conv.quantize(Quantization::Q8_0).unwrap();
+        assert!(quantized.is_quantized());
+        let actual = quantized.forward(&xs).unwrap();
+        assert_eq!(actual.dims(), expected.dims());
+        let error = relative_error(&actual, &expected);
+        assert!(
+            error < 0.01,
+            "relative error {error} with dilation {dilation}"
+        );
+
+        // Fewer bits, larger error
+        let actual = conv
+            .quantize(Quantization::Q4_0)
+            .unwrap()
+            .forward(&xs)
+            .unwrap();
+        assert!(relative_error(&actual, &expected) > error);
+    }
+}
+
+#[test]
+fn unquantizable_convs_stay_float() {
+    let config = Conv2dConfig {
+        padding: 1,
+        stride: 1,
+        dilation: 1,
+        groups: 1,
+    };
+    // 3 * 9 values per output channel, not a multiple of the block size
+    let conv = random_conv(3, 16, config);
+    let strided = random_conv(
+        32,
+        16,
+        Conv2dConfig {
+            stride: 2,
+            ..config
+        },
+    );
+    let grouped = random_conv(
+        32,
+        16,
+        Conv2dConfig {
+            groups: 2,
+            ..config
+        },
+    );
+    for conv in [conv, strided, grouped] {
+        assert!(!conv.quantizable(Quantization::Q8_0));
+        assert!(!conv.quantize(Quantization::Q8_0).unwrap().is_quantized());
+    }
+}
+
+#[test]
+fn convs_know_their_weight() {
+    let config = Conv2dConfig {
+        padding: 1,
+        stride: 1,
+        dilation: 1,
+        groups: 1,
+    };
+    let conv = random_conv(32, 16, config);
+    assert!(conv.quantizable(Quantization::Q8_0));
+    let quantized = conv.quantize(Quantization::Q8_0).unwrap();
+    assert!(!quantized.quantizable(Quantization::Q8_0));
+    assert_eq!(
+        quantized.weight_name().as_deref(),
+        Some("conv_first.weight")
+    );
+}
+
+#[test]
+fn mixed_precision_conv() {
+    let config = Conv2dConfig {
+        padding: 1,
+        stride: 1,
+        dilation: 1,
+        groups: 1,
+    };
+    let conv = random_conv(8, 8, config);
+    let xs = Tensor::rand(0f32, 1., (1, 8, 9, 7), &Device::Cpu).unwrap();
+    let expected = conv.forward(&xs).unwrap();
+
+    // f64 activations through the f32 weights
+    let actual = conv.forward(&xs.to_dtype(DType::F64).unwrap()).unwrap();
+    assert_eq!(actual.dtype(), DType::F64);
+    let actual = actual.to_dtype(DType::F32).unwrap();
+    assert!(relative_error(&actual, &expected) < 1e-6);
+}
@@ -0,0 +1,43 @@
+// The Real-ESRGAN degradations make LR images of the right size in the 0-1
+// range, and draw all their randomness from the RNG they are given, so a seed
+// reproduces the same LR images.
+
+use candle_core::{Device, Tensor};
+use esrgan_candle_rs::degradation::{Degradation, DegradationConfig};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+fn degrade(hr: &Tensor, seed: u64) -> Vec<f32> {
+    let degradation = Degradation::new(DegradationConfig::default(), 4);
+    let mut rng = StdRng::seed_from_u64(seed);
+    let lr = degradation.degrade(hr, &mut rng).unwrap();
+    assert_eq!(lr.dims4().unwrap(), (2, 3, 12, 12));
+    lr.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+}
+
+#[test]
+fn same_seed_same_output() {
+    let hr = Tensor::rand(0f32, 1., (2, 3, 48, 48), &Device::Cpu).unwrap();
+    assert_eq!(degrade(&hr, 7), degrade(&hr, 7));
+}
+
+#[test]
+fn different_seed_different_output() {
+    let hr = Tensor::rand(0f32, 1., (2, 3, 48, 48), &Device::Cpu).unwrap();
+    assert_ne!(degrade(&hr, 7), degrade(&hr, 8));
+}
+
+#[test]
+fn lr_images_are_downscaled_and_in_range() {
+    let hr = Tensor::rand(0f32, 1., (1, 3, 32, 24), &Device::Cpu).unwrap();
+    for scale in [1, 2, 4] {
+        let degradation = Degradation::new(DegradationConfig::default(), scale);
+        for seed in 0..4 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let lr = degradation.degrade(&hr, &mut rng).unwrap();
+            assert_eq!(lr.dims4().unwrap(), (1, 3, 32 / scale, 24 / scale));
+            let values = lr.flatten_all().unwrap().to_vec1::<f32>().unwrap();
+            assert!(values.iter().all(|v| (0. ..=1.).contains(v)));
+        }
+    }
+}
@@ -0,0 +1,52 @@
+// Parameter counts of the model descriptors, which leave out the buffers that
+// checkpoints keep next to the weights.
+
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Tensor};
+use esrgan_candle_rs::descriptor::{count_params, ModelDescriptor};
+use esrgan_candle_rs::synthetic::SyntheticModel;
+
+#[test]
+fn buffers_are_not_parameters() {
+    let zeros = |shape: &[usize], dtype| Tensor::zeros(shape, dtype, &Device::Cpu).unwrap();
+    let sd = HashMap::from([
+        ("conv.weight".to_string(), zeros(&[8, 3, 3, 3], DType::F32)),
+        ("conv.bias".to_string(), zeros(&[8], DType::F16)),
+        ("bn.weight".to_string(), zeros(&[8], DType::F32)),
+        ("bn.running_mean".to_string(), zeros(&[8], DType::F32)),
+        ("bn.running_var".to_string(), zeros(&[8], DType::F32)),
+        ("bn.num_batches_tracked".to_string(), zeros(&[], DType::I64)),
+        (
+            "attn.relative_position_index_SA".to_string(),
+            zeros(&[16, 16], DType::I64),
+        ),
+        (
+            "attn.attn_mask".to_string(),
+            zeros(&[4, 16, 16], DType::F32),
+        ),
+    ]);
+    assert_eq!(count_params(&sd), 8 * 27 + 8 + 8);
+
+    let descriptor = ModelDescriptor::new("test", 2, 3, 8, &sd, &Device::Cpu);
+    assert_eq!(descriptor.params, 8 * 27 + 8 + 8);
+    assert_eq!(descriptor.receptive_field, None);
+    assert_eq!(descriptor.input_multiple, 1);
+    assert_eq!(descriptor.dtypes, [DType::F32, DType::F64, DType::F16]);
+}
+
+#[test]
+fn counts_the_weights_of_a_compact_model() {
+    let sd = SyntheticModel::new("compact")
+        .in_channels(3)
+        .out_channels(3)
+        .scale(2)
+        .num_features(8)
+        .num_blocks(2)
+        .state_dict(&Device::Cpu)
+        .unwrap();
+    // First conv and PReLU, two more of each, then the conv to 3 * 2 * 2
+    // channels
+    let expected = (8 * 27 + 8 + 8) + 2 * (8 * 72 + 8 + 8) + (12 * 72 + 12);
+    assert_eq!(count_params(&sd), expected);
+}
@@ -0,0 +1,148 @@
+// The alignment maths of the face restoration, then the whole pass with a
+// detector whose heads are set to find one known face.
+
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use esrgan_candle_rs::face::{
+    invert_affine, similarity_transform, warp_affine, FaceModel, FaceRestorer, FACE_TEMPLATE,
+};
+use esrgan_candle_rs::gfpgan::{GFPGANConfig, GFPGAN};
+use esrgan_candle_rs::retinaface::{RetinaFace, RetinaFaceConfig};
+use esrgan_candle_rs::synthetic::{gfpgan_state_dict, retinaface_state_dict};
+
+fn vb(state_dict: HashMap<String, Tensor>) -> VarBuilder<'static> {
+    VarBuilder::from_tensors(state_dict, DType::F32, &Device::Cpu)
+}
+
+fn max_abs_diff(a: &Tensor, b: &Tensor) -> f32 {
+    (a - b)
+        .unwrap()
+        .abs()
+        .unwrap()
+        .flatten_all()
+        .unwrap()
+        .max(0)
+        .unwrap()
+        .to_scalar::<f32>()
+        .unwrap()
+}
+
+#[test]
+fn similarity_transform_recovers_a_similarity() {
+    let (scale, angle) = (1.7f32, 0.3f32);
+    let (a, b) = (scale * angle.cos(), scale * angle.sin());
+    let expected = [[a, -b, 12.], [b, a, -7.]];
+    let dst = FACE_TEMPLATE.map(|[x, y]| [a * x - b * y + 12., b * x + a * y - 7.]);
+    let affine = similarity_transform(&FACE_TEMPLATE, &dst);
+    for (row, expected_row) in affine.iter().zip(&expected) {
+        for (v, e) in row.iter().zip(expected_row) {
+            assert!((v - e).abs() < 1e-2, "{affine:?} instead of {expected:?}");
+        }
+    }
+
+    let inverse = invert_affine(&affine);
+    let [x, y] = dst[2];
+    let back = [
+        inverse[0][0] * x + inverse[0][1] * y + inverse[0][2],
+        inverse[1][0] * x + inverse[1][1] * y + inverse[1][2],
+    ];
+    assert!((back[0] - FACE_TEMPLATE[2][0]).abs() < 1e-2);
+    assert!((back[1] - FACE_TEMPLATE[2][1]).abs() < 1e-2);
+}
+
+#[test]
+fn warp_affine_translates_and_fills_the_border() {
+    let img = Tensor::rand(0f32, 1., (1, 2, 6, 7), &Device::Cpu).unwrap();
+    let affine = [[1., 0., -2.], [0., 1., -1.]];
+    let out = warp_affine(&img, &affine, 6, 7, &[0.25, 0.5]).unwrap();
+    let shifted = img.narrow(2, 1, 5).unwrap().narrow(3, 2, 5).unwrap();
+    let inside = out.narrow(2, 0, 5).unwrap().narrow(3, 0, 5).unwrap();
+    assert!(max_abs_diff(&inside, &shifted) < 1e-6);
+    // The last column comes from outside of the image
+    let column = out.narrow(3, 6, 1).unwrap().squeeze(0).unwrap();
+    let column = column.flatten_from(1).unwrap().to_vec2::<f32>().unwrap();
+    assert!(column[0].iter().all(|&v| v == 0.25));
+    assert!(column[1].iter().all(|&v| v == 0.5));
+}
+
+/// A detector whose heads ignore their input. Only the first anchor of the
+/// coarsest level is a face, with the landmarks of a 32 pixel face whose top
+/// left corner is at (16, 16) in a 64x64 image.
+fn detector(with_face: bool) -> RetinaFace {
+    let config = RetinaFaceConfig {
+        width: 4,
+        layers: [1, 1, 1, 1],
+        out_channels: 16,
+    };
+    let mut sd = retinaface_state_dict(&config, &Device::Cpu).unwrap();
+    for (head, k) in [("ClassHead", 2), ("BboxHead", 4), ("LandmarkHead", 10)] {
+        for i in 0..3 {
+            let weight = format!("{head}.{i}.conv1x1.weight");
+            sd.insert(weight.clone(), sd[&weight].zeros_like().unwrap());
+            let mut bias = vec![0f32; 2 * k];
+            if head == "ClassHead" {
+                bias = vec![10., -10., 10., -10.];
+                if i == 2 && with_face {
+                    bias[0..2].copy_from_slice(&[-10., 10.]);
+                }
+            }
+            if head == "LandmarkHead" && i == 2 {
+                // Offsets from the centre of the anchor at (16, 16), in tenths
+                // of its 256 pixel side
+                for (j, [x, y]) in FACE_TEMPLATE.iter().enumerate() {
+                    bias[2 * j] = x / 16. / 25.6;
+                    bias[2 * j + 1] = y / 16. / 25.6;
+                }
+            }
+            let bias = Tensor::new(bias, &Device::Cpu).unwrap();
+            sd.insert(format!("{head}.{i}.conv1x1.bias"), bias);
+        }
+    }
+    RetinaFace::load(vb(sd), &config).unwrap()
+}
+
+fn restorer(with_face: bool) -> FaceRestorer {
+    let config = GFPGANConfig {
+        out_size: 32,
+        num_style_feat: 8,
+        narrow: 1. / 32.,
+        ..Default::default()
+    };
+    let model = GFPGAN::load(
+        vb(gfpgan_state_dict(&config, &Device::Cpu).unwrap()),
+        &config,
+    );
+    FaceRestorer::new(detector(with_face), FaceModel::GFPGAN(model.unwrap()))
+}
+
+#[test]
+fn detects_the_face_of_the_heads() {
+    let img = Tensor::rand(0f32, 1., (1, 3, 64, 64), &Device::Cpu).unwrap();
+    let faces = restorer(true).detect(&img).unwrap();
+    // The same face on every pixel of the level, the overlapping ones are
+    // suppressed
+    assert_eq!(faces.len(), 1);
+    for (point, [x, y]) in faces[0].landmarks.iter().zip(FACE_TEMPLATE) {
+        assert!((point[0] - (x / 16. + 16.)).abs() < 1e-3);
+        assert!((point[1] - (y / 16. + 16.)).abs() < 1e-3);
+    }
+    assert!(restorer(false).detect(&img).unwrap().is_empty());
+}
+
+#[test]
+fn restores_only_around_the_face() {
+    let img = Tensor::rand(0f32, 1., (1, 3, 64, 64), &Device::Cpu).unwrap();
+    let upscaled = img.upsample_nearest2d(128, 128).unwrap();
+    let out = restorer(true).restore(&img, &upscaled).unwrap();
+    assert_eq!(out.dims(), [1, 3, 128, 128]);
+    // The face is at 32 to 96 in the upscaled image
+    let corner = |xs: &Tensor| xs.narrow(2, 0, 20).unwrap().narrow(3, 0, 20).unwrap();
+    assert_eq!(max_abs_diff(&corner(&out), &corner(&upscaled)), 0.);
+    let centre = |xs: &Tensor| xs.narrow(2, 48, 32).unwrap().narrow(3, 48, 32).unwrap();
+    assert!(max_abs_diff(&centre(&out), &centre(&upscaled)) > 1e-3);
+
+    let out = restorer(false).restore(&img, &upscaled).unwrap();
+    assert_eq!(max_abs_diff(&out, &upscaled), 0.);
+}
@@ -0,0 +1,580 @@
+"""Generate the parity fixtures used by tests/parity.rs.
+
+Every case is a small randomly initialised upstream PyTorch module, saved as
+`<case>.safetensors` with the key names of real checkpoints, and
+`<case>_io.safetensors` holding an `input` and the matching `output` of the
+module's forward pass, run in float64 on the float32 weights.
+
+The modules are imported from the upstream code, not rewritten:
+- old arch: `RRDB_Net` of `architecture.py` in the original ESRGAN repository
+  (https://github.com/xinntao/ESRGAN, the old-arch revision before
+  `RRDBNet_arch.py`), whose checkout is given with `--esrgan`, and
+  `RRDBNet` of ESRGAN+ (https://github.com/ncarraz/ESRGANplus), whose checkout
+  is given with `--esrganplus` and whose noise is only there in training
+- spsr: `SPSRNet` of SPSR (https://github.com/Maclory/SPSR), whose checkout is
+  given with `--spsr`
+- new arch: basicsr's `RRDBNet`, including the pixel unshuffle for x1 and x2
+- compact: basicsr's `SRVGGNetCompact` with PReLU
+- span: spandrel's `SPAN`, whose Conv3XC blocks merge their branches into the
+  eval conv in eval mode
+- swinir: spandrel's copy of the original `SwinIR`
+  (https://github.com/JingyunLiang/SwinIR), with all its upsamplers
+- hat, dat: spandrel's copies of the original `HAT`
+  (https://github.com/XPixelGroup/HAT) and `DAT`
+  (https://github.com/zhengchen1999/DAT), the batch norms of DAT in eval mode
+- srformer: the copy of the original `SRFormer`
+  (https://github.com/HVision-NKU/SRFormer) in spandrel_extra_arches
+- cugan: spandrel's copies of the `UpCunet2x`, `UpCunet3x` and `UpCunet4x` of
+  Real-CUGAN (https://github.com/bilibili/ailab), whose widths are fixed
+- omnisr, safmn: spandrel's copies of the original `OmniSR`
+  (https://github.com/Francis0625/Omni-SR) and `SAFMN`
+  (https://github.com/sunny2109/SAFMN)
+- gfpgan: `GFPGANv1Clean` of the gfpgan package
+  (https://github.com/TencentARC/GFPGAN), with zero noise strengths since the
+  noise is random
+- retinaface: the ResNet `RetinaFace` of facexlib
+  (https://github.com/xinntao/facexlib) the face restoration detects with, on
+  a torchvision ResNet narrowed to `width` channels after its stem
+
+Cases built from spandrel are loaded back with spandrel's `ModelLoader`, to
+check the key names are detected as the same architecture and scale.
+
+The code and versions a fixture was made with are stored in the `generator`
+metadata of its io file. Regenerate with
+`python tests/fixtures/generate.py --esrgan path/to/ESRGAN --esrganplus path/to/ESRGANplus
+--spsr path/to/SPSR` after
+`pip install torch basicsr spandrel spandrel_extra_arches gfpgan facexlib safetensors`.
+"""
+
+import argparse
+import importlib
+import math
+import os
+import subprocess
+import sys
+
+import torch
+from safetensors.torch import save_file
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+
+# Checkouts of the repositories whose code isn't packaged, from the arguments
+CHECKOUTS = {}
+
+
+def git_revision(path):
+    return subprocess.run(
+        ["git", "-C", path, "rev-parse", "--short", "HEAD"],
+        capture_output=True,
+        text=True,
+        check=True,
+    ).stdout.strip()
+
+
+def checkout_architecture(name, code_dir):
+    """`models.modules.architecture` of a checkout; ESRGAN+ and SPSR both have
+    one, so the package is dropped from the module cache before every import"""
+    for module in [m for m in sys.modules if m == "models" or m.startswith("models.")]:
+        del sys.modules[module]
+    root = os.path.join(CHECKOUTS[name], code_dir)
+    sys.path.insert(0, root)
+    try:
+        return importlib.import_module("models.modules.architecture")
+    finally:
+        sys.path.remove(root)
+
+
+def randomise(model, generator):
+    """Replace the upstream initialisation, which zeroes biases and scales some
+    convs down, with noise in the range of PyTorch's default conv init so every
+    weight shows in the output"""
+    with torch.no_grad():
+        for p in model.parameters():
+            # Fixed filters, like the gradient kernels of SPSR
+            if not p.requires_grad:
+                continue
+            if p.dim() > 1:
+                bound = 1 / math.sqrt(p[0].numel())
+                p.uniform_(-bound, bound, generator=generator)
+            else:
+                # Biases, PReLU slopes and norm scales stay around their init
+                p.add_(torch.empty_like(p).uniform_(-0.1, 0.1, generator=generator))
+        # Running statistics, so the batch norms of eval mode aren't identities
+        for m in model.modules():
+            if isinstance(m, torch.nn.modules.batchnorm._BatchNorm):
+                m.running_mean.uniform_(-0.1, 0.1, generator=generator)
+                m.running_var.uniform_(0.5, 1.5, generator=generator)
+            # The noise injections of StyleGAN2 add random noise, their
+            # strengths stay at zero so the output is deterministic
+            if type(m).__name__ == "StyleConv":
+                m.weight.zero_()
+
+
+def old_arch(in_nc, out_nc, scale, nf, nb, gc, plus=False):
+    if plus:
+        return esrgan_plus(in_nc, out_nc, scale, nf, nb, gc)
+
+    import architecture
+
+    model = architecture.RRDB_Net(
+        in_nc,
+        out_nc,
+        nf,
+        nb,
+        gc=gc,
+        upscale=scale,
+        norm_type=None,
+        act_type="leakyrelu",
+        mode="CNA",
+        res_scale=1,
+        upsample_mode="upconv",
+    )
+    revision = git_revision(os.path.dirname(os.path.abspath(architecture.__file__)))
+    return model, f"ESRGAN architecture.py {revision}"
+
+
+def esrgan_plus(in_nc, out_nc, scale, nf, nb, gc):
+    architecture = checkout_architecture("esrganplus", "codes")
+
+    model = architecture.RRDBNet(
+        in_nc,
+        out_nc,
+        nf,
+        nb,
+        gc=gc,
+        upscale=scale,
+        norm_type=None,
+        act_type="leakyrelu",
+        mode="CNA",
+        upsample_mode="upconv",
+    )
+    revision = git_revision(os.path.dirname(os.path.abspath(architecture.__file__)))
+    return model, f"ESRGANplus architecture.py {revision}"
+
+
+def spsr(in_nc, out_nc, scale, nf, nb, gc):
+    architecture = checkout_architecture("spsr", "code")
+
+    model = architecture.SPSRNet(
+        in_nc,
+        out_nc,
+        nf,
+        nb,
+        gc=gc,
+        upscale=scale,
+        norm_type=None,
+        act_type="leakyrelu",
+        mode="CNA",
+        upsample_mode="upconv",
+    )
+    revision = git_revision(os.path.dirname(os.path.abspath(architecture.__file__)))
+    return model, f"SPSR architecture.py {revision}"
+
+
+def new_arch(in_nc, out_nc, scale, nf, nb, gc):
+    import basicsr
+    from basicsr.archs.rrdbnet_arch import RRDBNet
+
+    model = RRDBNet(in_nc, out_nc, scale=scale, num_feat=nf, num_block=nb, num_grow_ch=gc)
+    return model, f"basicsr {basicsr.__version__} RRDBNet"
+
+
+def compact(in_nc, out_nc, scale, nf, num_conv):
+    import basicsr
+    from basicsr.archs.srvgg_arch import SRVGGNetCompact
+
+    model = SRVGGNetCompact(
+        in_nc, out_nc, num_feat=nf, num_conv=num_conv, upscale=scale, act_type="prelu"
+    )
+    return model, f"basicsr {basicsr.__version__} SRVGGNetCompact"
+
+
+def spandrel_model(model, scale):
+    """Check spandrel detects the randomised state dict as `model`"""
+    from spandrel import ModelLoader
+
+    descriptor = ModelLoader().load_from_state_dict(model.state_dict())
+    assert type(descriptor.model) is type(model), type(descriptor.model)
+    assert descriptor.scale == scale, descriptor.scale
+
+
+def span(in_nc, out_nc, scale, nf, norm):
+    import spandrel
+    from spandrel.architectures.SPAN import SPAN
+
+    model = SPAN(in_nc, out_nc, feature_channels=nf, upscale=scale, norm=norm)
+    return model, f"spandrel {spandrel.__version__} SPAN"
+
+
+def swinir(
+    in_nc,
+    out_nc,
+    scale,
+    embed_dim,
+    depths,
+    num_heads,
+    window_size,
+    upsampler,
+    resi_connection="1conv",
+    num_feat=64,
+):
+    import spandrel
+    from spandrel.architectures.SwinIR import SwinIR
+
+    assert in_nc == out_nc, "SwinIR has as many output channels as input ones"
+    model = SwinIR(
+        in_chans=in_nc,
+        embed_dim=embed_dim,
+        depths=depths,
+        num_heads=num_heads,
+        window_size=window_size,
+        mlp_ratio=2.0,
+        upscale=scale,
+        img_range=1.0,
+        upsampler=upsampler,
+        resi_connection=resi_connection,
+        num_feat=num_feat,
+    )
+    return model, f"spandrel {spandrel.__version__} SwinIR"
+
+
+def hat(
+    in_nc,
+    out_nc,
+    scale,
+    embed_dim,
+    depths,
+    num_heads,
+    window_size,
+    compress_ratio,
+    squeeze_factor,
+    conv_scale,
+    overlap_ratio=0.5,
+    num_feat=64,
+):
+    import spandrel
+    from spandrel.architectures.HAT import HAT
+
+    assert in_nc == out_nc, "HAT has as many output channels as input ones"
+    model = HAT(
+        in_chans=in_nc,
+        embed_dim=embed_dim,
+        depths=depths,
+        num_heads=num_heads,
+        window_size=window_size,
+        compress_ratio=compress_ratio,
+        squeeze_factor=squeeze_factor,
+        conv_scale=conv_scale,
+        overlap_ratio=overlap_ratio,
+        mlp_ratio=2.0,
+        upscale=scale,
+        img_range=1.0,
+        upsampler="pixelshuffle",
+        resi_connection="1conv",
+        num_feat=num_feat,
+    )
+    return model, f"spandrel {spandrel.__version__} HAT"
+
+
+def srformer(
+    in_nc, out_nc, scale, embed_dim, depths, num_heads, window_size, upsampler, num_feat=64
+):
+    import spandrel_extra_arches
+    from spandrel_extra_arches.architectures.SRFormer import SRFormer
+
+    # So that `ModelLoader` knows the architecture
+    spandrel_extra_arches.install(ignore_duplicates=True)
+
+    assert in_nc == out_nc, "SRFormer has as many output channels as input ones"
+    model = SRFormer(
+        in_chans=in_nc,
+        embed_dim=embed_dim,
+        depths=depths,
+        num_heads=num_heads,
+        window_size=window_size,
+        mlp_ratio=2.0,
+        upscale=scale,
+        img_range=1.0,
+        upsampler=upsampler,
+        resi_connection="1conv",
+        num_feat=num_feat,
+    )
+    return model, f"spandrel_extra_arches {spandrel_extra_arches.__version__} SRFormer"
+
+
+def dat(
+    in_nc,
+    out_nc,
+    scale,
+    embed_dim,
+    depths,
+    num_heads,
+    split_size,
+    expansion_factor,
+    upsampler,
+    num_feat=64,
+):
+    import spandrel
+    from spandrel.architectures.DAT import DAT
+
+    assert in_nc == out_nc, "DAT has as many output channels as input ones"
+    model = DAT(
+        in_chans=in_nc,
+        embed_dim=embed_dim,
+        split_size=split_size,
+        depth=depths,
+        num_heads=num_heads,
+        expansion_factor=expansion_factor,
+        upscale=scale,
+        img_range=1.0,
+        resi_connection="1conv",
+        upsampler=upsampler,
+        num_feat=num_feat,
+    )
+    return model, f"spandrel {spandrel.__version__} DAT"
+
+
+def cugan(in_nc, out_nc, scale, nf, pro):
+    import spandrel
+    from spandrel.architectures.RealCUGAN import UpCunet2x, UpCunet3x, UpCunet4x
+
+    assert nf == 32, "Real-CUGAN's U-Nets always have 32 features"
+    upcunet = {2: UpCunet2x, 3: UpCunet3x, 4: UpCunet4x}[scale]
+    model = upcunet(in_channels=in_nc, out_channels=out_nc, pro=pro)
+    # Pro checkpoints are told apart by an extra `pro` entry
+    if pro:
+        model.register_buffer("pro", torch.ones(1))
+    return model, f"spandrel {spandrel.__version__} {upcunet.__name__}"
+
+
+def omnisr(in_nc, out_nc, scale, num_feat, res_num, block_num, window_size, pe=True, bias=True):
+    import spandrel
+    from spandrel.architectures.OmniSR import OmniSR
+
+    model = OmniSR(
+        num_in_ch=in_nc,
+        num_out_ch=out_nc,
+        num_feat=num_feat,
+        block_num=block_num,
+        pe=pe,
+        window_size=window_size,
+        res_num=res_num,
+        up_scale=scale,
+        bias=bias,
+    )
+    return model, f"spandrel {spandrel.__version__} OmniSR"
+
+
+def safmn(in_nc, out_nc, scale, dim, n_blocks, ffn_scale):
+    import spandrel
+    from spandrel.architectures.SAFMN import SAFMN
+
+    model = SAFMN(dim=dim, n_blocks=n_blocks, ffn_scale=ffn_scale, upscaling_factor=scale)
+    # Upstream SAFMN is RGB only, other checkpoints differ in their first and
+    # last convs
+    if in_nc != 3:
+        model.to_feat = torch.nn.Conv2d(in_nc, dim, 3, 1, 1)
+    if out_nc != 3:
+        model.to_img[0] = torch.nn.Conv2d(dim, out_nc * scale**2, 3, 1, 1)
+    return model, f"spandrel {spandrel.__version__} SAFMN"
+
+
+def gfpgan(
+    in_nc,
+    out_nc,
+    scale,
+    out_size,
+    num_style_feat,
+    narrow,
+    channel_multiplier=2,
+    sft_half=True,
+    different_w=True,
+):
+    import gfpgan
+    from gfpgan.archs.gfpganv1_clean_arch import GFPGANv1Clean
+
+    assert in_nc == out_nc == 3 and scale == 1, "GFPGAN restores RGB faces at their size"
+    model = GFPGANv1Clean(
+        out_size=out_size,
+        num_style_feat=num_style_feat,
+        channel_multiplier=channel_multiplier,
+        decoder_load_path=None,
+        fix_decoder=False,
+        num_mlp=8,
+        input_is_latent=True,
+        different_w=different_w,
+        narrow=narrow,
+        sft_half=sft_half,
+    )
+    return model, f"gfpgan {gfpgan.__version__} GFPGANv1Clean"
+
+
+def retinaface(in_nc, out_nc, scale, width, layers, out_channels):
+    from unittest import mock
+
+    import facexlib
+    import torchvision
+    from facexlib.detection import retinaface as facexlib_retinaface
+    from torchvision.models.resnet import Bottleneck, ResNet
+
+    class NarrowResNet(ResNet):
+        """torchvision's ResNet with `width` channels after the stem instead
+        of 64, and its layers narrowed to match"""
+
+        def __init__(self):
+            self.width = width
+            super().__init__(Bottleneck, layers)
+            self.conv1 = torch.nn.Conv2d(in_nc, width, 7, 2, 3, bias=False)
+            self.bn1 = torch.nn.BatchNorm2d(width)
+
+        def _make_layer(self, block, planes, blocks, stride=1, dilate=False):
+            if planes == 64:
+                self.inplanes = self.width
+            return super()._make_layer(block, planes * self.width // 64, blocks, stride, dilate)
+
+    assert scale == 1 and out_nc == 16, "the output is 4 box, 2 class and 10 landmark values"
+    cfg = {
+        **facexlib_retinaface.generate_config("resnet50"),
+        "in_channel": width * 4,
+        "out_channel": out_channels,
+    }
+    with mock.patch.object(facexlib_retinaface, "generate_config", return_value=cfg), \
+            mock.patch.object(torchvision.models, "resnet50", return_value=NarrowResNet()):
+        model = facexlib_retinaface.RetinaFace("resnet50", phase="test", device=torch.device("cpu"))
+    return model, f"facexlib {facexlib.__version__} RetinaFace, torchvision {torchvision.__version__}"
+
+
+# Builders whose models spandrel can load back
+SPANDREL = {span, swinir, hat, srformer, dat, cugan, omnisr, safmn}
+
+# The single tensor compared for the builders whose models return several
+OUTPUTS = {
+    # The restored face, without the RGB images of the intermediate resolutions
+    gfpgan: lambda out: out[0],
+    # The box offsets, face probabilities and landmark offsets of every anchor
+    # side by side
+    retinaface: lambda out: torch.cat(out, -1),
+}
+
+# name: (builder, in_nc, out_nc, scale, extra hyperparameters, input height, input width)
+CASES = {
+    "old_x1": (old_arch, 3, 3, 1, dict(nf=8, nb=2, gc=4), 6, 5),
+    "old_x2": (old_arch, 3, 3, 2, dict(nf=8, nb=2, gc=4), 6, 5),
+    "old_x4": (old_arch, 3, 3, 4, dict(nf=8, nb=2, gc=4), 6, 5),
+    "old_x8": (old_arch, 3, 3, 8, dict(nf=8, nb=1, gc=4), 4, 3),
+    "old_x4_gray": (old_arch, 1, 1, 4, dict(nf=8, nb=1, gc=4), 6, 5),
+    "old_x2_rgba": (old_arch, 4, 4, 2, dict(nf=8, nb=1, gc=4), 6, 5),
+    "old_x4_rgb_to_gray": (old_arch, 3, 1, 4, dict(nf=8, nb=1, gc=4), 6, 5),
+    "new_x1": (new_arch, 3, 3, 1, dict(nf=8, nb=2, gc=4), 8, 4),
+    "new_x2": (new_arch, 3, 3, 2, dict(nf=8, nb=2, gc=4), 8, 4),
+    "new_x4": (new_arch, 3, 3, 4, dict(nf=8, nb=2, gc=4), 8, 4),
+    "new_x4_gray": (new_arch, 1, 1, 4, dict(nf=8, nb=1, gc=4), 8, 4),
+    "compact_x1": (compact, 3, 3, 1, dict(nf=8, num_conv=2), 6, 5),
+    "compact_x2": (compact, 3, 3, 2, dict(nf=8, num_conv=2), 6, 5),
+    "compact_x3": (compact, 3, 3, 3, dict(nf=8, num_conv=2), 6, 5),
+    "compact_x4": (compact, 3, 3, 4, dict(nf=8, num_conv=2), 6, 5),
+    "compact_x4_gray": (compact, 1, 1, 4, dict(nf=8, num_conv=1), 6, 5),
+    "span_x4": (span, 3, 3, 4, dict(nf=8, norm=True), 6, 5),
+    "span_x2_no_norm": (span, 3, 3, 2, dict(nf=8, norm=False), 6, 5),
+    "span_x3_gray": (span, 1, 1, 3, dict(nf=4, norm=False), 6, 5),
+    "swinir_x4": (swinir, 3, 3, 4, dict(
+        embed_dim=12, depths=[2, 2], num_heads=[2, 3], window_size=4, num_feat=6,
+        upsampler="pixelshuffle"), 6, 5),
+    "swinir_x3": (swinir, 3, 3, 3, dict(
+        embed_dim=12, depths=[2], num_heads=[2], window_size=4, num_feat=6,
+        upsampler="pixelshuffle"), 6, 5),
+    "swinir_x2_direct": (swinir, 3, 3, 2, dict(
+        embed_dim=12, depths=[2, 1], num_heads=[1, 2], window_size=4,
+        upsampler="pixelshuffledirect"), 6, 5),
+    "swinir_x4_nearest": (swinir, 3, 3, 4, dict(
+        embed_dim=12, depths=[2], num_heads=[2], window_size=4, num_feat=6,
+        upsampler="nearest+conv", resi_connection="3conv"), 6, 5),
+    "swinir_x1_gray": (swinir, 1, 1, 1, dict(
+        embed_dim=8, depths=[2], num_heads=[2], window_size=2, upsampler=""), 6, 5),
+    "hat_x4": (hat, 3, 3, 4, dict(
+        embed_dim=12, depths=[2, 1], num_heads=[2, 3], window_size=4, compress_ratio=3,
+        squeeze_factor=4, conv_scale=0.5, num_feat=6), 6, 5),
+    "hat_x2_gray": (hat, 1, 1, 2, dict(
+        embed_dim=12, depths=[2], num_heads=[2], window_size=4, compress_ratio=2,
+        squeeze_factor=3, conv_scale=0.5, num_feat=6), 6, 5),
+    "srformer_x4": (srformer, 3, 3, 4, dict(
+        embed_dim=12, depths=[2, 2], num_heads=[2, 3], window_size=4, num_feat=6,
+        upsampler="pixelshuffle"), 6, 5),
+    "srformer_x2_direct": (srformer, 3, 3, 2, dict(
+        embed_dim=12, depths=[2], num_heads=[2], window_size=4,
+        upsampler="pixelshuffledirect"), 6, 5),
+    # The position bias of DAT needs at least 64 channels for its layer norms to
+    # have more than one
+    "dat_x2": (dat, 3, 3, 2, dict(
+        embed_dim=64, depths=[3, 1], num_heads=[4, 4], split_size=[2, 4], expansion_factor=1,
+        num_feat=6, upsampler="pixelshuffle"), 6, 5),
+    "dat_x3_direct": (dat, 3, 3, 3, dict(
+        embed_dim=64, depths=[2], num_heads=[2], split_size=[2, 2], expansion_factor=2,
+        upsampler="pixelshuffledirect"), 5, 6),
+    # The U-Nets trim their borders, so the inputs can't be smaller
+    "cugan_x2": (cugan, 3, 3, 2, dict(nf=32, pro=False), 21, 22),
+    "cugan_x3": (cugan, 3, 3, 3, dict(nf=32, pro=False), 21, 22),
+    "cugan_x4": (cugan, 3, 3, 4, dict(nf=32, pro=False), 20, 21),
+    "cugan_x2_pro": (cugan, 1, 1, 2, dict(nf=32, pro=True), 20, 23),
+    "omnisr_x4": (omnisr, 3, 3, 4, dict(
+        num_feat=8, res_num=2, block_num=1, window_size=4), 22, 27),
+    "omnisr_x2_no_pe": (omnisr, 3, 3, 2, dict(
+        num_feat=8, res_num=1, block_num=2, window_size=4, pe=False, bias=False), 17, 14),
+    # Sizes that aren't multiples of 8 for the adaptive pooling of SAFMN
+    "safmn_x4": (safmn, 3, 3, 4, dict(dim=8, n_blocks=2, ffn_scale=2.0), 10, 13),
+    "safmn_x2_gray": (safmn, 1, 1, 2, dict(dim=12, n_blocks=1, ffn_scale=1.5), 16, 9),
+    "old_x2_plus": (old_arch, 3, 3, 2, dict(nf=8, nb=1, gc=4, plus=True), 6, 5),
+    # One block after the last one feeding the gradient branch
+    "spsr_x2": (spsr, 3, 3, 2, dict(nf=4, nb=21, gc=2), 6, 5),
+    # 12 channels in the decoder and 6 in the U-Net, not a side of the feature
+    # maps. GFPGAN only takes faces of its own size.
+    "gfpgan_32": (gfpgan, 3, 3, 1, dict(out_size=32, num_style_feat=8, narrow=3 / 128), 32, 32),
+    "gfpgan_32_full_sft": (gfpgan, 3, 3, 1, dict(
+        out_size=32, num_style_feat=8, narrow=3 / 128, sft_half=False, different_w=False), 32, 32),
+    # 16 channels keep the SSH branches at 4 and 8, not a side of the 5x5, 3x3
+    # and 2x2 pyramid levels.
+    "retinaface": (retinaface, 3, 16, 1, dict(width=3, layers=[1, 1, 1, 1], out_channels=16), 40, 36),
+}
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.split("\n")[0])
+    parser.add_argument(
+        "--esrgan", required=True, help="checkout of the original ESRGAN repository"
+    )
+    parser.add_argument(
+        "--esrganplus", required=True, help="checkout of the ESRGAN+ repository"
+    )
+    parser.add_argument("--spsr", required=True, help="checkout of the SPSR repository")
+    args = parser.parse_args()
+    sys.path.insert(0, args.esrgan)
+    CHECKOUTS["esrganplus"] = args.esrganplus
+    CHECKOUTS["spsr"] = args.spsr
+
+    for seed, (name, (builder, in_nc, out_nc, scale, params, h, w)) in enumerate(CASES.items()):
+        generator = torch.Generator().manual_seed(seed)
+        model, source = builder(in_nc, out_nc, scale, **params)
+        model = model.float().eval()
+        randomise(model, generator)
+        if builder in SPANDREL:
+            spandrel_model(model, scale)
+        weights = {k: v.clone().contiguous() for k, v in model.state_dict().items()}
+        x = torch.rand(1, in_nc, h, w, generator=generator)
+        with torch.no_grad():
+            y = model.double()(x.double())
+        y = OUTPUTS.get(builder, lambda out: out)(y).float()
+
+        config = {"in_nc": in_nc, "out_nc": out_nc, "scale": scale, **params}
+        metadata = {k: str(v) for k, v in config.items()}
+        save_file(weights, os.path.join(HERE, f"{name}.safetensors"), metadata)
+        metadata["generator"] = f"{source}, torch {torch.__version__}"
+        save_file(
+            {"input": x, "output": y.contiguous()},
+            os.path.join(HERE, f"{name}_io.safetensors"),
+            metadata,
+        )
+        print(f"{name}: {tuple(x.shape)} -> {tuple(y.shape)}")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,156 @@
+// Shapes, key layouts and reference values of the losses and networks used to
+// fine-tune with a discriminator.
+
+use candle_core::{DType, Device, Module, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+use esrgan_candle_rs::discriminator::{UNetDiscriminatorSN, VGGStyleDiscriminator};
+use esrgan_candle_rs::losses::{
+    bce_with_logits, relativistic_discriminator_loss, relativistic_generator_loss,
+};
+use esrgan_candle_rs::vgg::{PerceptualLoss, Vgg19};
+
+fn scalar(xs: &Tensor) -> f32 {
+    xs.to_scalar::<f32>().unwrap()
+}
+
+fn input(shape: (usize, usize, usize, usize)) -> Tensor {
+    Tensor::rand(0f32, 1., shape, &Device::Cpu).unwrap()
+}
+
+#[test]
+fn bce_with_logits_reference() {
+    let logits = Tensor::new(&[-2f32, 0., 3.], &Device::Cpu).unwrap();
+    // -log(sigmoid(x)) and -log(1 - sigmoid(x))
+    let real: f32 = [-2f32, 0., 3.]
+        .iter()
+        .map(|x| (1. + (-x).exp()).ln())
+        .sum::<f32>()
+        / 3.;
+    let fake: f32 = [-2f32, 0., 3.]
+        .iter()
+        .map(|x| (1. + x.exp()).ln())
+        .sum::<f32>()
+        / 3.;
+    assert!((scalar(&bce_with_logits(&logits, 1.).unwrap()) - real).abs() < 1e-6);
+    assert!((scalar(&bce_with_logits(&logits, 0.).unwrap()) - fake).abs() < 1e-6);
+
+    // Large logits don't overflow
+    let large = Tensor::new(&[200f32, -200.], &Device::Cpu).unwrap();
+    assert!((scalar(&bce_with_logits(&large, 1.).unwrap()) - 100.).abs() < 1e-3);
+}
+
+#[test]
+fn relativistic_losses() {
+    // A discriminator that can't tell the images apart is at log(2)
+    let scores = Tensor::new(&[0.5f32, -1., 2.], &Device::Cpu).unwrap();
+    let mean = scores.mean_all().unwrap();
+    let same = scores.broadcast_sub(&mean).unwrap();
+    let d_loss = scalar(&relativistic_discriminator_loss(&same, &same).unwrap());
+    let g_loss = scalar(&relativistic_generator_loss(&same, &same).unwrap());
+    assert!((d_loss - g_loss).abs() < 1e-6);
+
+    let zeros = Tensor::zeros(4, DType::F32, &Device::Cpu).unwrap();
+    let d_loss = scalar(&relativistic_discriminator_loss(&zeros, &zeros).unwrap());
+    assert!((d_loss - 2f32.ln()).abs() < 1e-6);
+
+    // Real scores far above the fake ones make the discriminator right and the
+    // generator wrong
+    let real = Tensor::full(10f32, 4, &Device::Cpu).unwrap();
+    let fake = Tensor::full(-10f32, 4, &Device::Cpu).unwrap();
+    assert!(scalar(&relativistic_discriminator_loss(&real, &fake).unwrap()) < 1e-3);
+    assert!(scalar(&relativistic_generator_loss(&real, &fake).unwrap()) > 10.);
+}
+
+#[test]
+fn unet_discriminator_round_trip() {
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    let net = UNetDiscriminatorSN::load(vb, 3, 8, true).unwrap();
+
+    // Same keys as PyTorch's spectral_norm
+    let state_dict: std::collections::HashMap<String, Tensor> = varmap
+        .data()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, var)| (name.clone(), var.as_tensor().clone()))
+        .collect();
+    for key in [
+        "conv0.weight",
+        "conv1.weight_orig",
+        "conv1.weight_u",
+        "conv8.weight_v",
+    ] {
+        assert!(state_dict.contains_key(key), "missing {key}");
+    }
+    assert_eq!(net.spectral_buffers().len(), 16);
+
+    let loaded = UNetDiscriminatorSN::load(
+        VarBuilder::from_tensors(state_dict, DType::F32, &Device::Cpu),
+        3,
+        8,
+        true,
+    )
+    .unwrap();
+    let xs = input((2, 3, 32, 48));
+    let output = net.forward(&xs).unwrap();
+    assert_eq!(output.dims(), [2, 1, 32, 48]);
+    let diff = (output - loaded.forward(&xs).unwrap())
+        .unwrap()
+        .abs()
+        .unwrap()
+        .flatten_all()
+        .unwrap()
+        .max(0)
+        .unwrap();
+    assert!(scalar(&diff) < 1e-5);
+}
+
+#[test]
+fn vgg_discriminator_shapes() {
+    for size in [128, 256] {
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let net = VGGStyleDiscriminator::load(vb, 3, 4, size).unwrap();
+        let output = net.forward(&input((2, 3, size, size))).unwrap();
+        assert_eq!(output.dims(), [2, 1]);
+    }
+}
+
+#[test]
+fn perceptual_loss() {
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    let vgg = Vgg19::load(vb).unwrap();
+    assert!(varmap
+        .data()
+        .lock()
+        .unwrap()
+        .contains_key("features.34.weight"));
+
+    let xs = input((1, 3, 16, 16));
+    let features = vgg.features(&xs, &["conv5_4", "conv1_2"]).unwrap();
+    assert_eq!(features[0].dims(), [1, 512, 1, 1]);
+    assert_eq!(features[1].dims(), [1, 64, 16, 16]);
+
+    let layers = PerceptualLoss::DEFAULT_LAYERS
+        .iter()
+        .map(|(name, weight)| (name.to_string(), *weight))
+        .collect();
+    let loss = PerceptualLoss::new(vgg, layers).unwrap();
+    assert_eq!(scalar(&loss.forward(&xs, &xs).unwrap()), 0.);
+    assert!(scalar(&loss.forward(&xs, &input((1, 3, 16, 16))).unwrap()) > 0.);
+}
+
+#[test]
+fn unknown_vgg_layers_are_errors() {
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    let vgg = Vgg19::load(vb).unwrap();
+    assert!(vgg.has_layer("conv3_4"));
+    assert!(!vgg.has_layer("conv3_5"));
+
+    let xs = input((1, 3, 16, 16));
+    assert!(vgg.features(&xs, &["conv1_1", "relu1_1"]).is_err());
+    assert!(PerceptualLoss::new(vgg, vec![("conv6_1".to_string(), 1.)]).is_err());
+}
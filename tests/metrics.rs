@@ -0,0 +1,81 @@
+use esrgan_candle_rs::metrics::{ms_ssim, psnr, ssim};
+use image::{Rgb, RgbImage};
+
+fn noise(width: u32, height: u32, seed: u32) -> RgbImage {
+    // Small xorshift so the images are the same on every run
+    let mut state = seed.max(1);
+    RgbImage::from_fn(width, height, |_, _| {
+        Rgb([0; 3].map(|_: u8| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state % 256) as u8
+        }))
+    })
+}
+
+#[test]
+fn identical_images() {
+    let img = noise(200, 180, 1);
+    assert!(psnr(&img, &img, 0, false).unwrap().is_infinite());
+    assert!((ssim(&img, &img, 4, false).unwrap() - 1.).abs() < 1e-12);
+    assert!((ms_ssim(&img, &img, 0, true).unwrap() - 1.).abs() < 1e-12);
+}
+
+#[test]
+fn psnr_of_constant_offset() {
+    let a = RgbImage::from_pixel(32, 32, Rgb([100, 100, 100]));
+    let b = RgbImage::from_pixel(32, 32, Rgb([110, 110, 110]));
+    let expected = 10. * (255f64 * 255. / 100.).log10();
+    assert!((psnr(&a, &b, 0, false).unwrap() - expected).abs() < 1e-9);
+    // Luma only moves by 219/255 of the offset
+    let y_diff = 10. * 219. / 255.;
+    let expected = 10. * (255f64 * 255. / (y_diff * y_diff)).log10();
+    assert!((psnr(&a, &b, 2, true).unwrap() - expected).abs() < 1e-9);
+}
+
+#[test]
+fn border_is_ignored() {
+    let a = noise(64, 48, 2);
+    let mut b = a.clone();
+    for x in 0..64 {
+        b.put_pixel(x, 0, Rgb([0, 255, 0]));
+    }
+    assert!(psnr(&a, &b, 0, false).unwrap().is_finite());
+    assert!(psnr(&a, &b, 1, false).unwrap().is_infinite());
+}
+
+#[test]
+fn ssim_drops_with_noise() {
+    let a = noise(64, 64, 3);
+    let b = noise(64, 64, 4);
+    let blurred = image::imageops::blur(&a, 1.);
+    let similar = ssim(&a, &blurred, 0, false).unwrap();
+    let unrelated = ssim(&a, &b, 0, false).unwrap();
+    assert!(similar < 1.);
+    assert!(unrelated < similar);
+    assert!(unrelated.abs() < 0.1);
+}
+
+#[test]
+fn ms_ssim_needs_large_images() {
+    let img = noise(175, 300, 5);
+    assert!(ms_ssim(&img, &img, 0, false).is_none());
+}
+
+#[test]
+fn ssim_needs_the_window() {
+    let img = noise(10, 40, 6);
+    assert!(ssim(&img, &img, 0, false).is_none());
+    // 11 pixels are left after cropping 2 on each side
+    let img = noise(15, 15, 7);
+    assert!((ssim(&img, &img, 2, true).unwrap() - 1.).abs() < 1e-12);
+}
+
+#[test]
+fn nothing_left_after_cropping() {
+    let img = noise(8, 30, 8);
+    assert!(psnr(&img, &img, 4, false).is_none());
+    assert!(ssim(&img, &img, 4, true).is_none());
+    assert!(ms_ssim(&img, &img, 4, false).is_none());
+}
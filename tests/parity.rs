@@ -0,0 +1,521 @@
+// Checks every architecture against reference outputs of its forward pass.
+// The checkpoints and reference outputs in tests/fixtures come from
+// tests/fixtures/generate.py, see there for how they are made.
+
+use std::collections::HashMap;
+
+use candle_core::{safetensors, DType, Device, Module, Result, Tensor};
+use candle_nn::VarBuilder;
+use esrgan_candle_rs::compact::SRVGGNetCompact;
+use esrgan_candle_rs::cugan::UpCunet;
+use esrgan_candle_rs::dat::{DATConfig, DAT};
+use esrgan_candle_rs::gfpgan::{GFPGANConfig, GFPGAN};
+use esrgan_candle_rs::hat::{HATConfig, HAT};
+use esrgan_candle_rs::omnisr::{OmniSR, OmniSRConfig};
+use esrgan_candle_rs::retinaface::{RetinaFace, RetinaFaceConfig};
+use esrgan_candle_rs::safmn::SAFMN;
+use esrgan_candle_rs::span::SPAN;
+use esrgan_candle_rs::spsr::SPSRNet;
+use esrgan_candle_rs::srformer::SRFormer;
+use esrgan_candle_rs::swinir::{ResiConnection, SwinIR, SwinIRConfig, Upsampler};
+use esrgan_candle_rs::{
+    cugan_helpers, gfpgan_helpers, new_arch, old_arch, old_arch_helpers, retinaface_helpers,
+};
+
+const TOLERANCE: f32 = 1e-4;
+
+struct Fixture {
+    weights: HashMap<String, Tensor>,
+    input: Tensor,
+    output: Tensor,
+}
+
+impl Fixture {
+    fn load(name: &str) -> Fixture {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+        let weights = safetensors::load(format!("{dir}/{name}.safetensors"), &Device::Cpu).unwrap();
+        let mut io =
+            safetensors::load(format!("{dir}/{name}_io.safetensors"), &Device::Cpu).unwrap();
+        Fixture {
+            weights,
+            input: io.remove("input").unwrap(),
+            output: io.remove("output").unwrap(),
+        }
+    }
+
+    fn vb(&self) -> VarBuilder<'static> {
+        VarBuilder::from_tensors(self.weights.clone(), DType::F32, &Device::Cpu)
+    }
+
+    fn check(&self, model: &impl Module) {
+        let output = model.forward(&self.input).unwrap();
+        assert_eq!(output.dims(), self.output.dims());
+        let diff = (output - &self.output)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert!(diff < TOLERANCE, "max difference {diff} over {TOLERANCE}");
+    }
+}
+
+fn old_arch(name: &str, in_nc: usize, out_nc: usize, scale: usize, nb: usize) {
+    let fixture = Fixture::load(name);
+    let model =
+        old_arch::RRDBNet::load(fixture.vb(), in_nc, out_nc, scale, 8, nb, 4, false).unwrap();
+    fixture.check(&model);
+}
+
+fn new_arch(name: &str, in_nc: usize, out_nc: usize, scale: usize, nb: usize) {
+    let fixture = Fixture::load(name);
+    let model = new_arch::RRDBNet::load(fixture.vb(), in_nc, out_nc, scale, 8, nb, 4).unwrap();
+    fixture.check(&model);
+}
+
+fn compact(name: &str, channels: usize, scale: usize, num_conv: usize) {
+    let fixture = Fixture::load(name);
+    let model =
+        SRVGGNetCompact::load(fixture.vb(), channels, channels, 8, num_conv, scale).unwrap();
+    fixture.check(&model);
+}
+
+fn span(name: &str, channels: usize, scale: usize, nf: usize, norm: bool) {
+    let fixture = Fixture::load(name);
+    let model = SPAN::load(fixture.vb(), channels, channels, nf, scale, norm).unwrap();
+    fixture.check(&model);
+}
+
+fn swinir(name: &str, config: SwinIRConfig) {
+    let fixture = Fixture::load(name);
+    let model = SwinIR::load(fixture.vb(), &config).unwrap();
+    fixture.check(&model);
+}
+
+/// The hyperparameters the SwinIR fixtures share
+fn swinir_config(scale: usize, depths: Vec<usize>, num_heads: Vec<usize>) -> SwinIRConfig {
+    SwinIRConfig {
+        scale,
+        embed_dim: 12,
+        depths,
+        num_heads,
+        window_size: 4,
+        num_feat: 6,
+        ..Default::default()
+    }
+}
+
+fn hat(name: &str, config: HATConfig) {
+    let fixture = Fixture::load(name);
+    let model = HAT::load(fixture.vb(), &config).unwrap();
+    fixture.check(&model);
+}
+
+/// The hyperparameters the HAT fixtures share
+fn hat_config(scale: usize, depths: Vec<usize>, num_heads: Vec<usize>) -> HATConfig {
+    HATConfig {
+        scale,
+        embed_dim: 12,
+        depths,
+        num_heads,
+        window_size: 4,
+        compress_ratio: 3,
+        squeeze_factor: 4,
+        conv_scale: 0.5,
+        num_feat: 6,
+        ..Default::default()
+    }
+}
+
+fn srformer(name: &str, config: SwinIRConfig) {
+    let fixture = Fixture::load(name);
+    let model = SRFormer::load(fixture.vb(), &config).unwrap();
+    fixture.check(&model);
+}
+
+fn dat(name: &str, config: DATConfig) {
+    let fixture = Fixture::load(name);
+    let model = DAT::load(fixture.vb(), &config).unwrap();
+    fixture.check(&model);
+}
+
+fn cugan(name: &str, channels: usize, scale: usize, pro: bool) {
+    let fixture = Fixture::load(name);
+    let nf = cugan_helpers::get_nf(&fixture.weights);
+    let model = UpCunet::load(fixture.vb(), channels, channels, scale, nf, pro).unwrap();
+    fixture.check(&model);
+}
+
+fn omnisr(name: &str, config: OmniSRConfig) {
+    let fixture = Fixture::load(name);
+    let model = OmniSR::load(fixture.vb(), &config).unwrap();
+    fixture.check(&model);
+}
+
+fn safmn(name: &str, channels: usize, scale: usize, dim: usize, n_blocks: usize, ffn_scale: f64) {
+    let fixture = Fixture::load(name);
+    let model = SAFMN::load(
+        fixture.vb(),
+        channels,
+        channels,
+        dim,
+        n_blocks,
+        ffn_scale,
+        scale,
+    )
+    .unwrap();
+    fixture.check(&model);
+}
+
+/// Also checks that the config is read back from the shapes
+fn gfpgan(name: &str, config: GFPGANConfig) {
+    let fixture = Fixture::load(name);
+    let weights = &fixture.weights;
+    let detected = GFPGANConfig {
+        out_size: gfpgan_helpers::get_out_size(weights),
+        num_style_feat: gfpgan_helpers::get_num_style_feat(weights),
+        channel_multiplier: gfpgan_helpers::get_channel_multiplier(weights),
+        narrow: gfpgan_helpers::get_narrow(weights),
+        sft_half: gfpgan_helpers::get_sft_half(weights),
+        different_w: gfpgan_helpers::get_different_w(weights),
+    };
+    assert_eq!(detected, config);
+    let model = GFPGAN::load(fixture.vb(), &config).unwrap();
+    fixture.check(&model);
+}
+
+/// The three outputs of the detector side by side, like the fixture
+struct RetinaFaceOutputs(RetinaFace);
+
+impl Module for RetinaFaceOutputs {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (loc, conf, landmarks) = self.0.forward_raw(xs)?;
+        Tensor::cat(&[loc, conf, landmarks], 2)
+    }
+}
+
+#[test]
+fn old_arch_x1() {
+    old_arch("old_x1", 3, 3, 1, 2);
+}
+
+#[test]
+fn old_arch_x2() {
+    old_arch("old_x2", 3, 3, 2, 2);
+}
+
+#[test]
+fn old_arch_x4() {
+    old_arch("old_x4", 3, 3, 4, 2);
+}
+
+#[test]
+fn old_arch_x8() {
+    old_arch("old_x8", 3, 3, 8, 1);
+}
+
+#[test]
+fn old_arch_grayscale() {
+    old_arch("old_x4_gray", 1, 1, 4, 1);
+}
+
+#[test]
+fn old_arch_rgba() {
+    old_arch("old_x2_rgba", 4, 4, 2, 1);
+}
+
+#[test]
+fn old_arch_rgb_to_grayscale() {
+    old_arch("old_x4_rgb_to_gray", 3, 1, 4, 1);
+}
+
+#[test]
+fn old_arch_plus() {
+    let fixture = Fixture::load("old_x2_plus");
+    assert!(old_arch_helpers::get_plus(&fixture.weights));
+    let model = old_arch::RRDBNet::load(fixture.vb(), 3, 3, 2, 8, 1, 4, true).unwrap();
+    fixture.check(&model);
+}
+
+#[test]
+fn spsr_x2() {
+    let fixture = Fixture::load("spsr_x2");
+    let model = SPSRNet::load(fixture.vb(), 3, 3, 2, 4, 21, 2).unwrap();
+    fixture.check(&model);
+}
+
+#[test]
+fn new_arch_x1() {
+    new_arch("new_x1", 3, 3, 1, 2);
+}
+
+#[test]
+fn new_arch_x2() {
+    new_arch("new_x2", 3, 3, 2, 2);
+}
+
+#[test]
+fn new_arch_x4() {
+    new_arch("new_x4", 3, 3, 4, 2);
+}
+
+#[test]
+fn new_arch_grayscale() {
+    new_arch("new_x4_gray", 1, 1, 4, 1);
+}
+
+#[test]
+fn compact_x1() {
+    compact("compact_x1", 3, 1, 2);
+}
+
+#[test]
+fn compact_x2() {
+    compact("compact_x2", 3, 2, 2);
+}
+
+#[test]
+fn compact_x3() {
+    compact("compact_x3", 3, 3, 2);
+}
+
+#[test]
+fn compact_x4() {
+    compact("compact_x4", 3, 4, 2);
+}
+
+#[test]
+fn compact_grayscale() {
+    compact("compact_x4_gray", 1, 4, 1);
+}
+
+#[test]
+fn span_x4() {
+    span("span_x4", 3, 4, 8, true);
+}
+
+#[test]
+fn span_x2_no_norm() {
+    span("span_x2_no_norm", 3, 2, 8, false);
+}
+
+#[test]
+fn span_grayscale() {
+    span("span_x3_gray", 1, 3, 4, false);
+}
+
+#[test]
+fn swinir_x4() {
+    swinir("swinir_x4", swinir_config(4, vec![2, 2], vec![2, 3]));
+}
+
+#[test]
+fn swinir_x3() {
+    swinir("swinir_x3", swinir_config(3, vec![2], vec![2]));
+}
+
+#[test]
+fn swinir_pixelshuffledirect() {
+    let config = SwinIRConfig {
+        upsampler: Upsampler::PixelShuffleDirect,
+        ..swinir_config(2, vec![2, 1], vec![1, 2])
+    };
+    swinir("swinir_x2_direct", config);
+}
+
+#[test]
+fn swinir_nearest_conv() {
+    let config = SwinIRConfig {
+        upsampler: Upsampler::NearestConv,
+        resi_connection: ResiConnection::ThreeConv,
+        ..swinir_config(4, vec![2], vec![2])
+    };
+    swinir("swinir_x4_nearest", config);
+}
+
+#[test]
+fn swinir_grayscale_without_upsampler() {
+    let config = SwinIRConfig {
+        in_nc: 1,
+        out_nc: 1,
+        embed_dim: 8,
+        window_size: 2,
+        upsampler: Upsampler::None,
+        ..swinir_config(1, vec![2], vec![2])
+    };
+    swinir("swinir_x1_gray", config);
+}
+
+#[test]
+fn hat_x4() {
+    hat("hat_x4", hat_config(4, vec![2, 1], vec![2, 3]));
+}
+
+#[test]
+fn hat_grayscale() {
+    let config = HATConfig {
+        in_nc: 1,
+        out_nc: 1,
+        compress_ratio: 2,
+        squeeze_factor: 3,
+        ..hat_config(2, vec![2], vec![2])
+    };
+    hat("hat_x2_gray", config);
+}
+
+#[test]
+fn srformer_x4() {
+    srformer("srformer_x4", swinir_config(4, vec![2, 2], vec![2, 3]));
+}
+
+#[test]
+fn srformer_pixelshuffledirect() {
+    let config = SwinIRConfig {
+        upsampler: Upsampler::PixelShuffleDirect,
+        ..swinir_config(2, vec![2], vec![2])
+    };
+    srformer("srformer_x2_direct", config);
+}
+
+#[test]
+fn dat_x2() {
+    let config = DATConfig {
+        scale: 2,
+        embed_dim: 64,
+        depths: vec![3, 1],
+        num_heads: vec![4, 4],
+        split_size: (2, 4),
+        expansion_factor: 1.,
+        num_feat: 6,
+        ..Default::default()
+    };
+    dat("dat_x2", config);
+}
+
+#[test]
+fn dat_pixelshuffledirect() {
+    let config = DATConfig {
+        scale: 3,
+        embed_dim: 64,
+        depths: vec![2],
+        num_heads: vec![2],
+        split_size: (2, 2),
+        expansion_factor: 2.,
+        upsampler: Upsampler::PixelShuffleDirect,
+        ..Default::default()
+    };
+    dat("dat_x3_direct", config);
+}
+
+#[test]
+fn cugan_x2() {
+    cugan("cugan_x2", 3, 2, false);
+}
+
+#[test]
+fn cugan_x3() {
+    cugan("cugan_x3", 3, 3, false);
+}
+
+#[test]
+fn cugan_x4() {
+    cugan("cugan_x4", 3, 4, false);
+}
+
+#[test]
+fn cugan_pro_grayscale() {
+    cugan("cugan_x2_pro", 1, 2, true);
+}
+
+#[test]
+fn omnisr_x4() {
+    omnisr(
+        "omnisr_x4",
+        OmniSRConfig {
+            num_feat: 8,
+            res_num: 2,
+            window_size: 4,
+            ..Default::default()
+        },
+    );
+}
+
+#[test]
+fn omnisr_x2_without_position_bias() {
+    omnisr(
+        "omnisr_x2_no_pe",
+        OmniSRConfig {
+            scale: 2,
+            num_feat: 8,
+            res_num: 1,
+            block_num: 2,
+            window_size: 4,
+            pe: false,
+            bias: false,
+            ..Default::default()
+        },
+    );
+}
+
+#[test]
+fn safmn_x4() {
+    safmn("safmn_x4", 3, 4, 8, 2, 2.);
+}
+
+#[test]
+fn safmn_grayscale() {
+    safmn("safmn_x2_gray", 1, 2, 12, 1, 1.5);
+}
+
+#[test]
+fn gfpgan_32() {
+    gfpgan(
+        "gfpgan_32",
+        GFPGANConfig {
+            out_size: 32,
+            num_style_feat: 8,
+            narrow: 3. / 128.,
+            ..Default::default()
+        },
+    );
+}
+
+#[test]
+fn gfpgan_without_half_sft_or_different_styles() {
+    gfpgan(
+        "gfpgan_32_full_sft",
+        GFPGANConfig {
+            out_size: 32,
+            num_style_feat: 8,
+            narrow: 3. / 128.,
+            sft_half: false,
+            different_w: false,
+            ..Default::default()
+        },
+    );
+}
+
+#[test]
+fn retinaface() {
+    let fixture = Fixture::load("retinaface");
+    let weights = &fixture.weights;
+    let config = RetinaFaceConfig {
+        width: retinaface_helpers::get_width(weights),
+        layers: retinaface_helpers::get_layers(weights),
+        out_channels: retinaface_helpers::get_out_channels(weights),
+    };
+    assert_eq!(
+        config,
+        RetinaFaceConfig {
+            width: 3,
+            layers: [1, 1, 1, 1],
+            out_channels: 16,
+        }
+    );
+    let model = RetinaFace::load(fixture.vb(), &config).unwrap();
+    fixture.check(&RetinaFaceOutputs(model));
+}
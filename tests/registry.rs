@@ -0,0 +1,207 @@
+// Detection and loading through the architecture registry, for the built-in
+// architectures and for one registered from outside of the crate.
+
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_nn::{Conv2d, Conv2dConfig, VarBuilder};
+use esrgan_candle_rs::conv::{self, Quantization};
+use esrgan_candle_rs::descriptor::count_params;
+use esrgan_candle_rs::registry::{Architecture, Params, Registry, Upscaler};
+use esrgan_candle_rs::synthetic::SyntheticModel;
+
+/// A small model of `arch`, with two groups of blocks for the transformers
+fn synthetic(arch: &str, in_nc: usize, scale: usize, nf: usize, nb: usize) -> SyntheticModel {
+    SyntheticModel::new(arch)
+        .in_channels(in_nc)
+        .out_channels(in_nc)
+        .scale(scale)
+        .num_features(nf)
+        .num_blocks(nb)
+        .growth_channels(4)
+        .num_groups(2)
+        .num_heads(2)
+        .window_size(4)
+}
+
+fn vb(state_dict: &HashMap<String, Tensor>, dtype: DType) -> VarBuilder<'static> {
+    VarBuilder::from_tensors(state_dict.clone(), dtype, &Device::Cpu)
+}
+
+#[test]
+fn builtin_architectures() {
+    let registry = Registry::builtin();
+    assert_eq!(
+        registry.names(),
+        [
+            "old", "new", "compact", "span", "swinir", "hat", "dat", "srformer", "cugan", "omnisr",
+            "safmn", "spsr"
+        ]
+    );
+    // Name, input channels, scale, features, blocks and the blocks as
+    // detected: the transformers and Omni-SR count them in groups, SPAN
+    // has 6 and Real-CUGAN none
+    let cases = [
+        ("old", 3, 4, 8, 2, 2),
+        ("new", 3, 2, 8, 2, 2),
+        ("compact", 1, 3, 8, 2, 2),
+        ("span", 3, 2, 8, 0, 6),
+        ("swinir", 3, 2, 8, 2, 2),
+        ("hat", 3, 2, 60, 2, 2),
+        ("dat", 1, 3, 64, 3, 2),
+        ("srformer", 3, 4, 12, 2, 2),
+        ("cugan", 3, 2, 4, 0, 0),
+        ("omnisr", 3, 2, 16, 2, 2),
+        ("safmn", 3, 4, 8, 2, 2),
+        ("spsr", 3, 4, 8, 20, 20),
+    ];
+    let state_dicts: Vec<_> = cases
+        .iter()
+        .map(|&(name, in_nc, scale, nf, nb, _)| {
+            let model = synthetic(name, in_nc, scale, nf, nb);
+            // DAT splits its windows in halves
+            let model = match name {
+                "dat" => model.window_size(2),
+                _ => model,
+            };
+            model.state_dict(&Device::Cpu).unwrap()
+        })
+        .collect();
+    for (&(name, in_nc, scale, nf, _, num_blocks), sd) in cases.iter().zip(&state_dicts) {
+        let (arch, params) = registry.detect(sd).unwrap();
+        assert_eq!(arch.name(), name);
+        assert_eq!(params.in_nc, in_nc);
+        assert_eq!(params.out_nc, in_nc);
+        assert_eq!(params.scale, scale);
+        assert_eq!(params.num_features, nf);
+        assert_eq!(params.num_blocks, num_blocks);
+
+        let descriptor = arch.descriptor(sd, &params, &Device::Cpu);
+        assert_eq!(descriptor.arch, name);
+        assert_eq!(descriptor.scale, scale);
+        assert_eq!((descriptor.in_nc, descriptor.out_nc), (in_nc, in_nc));
+        assert_eq!(descriptor.params, count_params(sd));
+        // Receptive fields of 2 blocks, the others see the whole image
+        let (receptive_field, input_multiple) = match name {
+            "old" => (Some(36), 1),
+            "new" => (Some(72), 2),
+            "compact" => (Some(4), 1),
+            "span" => (Some(21), 1),
+            _ => (None, 1),
+        };
+        assert_eq!(descriptor.receptive_field, receptive_field);
+        assert_eq!(descriptor.input_multiple, input_multiple);
+        assert!(!descriptor.dtypes.contains(&DType::BF16));
+
+        // The model runs in every dtype of its descriptor
+        for &dtype in &descriptor.dtypes {
+            let model = arch.load(vb(sd, dtype), sd, &params).unwrap();
+            // Big enough for the spatial attention of Omni-SR, even for the
+            // pixel unshuffle of new-arch x2 models
+            let input = Tensor::rand(0f32, 1., (1, in_nc, 16, 16), &Device::Cpu)
+                .unwrap()
+                .to_dtype(dtype)
+                .unwrap();
+            let output = model.forward(&input).unwrap();
+            assert_eq!(output.dims(), [1, in_nc, 16 * scale, 16 * scale]);
+        }
+    }
+
+    // Detection doesn't depend on the order of the registry, each
+    // architecture only recognises its own models
+    for (i, name) in registry.names().into_iter().enumerate() {
+        let arch = registry.get(name).unwrap();
+        for (j, sd) in state_dicts.iter().enumerate() {
+            assert_eq!(
+                arch.detect(sd).is_some(),
+                i == j,
+                "{name} on {}",
+                cases[j].0
+            );
+        }
+    }
+}
+
+/// A single conv and a pixel shuffle, as a crate using this one could add
+struct PixelShuffleNet {
+    conv: Conv2d,
+    scale: usize,
+}
+
+impl Upscaler for PixelShuffleNet {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        candle_nn::ops::pixel_shuffle(&self.conv.forward(xs)?, self.scale)
+    }
+}
+
+struct PixelShuffleArch;
+
+impl Architecture for PixelShuffleArch {
+    fn name(&self) -> &'static str {
+        "pixel-shuffle"
+    }
+
+    fn detect(&self, state_dict: &HashMap<String, Tensor>) -> Option<Params> {
+        let (c_out, c_in, _, _) = state_dict.get("shuffle_conv.weight")?.dims4().ok()?;
+        let scale = ((c_out / c_in) as f64).sqrt() as usize;
+        Some(Params {
+            in_nc: c_in,
+            out_nc: c_in,
+            scale,
+            num_features: c_in,
+            num_blocks: 0,
+            extra: HashMap::new(),
+        })
+    }
+
+    fn load(
+        &self,
+        vb: VarBuilder,
+        _state_dict: &HashMap<String, Tensor>,
+        params: &Params,
+    ) -> Result<Box<dyn Upscaler>> {
+        let config = Conv2dConfig {
+            padding: 1,
+            stride: 1,
+            dilation: 1,
+            groups: 1,
+        };
+        let c_out = params.out_nc * params.scale * params.scale;
+        let conv = candle_nn::conv2d(params.in_nc, c_out, 3, config, vb.pp("shuffle_conv"))?;
+        Ok(Box::new(PixelShuffleNet {
+            conv,
+            scale: params.scale,
+        }))
+    }
+}
+
+#[test]
+fn custom_architecture() {
+    let mut registry = Registry::builtin();
+    registry.register(PixelShuffleArch);
+    assert!(registry.get("pixel-shuffle").is_some());
+    assert!(registry.get("esrgan").is_none());
+
+    let sd = HashMap::from([
+        (
+            "shuffle_conv.weight".to_string(),
+            Tensor::randn(0f32, 0.1, (3 * 9, 3, 3, 3), &Device::Cpu).unwrap(),
+        ),
+        (
+            "shuffle_conv.bias".to_string(),
+            Tensor::zeros(3 * 9, DType::F32, &Device::Cpu).unwrap(),
+        ),
+    ]);
+    let (arch, params) = registry.detect(&sd).unwrap();
+    assert_eq!(arch.name(), "pixel-shuffle");
+    assert_eq!(params.scale, 3);
+    assert_eq!(
+        arch.descriptor(&sd, &params, &Device::Cpu).params,
+        3 * 9 * 27 + 3 * 9
+    );
+    let mut model = arch.load(vb(&sd, DType::F32), &sd, &params).unwrap();
+    // Models without convs of this crate are left as they are
+    conv::quantize(model.convs_mut(), Quantization::Q8_0).unwrap();
+    let input = Tensor::rand(0f32, 1., (1, 3, 4, 5), &Device::Cpu).unwrap();
+    assert_eq!(model.forward(&input).unwrap().dims(), [1, 3, 12, 15]);
+}
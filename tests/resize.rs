@@ -0,0 +1,63 @@
+// The resize filters on identity and constant images, and the output sizes
+// the size options ask for.
+
+use candle_core::{Device, Tensor};
+use esrgan_candle_rs::resize::{resize, OutputSize, ResizeFilter};
+
+const FILTERS: [ResizeFilter; 4] = [
+    ResizeFilter::Lanczos,
+    ResizeFilter::Bicubic,
+    ResizeFilter::Area,
+    ResizeFilter::Bilinear,
+];
+
+fn max_abs_diff(a: &Tensor, b: &Tensor) -> f32 {
+    (a - b)
+        .unwrap()
+        .abs()
+        .unwrap()
+        .flatten_all()
+        .unwrap()
+        .max(0)
+        .unwrap()
+        .to_scalar::<f32>()
+        .unwrap()
+}
+
+#[test]
+fn same_size_is_identity() {
+    let xs = Tensor::rand(0f32, 1., (1, 3, 9, 14), &Device::Cpu).unwrap();
+    for filter in FILTERS {
+        let out = resize(&xs, 9, 14, filter).unwrap();
+        assert_eq!(max_abs_diff(&out, &xs), 0., "{filter:?}");
+    }
+}
+
+#[test]
+fn constant_images_stay_constant() {
+    let xs = (Tensor::ones((1, 3, 10, 13), candle_core::DType::F32, &Device::Cpu).unwrap() * 0.37)
+        .unwrap();
+    // Up along one side and down along the other
+    for filter in FILTERS {
+        let out = resize(&xs, 23, 7, filter).unwrap();
+        assert_eq!(out.dims(), [1, 3, 23, 7]);
+        let expected = out.ones_like().unwrap().affine(0.37, 0.).unwrap();
+        assert!(max_abs_diff(&out, &expected) < 1e-5, "{filter:?}");
+    }
+}
+
+#[test]
+fn target_sizes() {
+    // Halves round away from zero
+    assert_eq!(OutputSize::Scale(1.5).target(11, 7), (17, 11));
+    assert_eq!(OutputSize::Scale(2.).target(40, 30), (80, 60));
+    // Never smaller than a pixel
+    assert_eq!(OutputSize::Scale(0.01).target(10, 10), (1, 1));
+    assert_eq!(OutputSize::Exact(5, 9).target(40, 30), (5, 9));
+    assert_eq!(OutputSize::Width(100).target(40, 30), (100, 75));
+    assert_eq!(OutputSize::Width(10).target(30, 40), (10, 13));
+    assert_eq!(OutputSize::Height(50).target(40, 30), (67, 50));
+    // The side that fills the box first decides the scale
+    assert_eq!(OutputSize::Fit(100, 100).target(40, 30), (100, 75));
+    assert_eq!(OutputSize::Fit(100, 60).target(40, 30), (80, 60));
+}
@@ -0,0 +1,636 @@
+// Round-trips synthetic checkpoints through architecture and parameter
+// detection, then loads them with the detected parameters.
+
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Module, Tensor};
+use candle_nn::VarBuilder;
+use esrgan_candle_rs::compact::SRVGGNetCompact;
+use esrgan_candle_rs::cugan::UpCunet;
+use esrgan_candle_rs::dat::{DATConfig, DAT};
+use esrgan_candle_rs::gfpgan::{GFPGANConfig, GFPGAN};
+use esrgan_candle_rs::hat::{HATConfig, HAT};
+use esrgan_candle_rs::omnisr::OmniSR;
+use esrgan_candle_rs::registry::Registry;
+use esrgan_candle_rs::retinaface::{self, RetinaFace, RetinaFaceConfig};
+use esrgan_candle_rs::safmn::SAFMN;
+use esrgan_candle_rs::span::SPAN;
+use esrgan_candle_rs::spsr::SPSRNet;
+use esrgan_candle_rs::srformer::SRFormer;
+use esrgan_candle_rs::swinir::{SwinIR, Upsampler};
+use esrgan_candle_rs::synthetic::{gfpgan_state_dict, retinaface_state_dict, SyntheticModel};
+use esrgan_candle_rs::{compact_helpers, new_arch, new_arch_helpers, old_arch, old_arch_helpers};
+use esrgan_candle_rs::{cugan_helpers, dat_helpers, hat_helpers, omnisr_helpers, safmn_helpers};
+use esrgan_candle_rs::{gfpgan_helpers, retinaface_helpers};
+use esrgan_candle_rs::{span_helpers, spsr_helpers};
+use esrgan_candle_rs::{srformer_helpers, swinir_helpers};
+
+struct Params {
+    in_nc: usize,
+    out_nc: usize,
+    scale: usize,
+    nf: usize,
+    nb: usize,
+    gc: usize,
+}
+
+const GRID: [Params; 5] = [
+    Params {
+        in_nc: 3,
+        out_nc: 3,
+        scale: 4,
+        nf: 8,
+        nb: 2,
+        gc: 4,
+    },
+    Params {
+        in_nc: 3,
+        out_nc: 3,
+        scale: 2,
+        nf: 16,
+        nb: 1,
+        gc: 8,
+    },
+    Params {
+        in_nc: 1,
+        out_nc: 1,
+        scale: 1,
+        nf: 4,
+        nb: 3,
+        gc: 2,
+    },
+    Params {
+        in_nc: 4,
+        out_nc: 4,
+        scale: 4,
+        nf: 12,
+        nb: 1,
+        gc: 6,
+    },
+    Params {
+        in_nc: 3,
+        out_nc: 1,
+        scale: 2,
+        nf: 8,
+        nb: 1,
+        gc: 16,
+    },
+];
+
+fn state_dict(arch: &str, p: &Params) -> HashMap<String, Tensor> {
+    SyntheticModel::new(arch)
+        .in_channels(p.in_nc)
+        .out_channels(p.out_nc)
+        .scale(p.scale)
+        .num_features(p.nf)
+        .num_blocks(p.nb)
+        .growth_channels(p.gc)
+        .state_dict(&Device::Cpu)
+        .unwrap()
+}
+
+fn vb(state_dict: &HashMap<String, Tensor>) -> VarBuilder<'static> {
+    VarBuilder::from_tensors(state_dict.clone(), DType::F32, &Device::Cpu)
+}
+
+/// Name of the architecture the registry detects `state_dict` as
+fn detected(state_dict: &HashMap<String, Tensor>) -> &'static str {
+    Registry::builtin().detect(state_dict).unwrap().0.name()
+}
+
+fn check_output(model: &impl Module, p: &Params) {
+    let input = Tensor::rand(0f32, 1., (1, p.in_nc, 8, 12), &Device::Cpu).unwrap();
+    let output = model.forward(&input).unwrap();
+    assert_eq!(output.dims(), [1, p.out_nc, 8 * p.scale, 12 * p.scale]);
+}
+
+#[test]
+fn old_arch_round_trip() {
+    for p in &GRID {
+        let sd = state_dict("old", p);
+        assert_eq!(detected(&sd), "old");
+        assert_eq!(old_arch_helpers::get_in_nc(&sd), p.in_nc);
+        assert_eq!(old_arch_helpers::get_out_nc(&sd), p.out_nc);
+        assert_eq!(old_arch_helpers::get_scale(&sd), p.scale);
+        assert_eq!(old_arch_helpers::get_nf(&sd), p.nf);
+        assert_eq!(old_arch_helpers::get_nb(&sd), p.nb);
+        assert_eq!(old_arch_helpers::get_gc(&sd), p.gc);
+        assert!(!old_arch_helpers::get_plus(&sd));
+        let model =
+            old_arch::RRDBNet::load(vb(&sd), p.in_nc, p.out_nc, p.scale, p.nf, p.nb, p.gc, false)
+                .unwrap();
+        check_output(&model, p);
+    }
+}
+
+#[test]
+fn old_arch_x8() {
+    let p = Params {
+        in_nc: 3,
+        out_nc: 3,
+        scale: 8,
+        nf: 4,
+        nb: 1,
+        gc: 2,
+    };
+    let sd = state_dict("old", &p);
+    assert_eq!(old_arch_helpers::get_scale(&sd), 8);
+    let model = old_arch::RRDBNet::load(vb(&sd), 3, 3, 8, 4, 1, 2, false).unwrap();
+    check_output(&model, &p);
+}
+
+#[test]
+fn new_arch_round_trip() {
+    for p in GRID.iter().filter(|p| p.in_nc == p.out_nc) {
+        let sd = state_dict("new", p);
+        assert_eq!(detected(&sd), "new");
+        assert_eq!(new_arch_helpers::get_in_nc(&sd), p.in_nc);
+        assert_eq!(new_arch_helpers::get_out_nc(&sd), p.out_nc);
+        assert_eq!(new_arch_helpers::get_scale(&sd), p.scale);
+        assert_eq!(new_arch_helpers::get_nf(&sd), p.nf);
+        assert_eq!(new_arch_helpers::get_nb(&sd), p.nb);
+        assert_eq!(new_arch_helpers::get_gc(&sd), p.gc);
+        let model =
+            new_arch::RRDBNet::load(vb(&sd), p.in_nc, p.out_nc, p.scale, p.nf, p.nb, p.gc).unwrap();
+        check_output(&model, p);
+    }
+}
+
+#[test]
+fn new_arch_only_has_x1_x2_and_x4() {
+    for scale in [3, 8] {
+        let synthetic = SyntheticModel::new("new").scale(scale);
+        assert!(synthetic.state_dict(&Device::Cpu).is_err());
+        let sd = state_dict("new", &GRID[0]);
+        assert!(new_arch::RRDBNet::load(vb(&sd), 3, 3, scale, 8, 2, 4).is_err());
+    }
+}
+
+#[test]
+fn compact_round_trip() {
+    // Compact models add the upscaled input to their output, so the channels match
+    for p in GRID.iter().filter(|p| p.in_nc == p.out_nc) {
+        let sd = state_dict("compact", p);
+        assert_eq!(detected(&sd), "compact");
+        assert_eq!(compact_helpers::get_in_nc(&sd), p.in_nc);
+        assert_eq!(compact_helpers::get_scale(&sd), p.scale);
+        assert_eq!(compact_helpers::get_nf(&sd), p.nf);
+        assert_eq!(compact_helpers::get_num_conv(&sd), p.nb);
+        let model = SRVGGNetCompact::load(vb(&sd), p.in_nc, p.out_nc, p.nf, p.nb, p.scale).unwrap();
+        check_output(&model, p);
+    }
+}
+
+#[test]
+fn compact_x3() {
+    let p = Params {
+        in_nc: 3,
+        out_nc: 3,
+        scale: 3,
+        nf: 8,
+        nb: 0,
+        gc: 0,
+    };
+    let sd = state_dict("compact", &p);
+    assert_eq!(compact_helpers::get_scale(&sd), 3);
+    assert_eq!(compact_helpers::get_num_conv(&sd), 0);
+    let model = SRVGGNetCompact::load(vb(&sd), 3, 3, 8, 0, 3).unwrap();
+    check_output(&model, &p);
+}
+
+#[test]
+fn span_round_trip() {
+    for p in GRID.iter().filter(|p| p.in_nc == p.out_nc) {
+        // The input normalisation subtracts an RGB mean
+        let norms: &[bool] = if p.in_nc == 3 {
+            &[true, false]
+        } else {
+            &[false]
+        };
+        for &norm in norms {
+            let sd = SyntheticModel::new("span")
+                .in_channels(p.in_nc)
+                .out_channels(p.out_nc)
+                .scale(p.scale)
+                .num_features(p.nf)
+                .norm(norm)
+                .state_dict(&Device::Cpu)
+                .unwrap();
+            assert_eq!(detected(&sd), "span");
+            assert_eq!(span_helpers::get_in_nc(&sd), p.in_nc);
+            assert_eq!(span_helpers::get_scale(&sd), p.scale);
+            assert_eq!(span_helpers::get_nf(&sd), p.nf);
+            assert_eq!(span_helpers::get_norm(&sd), norm);
+            let model = SPAN::load(vb(&sd), p.in_nc, p.out_nc, p.nf, p.scale, norm).unwrap();
+            check_output(&model, p);
+        }
+    }
+}
+
+#[test]
+fn cugan_round_trip() {
+    for (in_nc, out_nc) in [(3, 3), (1, 1), (3, 1)] {
+        // x4 models add the upscaled input to their output
+        let scales: &[usize] = if in_nc == out_nc { &[2, 3, 4] } else { &[2, 3] };
+        for &scale in scales {
+            let sd = SyntheticModel::new("cugan")
+                .in_channels(in_nc)
+                .out_channels(out_nc)
+                .scale(scale)
+                .num_features(4)
+                .state_dict(&Device::Cpu)
+                .unwrap();
+            assert_eq!(detected(&sd), "cugan");
+            assert_eq!(cugan_helpers::get_in_nc(&sd), in_nc);
+            assert_eq!(cugan_helpers::get_out_nc(&sd), out_nc);
+            assert_eq!(cugan_helpers::get_scale(&sd), scale);
+            assert_eq!(cugan_helpers::get_nf(&sd), 4);
+            assert!(!cugan_helpers::get_pro(&sd));
+            let model = UpCunet::load(vb(&sd), in_nc, out_nc, scale, 4, false).unwrap();
+            // Odd sizes go through the reflect padding to a multiple of 2 or 4
+            let input = Tensor::rand(0f32, 1., (1, in_nc, 9, 13), &Device::Cpu).unwrap();
+            let output = model.forward(&input).unwrap();
+            assert_eq!(output.dims(), [1, out_nc, 9 * scale, 13 * scale]);
+        }
+    }
+}
+
+#[test]
+fn omnisr_round_trip() {
+    for p in GRID.iter().filter(|p| p.in_nc == p.out_nc) {
+        // A multiple of 4 for the heads, 16 keeps the tests fast
+        let nf = p.nf.next_multiple_of(4).min(16);
+        let sd = SyntheticModel::new("omnisr")
+            .in_channels(p.in_nc)
+            .out_channels(p.out_nc)
+            .scale(p.scale)
+            .num_features(nf)
+            .num_blocks(p.nb.min(2))
+            .num_groups(2)
+            .window_size(4)
+            .state_dict(&Device::Cpu)
+            .unwrap();
+        assert_eq!(detected(&sd), "omnisr");
+        let config = omnisr_helpers::get_config(&sd);
+        assert_eq!(config.in_nc, p.in_nc);
+        assert_eq!(config.out_nc, p.out_nc);
+        assert_eq!(config.scale, p.scale);
+        assert_eq!(config.num_feat, nf);
+        assert_eq!(config.res_num, 2);
+        assert_eq!(config.block_num, p.nb.min(2));
+        assert_eq!(config.window_size, 4);
+        assert!(config.pe);
+        assert!(config.bias);
+        let model = OmniSR::load(vb(&sd), &config).unwrap();
+        // The spatial attention of the groups needs 15 pixels, and the
+        // padding to the windows is cropped away
+        let input = Tensor::rand(0f32, 1., (1, p.in_nc, 15, 17), &Device::Cpu).unwrap();
+        let output = model.forward(&input).unwrap();
+        assert_eq!(output.dims(), [1, p.out_nc, 15 * p.scale, 17 * p.scale]);
+    }
+}
+
+#[test]
+fn safmn_round_trip() {
+    for p in GRID.iter().filter(|p| p.in_nc == p.out_nc) {
+        let nf = p.nf.next_multiple_of(4);
+        let sd = SyntheticModel::new("safmn")
+            .in_channels(p.in_nc)
+            .out_channels(p.out_nc)
+            .scale(p.scale)
+            .num_features(nf)
+            .num_blocks(p.nb)
+            .state_dict(&Device::Cpu)
+            .unwrap();
+        assert_eq!(detected(&sd), "safmn");
+        assert_eq!(safmn_helpers::get_in_nc(&sd), p.in_nc);
+        assert_eq!(safmn_helpers::get_scale(&sd), p.scale);
+        assert_eq!(safmn_helpers::get_dim(&sd), nf);
+        assert_eq!(safmn_helpers::get_n_blocks(&sd), p.nb);
+        assert_eq!(safmn_helpers::get_ffn_scale(&sd), 2.);
+        let model = SAFMN::load(vb(&sd), p.in_nc, p.out_nc, nf, p.nb, 2., p.scale).unwrap();
+        check_output(&model, p);
+    }
+}
+
+#[test]
+fn spsr_round_trip() {
+    // The gradient branch takes the features of the first 20 blocks
+    for p in &GRID {
+        let p = Params { nb: 20, ..*p };
+        let sd = state_dict("spsr", &p);
+        assert_eq!(detected(&sd), "spsr");
+        assert_eq!(old_arch_helpers::get_in_nc(&sd), p.in_nc);
+        assert_eq!(spsr_helpers::get_out_nc(&sd), p.out_nc);
+        assert_eq!(spsr_helpers::get_scale(&sd), p.scale);
+        assert_eq!(old_arch_helpers::get_nf(&sd), p.nf);
+        assert_eq!(old_arch_helpers::get_nb(&sd), p.nb);
+        assert_eq!(old_arch_helpers::get_gc(&sd), p.gc);
+        let model = SPSRNet::load(vb(&sd), p.in_nc, p.out_nc, p.scale, p.nf, p.nb, p.gc).unwrap();
+        check_output(&model, &p);
+    }
+    let p = &GRID[0];
+    let sd = state_dict("spsr", p);
+    assert!(SPSRNet::load(vb(&sd), p.in_nc, p.out_nc, p.scale, p.nf, p.nb, p.gc).is_err());
+}
+
+#[test]
+fn swinir_round_trip() {
+    let upsamplers = [
+        (Upsampler::PixelShuffle, [2, 3, 4].as_slice()),
+        (Upsampler::PixelShuffleDirect, &[2, 3]),
+        (Upsampler::NearestConv, &[2, 4]),
+        (Upsampler::None, &[1]),
+    ];
+    for p in GRID.iter().filter(|p| p.in_nc == p.out_nc) {
+        for (upsampler, scales) in upsamplers {
+            for &scale in scales {
+                let sd = SyntheticModel::new("swinir")
+                    .in_channels(p.in_nc)
+                    .out_channels(p.out_nc)
+                    .scale(scale)
+                    .num_features(p.nf)
+                    .num_blocks(p.nb)
+                    .num_groups(2)
+                    .num_heads(2)
+                    .window_size(4)
+                    .upsampler(upsampler)
+                    .state_dict(&Device::Cpu)
+                    .unwrap();
+                assert_eq!(detected(&sd), "swinir");
+                let config = swinir_helpers::get_config(&sd);
+                assert_eq!(config.in_nc, p.in_nc);
+                assert_eq!(config.out_nc, p.out_nc);
+                assert_eq!(config.scale, scale);
+                assert_eq!(config.embed_dim, p.nf);
+                assert_eq!(config.depths, [p.nb, p.nb]);
+                assert_eq!(config.num_heads, [2, 2]);
+                assert_eq!(config.window_size, 4);
+                assert_eq!(config.upsampler, upsampler);
+                let model = SwinIR::load(vb(&sd), &config).unwrap();
+                check_output(&model, &Params { scale, ..*p });
+                // Sizes that aren't a multiple of the window are padded and cropped back
+                let input = Tensor::rand(0f32, 1., (1, p.in_nc, 7, 5), &Device::Cpu).unwrap();
+                let output = model.forward(&input).unwrap();
+                assert_eq!(output.dims(), [1, p.out_nc, 7 * scale, 5 * scale]);
+            }
+        }
+    }
+}
+
+/// Synthetic model of a transformer with two groups of `nb` blocks of two heads
+fn transformer(arch: &str, in_nc: usize, scale: usize, nf: usize, nb: usize) -> SyntheticModel {
+    SyntheticModel::new(arch)
+        .in_channels(in_nc)
+        .out_channels(in_nc)
+        .scale(scale)
+        .num_features(nf)
+        .num_blocks(nb)
+        .num_groups(2)
+        .num_heads(2)
+        .window_size(4)
+}
+
+/// Output sizes for an input of 8x12, and one that isn't a multiple of the windows
+fn check_transformer_output(model: &impl Module, in_nc: usize, scale: usize) {
+    check_output(
+        model,
+        &Params {
+            in_nc,
+            out_nc: in_nc,
+            scale,
+            nf: 0,
+            nb: 0,
+            gc: 0,
+        },
+    );
+    let input = Tensor::rand(0f32, 1., (1, in_nc, 7, 5), &Device::Cpu).unwrap();
+    let output = model.forward(&input).unwrap();
+    assert_eq!(output.dims(), [1, in_nc, 7 * scale, 5 * scale]);
+}
+
+#[test]
+fn hat_round_trip() {
+    for (in_nc, scale, upsampler) in [
+        (3, 4, Upsampler::PixelShuffle),
+        (3, 3, Upsampler::PixelShuffle),
+        (1, 2, Upsampler::PixelShuffleDirect),
+    ] {
+        let sd = transformer("hat", in_nc, scale, 60, 2)
+            .upsampler(upsampler)
+            .state_dict(&Device::Cpu)
+            .unwrap();
+        assert_eq!(detected(&sd), "hat");
+        let config = hat_helpers::get_config(&sd);
+        assert_eq!(config.in_nc, in_nc);
+        assert_eq!(config.scale, scale);
+        assert_eq!(config.embed_dim, 60);
+        assert_eq!(config.depths, [2, 2]);
+        assert_eq!(config.num_heads, [2, 2]);
+        assert_eq!(config.window_size, 4);
+        assert_eq!(config.compress_ratio, 3);
+        assert_eq!(config.squeeze_factor, 30);
+        assert_eq!(config.overlap_ratio, 0.5);
+        assert_eq!(config.upsampler, upsampler);
+        let model = HAT::load(vb(&sd), &config).unwrap();
+        check_transformer_output(&model, in_nc, scale);
+    }
+}
+
+#[test]
+fn dat_round_trip() {
+    for (in_nc, scale, upsampler) in [
+        (3, 2, Upsampler::PixelShuffle),
+        (1, 3, Upsampler::PixelShuffleDirect),
+    ] {
+        // Three blocks, so that both groups have a shifted spatial attention
+        let sd = transformer("dat", in_nc, scale, 64, 3)
+            .window_size(2)
+            .upsampler(upsampler)
+            .state_dict(&Device::Cpu)
+            .unwrap();
+        assert_eq!(detected(&sd), "dat");
+        let config = dat_helpers::get_config(&sd);
+        assert_eq!(config.in_nc, in_nc);
+        assert_eq!(config.scale, scale);
+        assert_eq!(config.embed_dim, 64);
+        assert_eq!(config.depths, [3, 3]);
+        assert_eq!(config.num_heads, [2, 2]);
+        assert_eq!(config.split_size, (2, 8));
+        assert_eq!(config.expansion_factor, 4.);
+        assert_eq!(config.upsampler, upsampler);
+        let model = DAT::load(vb(&sd), &config).unwrap();
+        check_transformer_output(&model, in_nc, scale);
+    }
+}
+
+#[test]
+fn srformer_round_trip() {
+    for (in_nc, scale, upsampler) in [
+        (3, 4, Upsampler::PixelShuffle),
+        (3, 2, Upsampler::NearestConv),
+        (1, 3, Upsampler::PixelShuffleDirect),
+    ] {
+        let sd = transformer("srformer", in_nc, scale, 12, 2)
+            .upsampler(upsampler)
+            .state_dict(&Device::Cpu)
+            .unwrap();
+        assert_eq!(detected(&sd), "srformer");
+        let config = srformer_helpers::get_config(&sd);
+        assert_eq!(config.in_nc, in_nc);
+        assert_eq!(config.scale, scale);
+        assert_eq!(config.embed_dim, 12);
+        assert_eq!(config.depths, [2, 2]);
+        assert_eq!(config.num_heads, [2, 2]);
+        assert_eq!(config.window_size, 4);
+        assert_eq!(config.upsampler, upsampler);
+        let model = SRFormer::load(vb(&sd), &config).unwrap();
+        check_transformer_output(&model, in_nc, scale);
+    }
+}
+
+#[test]
+fn transformer_variants() {
+    let hat_s = HATConfig {
+        embed_dim: 144,
+        compress_ratio: 24,
+        squeeze_factor: 24,
+        ..Default::default()
+    };
+    assert_eq!(hat_s.variant(), "hat-s");
+    assert_eq!(HATConfig::default().variant(), "hat-m");
+    let hat_l = HATConfig {
+        depths: vec![6; 12],
+        num_heads: vec![6; 12],
+        ..Default::default()
+    };
+    assert_eq!(hat_l.variant(), "hat-l");
+
+    assert_eq!(DATConfig::default().variant(), "dat");
+    let dat_s = DATConfig {
+        split_size: (8, 16),
+        expansion_factor: 2.,
+        ..Default::default()
+    };
+    assert_eq!(dat_s.variant(), "dat-s");
+    let dat_light = DATConfig {
+        embed_dim: 60,
+        depths: vec![18],
+        num_heads: vec![6],
+        upsampler: Upsampler::PixelShuffleDirect,
+        ..Default::default()
+    };
+    assert_eq!(dat_light.variant(), "dat-light");
+}
+
+#[test]
+fn gfpgan_round_trip() {
+    let configs = [
+        GFPGANConfig {
+            out_size: 32,
+            num_style_feat: 8,
+            narrow: 1. / 32.,
+            ..Default::default()
+        },
+        GFPGANConfig {
+            out_size: 64,
+            num_style_feat: 16,
+            channel_multiplier: 1,
+            narrow: 1. / 64.,
+            sft_half: false,
+            different_w: false,
+        },
+    ];
+    for config in configs {
+        let sd = gfpgan_state_dict(&config, &Device::Cpu).unwrap();
+        let detected = GFPGANConfig {
+            out_size: gfpgan_helpers::get_out_size(&sd),
+            num_style_feat: gfpgan_helpers::get_num_style_feat(&sd),
+            channel_multiplier: gfpgan_helpers::get_channel_multiplier(&sd),
+            narrow: gfpgan_helpers::get_narrow(&sd),
+            sft_half: gfpgan_helpers::get_sft_half(&sd),
+            different_w: gfpgan_helpers::get_different_w(&sd),
+        };
+        assert_eq!(detected, config);
+        let model = GFPGAN::load(vb(&sd), &config).unwrap();
+        let size = config.out_size;
+        let input = Tensor::rand(-1f32, 1., (1, 3, size, size), &Device::Cpu).unwrap();
+        assert_eq!(model.forward(&input).unwrap().dims(), [1, 3, size, size]);
+    }
+}
+
+#[test]
+fn retinaface_round_trip() {
+    let config = RetinaFaceConfig {
+        width: 4,
+        layers: [1, 2, 1, 1],
+        out_channels: 16,
+    };
+    let sd = retinaface_state_dict(&config, &Device::Cpu).unwrap();
+    assert_eq!(retinaface_helpers::get_width(&sd), config.width);
+    assert_eq!(retinaface_helpers::get_layers(&sd), config.layers);
+    assert_eq!(
+        retinaface_helpers::get_out_channels(&sd),
+        config.out_channels
+    );
+    let model = RetinaFace::load(vb(&sd), &config).unwrap();
+    // One prediction per anchor, for sizes that aren't multiples of the strides
+    let input = Tensor::rand(0f32, 1., (1, 3, 45, 70), &Device::Cpu).unwrap();
+    let (loc, conf, landmarks) = model.forward_raw(&input).unwrap();
+    let anchors = retinaface::priors(45, 70).len();
+    assert_eq!(loc.dims(), [1, anchors, 4]);
+    assert_eq!(conf.dims(), [1, anchors, 2]);
+    assert_eq!(landmarks.dims(), [1, anchors, 10]);
+}
+
+#[test]
+fn default_hyperparameters() {
+    let sd = state_dict_of("old");
+    assert_eq!(old_arch_helpers::get_nb(&sd), 23);
+    assert_eq!(old_arch_helpers::get_nf(&sd), 64);
+    let sd = state_dict_of("compact");
+    assert_eq!(compact_helpers::get_num_conv(&sd), 16);
+    let sd = state_dict_of("span");
+    assert_eq!(span_helpers::get_nf(&sd), 48);
+    let sd = state_dict_of("cugan");
+    assert_eq!(cugan_helpers::get_nf(&sd), 32);
+    assert_eq!(cugan_helpers::get_scale(&sd), 4);
+    let config = omnisr_helpers::get_config(&state_dict_of("omnisr"));
+    assert_eq!(config.num_feat, 64);
+    assert_eq!(config.res_num, 5);
+    assert_eq!(config.block_num, 1);
+    assert_eq!(config.window_size, 8);
+    let sd = state_dict_of("safmn");
+    assert_eq!(safmn_helpers::get_dim(&sd), 36);
+    assert_eq!(safmn_helpers::get_n_blocks(&sd), 8);
+    let sd = state_dict_of("spsr");
+    assert_eq!(old_arch_helpers::get_nb(&sd), 23);
+    assert_eq!(spsr_helpers::get_scale(&sd), 4);
+    let config = swinir_helpers::get_config(&state_dict_of("swinir"));
+    assert_eq!(config.embed_dim, 180);
+    assert_eq!(config.depths, [6; 6]);
+    assert_eq!(config.num_heads, [6; 6]);
+    assert_eq!(config.window_size, 8);
+    assert_eq!(config.mlp_ratio, 2.);
+    assert_eq!(config.upsampler, Upsampler::PixelShuffle);
+    assert_eq!(config.scale, 4);
+    let config = hat_helpers::get_config(&state_dict_of("hat"));
+    assert_eq!(config.variant(), "hat-m");
+    assert_eq!(config.window_size, 16);
+    let config = dat_helpers::get_config(&state_dict_of("dat"));
+    assert_eq!(config.variant(), "dat");
+    assert_eq!(config.split_size, (8, 32));
+    let config = srformer_helpers::get_config(&state_dict_of("srformer"));
+    assert_eq!(esrgan_candle_rs::srformer::variant(&config), "srformer");
+    assert_eq!(config.window_size, 24);
+    let sd = HashMap::new();
+    assert_eq!(
+        retinaface_helpers::get_layers(&sd),
+        RetinaFaceConfig::default().layers
+    );
+    assert_eq!(gfpgan_helpers::get_channel_multiplier(&sd), 2);
+}
+
+fn state_dict_of(arch: &str) -> HashMap<String, Tensor> {
+    SyntheticModel::new(arch).state_dict(&Device::Cpu).unwrap()
+}
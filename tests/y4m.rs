@@ -0,0 +1,111 @@
+// YUV4MPEG2 streams built by hand go through the reader and the writer and
+// come back the same, for every chroma layout and both colour matrices.
+
+use std::io::Cursor;
+
+use esrgan_candle_rs::y4m::{Reader, Writer};
+use image::{Rgb, RgbImage};
+
+/// A stream of `frames` frames, with one chroma value for every chroma sample
+/// so that resampling it doesn't lose anything, and values that stay inside
+/// of the RGB gamut
+fn stream(
+    tags: &str,
+    width: usize,
+    height: usize,
+    chroma: (usize, usize),
+    frames: usize,
+) -> Vec<u8> {
+    let mut data = format!("YUV4MPEG2 W{width} H{height} {tags}\n").into_bytes();
+    for f in 0..frames {
+        data.extend_from_slice(b"FRAME\n");
+        data.extend((0..width * height).map(|i| (90 + (i * 7 + f * 13) % 80) as u8));
+        let (cw, ch) = chroma;
+        data.extend((0..cw * ch).map(|i| (118 + (i * 5 + f) % 20) as u8));
+        data.extend((0..cw * ch).map(|i| (118 + (i * 3 + f) % 20) as u8));
+    }
+    data
+}
+
+fn round_trip(data: &[u8]) -> Vec<u8> {
+    let mut reader = Reader::new(Cursor::new(data)).unwrap();
+    let mut out = vec![];
+    let mut writer = Writer::new(&mut out, reader.header.clone()).unwrap();
+    while let Some(frame) = reader.next_frame().unwrap() {
+        writer.write_frame(&frame).unwrap();
+    }
+    drop(writer);
+    out
+}
+
+#[test]
+fn round_trips() {
+    let cases = [
+        ("C420jpeg F25:1 Ip", (3, 2)),
+        ("C422 F30000:1001", (3, 4)),
+        ("C444 XCOLORRANGE=FULL", (6, 4)),
+        ("Cmono", (0, 0)),
+    ];
+    for (tags, chroma) in cases {
+        let data = stream(tags, 6, 4, chroma, 2);
+        let out = round_trip(&data);
+        assert_eq!(out.len(), data.len(), "{tags}");
+        let header_len = data.iter().position(|&b| b == b'\n').unwrap();
+        assert_eq!(out[..header_len], data[..header_len], "{tags}");
+        // Off by one at most from the rounding of the RGB frames
+        for (i, (a, b)) in out.iter().zip(&data).enumerate() {
+            assert!(
+                a.abs_diff(*b) <= 1,
+                "{tags}: byte {i} is {a} instead of {b}"
+            );
+        }
+    }
+}
+
+/// The first pixel of a full range 4:4:4 frame of `height` rows, with a luma
+/// of 128 and a red difference of 72
+fn red_difference(height: usize) -> [u8; 3] {
+    let mut data = format!("YUV4MPEG2 W1 H{height} C444 XCOLORRANGE=FULL\nFRAME\n").into_bytes();
+    data.extend(std::iter::repeat(128).take(height));
+    data.extend(std::iter::repeat(128).take(height));
+    data.extend(std::iter::repeat(200).take(height));
+    let mut reader = Reader::new(Cursor::new(data)).unwrap();
+    reader.next_frame().unwrap().unwrap().get_pixel(0, 0).0
+}
+
+/// The luma written for a pure red frame of `height` rows
+fn red_luma(height: usize) -> u8 {
+    let header = format!("YUV4MPEG2 W1 H{height} C444 XCOLORRANGE=FULL\n");
+    let header = Reader::new(Cursor::new(header)).unwrap().header;
+    let mut out = vec![];
+    let img = RgbImage::from_pixel(1, height as u32, Rgb([255, 0, 0]));
+    Writer::new(&mut out, header)
+        .unwrap()
+        .write_frame(&img)
+        .unwrap();
+    let frame = out.windows(6).position(|w| w == b"FRAME\n").unwrap() + 6;
+    out[frame]
+}
+
+#[test]
+fn hd_streams_use_bt709() {
+    // BT.601: R = Y + 1.402 Pr, BT.709: R = Y + 1.5748 Pr
+    assert_eq!(red_difference(16)[0], 229);
+    assert_eq!(red_difference(720)[0], 241);
+    // 0.299 and 0.2126 of 255
+    assert_eq!(red_luma(16), 76);
+    assert_eq!(red_luma(720), 54);
+}
+
+#[test]
+fn unsupported_headers_are_errors() {
+    for header in [
+        "YUV4MPEG2 W4 H4 C420p10\n",
+        "YUV4MPEG2 W4 H4 Cyuva\n",
+        "YUV4MPEG2 H4\n",
+        "YUV4MPEG2 Ç4 H4\n",
+        "RIFF\n",
+    ] {
+        assert!(Reader::new(Cursor::new(header)).is_err(), "{header}");
+    }
+}